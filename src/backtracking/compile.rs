@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use common::{BigInts, Clause, Functor, Structure, Variable};
+use flat::{self, Instruction as FlatInstruction};
+
+use super::control::Instruction;
+
+/// Compiles a program into a series of instructions. Also returns a list of
+/// labels. Big integers in the program are added to the given table.
+///
+/// Clauses with the same functor are grouped into a single procedure, in the
+/// order they appear in the program, and chained together with choice point
+/// instructions.
+pub fn compile_program(
+    program: &[Clause],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, HashMap<Functor, usize>) {
+    let mut procedures: Vec<(Functor, Vec<&Clause>)> = Vec::new();
+    for clause in program {
+        let functor = clause.0.functor();
+        if let Some(procedure) =
+            procedures.iter_mut().find(|&&mut (f, _)| f == functor)
+        {
+            procedure.1.push(clause);
+            continue;
+        }
+        procedures.push((functor, vec![clause]));
+    }
+
+    let mut code = Vec::new();
+    let mut labels = HashMap::new();
+    for (functor, clauses) in procedures {
+        labels.insert(functor, code.len());
+
+        let last = clauses.len() - 1;
+        for (i, clause) in clauses.into_iter().enumerate() {
+            let clause_code =
+                compile_cuts(flat::compile_clause(clause, big_ints));
+
+            // The address of the next clause, after this clause's choice
+            // instruction and code.
+            let next = code.len() + clause_code.len() + 1;
+            if last == 0 {
+                // A procedure with a single clause needs no choice point.
+            } else if i == 0 {
+                code.push(Instruction::TryMeElse(next));
+            } else if i < last {
+                code.push(Instruction::RetryMeElse(next));
+            } else {
+                code.push(Instruction::TrustMe);
+            }
+            code.extend(clause_code);
+        }
+    }
+
+    (code, labels)
+}
+
+/// Compiles a query into a series of instructions. Also returns a list of
+/// variable assignments. Big integers in the query are added to the given
+/// table.
+pub fn compile_query(
+    query: &[Structure],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, Vec<Variable>) {
+    let (code, vars) = flat::compile_query(query, big_ints);
+    (compile_cuts(code), vars)
+}
+
+/// Replaces the calls to `!/0` in M<sub>2</sub> code with cut instructions.
+///
+/// A cut before the first call in a clause can cut back to the value `b` had
+/// when the clause's procedure was called. Later cuts need that value to be
+/// saved in an extra permanent variable when the environment is allocated.
+fn compile_cuts(code: Vec<FlatInstruction>) -> Vec<Instruction> {
+    let cut = Functor("!".into(), 0);
+    let is_call = |instr: &FlatInstruction| match *instr {
+        FlatInstruction::Call(f) => f != cut,
+        _ => false,
+    };
+    let deep = code.iter()
+        .skip_while(|instr| !is_call(instr))
+        .any(|&instr| instr == FlatInstruction::Call(cut));
+
+    let mut out = Vec::with_capacity(code.len() + 1);
+    let mut level = None;
+    let mut seen_call = false;
+    for instr in code {
+        match instr {
+            FlatInstruction::Allocate(n) if deep && level.is_none() => {
+                out.push(Instruction::Flat(FlatInstruction::Allocate(n + 1)));
+                out.push(Instruction::GetLevel(n));
+                level = Some(n);
+            }
+            FlatInstruction::Call(f) if f == cut => {
+                out.push(match level {
+                    Some(n) if seen_call => Instruction::Cut(n),
+                    _ => Instruction::NeckCut,
+                });
+            }
+            FlatInstruction::Call(_) => {
+                seen_call = true;
+                out.push(Instruction::Flat(instr));
+            }
+            _ => out.push(Instruction::Flat(instr)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Clause;
+    use flat::Location;
+    use super::*;
+
+    #[test]
+    fn compiles_procedures() {
+        let program = vec![
+            Clause::parse("p(a).").unwrap(),
+            Clause::parse("q(a).").unwrap(),
+            Clause::parse("p(b).").unwrap(),
+            Clause::parse("p(c).").unwrap(),
+        ];
+        let get = |f| {
+            Instruction::Flat(FlatInstruction::GetStructure(
+                f,
+                Location::Register(0),
+            ))
+        };
+        let proceed = Instruction::Flat(FlatInstruction::Proceed);
+        assert_eq!(
+            compile_program(&program, &mut BigInts::new()),
+            (
+                vec![
+                    Instruction::TryMeElse(3),
+                    get(functor!(a / 0)),
+                    proceed,
+                    Instruction::RetryMeElse(6),
+                    get(functor!(b / 0)),
+                    proceed,
+                    Instruction::TrustMe,
+                    get(functor!(c / 0)),
+                    proceed,
+                    get(functor!(a / 0)),
+                    proceed,
+                ],
+                vec![(functor!(p / 1), 0), (functor!(q / 1), 9)]
+                    .into_iter()
+                    .collect(),
+            )
+        );
+    }
+
+    #[test]
+    fn compiles_cuts() {
+        let program = vec![
+            Clause::parse("p(X) :- !, q(X), !, r.").unwrap(),
+        ];
+        let flat = |instr| Instruction::Flat(instr);
+        assert_eq!(
+            compile_program(&program, &mut BigInts::new()).0,
+            vec![
+                flat(FlatInstruction::Allocate(2)),
+                Instruction::GetLevel(1),
+                flat(FlatInstruction::GetVariable(Location::Local(0), 0)),
+                Instruction::NeckCut,
+                flat(FlatInstruction::PutValue(Location::Local(0), 0)),
+                flat(FlatInstruction::Call(functor!(q / 1))),
+                Instruction::Cut(1),
+                flat(FlatInstruction::Call(functor!(r / 0))),
+                flat(FlatInstruction::Deallocate),
+            ]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use flat::Instruction as FlatInstruction;
+
+/// A single M<sub>3</sub> instruction.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// An instruction carried over from M<sub>2</sub>. These behave as they
+    /// do in M<sub>2</sub>, except that bindings are trailed, environments
+    /// are placed above any choice points, and failure backtracks.
+    Flat(FlatInstruction),
+
+    /// Creates a choice point whose next alternative clause is at the given
+    /// address, then proceeds with the following instruction.
+    TryMeElse(usize),
+
+    /// Restores the state saved in the current choice point, updates its next
+    /// alternative clause to the given address, and proceeds with the
+    /// following instruction.
+    RetryMeElse(usize),
+
+    /// Restores the state saved in the current choice point, discards it, and
+    /// proceeds with the following instruction.
+    TrustMe,
+
+    /// Discards any choice points created since the current procedure was
+    /// called. Only valid before the first call in a clause.
+    NeckCut,
+
+    /// Stores the choice point to cut back to in the numbered permanent
+    /// variable.
+    GetLevel(usize),
+
+    /// Discards any choice points created since the choice point stored in
+    /// the numbered permanent variable.
+    Cut(usize),
+}
+
+impl Display for Instruction {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Instruction::Flat(instr) => Display::fmt(&instr, fmt),
+
+            Instruction::TryMeElse(l) => write!(fmt, "try_me_else {}", l),
+            Instruction::RetryMeElse(l) => {
+                write!(fmt, "retry_me_else {}", l)
+            }
+            Instruction::TrustMe => fmt.write_str("trust_me"),
+
+            Instruction::NeckCut => fmt.write_str("neck_cut"),
+            Instruction::GetLevel(n) => write!(fmt, "get_level Y{}", n),
+            Instruction::Cut(n) => write!(fmt, "cut Y{}", n),
+        }
+    }
+}
+
+impl From<FlatInstruction> for Instruction {
+    fn from(instr: FlatInstruction) -> Instruction {
+        Instruction::Flat(instr)
+    }
+}
@@ -0,0 +1,1301 @@
+//! M<sub>3</sub>, an extension of M<sub>2</sub> that allows for disjunctions
+//! via backtracking.
+
+mod compile;
+mod control;
+mod store;
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use failure::Error;
+
+use common::{BigInts, Cells, Clause, Constant, Extractor, Functor, HeapCell,
+             Interrupted, MachineError, Structure, Term, Unknown, Variable};
+use common::builtins::{record_variables, wam_only, Args, Builtin, Builtins,
+                       Redo};
+use flat::{Instruction as FlatInstruction, Location};
+
+pub use self::control::Instruction;
+pub use self::compile::{compile_program, compile_query};
+use self::store::{Heap, Registers, Stack};
+
+/// The value of the `e` and `b` registers when there is no environment or
+/// choice point, respectively.
+const NONE: usize = usize::MAX;
+
+/// The next alternative of a choice point created by a nondeterministic
+/// built-in predicate, whose further solutions come from Rust rather than
+/// code.
+const REDO: usize = usize::MAX;
+
+/// An abstract machine for M<sub>3</sub>.
+///
+/// Goals can't be called from terms, so `call/1` and `catch/3` are not
+/// supported, and calling either raises an error that ends the query, unless
+/// the program defines it.
+#[derive(Debug)]
+pub struct Machine {
+    /// All stored code.
+    code: Vec<Instruction>,
+
+    /// The address at which the code for the current query starts. Everything
+    /// before it is program code.
+    query_start: usize,
+
+    /// All code labels.
+    labels: HashMap<Functor, usize>,
+
+    /// The built-in predicates, which calls check before user code.
+    builtins: Builtins,
+
+    /// What to do when a procedure with no clauses is called.
+    unknown: Unknown,
+
+    /// Whether unification fails rather than binding a variable to a term
+    /// that contains it.
+    occurs_check: bool,
+
+    /// Set from outside the machine to interrupt the running query.
+    interrupt: Arc<AtomicBool>,
+
+    /// The remaining solutions of calls to nondeterministic built-in
+    /// predicates, by the index of the choice point each call created.
+    redos: HashMap<usize, Redo>,
+
+    /// The instruction pointer.
+    p: usize,
+
+    /// The stored instruction pointer, aka the continuation point.
+    cp: usize,
+
+    /// The index of the current environment frame on the stack.
+    e: usize,
+
+    /// The index of the most recent choice point on the stack.
+    b: usize,
+
+    /// The value of `b` when the current procedure was called, which is the
+    /// choice point that a cut discards back to.
+    b0: usize,
+
+    /// The heap address at the time the most recent choice point was created.
+    /// Bindings to variables below it must be trailed.
+    hb: usize,
+
+    /// The unification pointer.
+    s: usize,
+
+    /// The arity of the most recently called procedure, which is the number
+    /// of argument registers a choice point needs to save.
+    num_args: usize,
+
+    /// Whether unification failed, and there were no choice points left to
+    /// backtrack to.
+    fail: bool,
+
+    /// An error raised by a built-in predicate, which ends the query.
+    error: Option<Error>,
+
+    /// Whether the machine is in write mode.
+    write_mode: bool,
+
+    /// The registers.
+    registers: Registers,
+
+    /// The stack, which holds both environment frames and choice points.
+    ///
+    /// An environment frame consists of the previous value of `e`, the
+    /// previous value of `cp`, the number of permanent variables, and the
+    /// contents of the permanent variables, in that order.
+    ///
+    /// A choice point consists of the number of saved arguments, the saved
+    /// arguments, and the saved values of `e`, `cp`, `b`, the address of the
+    /// next alternative clause, the trail pointer, and the heap pointer, in
+    /// that order.
+    stack: Stack,
+
+    /// The trail, which holds the addresses of bindings to undo when
+    /// backtracking.
+    trail: Vec<usize>,
+
+    /// The heap.
+    heap: Heap,
+}
+
+impl Machine {
+    /// Compiles a set of clauses into a program.
+    pub fn new(program: &[Clause]) -> Machine {
+        let mut big_ints = BigInts::new();
+        let (code, labels) = compile_program(program, &mut big_ints);
+        Machine::with_code(code, labels, big_ints)
+    }
+
+    /// Creates a new Machine containing the given code and labels, and the
+    /// big integers the code refers to.
+    pub fn with_code(
+        code: Vec<Instruction>,
+        labels: HashMap<Functor, usize>,
+        big_ints: BigInts,
+    ) -> Machine {
+        Machine {
+            query_start: code.len(),
+            code,
+            labels,
+            builtins: Builtins::new(),
+            unknown: Unknown::default(),
+            occurs_check: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            redos: HashMap::new(),
+            p: 0,
+            cp: 0,
+            e: NONE,
+            b: NONE,
+            b0: NONE,
+            hb: 0,
+            s: 0,
+            num_args: 0,
+            fail: false,
+            error: None,
+            write_mode: false,
+            registers: Registers::new(),
+            stack: Stack::new(),
+            trail: Vec::new(),
+            heap: Heap::new(big_ints),
+        }
+    }
+
+    /// Resets the state of the machine, unloading any query code.
+    pub fn reset(&mut self) {
+        self.code.truncate(self.query_start);
+        self.p = 0;
+        self.cp = 0;
+        self.e = NONE;
+        self.b = NONE;
+        self.b0 = NONE;
+        self.hb = 0;
+        self.num_args = 0;
+        self.fail = false;
+        self.error = None;
+        self.write_mode = false;
+        self.registers.reset();
+        self.stack.reset();
+        self.trail.clear();
+        self.heap.reset();
+        self.redos.clear();
+    }
+
+    /// Runs a single instruction. Returns whether the query just succeeded,
+    /// which is signalled by control reaching the end of the loaded code.
+    pub fn run_instruction(
+        &mut self,
+        instr: Instruction,
+    ) -> Result<bool, MachineError> {
+        trace!("{}", instr);
+        match instr {
+            Instruction::Flat(FlatInstruction::Call(_))
+            | Instruction::Flat(FlatInstruction::Proceed)
+            | Instruction::Flat(FlatInstruction::Deallocate) => {}
+            _ => self.p += 1,
+        }
+        match instr {
+            Instruction::Flat(instr) => self.run_flat_instruction(instr)?,
+
+            Instruction::TryMeElse(l) => self.push_choice_point(l)?,
+            Instruction::RetryMeElse(l) => {
+                let n = self.restore_choice_point()?;
+                self.stack.set(self.b + n + 4, l)?;
+            }
+            Instruction::TrustMe => {
+                let n = self.restore_choice_point()?;
+                let b = self.stack.get(self.b + n + 3)?;
+                self.set_b(b)?;
+            }
+
+            Instruction::NeckCut => {
+                let b0 = self.b0;
+                self.cut(b0)?;
+            }
+            Instruction::GetLevel(n) => {
+                let b0 = self.b0;
+                self.write(Location::Local(n), b0)?;
+            }
+            Instruction::Cut(n) => {
+                let b0 = self.read(Location::Local(n))?;
+                self.cut(b0)?;
+            }
+        }
+        if self.fail {
+            self.backtrack()?;
+        }
+        Ok(self.succeeded())
+    }
+
+    /// Returns whether the query has succeeded, which is signalled by control
+    /// reaching the end of the loaded code.
+    fn succeeded(&self) -> bool {
+        !self.fail && self.error.is_none() && self.p == self.code.len()
+    }
+
+    /// Runs an instruction carried over from M<sub>2</sub>.
+    fn run_flat_instruction(
+        &mut self,
+        instr: FlatInstruction,
+    ) -> Result<(), MachineError> {
+        match instr {
+            FlatInstruction::GetStructure(functor, loc) => {
+                let addr = self.heap.deref(self.read(loc)?)?;
+                match self.heap.get(addr)? {
+                    HeapCell::Ref(_) => {
+                        let n = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
+                        self.heap.alloc(HeapCell::Functor(functor));
+                        self.bind(addr, n)?;
+                        self.write_mode = true;
+                    }
+                    HeapCell::Str(a)
+                        if self.heap.get_functor(a)? == functor =>
+                    {
+                        self.s = a + 1;
+                        self.write_mode = false;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s = 0;
+                        self.fail = true;
+                    }
+                }
+            }
+
+            FlatInstruction::GetList(loc) => {
+                let addr = self.heap.deref(self.read(loc)?)?;
+                match self.heap.get(addr)? {
+                    HeapCell::Ref(_) => {
+                        let n = self.heap.alloc_with(|n| HeapCell::Lis(n + 1));
+                        self.bind(addr, n)?;
+                        self.write_mode = true;
+                    }
+                    HeapCell::Lis(a) => {
+                        self.s = a;
+                        self.write_mode = false;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s = 0;
+                        self.fail = true;
+                    }
+                }
+            }
+
+            FlatInstruction::GetConstant(c, loc) => {
+                let addr = self.read(loc)?;
+                self.unify_constant(addr, c)?;
+            }
+
+            FlatInstruction::PutStructure(functor, loc) => {
+                let n = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
+                self.heap.alloc(HeapCell::Functor(functor));
+                self.write(loc, n)?;
+            }
+            FlatInstruction::PutList(loc) => {
+                let n = self.heap.alloc_with(|n| HeapCell::Lis(n + 1));
+                self.write(loc, n)?;
+            }
+            FlatInstruction::PutConstant(c, loc) => {
+                let addr = self.heap.alloc(c.cell());
+                self.write(loc, addr)?;
+            }
+            FlatInstruction::GetValue(loc, reg) => {
+                let a1 = self.read(loc)?;
+                let a2 = self.registers.get(reg)?;
+                self.unify(a1, a2)?;
+            }
+            FlatInstruction::GetVariable(loc, reg) => {
+                let addr = self.registers.get(reg)?;
+                self.write(loc, addr)?;
+            }
+
+            FlatInstruction::PutValue(loc, reg) => {
+                self.registers[reg] = self.read(loc)?;
+            }
+            FlatInstruction::PutVariable(loc, reg) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.write(loc, addr)?;
+                self.registers[reg] = addr;
+            }
+
+            FlatInstruction::SetValue(loc) => {
+                let val = self.heap.get(self.read(loc)?)?;
+                self.heap.alloc(val);
+            }
+            FlatInstruction::SetVariable(loc) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.write(loc, addr)?;
+            }
+            FlatInstruction::SetConstant(c) => {
+                self.heap.alloc(c.cell());
+            }
+
+            FlatInstruction::UnifyValue(loc) => {
+                if self.write_mode {
+                    let val = self.heap.get(self.read(loc)?)?;
+                    self.heap.alloc(val);
+                } else {
+                    let a1 = self.read(loc)?;
+                    let a2 = self.s;
+                    self.unify(a1, a2)?;
+                }
+                self.s += 1;
+            }
+            FlatInstruction::UnifyVariable(loc) => {
+                let addr = if self.write_mode {
+                    self.heap.alloc_with(HeapCell::Ref)
+                } else {
+                    self.s
+                };
+                self.write(loc, addr)?;
+                self.s += 1;
+            }
+            FlatInstruction::UnifyConstant(c) => {
+                if self.write_mode {
+                    self.heap.alloc(c.cell());
+                } else {
+                    let addr = self.s;
+                    self.unify_constant(addr, c)?;
+                }
+                self.s += 1;
+            }
+
+            FlatInstruction::Call(f) => {
+                if let Some(builtin) = self.builtins.get(f) {
+                    let next = self.p + 1;
+                    self.call_builtin(builtin, f.1, next);
+                } else if let Some(&addr) = self.labels.get(&f) {
+                    self.cp = self.p + 1;
+                    self.b0 = self.b;
+                    self.num_args = f.1;
+                    self.p = addr;
+                } else {
+                    self.call_unknown(f);
+                }
+            }
+            FlatInstruction::Proceed => {
+                self.p = self.cp;
+            }
+
+            FlatInstruction::Allocate(n) => {
+                let e = self.stack_top()?;
+                self.stack.truncate(e);
+                self.stack.push(self.e);
+                self.stack.push(self.cp);
+                self.stack.push(n);
+                for _ in 0..n {
+                    self.stack.push(usize::MAX);
+                }
+                self.e = e;
+            }
+            FlatInstruction::Deallocate => {
+                let e = self.e;
+                self.p = self.stack.get(e + 1)?;
+                self.cp = self.stack.get(e + 1)?;
+                self.e = self.stack.get(e)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a value from the given location. Returns a heap address.
+    pub fn read(&self, loc: Location) -> Result<usize, MachineError> {
+        match loc {
+            Location::Register(n) => self.registers.get(n),
+            Location::Local(n) => self.stack.get(self.e + n + 3),
+        }
+    }
+
+    /// Runs a single instruction, based on the current instruction pointer.
+    /// Returns whether the query just succeeded.
+    pub fn step(&mut self) -> Result<bool, MachineError> {
+        match self.code.get(self.p) {
+            Some(&instr) => self.run_instruction(instr),
+            None => Err(MachineError::IpOutOfRange(self.p)),
+        }
+    }
+
+    /// Resumes execution at the next alternative clause of the most recent
+    /// choice point. If there are no choice points, the machine fails.
+    pub fn backtrack(&mut self) -> Result<(), MachineError> {
+        loop {
+            if self.b == NONE {
+                self.fail = true;
+                return Ok(());
+            }
+            self.fail = false;
+            let n = self.stack.get(self.b)?;
+            let next = self.stack.get(self.b + n + 4)?;
+            if next != REDO {
+                self.p = next;
+                return Ok(());
+            }
+            self.restore_choice_point()?;
+            if self.redo()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Binds two heap terms, trailing the binding if it would need to be
+    /// undone on backtracking.
+    fn bind(&mut self, a1: usize, a2: usize) -> Result<(), MachineError> {
+        let addr = self.heap.bind(a1, a2)?;
+        if addr < self.hb {
+            self.trail.push(addr);
+        }
+        Ok(())
+    }
+
+    /// Discards every choice point newer than the given one.
+    fn cut(&mut self, b0: usize) -> Result<(), MachineError> {
+        // NONE is the oldest possible choice point, despite its value.
+        let as_option = |b| if b == NONE { None } else { Some(b) };
+        if as_option(self.b) > as_option(b0) {
+            self.set_b(b0)?;
+            self.redos.retain(|&b, _| as_option(b) <= as_option(b0));
+        }
+        Ok(())
+    }
+
+    /// Creates a choice point whose next alternative is at the given address.
+    fn push_choice_point(&mut self, next: usize) -> Result<(), MachineError> {
+        let b = self.stack_top()?;
+        self.stack.truncate(b);
+        self.stack.push(self.num_args);
+        for i in 0..self.num_args {
+            let arg = self.registers.get(i)?;
+            self.stack.push(arg);
+        }
+        self.stack.push(self.e);
+        self.stack.push(self.cp);
+        self.stack.push(self.b);
+        self.stack.push(next);
+        self.stack.push(self.trail.len());
+        self.stack.push(self.heap.next_addr());
+        self.b = b;
+        self.hb = self.heap.next_addr();
+        Ok(())
+    }
+
+    /// Tries the next solution of the nondeterministic built-in predicate
+    /// whose choice point is the most recent one, discarding the choice point
+    /// once there are none left. Returns false if the machine needs to
+    /// backtrack further.
+    fn redo(&mut self) -> Result<bool, MachineError> {
+        let b = self.b;
+        let next = self.redos.get_mut(&b).and_then(|r| r.solutions.next());
+        let res = match next {
+            Some(Ok(terms)) => {
+                let vars = self.redos[&b].vars.clone();
+                BuiltinArgs { machine: self, vars }.unify_all(&terms)
+            }
+            Some(Err(err)) => Err(err),
+            None => {
+                self.redos.remove(&b);
+                let n = self.stack.get(b)?;
+                let prev = self.stack.get(b + n + 3)?;
+                self.set_b(prev)?;
+                return Ok(false);
+            }
+        };
+        match res {
+            Ok(true) => self.p = self.cp,
+            Ok(false) => return Ok(false),
+            Err(err) => self.error = Some(err),
+        }
+        Ok(true)
+    }
+
+    /// Restores the argument registers, environment, continuation, trail,
+    /// and heap saved in the current choice point. Returns the number of
+    /// saved arguments.
+    fn restore_choice_point(&mut self) -> Result<usize, MachineError> {
+        let b = self.b;
+        let n = self.stack.get(b)?;
+        for i in 0..n {
+            self.registers[i] = self.stack.get(b + i + 1)?;
+        }
+        self.e = self.stack.get(b + n + 1)?;
+        self.cp = self.stack.get(b + n + 2)?;
+        let tr = self.stack.get(b + n + 5)?;
+        self.unwind_trail(tr)?;
+        self.heap.truncate(self.stack.get(b + n + 6)?);
+        self.hb = self.heap.next_addr();
+        Ok(n)
+    }
+
+    /// Makes the given choice point the most recent one.
+    fn set_b(&mut self, b: usize) -> Result<(), MachineError> {
+        self.b = b;
+        self.hb = if b == NONE {
+            0
+        } else {
+            let n = self.stack.get(b)?;
+            self.stack.get(b + n + 6)?
+        };
+        Ok(())
+    }
+
+    /// Returns the index of the first stack slot not used by either the
+    /// current environment or the most recent choice point.
+    fn stack_top(&self) -> Result<usize, MachineError> {
+        let e_top = if self.e == NONE {
+            0
+        } else {
+            self.e + self.stack.get(self.e + 2)? + 3
+        };
+        let b_top = if self.b == NONE {
+            0
+        } else {
+            self.b + self.stack.get(self.b)? + 7
+        };
+        Ok(max(e_top, b_top))
+    }
+
+    /// Calls a built-in predicate with the given arity on the argument
+    /// registers, continuing at the given address if it succeeds.
+    fn call_builtin(&mut self, builtin: Builtin, arity: usize, next: usize) {
+        let mut args = BuiltinArgs {
+            machine: self,
+            vars: HashMap::new(),
+        };
+        match builtin {
+            Builtin::Det(predicate) => match predicate(&mut args) {
+                Ok(true) => self.p = next,
+                Ok(false) => self.fail = true,
+                Err(err) => self.error = Some(err),
+            },
+            Builtin::NonDet(predicate) => match predicate(&mut args) {
+                Ok(solutions) => {
+                    let vars = args.vars;
+                    self.cp = next;
+                    self.num_args = arity;
+                    if let Err(err) = self.push_choice_point(REDO) {
+                        self.error = Some(err.into());
+                        return;
+                    }
+                    self.redos.insert(self.b, Redo { solutions, vars });
+
+                    // The first solution is tried the same way as the rest,
+                    // by backtracking into the choice point.
+                    self.fail = true;
+                }
+                Err(err) => self.error = Some(err),
+            },
+        }
+    }
+
+    /// Handles a call to a procedure that has no clauses, as directed by the
+    /// `unknown` flag. Calls to `call/1` and `catch/3` raise an error
+    /// instead.
+    fn call_unknown(&mut self, f: Functor) {
+        if let Some(err) = wam_only(f) {
+            self.error = Some(err);
+            return;
+        }
+        match self.unknown.call(f) {
+            Ok(()) => self.fail = true,
+            Err(err) => self.error = Some(err.into()),
+        }
+    }
+
+    /// Performs unification between two heap terms.
+    fn unify(&mut self, a1: usize, a2: usize) -> Result<(), MachineError> {
+        let mut pdl = vec![a1, a2];
+        // The pairs of compound terms already unified, so that cyclic terms
+        // do not send unification round in circles.
+        let mut seen = HashSet::new();
+        while !pdl.is_empty() && !self.fail {
+            let d1 = self.heap.deref(pdl.pop().unwrap())?;
+            let d2 = self.heap.deref(pdl.pop().unwrap())?;
+            if d1 != d2 {
+                match (self.heap.get(d1)?, self.heap.get(d2)?) {
+                    (HeapCell::Str(v1), HeapCell::Str(v2)) => {
+                        if !seen.insert((v1, v2)) {
+                            continue;
+                        }
+                        let f1 = self.heap.get_functor(v1)?;
+                        let f2 = self.heap.get_functor(v2)?;
+                        if f1 == f2 {
+                            for i in 1..(f1.1 + 1) {
+                                pdl.push(v1 + i);
+                                pdl.push(v2 + i);
+                            }
+                        } else {
+                            self.fail = true;
+                            return Ok(());
+                        }
+                    }
+                    (HeapCell::Lis(v1), HeapCell::Lis(v2)) => {
+                        if !seen.insert((v1, v2)) {
+                            continue;
+                        }
+                        for i in 0..2 {
+                            pdl.push(v1 + i);
+                            pdl.push(v2 + i);
+                        }
+                    }
+                    (HeapCell::Ref(_), _) | (_, HeapCell::Ref(_)) => {
+                        if self.occurs_check
+                            && (self.heap.occurs(d1, HeapCell::Ref(d2))?
+                                || self.heap.occurs(d2, HeapCell::Ref(d1))?)
+                        {
+                            self.fail = true;
+                            return Ok(());
+                        }
+                        self.bind(d1, d2)?
+                    }
+                    (c1, c2) if c1.is_constant() && c1 == c2 => {}
+                    _ => {
+                        self.fail = true;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes the bindings recorded in the trail above the given trail
+    /// pointer.
+    fn unwind_trail(&mut self, tr: usize) -> Result<(), MachineError> {
+        for addr in self.trail.drain(tr..) {
+            self.heap.unbind(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether two heap terms unify, undoing any bindings made to
+    /// find out.
+    fn unifiable(
+        &mut self,
+        a1: usize,
+        a2: usize,
+    ) -> Result<bool, MachineError> {
+        // Every variable that exists now is below the HB register, so all the
+        // bindings are trailed.
+        let hb = mem::replace(&mut self.hb, self.heap.next_addr());
+        let tr = self.trail.len();
+        let res = self.unify(a1, a2);
+        let undone = self.unwind_trail(tr);
+        self.hb = hb;
+        res?;
+        undone?;
+        Ok(!mem::replace(&mut self.fail, false))
+    }
+
+    /// Places a term onto the heap, returning its address. Variables are
+    /// looked up in the given map, and ones not in it are added to it as new
+    /// unbound variables.
+    fn put_term(
+        &mut self,
+        term: &Term,
+        vars: &mut HashMap<Variable, usize>,
+    ) -> Result<usize, MachineError> {
+        Ok(match *term {
+            Term::Anonymous => self.heap.alloc_with(HeapCell::Ref),
+            Term::Variable(var) => {
+                if let Some(&addr) = vars.get(&var) {
+                    return Ok(addr);
+                }
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                vars.insert(var, addr);
+                addr
+            }
+            // Atoms are stored as functors of arity 0, as compiled code does.
+            Term::Structure(ref s) => {
+                let mut cells = Vec::with_capacity(s.1.len());
+                for t in &s.1 {
+                    let addr = self.put_term(t, vars)?;
+                    cells.push(self.heap.get(addr)?);
+                }
+                let functor = s.functor();
+                let addr = if functor.is_list() {
+                    self.heap.alloc_with(|n| HeapCell::Lis(n + 1))
+                } else {
+                    let addr = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
+                    self.heap.alloc(HeapCell::Functor(functor));
+                    addr
+                };
+                for cell in cells {
+                    self.heap.alloc(cell);
+                }
+                addr
+            }
+            _ => {
+                let c = Constant::from_term(term, &mut self.heap.big_ints);
+                self.heap.alloc(c.unwrap().cell())
+            }
+        })
+    }
+
+    /// Unifies the value at the given address with a constant.
+    fn unify_constant(
+        &mut self,
+        addr: usize,
+        c: Constant,
+    ) -> Result<(), MachineError> {
+        let addr = self.heap.deref(addr)?;
+        match self.heap.get(addr)? {
+            HeapCell::Ref(_) => {
+                let n = self.heap.alloc(c.cell());
+                self.bind(addr, n)?;
+            }
+            cell if cell == c.cell() => {}
+            _ => self.fail = true,
+        }
+        Ok(())
+    }
+
+    /// Writes a heap address to the given location.
+    pub fn write(
+        &mut self,
+        loc: Location,
+        addr: usize,
+    ) -> Result<(), MachineError> {
+        match loc {
+            Location::Register(n) => self.registers[n] = addr,
+            Location::Local(n) => self.stack.set(self.e + n + 3, addr)?,
+        }
+        Ok(())
+    }
+}
+
+/// The arguments to a call to a built-in predicate.
+struct BuiltinArgs<'a> {
+    machine: &'a mut Machine,
+    vars: HashMap<Variable, usize>,
+}
+
+impl<'a> Args for BuiltinArgs<'a> {
+    fn get(&mut self, n: usize) -> Result<Term, Error> {
+        let addr = self.machine.registers.get(n)?;
+        let term = self.machine.heap.extract_term(addr)?;
+        record_variables(&term, &mut self.vars);
+        Ok(term)
+    }
+
+    fn unify(&mut self, n: usize, term: &Term) -> Result<bool, Error> {
+        let addr = self.machine.put_term(term, &mut self.vars)?;
+        let arg = self.machine.registers.get(n)?;
+        self.machine.unify(arg, addr)?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unify_with_occurs_check(
+        &mut self,
+        n: usize,
+        term: &Term,
+    ) -> Result<bool, Error> {
+        let occurs_check = mem::replace(&mut self.machine.occurs_check, true);
+        let res = self.unify(n, term);
+        self.machine.occurs_check = occurs_check;
+        res
+    }
+
+    fn unify_args(
+        &mut self,
+        a: usize,
+        b: usize,
+        occurs_check: bool,
+    ) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        let occurs_check = self.machine.occurs_check || occurs_check;
+        let occurs_check =
+            mem::replace(&mut self.machine.occurs_check, occurs_check);
+        let res = self.machine.unify(a, b);
+        self.machine.occurs_check = occurs_check;
+        res?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unifiable(&mut self, a: usize, b: usize) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        Ok(self.machine.unifiable(a, b)?)
+    }
+}
+
+impl ::Machine for Machine {
+    fn run_query<'a>(
+        &'a mut self,
+        query: Vec<Structure>,
+    ) -> Box<'a + Iterator<Item = Result<HashMap<Variable, Term>, Error>>> {
+        self.reset();
+
+        // The query is loaded after the program, and its continuation is the
+        // end of the code; reaching it means the query succeeded.
+        let (query_code, vars) =
+            compile_query(&query, &mut self.heap.big_ints);
+        self.p = self.code.len();
+        self.code.extend(query_code);
+        self.cp = self.code.len();
+
+        Box::new(MachineIter {
+            machine: self,
+            vars,
+            started: false,
+            done: false,
+        })
+    }
+
+    fn load_program(&mut self, program: &[Clause]) -> Result<(), Error> {
+        let mut big_ints = BigInts::new();
+        let (code, labels) = compile_program(program, &mut big_ints);
+        self.reset();
+        self.query_start = code.len();
+        self.code = code;
+        self.labels = labels;
+        self.heap = Heap::new(big_ints);
+        Ok(())
+    }
+
+    fn builtins_mut(&mut self) -> Option<&mut Builtins> {
+        Some(&mut self.builtins)
+    }
+
+    fn set_unknown(&mut self, unknown: Unknown) {
+        self.unknown = unknown;
+    }
+
+    fn set_occurs_check(&mut self, occurs_check: bool) {
+        self.occurs_check = occurs_check;
+    }
+
+    fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt;
+    }
+}
+
+struct MachineIter<'a> {
+    machine: &'a mut Machine,
+    vars: Vec<Variable>,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> MachineIter<'a> {
+    /// Reads the values of the query variables out of the query's environment
+    /// frame.
+    fn extract_bindings(&self) -> Result<HashMap<Variable, Term>, Error> {
+        let machine = &*self.machine;
+        let addrs = (0..self.vars.len())
+            .map(|i| machine.read(Location::Local(i)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Unbound variables are named after the last query variable that
+        // refers to them, so `'='(X, Y)` gives `X = Y` and `Y = Y`.
+        let mut names = HashMap::new();
+        for (&var, &addr) in self.vars.iter().zip(&addrs) {
+            let addr = machine.heap.deref(addr)?;
+            if machine.heap.get(addr)?.is_ref() {
+                names.insert(addr, var);
+            }
+        }
+
+        let mut extractor = Extractor::new(&machine.heap, names);
+        let mut bindings = self
+            .vars
+            .iter()
+            .zip(addrs)
+            .map(|(&var, addr)| {
+                let cell = machine.heap.get(addr)?;
+                Ok((var, extractor.extract(cell, Some(var))?))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        bindings.extend(extractor.into_bindings());
+        Ok(bindings)
+    }
+}
+
+impl<'a> Iterator for MachineIter<'a> {
+    type Item = Result<HashMap<Variable, Term>, Error>;
+
+    fn next(&mut self) -> Option<Result<HashMap<Variable, Term>, Error>> {
+        if self.done {
+            return None;
+        }
+
+        // After the first solution, further solutions are found by
+        // backtracking into the most recent choice point.
+        if self.started {
+            if let Err(err) = self.machine.backtrack() {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        }
+        self.started = true;
+
+        // Backtracking into a nondeterministic built-in predicate may find
+        // the next solution without running any instructions.
+        while !self.machine.fail {
+            if let Some(err) = self.machine.error.take() {
+                self.done = true;
+                return Some(Err(err));
+            } else if self.machine.succeeded() {
+                return Some(self.extract_bindings());
+            } else if self.machine.interrupt.swap(false, Ordering::SeqCst) {
+                // The next call picks up where this one stopped, rather than
+                // backtracking.
+                self.started = false;
+                return Some(Err(Interrupted.into()));
+            }
+            if let Err(err) = self.machine.step() {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Machine as MachineTrait;
+    use common::Atom;
+    use super::*;
+
+    fn bool_program() -> Vec<Clause> {
+        vec![
+            "evalBool(false, false).",
+            "evalBool(true, true).",
+            "evalBool(and(X, Y), true) :-
+                evalBool(X, true),
+                evalBool(Y, true).",
+            "evalBool(and(X, _), false) :- evalBool(X, false).",
+            "evalBool(and(_, Y), false) :- evalBool(Y, false).",
+            "evalBool(or(X, _), true) :- evalBool(X, true).",
+            "evalBool(or(_, Y), true) :- evalBool(Y, true).",
+            "evalBool(or(X, Y), false) :-
+                evalBool(X, false),
+                evalBool(Y, false).",
+            "evalBool(not(X), true) :- evalBool(X, false).",
+            "evalBool(not(X), false) :- evalBool(X, true).",
+        ].into_iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect()
+    }
+
+    fn constant(name: &str) -> Term {
+        Term::Structure(Structure(Atom::from(name), vec![]))
+    }
+
+    #[test]
+    fn reports_machine_errors() {
+        let mut machine =
+            Machine::with_code(Vec::new(), HashMap::new(), BigInts::new());
+
+        // A permanent variable past the end of its environment, and an
+        // environment that was never allocated.
+        let allocate = Instruction::Flat(FlatInstruction::Allocate(1));
+        assert_eq!(machine.run_instruction(allocate), Ok(false));
+        let put = FlatInstruction::PutValue(Location::Local(1), 0);
+        assert_eq!(
+            machine.run_instruction(Instruction::Flat(put)),
+            Err(MachineError::StackOverflow(4))
+        );
+        machine.reset();
+        machine.e = 0;
+        let deallocate = Instruction::Flat(FlatInstruction::Deallocate);
+        assert_eq!(
+            machine.run_instruction(deallocate),
+            Err(MachineError::StackOverflow(1))
+        );
+
+        // A choice point that was never pushed.
+        machine.reset();
+        machine.b = 0;
+        assert_eq!(machine.backtrack(), Err(MachineError::StackOverflow(0)));
+        assert_eq!(
+            machine.run_instruction(Instruction::TrustMe),
+            Err(MachineError::StackOverflow(0))
+        );
+    }
+
+    #[test]
+    fn finds_all_solutions() {
+        let mut machine = Machine::new(&bool_program());
+        let results = machine
+            .run_query(vec![Structure::parse("evalBool(X, Y)").unwrap()])
+            .take(3)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            results,
+            vec![
+                vec![
+                    (variable!("X"), constant("false")),
+                    (variable!("Y"), constant("false")),
+                ].into_iter()
+                    .collect(),
+                vec![
+                    (variable!("X"), constant("true")),
+                    (variable!("Y"), constant("true")),
+                ].into_iter()
+                    .collect(),
+                vec![
+                    (
+                        variable!("X"),
+                        Term::Structure(Structure(
+                            atom!(and),
+                            vec![constant("true"), constant("true")],
+                        )),
+                    ),
+                    (variable!("Y"), constant("true")),
+                ].into_iter()
+                    .collect(),
+            ]
+        );
+    }
+
+    #[test]
+    fn undoes_bindings_when_backtracking() {
+        let mut machine = Machine::new(&bool_program());
+        let results = machine
+            .run_query(vec![
+                Structure::parse("evalBool(or(true, not(false)), R)").unwrap(),
+            ])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            results,
+            vec![
+                vec![(variable!("R"), constant("true"))]
+                    .into_iter()
+                    .collect(),
+                vec![(variable!("R"), constant("true"))]
+                    .into_iter()
+                    .collect(),
+            ]
+        );
+
+        let results = machine
+            .run_query(vec![
+                Structure::parse("evalBool(and(true, or(false, false)), R)")
+                    .unwrap(),
+            ])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            results,
+            vec![
+                vec![(variable!("R"), constant("false"))]
+                    .into_iter()
+                    .collect(),
+            ]
+        );
+    }
+
+    #[test]
+    fn cut_discards_choice_points() {
+        let program = vec![
+            "member(a).",
+            "member(b).",
+            "member(c).",
+            "first(X) :- member(X), !.",
+            "first(z).",
+            "all(X) :- !, member(X).",
+            "all(z).",
+            "max(X, Y, X) :- X >= Y, !.",
+            "max(_, Y, Y).",
+        ].into_iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let mut run = |query| {
+            machine
+                .run_query(vec![Structure::parse(query).unwrap()])
+                .map(|r| r.map(|mut r| r.remove(&variable!("X")).unwrap()))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query")
+        };
+        assert_eq!(run("first(X)"), vec![constant("a")]);
+        assert_eq!(
+            run("all(X)"),
+            vec![constant("a"), constant("b"), constant("c")]
+        );
+
+        // The goal before the cut fails, so the next clause is tried.
+        let five = Term::Integer(5.into());
+        assert_eq!(run("max(3, 5, X)"), vec![five.clone()]);
+        assert_eq!(run("max(5, 3, X)"), vec![five]);
+    }
+
+    #[test]
+    fn rejects_catch() {
+        // Errors from built-in predicates can't be caught, so they end the
+        // query.
+        let mut machine = Machine::new(&[]);
+        let query = "catch(X is foo + 1, E, true)";
+        let err = machine
+            .run_query(vec![Structure::parse(query).unwrap()])
+            .next()
+            .expect("Query failed")
+            .expect_err("Query didn't raise an error");
+        assert_eq!(err.to_string(), "catch/3 is only supported by the WAM");
+
+        // A program can still define its own procedures with these names.
+        let program = vec![Clause::parse("call(X) :- X = a.").unwrap()];
+        let mut machine = Machine::new(&program);
+        let results = machine
+            .run_query(vec![Structure::parse("call(X)").unwrap()])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(results[0][&variable!("X")], constant("a"));
+    }
+
+    #[test]
+    fn occurs_check_rejects_cyclic_terms() {
+        let count = |occurs_check, query| {
+            let mut machine = Machine::new(&[]);
+            machine.set_occurs_check(occurs_check);
+            let results = machine
+                .run_query(vec![Structure::parse(query).unwrap()])
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query");
+            results.len()
+        };
+        assert_eq!(count(false, "unify_with_occurs_check(X, f(X))"), 0);
+        assert_eq!(count(false, "unify_with_occurs_check(X, f(Y))"), 1);
+        assert_eq!(count(true, "'='(X, f(g(X)))"), 0);
+        assert_eq!(count(true, "'='(f(X, Y), f(Y, [X]))"), 0);
+        assert_eq!(count(true, "'='(X, X)"), 1);
+
+        // The check terminates on a term that is already cyclic.
+        let mut machine = Machine::new(&[]);
+        let query = vec![
+            Structure::parse("'='(X, f(X))").unwrap(),
+            Structure::parse("unify_with_occurs_check(Y, X)").unwrap(),
+        ];
+        let results = machine
+            .run_query(query)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn extracts_cyclic_terms() {
+        let mut machine = Machine::new(&[]);
+        let results = machine
+            .run_query(vec![Structure::parse("'='(X, f(Y, X))").unwrap()])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            results,
+            vec![
+                vec![
+                    (variable!("X"), Term::parse("f(Y, X)").unwrap()),
+                    (variable!("Y"), Term::parse("Y").unwrap()),
+                ].into_iter()
+                    .collect(),
+            ]
+        );
+    }
+
+    #[test]
+    fn unifies_terms_on_the_heap() {
+        let program = vec![
+            Clause::parse("q(a).").unwrap(),
+            Clause::parse("p(X) :- q(X).").unwrap(),
+        ];
+        let mut machine = Machine::new(&program);
+        let mut count = |occurs_check, query: &[&str]| {
+            machine.set_occurs_check(occurs_check);
+            let query = query
+                .iter()
+                .map(|s| Structure::parse(s).unwrap())
+                .collect();
+            let results = machine
+                .run_query(query)
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query");
+            results.len()
+        };
+        assert_eq!(count(false, &["X = a", "p(X)"]), 1);
+        assert_eq!(count(false, &["q(X)", "X = a"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = X"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = f(Y)", "X = Y"]), 1);
+        assert_eq!(count(false, &["f(A, f(A)) \\= f(f(A), A)"]), 0);
+        assert_eq!(count(true, &["f(A, f(A)) \\= f(f(A), A)"]), 1);
+        assert_eq!(count(false, &["f(X, X) \\= f(a, b)", "var(X)"]), 1);
+    }
+
+    #[test]
+    fn works_with_lists() {
+        let program = vec![
+            "append([], L, L).",
+            "append([H | T], L2, [H | L3]) :- append(T, L2, L3).",
+        ].into_iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let results = machine
+            .run_query(vec![Structure::parse("append(X, Y, [a, b])").unwrap()])
+            .map(|r| {
+                r.map(|mut r| {
+                    let x = r.remove(&variable!("X")).unwrap();
+                    (x, r.remove(&variable!("Y")).unwrap())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        let list = |s| Term::parse(s).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                (list("[]"), list("[a, b]")),
+                (list("[a]"), list("[b]")),
+                (list("[a, b]"), list("[]")),
+            ]
+        );
+    }
+
+    #[test]
+    fn backtracks_into_foreign_predicates() {
+        let program = vec![
+            Clause::parse("big(X) :- digit(X), X > 7.").unwrap(),
+            Clause::parse("first(X) :- digit(X), !.").unwrap(),
+        ];
+        let mut machine = Machine::new(&program);
+        machine.builtins_mut().unwrap().register_nondet(
+            functor!(digit / 1),
+            |_| {
+                let digits = (0..10).map(|n| Ok(vec![Term::Integer(n.into())]));
+                Ok(Box::new(digits))
+            },
+        );
+        let mut run = |query| {
+            machine
+                .run_query(vec![Structure::parse(query).unwrap()])
+                .map(|r| r.map(|mut r| r.remove(&variable!("X")).unwrap()))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query")
+        };
+        let ints = |ns: &[i32]| {
+            ns.iter()
+                .map(|&n| Term::Integer(n.into()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run("big(X)"), ints(&[8, 9]));
+        assert_eq!(run("first(X)"), ints(&[0]));
+        assert_eq!(run("digit(X)").len(), 10);
+    }
+}
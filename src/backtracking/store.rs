@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+use failure::Error;
+
+use common::{BigInts, Cells, Extractor, Functor, HeapCell, MachineError, Term};
+
+/// The heap, aka the global stack.
+#[derive(Debug)]
+pub struct Heap {
+    cells: Vec<HeapCell>,
+
+    /// The big integers that integer cells, and the constants in the
+    /// machine's code, refer to.
+    pub big_ints: BigInts,
+}
+
+impl Heap {
+    /// Constructs a new, empty Heap. The given big integers are the
+    /// program's, and are kept when the heap is reset.
+    pub fn new(mut big_ints: BigInts) -> Heap {
+        big_ints.keep();
+        Heap {
+            cells: Vec::new(),
+            big_ints,
+        }
+    }
+
+    /// Allocates a single heap cell whose value is not address-dependent.
+    /// Returns the address the cell was allocated at.
+    pub fn alloc(&mut self, cell: HeapCell) -> usize {
+        self.alloc_with(|_| cell)
+    }
+
+    /// Allocates a single heap cell. The address the heap cell will be placed
+    /// at is passed to the function. Returns the address the cell was
+    /// allocated at.
+    pub fn alloc_with<F: FnOnce(usize) -> HeapCell>(&mut self, f: F) -> usize {
+        let n = self.cells.len();
+        self.cells.push(f(n));
+        n
+    }
+
+    /// Binds one term to another. At least one given address must deref to a
+    /// self-referential (unbound) `Ref` cell. Returns the address of the cell
+    /// that was bound, so that it can be trailed.
+    pub fn bind(&mut self, a: usize, b: usize) -> Result<usize, MachineError> {
+        let da = self.deref(a)?;
+        let db = self.deref(b)?;
+        if self.get(da)?.is_ref() {
+            self.cells[da] = HeapCell::Ref(db);
+            Ok(da)
+        } else {
+            match self.get(db)? {
+                HeapCell::Ref(_) => {
+                    self.cells[db] = HeapCell::Ref(da);
+                    Ok(db)
+                }
+                cell => {
+                    let op = "bind".to_string();
+                    Err(MachineError::InvalidDeref(cell, op))
+                }
+            }
+        }
+    }
+
+    /// Derefs an address, resolving any `Ref` cells.
+    pub fn deref(&self, mut addr: usize) -> Result<usize, MachineError> {
+        loop {
+            match self.get(addr)? {
+                HeapCell::Ref(a) if a != addr => addr = a,
+                _ => return Ok(addr),
+            }
+        }
+    }
+
+    /// Extracts the term at the given address, naming unbound variables
+    /// after their addresses. Raises a type error if the term is cyclic.
+    pub fn extract_term(&self, addr: usize) -> Result<Term, Error> {
+        Extractor::new(self, HashMap::new()).extract_acyclic(self.get(addr)?)
+    }
+
+    /// Gets the cell stored at the given address.
+    pub fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        self.cells
+            .get(addr)
+            .cloned()
+            .ok_or(MachineError::HeapOverflow(addr))
+    }
+
+    /// Gets the functor stored at the given address.
+    pub fn get_functor(&self, addr: usize) -> Result<Functor, MachineError> {
+        match self.get(addr)? {
+            HeapCell::Functor(f) => Ok(f),
+            cell => Err(MachineError::BadFunctorCell(addr, cell)),
+        }
+    }
+
+    /// Returns the address that will be returned by the next allocation, aka
+    /// the H register.
+    pub fn next_addr(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Clears the heap, discarding the big integers added since the program
+    /// was loaded.
+    pub fn reset(&mut self) {
+        self.cells.clear();
+        self.big_ints.reset();
+    }
+
+    /// Discards every cell at or above the given address.
+    pub fn truncate(&mut self, h: usize) {
+        self.cells.truncate(h);
+    }
+
+    /// Resets the cell at the given address to an unbound variable.
+    pub fn unbind(&mut self, addr: usize) -> Result<(), MachineError> {
+        match self.cells.get_mut(addr) {
+            Some(cell) => {
+                *cell = HeapCell::Ref(addr);
+                Ok(())
+            }
+            None => Err(MachineError::HeapOverflow(addr)),
+        }
+    }
+}
+
+impl Cells for Heap {
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        Heap::get(self, addr)
+    }
+
+    fn big_ints(&self) -> &BigInts {
+        &self.big_ints
+    }
+}
+
+impl Index<usize> for Heap {
+    type Output = HeapCell;
+
+    fn index(&self, i: usize) -> &HeapCell {
+        &self.cells[i]
+    }
+}
+
+impl IndexMut<usize> for Heap {
+    fn index_mut(&mut self, i: usize) -> &mut HeapCell {
+        &mut self.cells[i]
+    }
+}
+
+#[derive(Debug)]
+pub struct Registers(Vec<usize>);
+
+impl Registers {
+    /// Constructs a new, empty Registers object.
+    pub fn new() -> Registers {
+        Registers(Vec::new())
+    }
+
+    /// Reads the value of a register.
+    pub fn get(&self, i: usize) -> Result<usize, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::RegisterOutOfRange(i))
+    }
+
+    /// Resets all the registers.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Index<usize> for Registers {
+    type Output = usize;
+
+    fn index(&self, i: usize) -> &usize {
+        &self.0[i]
+    }
+}
+
+impl IndexMut<usize> for Registers {
+    fn index_mut(&mut self, i: usize) -> &mut usize {
+        while self.0.len() <= i {
+            self.0.push(usize::MAX);
+        }
+        &mut self.0[i]
+    }
+}
+
+/// The stack, whose cells hold heap addresses and control values.
+#[derive(Debug)]
+pub struct Stack(Vec<usize>);
+
+impl Stack {
+    /// Constructs a new, empty Stack.
+    pub fn new() -> Stack {
+        Stack(Vec::new())
+    }
+
+    /// Gets the value stored at the given index.
+    pub fn get(&self, i: usize) -> Result<usize, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::StackOverflow(i))
+    }
+
+    /// Pushes a value onto the top of the stack.
+    pub fn push(&mut self, value: usize) {
+        self.0.push(value);
+    }
+
+    /// Clears the stack.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+
+    /// Writes the value at the given index.
+    pub fn set(&mut self, i: usize, value: usize) -> Result<(), MachineError> {
+        match self.0.get_mut(i) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(MachineError::StackOverflow(i)),
+        }
+    }
+
+    /// Discards every cell at or above the given index.
+    pub fn truncate(&mut self, n: usize) {
+        self.0.truncate(n);
+    }
+}
@@ -5,14 +5,25 @@ use linefeed::reader::LogSender;
 use log::{set_boxed_logger, set_max_level, Level, LevelFilter, Log, Metadata,
           Record};
 
-/// Initializes the logger.
+/// Initializes the logger, which writes through the reader so that messages
+/// don't garble the line being edited.
 pub fn init<Term: Terminal>(
     reader: &mut Reader<Term>,
     level: LevelFilter,
 ) -> bool {
+    install(Some(reader.get_log_sender()), level)
+}
+
+/// Initializes the logger to write straight to stderr, for when no lines are
+/// read.
+pub fn init_stderr(level: LevelFilter) -> bool {
+    install(None, level)
+}
+
+fn install(sender: Option<LogSender>, level: LevelFilter) -> bool {
     let r = set_boxed_logger(Box::new(Logger {
         level,
-        sender: Mutex::new(reader.get_log_sender()),
+        sender: Mutex::new(sender),
     }));
     if r.is_ok() {
         set_max_level(level);
@@ -24,7 +35,9 @@ pub fn init<Term: Terminal>(
 
 struct Logger {
     level: LevelFilter,
-    sender: Mutex<LogSender>,
+
+    /// Where to send messages, or `None` to write them to stderr.
+    sender: Mutex<Option<LogSender>>,
 }
 
 impl Log for Logger {
@@ -45,8 +58,12 @@ impl Log for Logger {
             Level::Trace => "TRC",
         };
 
-        writeln!(self.sender.lock().unwrap(), "[{}] {}", level, record.args(),)
-            .ok();
+        match *self.sender.lock().unwrap() {
+            Some(ref mut sender) => {
+                writeln!(sender, "[{}] {}", level, record.args()).ok();
+            }
+            None => eprintln!("[{}] {}", level, record.args()),
+        }
     }
 
     fn flush(&self) {
@@ -4,6 +4,9 @@
 extern crate ansi_term;
 #[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
 extern crate linefeed;
 extern crate log;
 extern crate nom;
@@ -14,21 +17,39 @@ extern crate wam_tutorial_reconstruction;
 mod logger;
 mod options;
 
+use std::io::{stdout, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use ansi_term::{Color, Style};
 use failure::Error;
-use linefeed::{ReadResult, Reader};
+use linefeed::{DefaultTerminal, ReadResult, Reader, Signal, Terminal};
+use linefeed::terminal::SignalSet;
 use structopt::StructOpt;
 use wam_tutorial_reconstruction::*;
 use wam_tutorial_reconstruction::common::*;
+use wam_tutorial_reconstruction::common::builtins::{as_atom, proper_list};
+
+use options::{read_src_file, Options, SrcFileErrors};
 
-use options::Options;
+lazy_static! {
+    /// Set when Ctrl-C is pressed outside of line editing, which interrupts
+    /// the running query.
+    static ref INTERRUPT: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPT.store(true, Ordering::SeqCst);
+}
 
 fn main() {
     let options = Options::from_args();
     match run(options) {
         Ok(()) => {}
         Err(err) => {
-            eprintln!("{}", err);
+            print_error(&err);
             std::process::exit(1);
         }
     }
@@ -36,8 +57,26 @@ fn main() {
 
 fn run(options: Options) -> Result<(), Error> {
     let verbosity = options.verbosity();
-    let mut machine = options.machine.new_machine()?;
-    let expr = options.expr;
+    let (mut machine, ops) = options.machine.new_machine()?;
+    machine.set_unknown(options.unknown);
+    machine.set_occurs_check(options.occurs_check);
+    let mut session = Session {
+        machine,
+        ops,
+        files: vec![options.machine.src_file().to_owned()],
+    };
+
+    if let Some(expr) = options.expr {
+        // Without a terminal, there's nobody to ask whether to look for more
+        // solutions, so only the first is printed.
+        assert!(logger::init_stderr(verbosity));
+        let term = DefaultTerminal::new().ok();
+        let keep_going = || match term {
+            Some(ref term) => read_action(term),
+            None => Ok(false),
+        };
+        return session.eval(&expr, keep_going, || Ok(false));
+    }
 
     let mut reader = Reader::new(Options::clap().get_name().to_string())?;
     reader.set_blink_matching_paren(true);
@@ -53,73 +92,204 @@ fn run(options: Options) -> Result<(), Error> {
         .map(|ds| reader.evaluate_directives(ds));
     assert!(logger::init(&mut reader, verbosity));
 
-    if let Some(expr) = expr {
-        run_query(&mut *machine, &expr, || true)
-    } else {
-        let mut query_buf = String::new();
-        loop {
-            // Read a line of input.
-            reader.set_prompt(if query_buf.len() == 0 { "?- " } else { "   " });
-            match reader.read_line().expect("Couldn't read a line") {
-                ReadResult::Eof => break Ok(()),
-                ReadResult::Input(s) => {
-                    query_buf += &s;
-                    reader.add_history(s);
-                }
-                ReadResult::Signal(sig) => unimplemented!("{:?}", sig),
-            };
-
-            // Try running the query.
-            let keep_going = || {
-                reader.set_prompt(" ");
-
-                // TODO: Read one char ({';', ' ', '\n', '\t'} or {'.'}) from
-                // reader. https://github.com/murarth/linefeed/issues/27
-                true
-            };
-            match run_query(&mut *machine, &query_buf, keep_going) {
-                Ok(()) => {
-                    query_buf.clear();
+    // Ctrl-C clears the input while a line is being read, and interrupts
+    // the query otherwise.
+    session.machine.set_interrupt(INTERRUPT.clone());
+    reader.set_report_signal(Signal::Interrupt, true);
+    unsafe {
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+
+    let term = DefaultTerminal::new()?;
+    let mut query_buf = String::new();
+    loop {
+        // Read a line of input.
+        reader.set_prompt(if query_buf.len() == 0 { "?- " } else { "   " });
+        match reader.read_line().expect("Couldn't read a line") {
+            ReadResult::Eof => break Ok(()),
+            ReadResult::Input(s) => {
+                // Lines are kept separate, so that errors are reported at
+                // the right line.
+                if !query_buf.is_empty() {
+                    query_buf.push('\n');
                 }
-                Err(err) => match err.downcast::<ParseError>() {
-                    Ok(ParseError::Incomplete(_)) => continue,
-                    Ok(err) => {
-                        query_buf.clear();
-                        eprintln!("{}", err);
-                    }
-                    Err(err) => {
-                        query_buf.clear();
-                        eprintln!("{}", err);
-                    }
-                },
+                query_buf += &s;
+                reader.add_history(s);
             }
+            ReadResult::Signal(Signal::Interrupt) => {
+                println!();
+                query_buf.clear();
+                continue;
+            }
+            ReadResult::Signal(_) => continue,
+        };
+
+        // Try running the query, asking after each solution whether to look
+        // for another, and what to do if it is interrupted.
+        INTERRUPT.store(false, Ordering::SeqCst);
+        let keep_going = || read_action(&term);
+        let on_interrupt = || read_break(&term);
+        if let Err(err) = session.eval(&query_buf, keep_going, on_interrupt) {
+            let incomplete = err.downcast_ref::<ParseError>()
+                .is_some_and(|err| err.is_incomplete());
+            if incomplete {
+                continue;
+            }
+            print_error(&err);
         }
+        query_buf.clear();
     }
 }
 
-fn run_query<F: FnMut() -> bool>(
+/// The state of the toplevel.
+struct Session {
+    machine: Box<Machine>,
+
+    /// The operators for reading queries, as defined by the loaded files.
+    ops: OpTable,
+
+    /// The files the program was loaded from, which `make` reloads.
+    files: Vec<PathBuf>,
+}
+
+impl Session {
+    /// Reads a query, then runs it with `run_query` unless it is a toplevel
+    /// command.
+    fn eval<F, G>(
+        &mut self,
+        q: &str,
+        keep_going: F,
+        on_interrupt: G,
+    ) -> Result<(), Error>
+    where
+        F: FnMut() -> Result<bool, Error>,
+        G: FnMut() -> Result<bool, Error>,
+    {
+        let res = parsers::query_with_ops(q, &self.ops);
+        let query = ParseError::from_iresult(res, q)?;
+        if let Some(files) = self.files_to_load(&query)? {
+            self.consult(files)?;
+            println!("true.");
+            Ok(())
+        } else {
+            let m = &mut *self.machine;
+            run_query(m, &self.ops, query, keep_going, on_interrupt)
+        }
+    }
+
+    /// Returns the files to load if a query is a toplevel command that loads
+    /// files: `consult(File)`, its shorthand `[File, ...]`, or `make`, which
+    /// reloads the loaded files.
+    fn files_to_load(
+        &self,
+        query: &[Structure],
+    ) -> Result<Option<Vec<PathBuf>>, Error> {
+        let goal = match query {
+            [goal] => goal,
+            _ => return Ok(None),
+        };
+        match (goal.0.as_ref(), goal.1.len()) {
+            ("consult", 1) => paths(&goal.1[0]).map(Some),
+            (".", 2) => paths(&Term::Structure(goal.clone())).map(Some),
+            ("make", 0) => Ok(Some(self.files.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Replaces the program with the clauses in the given files. Nothing
+    /// changes if a file can't be read or the program can't be compiled.
+    fn consult(&mut self, files: Vec<PathBuf>) -> Result<(), Error> {
+        let mut ops = OpTable::new();
+        let mut program = Vec::new();
+        for file in &files {
+            program.extend(read_src_file(file, &mut ops)?);
+        }
+        self.machine.load_program(&program)?;
+        self.ops = ops;
+        self.files = files;
+        Ok(())
+    }
+}
+
+/// Converts the argument of `consult/1`, which is a file name or a list of
+/// them, to paths. A name with no extension refers to a `.pl` file if there
+/// is no file with exactly that name.
+fn paths(term: &Term) -> Result<Vec<PathBuf>, Error> {
+    let names = proper_list(term).unwrap_or_else(|| vec![term]);
+    names
+        .into_iter()
+        .map(|name| match *name {
+            Term::Anonymous | Term::Variable(_) => {
+                Err(PrologError::Instantiation.into())
+            }
+            _ => match as_atom(name) {
+                Some(name) => {
+                    let mut path = PathBuf::from(name.as_ref());
+                    if !path.exists() && path.extension().is_none() {
+                        path.set_extension("pl");
+                    }
+                    Ok(path)
+                }
+                None => {
+                    Err(PrologError::Type("atom".into(), name.clone()).into())
+                }
+            },
+        })
+        .collect()
+}
+
+/// Runs a query, printing each solution. After each one, `keep_going` is
+/// called to decide whether to look for the next, so a query with infinitely
+/// many solutions only runs for as long as the user asks for more. If the
+/// query is interrupted, `on_interrupt` decides whether to continue it.
+fn run_query<F, G>(
     m: &mut Machine,
-    q: &str,
+    ops: &OpTable,
+    query: Vec<Structure>,
     mut keep_going: F,
-) -> Result<(), Error> {
-    let query = ParseError::from_iresult(parsers::query(q), q)?;
-    let mut iter = m.run_query(query);
+    mut on_interrupt: G,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Result<bool, Error>,
+    G: FnMut() -> Result<bool, Error>,
+{
+    let writer = TermWriter::new().ops(ops).priority(699);
+    let mut vars = Vec::new();
+    for goal in &query {
+        for arg in &goal.1 {
+            query_variables(arg, &mut vars);
+        }
+    }
 
-    let mut first_binding_set = true;
+    let mut solutions = m.run_query(query);
     loop {
-        let bindings = if let Some(r) = iter.next() {
-            let b = r?;
-            if !first_binding_set {
-                println!(";");
-            }
-            b
-        } else {
-            if !first_binding_set {
-                println!(".");
+        let bindings = match solutions.next() {
+            Some(Ok(bindings)) => bindings,
+            Some(Err(err)) => {
+                if err.downcast_ref::<Interrupted>().is_none() {
+                    return Err(err);
+                } else if on_interrupt()? {
+                    continue;
+                } else {
+                    return Ok(());
+                }
             }
-            break;
+            None => break,
         };
 
+        // Unbound variables are left out, and the rest are printed in the
+        // order they appear in the query. Any others name cyclic subterms,
+        // and come last.
+        let mut bindings = bindings
+            .into_iter()
+            .filter(|&(var, ref val)| *val != Term::Variable(var))
+            .collect::<Vec<_>>();
+        bindings.sort_by_key(|&(var, _)| {
+            let pos = vars.iter().position(|&v| v == var);
+            (pos.unwrap_or(vars.len()), var)
+        });
+
         let mut first = true;
         for (var, val) in bindings {
             if first {
@@ -127,21 +297,127 @@ fn run_query<F: FnMut() -> bool>(
             } else {
                 println!(",");
             }
-            print!("{} = {}", var, val);
+            print!("{} = {}", var, writer.term(&val));
         }
         if first {
             print!("true");
         }
-        first_binding_set = false;
+        stdout().flush()?;
 
-        if !keep_going() {
+        if keep_going()? {
+            println!(" ;");
+        } else {
             println!(".");
-            break;
+            return Ok(());
         }
     }
 
-    if first_binding_set {
-        println!("{}", Style::new().bold().fg(Color::Red).paint("false."));
-    }
+    println!("{}", Style::new().bold().fg(Color::Red).paint("false."));
     Ok(())
 }
+
+/// Adds the variables in a term to a list, in the order they first appear.
+fn query_variables(term: &Term, vars: &mut Vec<Variable>) {
+    match *term {
+        Term::Structure(ref s) => for t in &s.1 {
+            query_variables(t, vars);
+        },
+        Term::Variable(var) if !vars.contains(&var) => vars.push(var),
+        _ => {}
+    }
+}
+
+/// Prints an error to stderr. Parse errors are printed like rustc's
+/// diagnostics, with a blank line between them if there are several.
+fn print_error(err: &Error) {
+    let label = Style::new().bold().fg(Color::Red).paint("error");
+    if let Some(errs) = err.downcast_ref::<SrcFileErrors>() {
+        for (i, err) in errs.0.iter().enumerate() {
+            if i > 0 {
+                eprintln!();
+            }
+            eprintln!("{}: {}", label, err);
+        }
+    } else if err.downcast_ref::<ParseError>().is_some() {
+        eprintln!("{}: {}", label, err);
+    } else {
+        eprintln!("{}", err);
+    }
+}
+
+/// Reads a single key press after a solution is printed, returning whether
+/// to look for another solution. `;`, space, tab, `n` and `r` ask for another;
+/// Enter, `.`, `a`, `c` and Ctrl-C stop.
+fn read_action(term: &DefaultTerminal) -> Result<bool, Error> {
+    loop {
+        match read_key(term)? {
+            Some(b';') | Some(b' ') | Some(b'\t') | Some(b'n') | Some(b'r') => {
+                return Ok(true)
+            }
+            Some(b'h') | Some(b'?') => {
+                println!();
+                println!("Actions:");
+                println!("  ; (or space, tab, n, r): next solution");
+                println!("  . (or Enter, a, c): stop");
+                print!("? ");
+                stdout().flush()?;
+            }
+            Some(b'\r') | Some(b'\n') | Some(b'.') | Some(b'a') | Some(b'c')
+            | Some(EOT) | None => return Ok(false),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Asks what to do with an interrupted query, returning whether to continue
+/// it.
+fn read_break(term: &DefaultTerminal) -> Result<bool, Error> {
+    println!();
+    loop {
+        print!("Interrupted. Action (a)bort, (c)ontinue, (h)elp? ");
+        stdout().flush()?;
+        match read_key(term)? {
+            Some(b'c') => {
+                println!("continue");
+                return Ok(true);
+            }
+            Some(b'h') | Some(b'?') => {
+                println!();
+                println!("Actions:");
+                println!("  a (or Enter, Ctrl-C): abort the query");
+                println!("  c: continue the query");
+            }
+            Some(b'a') | Some(b'\r') | Some(b'\n') | Some(EOT) | None => {
+                println!("abort");
+                return Ok(false);
+            }
+            Some(_) => println!(),
+        }
+    }
+}
+
+/// Reads a single key press, returning its first byte. Returns `None` if
+/// input isn't coming from a terminal, so there's nobody to ask, or if Ctrl-C
+/// is pressed.
+fn read_key(term: &DefaultTerminal) -> Result<Option<u8>, Error> {
+    let _guard = match term.prepare(false, SignalSet::new()) {
+        Ok(guard) => guard,
+        Err(_) => return Ok(None),
+    };
+
+    // Ctrl-C still raises SIGINT, which only sets the flag, so the flag is
+    // polled while waiting.
+    let mut buf = Vec::new();
+    loop {
+        if INTERRUPT.swap(false, Ordering::SeqCst) {
+            return Ok(None);
+        } else if term.wait_for_input(Some(Duration::from_millis(100)))?
+            && term.read(&mut buf)? > 0
+        {
+            return Ok(Some(buf[0]));
+        }
+    }
+}
+
+/// The byte sent by Ctrl-D, which ends input.
+const EOT: u8 = 0x04;
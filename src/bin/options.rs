@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
@@ -21,6 +22,15 @@ pub struct Options {
     #[structopt(short = "e", long = "eval")]
     pub expr: Option<String>,
 
+    /// What to do when a query calls a procedure with no clauses: error,
+    /// fail, or warning.
+    #[structopt(long = "unknown", default_value = "error")]
+    pub unknown: Unknown,
+
+    /// Makes unification fail rather than create cyclic terms.
+    #[structopt(long = "occurs-check")]
+    pub occurs_check: bool,
+
     /// Turns off message output.
     #[structopt(short = "q", long = "quiet")]
     pub quiet: bool,
@@ -66,37 +76,98 @@ pub enum MachineOpts {
         #[structopt(name = "FILE", parse(from_os_str))]
         src_file: PathBuf,
     },
+
+    /// The backtracking machine from chapter 4.
+    #[structopt(name = "backtracking")]
+    Backtracking {
+        /// The file to read. Can contain facts or rules.
+        #[structopt(name = "FILE", parse(from_os_str))]
+        src_file: PathBuf,
+    },
+
+    /// The full Warren Abstract Machine from chapter 5.
+    #[structopt(name = "wam")]
+    Wam {
+        /// The file to read. Can contain facts or rules.
+        #[structopt(name = "FILE", parse(from_os_str))]
+        src_file: PathBuf,
+    },
 }
 
 impl MachineOpts {
-    /// Creates a new instance of the specified machine.
-    pub fn new_machine(&self) -> Result<Box<Machine>, Error> {
+    /// Returns the path of the source file to load.
+    pub fn src_file(&self) -> &Path {
         match *self {
+            MachineOpts::Unification { ref src_file }
+            | MachineOpts::Flat { ref src_file }
+            | MachineOpts::Backtracking { ref src_file }
+            | MachineOpts::Wam { ref src_file } => src_file,
+        }
+    }
+
+    /// Creates a new instance of the specified machine. Also returns the
+    /// operators defined by the source file, for reading queries with.
+    pub fn new_machine(&self) -> Result<(Box<Machine>, OpTable), Error> {
+        let mut ops = OpTable::new();
+        let machine: Box<Machine> = match *self {
             MachineOpts::Unification { ref src_file } => {
-                let mut program = read_src_file(src_file)?;
-                if program.len() != 1 {
-                    bail!("M0 only supports one clause in the program.");
-                }
-                let Clause(head, body) = program.remove(0);
-                if !body.is_empty() {
-                    bail!("M0 doesn't support implications.");
-                }
-                let program = Term::Structure(head);
-                Ok(Box::new(unification::Machine::new(&program)))
+                let program = read_src_file(src_file, &mut ops)?;
+                let mut machine = unification::Machine::empty();
+                machine.load_program(&program)?;
+                Box::new(machine)
             }
             MachineOpts::Flat { ref src_file } => {
-                let program = read_src_file(src_file)?;
-                let machine = flat::Machine::new(&program)?;
-                Ok(Box::new(machine))
+                let program = read_src_file(src_file, &mut ops)?;
+                Box::new(flat::Machine::new(&program)?)
             }
-        }
+            MachineOpts::Backtracking { ref src_file } => {
+                let program = read_src_file(src_file, &mut ops)?;
+                Box::new(backtracking::Machine::new(&program))
+            }
+            MachineOpts::Wam { ref src_file } => {
+                let program = read_src_file(src_file, &mut ops)?;
+                Box::new(wam::Machine::new(&program))
+            }
+        };
+        Ok((machine, ops))
     }
 }
 
-fn read_src_file<P: AsRef<Path>>(path: P) -> Result<Vec<Clause>, Error> {
-    let src = File::open(path).and_then(|mut file| {
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).map(|_| buf)
-    })?;
-    ParseError::from_iresult(parsers::program(&src), &src).map_err(Into::into)
+/// Reads and parses a source file, adding any operators it defines to the
+/// table. If there are syntax errors, they are all returned, as
+/// `SrcFileErrors`.
+pub fn read_src_file<P: AsRef<Path>>(
+    path: P,
+    ops: &mut OpTable,
+) -> Result<Vec<Clause>, Error> {
+    let path = path.as_ref();
+    let src = File::open(path)
+        .and_then(|mut file| {
+            let mut buf = String::new();
+            file.read_to_string(&mut buf).map(|_| buf)
+        })
+        .map_err(|err| format_err!("{}: {}", path.display(), err))?;
+    let (clauses, errors) = parsers::program_with_ops(&src, ops);
+    if errors.is_empty() {
+        Ok(clauses)
+    } else {
+        let errors = errors.into_iter().map(|err| err.in_file(path));
+        Err(SrcFileErrors(errors.collect()).into())
+    }
+}
+
+/// The syntax errors in a source file.
+#[derive(Debug, Fail)]
+pub struct SrcFileErrors(pub Vec<ParseError>);
+
+impl Display for SrcFileErrors {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                fmt.write_str("\n\n")?;
+            }
+            write!(fmt, "{}", err)?;
+        }
+        Ok(())
+    }
 }
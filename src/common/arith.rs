@@ -0,0 +1,169 @@
+//! Arithmetic evaluation, for `is/2` and the arithmetic comparison
+//! predicates.
+
+use std::cmp::Ordering;
+
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive, Zero};
+
+use common::{Float, PrologError, Structure, Term};
+
+/// The value of an arithmetic expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    /// An integer.
+    Int(BigInt),
+
+    /// A floating-point number, which is never infinite or NaN.
+    Float(f64),
+}
+
+impl Number {
+    /// Converts the number to a term.
+    pub fn to_term(self) -> Term {
+        match self {
+            Number::Int(n) => Term::Integer(n),
+            Number::Float(f) => Term::Float(Float(f)),
+        }
+    }
+
+    /// Compares two numbers by value. An integer and a float are compared by
+    /// converting the integer to a float.
+    pub fn compare(&self, other: &Number) -> Ordering {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(b),
+            (a, b) => a
+                .to_f64()
+                .partial_cmp(&b.to_f64())
+                .unwrap_or(Ordering::Equal),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match *self {
+            Number::Int(ref n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Float(f) => f,
+        }
+    }
+
+    /// Returns the number as an integer, or a type error if it is a float.
+    fn into_int(self) -> Result<BigInt, PrologError> {
+        match self {
+            Number::Int(n) => Ok(n),
+            Number::Float(f) => Err(PrologError::Type(
+                "integer".into(),
+                Term::Float(Float(f)),
+            )),
+        }
+    }
+}
+
+/// Evaluates an arithmetic expression.
+pub fn eval(term: &Term) -> Result<Number, PrologError> {
+    match *term {
+        Term::Anonymous | Term::Variable(_) => Err(PrologError::Instantiation),
+        Term::Float(f) => Ok(Number::Float(f.0)),
+        Term::Integer(ref n) => Ok(Number::Int(n.clone())),
+        Term::Structure(ref s) => eval_structure(s),
+    }
+}
+
+/// The evaluable functors, as names and arities.
+const EVALUABLE: &[(&str, usize)] = &[
+    ("+", 1),
+    ("-", 1),
+    ("abs", 1),
+    ("+", 2),
+    ("-", 2),
+    ("*", 2),
+    ("/", 2),
+    ("//", 2),
+    ("mod", 2),
+    ("rem", 2),
+    ("min", 2),
+    ("max", 2),
+];
+
+fn eval_structure(s: &Structure) -> Result<Number, PrologError> {
+    let Structure(name, ref args) = *s;
+    let name = name.as_ref();
+    if !EVALUABLE.contains(&(name, args.len())) {
+        let culprit = s.functor().indicator();
+        return Err(PrologError::Type("evaluable".into(), culprit));
+    }
+    let mut args = args.iter().map(eval).collect::<Result<Vec<_>, _>>()?;
+
+    if args.len() == 1 {
+        let x = args.pop().unwrap();
+        return Ok(match (name, x) {
+            ("+", x) => x,
+            ("-", Number::Int(n)) => Number::Int(-n),
+            ("-", Number::Float(f)) => Number::Float(-f),
+            ("abs", Number::Int(n)) => Number::Int(n.abs()),
+            ("abs", Number::Float(f)) => Number::Float(f.abs()),
+            _ => unreachable!(),
+        });
+    }
+
+    let y = args.pop().unwrap();
+    let x = args.pop().unwrap();
+    match name {
+        "+" | "-" | "*" => Ok(match (x, y) {
+            (Number::Int(x), Number::Int(y)) => Number::Int(match name {
+                "+" => x + y,
+                "-" => x - y,
+                _ => x * y,
+            }),
+            (x, y) => {
+                let (x, y) = (x.to_f64(), y.to_f64());
+                float(match name {
+                    "+" => x + y,
+                    "-" => x - y,
+                    _ => x * y,
+                })?
+            }
+        }),
+        "/" => {
+            let y = y.to_f64();
+            if y == 0.0 {
+                Err(PrologError::Evaluation("zero_divisor".into()))
+            } else {
+                float(x.to_f64() / y)
+            }
+        }
+        "//" | "mod" | "rem" => {
+            let (x, y) = (x.into_int()?, y.into_int()?);
+            if y.is_zero() {
+                return Err(PrologError::Evaluation("zero_divisor".into()));
+            }
+            Ok(Number::Int(match name {
+                "//" => x / y,
+                "rem" => x % y,
+                _ => {
+                    // The result of mod takes the sign of the divisor, unlike
+                    // rem, which takes the sign of the dividend.
+                    let r = x % &y;
+                    if !r.is_zero() && r.is_negative() != y.is_negative() {
+                        r + y
+                    } else {
+                        r
+                    }
+                }
+            }))
+        }
+        "min" => Ok(if y.compare(&x) == Ordering::Less { y } else { x }),
+        "max" => Ok(if y.compare(&x) == Ordering::Greater { y } else { x }),
+        _ => unreachable!(),
+    }
+}
+
+/// Checks that the result of a floating-point operation is a number.
+fn float(f: f64) -> Result<Number, PrologError> {
+    if f.is_nan() {
+        Err(PrologError::Evaluation("undefined".into()))
+    } else if f.is_infinite() {
+        Err(PrologError::Evaluation("float_overflow".into()))
+    } else {
+        Ok(Number::Float(f))
+    }
+}
@@ -0,0 +1,448 @@
+//! Built-in predicates, which are implemented in Rust rather than compiled
+//! from clauses.
+//!
+//! Host code can add its own foreign predicates to a machine's table, either
+//! deterministic ones, which succeed at most once, or nondeterministic ones,
+//! whose further solutions are found on backtracking.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::rc::Rc;
+
+use failure::Error;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use common::{Atom, Functor, PrologError, Structure, Term, Thrown, Variable};
+use common::arith::eval;
+
+/// The arguments to a call to a built-in predicate. Each machine implements
+/// this over its own heap.
+pub trait Args {
+    /// Reads the numbered argument as a term.
+    ///
+    /// Unbound variables are named after their heap address, and keep
+    /// referring to the same variable if the term is passed back to
+    /// `unify`.
+    fn get(&mut self, n: usize) -> Result<Term, Error>;
+
+    /// Unifies the numbered argument with a term, returning whether
+    /// unification succeeded.
+    ///
+    /// Variables that did not come from `get` are created fresh, once per
+    /// name for the rest of the call.
+    fn unify(&mut self, n: usize, term: &Term) -> Result<bool, Error>;
+
+    /// Like `unify`, but performs the occurs check whether or not the machine
+    /// has it turned on.
+    fn unify_with_occurs_check(
+        &mut self,
+        n: usize,
+        term: &Term,
+    ) -> Result<bool, Error>;
+
+    /// Unifies two of the arguments with each other on the machine's heap,
+    /// returning whether unification succeeded. The occurs check is performed
+    /// if `occurs_check` is set or the machine has it turned on.
+    fn unify_args(
+        &mut self,
+        a: usize,
+        b: usize,
+        occurs_check: bool,
+    ) -> Result<bool, Error>;
+
+    /// Returns whether two of the arguments unify, undoing any bindings made
+    /// to find out.
+    fn unifiable(&mut self, a: usize, b: usize) -> Result<bool, Error>;
+
+    /// Reads the numbered argument as an atom, or raises an instantiation or
+    /// type error if it is not one.
+    fn get_atom(&mut self, n: usize) -> Result<Atom, Error> {
+        match self.get(n)? {
+            Term::Anonymous | Term::Variable(_) => {
+                Err(PrologError::Instantiation.into())
+            }
+            term => match as_atom(&term) {
+                Some(atom) => Ok(atom),
+                None => Err(PrologError::Type("atom".into(), term).into()),
+            },
+        }
+    }
+
+    /// Reads the numbered argument as an integer, or raises an instantiation
+    /// or type error if it is not one.
+    fn get_integer(&mut self, n: usize) -> Result<BigInt, Error> {
+        match self.get(n)? {
+            Term::Anonymous | Term::Variable(_) => {
+                Err(PrologError::Instantiation.into())
+            }
+            Term::Integer(n) => Ok(n),
+            term => Err(PrologError::Type("integer".into(), term).into()),
+        }
+    }
+
+    /// Unifies each argument with the term at the same position, stopping at
+    /// the first that fails.
+    fn unify_all(&mut self, terms: &[Term]) -> Result<bool, Error> {
+        for (n, term) in terms.iter().enumerate() {
+            if !self.unify(n, term)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The solutions of a call to a nondeterministic predicate, each given as the
+/// terms to unify with the predicate's arguments. Later solutions are only
+/// produced on backtracking.
+pub type Solutions = Box<dyn Iterator<Item = Result<Vec<Term>, Error>>>;
+
+/// A deterministic predicate, which returns whether the call succeeded.
+pub type Predicate = dyn Fn(&mut dyn Args) -> Result<bool, Error>;
+
+/// A nondeterministic predicate, which returns the solutions of the call.
+pub type NondetPredicate = dyn Fn(&mut dyn Args) -> Result<Solutions, Error>;
+
+/// A built-in or foreign predicate.
+#[derive(Clone)]
+pub enum Builtin {
+    /// A deterministic predicate.
+    Det(Rc<Predicate>),
+
+    /// A nondeterministic predicate.
+    NonDet(Rc<NondetPredicate>),
+}
+
+impl Debug for Builtin {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Builtin::Det(_) => fmt.write_str("Det(..)"),
+            Builtin::NonDet(_) => fmt.write_str("NonDet(..)"),
+        }
+    }
+}
+
+/// The solutions a call to a nondeterministic predicate has yet to produce,
+/// which a machine keeps alongside the call's choice point.
+pub struct Redo {
+    /// The remaining solutions.
+    pub solutions: Solutions,
+
+    /// The variables the call read from its arguments, by heap address.
+    pub vars: HashMap<Variable, usize>,
+}
+
+impl Debug for Redo {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Redo").field("vars", &self.vars).finish()
+    }
+}
+
+/// A table of built-in predicates, which calls check before user code.
+#[derive(Clone)]
+pub struct Builtins(HashMap<Functor, Builtin>);
+
+impl Builtins {
+    /// Creates a table holding the standard built-in predicates.
+    pub fn new() -> Builtins {
+        let mut builtins = Builtins(HashMap::new());
+        for &(name, arity, builtin) in STANDARD {
+            builtins.register(Functor(name.into(), arity), builtin);
+        }
+        builtins
+    }
+
+    /// Returns the built-in predicate with the given functor, if there is
+    /// one.
+    pub fn get(&self, functor: Functor) -> Option<Builtin> {
+        self.0.get(&functor).cloned()
+    }
+
+    /// Adds a deterministic predicate, replacing any existing one with the
+    /// same functor.
+    pub fn register<F>(&mut self, functor: Functor, predicate: F)
+    where
+        F: 'static + Fn(&mut dyn Args) -> Result<bool, Error>,
+    {
+        self.0.insert(functor, Builtin::Det(Rc::new(predicate)));
+    }
+
+    /// Adds a nondeterministic predicate, replacing any existing one with the
+    /// same functor.
+    pub fn register_nondet<F>(&mut self, functor: Functor, predicate: F)
+    where
+        F: 'static + Fn(&mut dyn Args) -> Result<Solutions, Error>,
+    {
+        self.0.insert(functor, Builtin::NonDet(Rc::new(predicate)));
+    }
+}
+
+impl Default for Builtins {
+    fn default() -> Builtins {
+        Builtins::new()
+    }
+}
+
+impl Debug for Builtins {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Returns the error raised for a call to `call/1` or `catch/3` on a machine
+/// that cannot run a goal held in a term, which only the WAM can do. Other
+/// procedures give `None`, and are handled as unknown procedures.
+pub fn wam_only(f: Functor) -> Option<Error> {
+    match (f.0.as_ref(), f.1) {
+        ("call", 1) | ("catch", 3) => {
+            Some(format_err!("{} is only supported by the WAM", f))
+        }
+        _ => None,
+    }
+}
+
+/// Records the heap address of each variable in a term read by
+/// `Args::get`. Such variables are named `_N`, after their address `N`.
+pub fn record_variables(term: &Term, vars: &mut HashMap<Variable, usize>) {
+    match *term {
+        Term::Structure(ref s) => for t in &s.1 {
+            record_variables(t, vars);
+        },
+        Term::Variable(var) => if let Ok(addr) = var.as_ref()[1..].parse() {
+            vars.insert(var, addr);
+        },
+        _ => {}
+    }
+}
+
+/// A deterministic built-in predicate implemented as a plain function.
+type DetFn = fn(&mut dyn Args) -> Result<bool, Error>;
+
+/// The standard built-in predicates, as names, arities, and functions.
+const STANDARD: &[(&str, usize, DetFn)] = &[
+    ("true", 0, |_| Ok(true)),
+    ("fail", 0, |_| Ok(false)),
+    ("=", 2, unify),
+    ("\\=", 2, not_unifiable),
+    ("unify_with_occurs_check", 2, unify_with_occurs_check),
+    ("var", 1, |a| Ok(is_var(&a.get(0)?))),
+    ("nonvar", 1, |a| Ok(!is_var(&a.get(0)?))),
+    ("atom", 1, |a| Ok(as_atom(&a.get(0)?).is_some())),
+    ("functor", 3, functor),
+    ("arg", 3, arg),
+    ("=..", 2, univ),
+    ("copy_term", 2, copy_term),
+    ("is", 2, is),
+    ("=:=", 2, |a| compare(a, |o| o.is_eq())),
+    ("=\\=", 2, |a| compare(a, |o| o.is_ne())),
+    ("<", 2, |a| compare(a, |o| o.is_lt())),
+    (">", 2, |a| compare(a, |o| o.is_gt())),
+    ("=<", 2, |a| compare(a, |o| o.is_le())),
+    (">=", 2, |a| compare(a, |o| o.is_ge())),
+    ("throw", 1, throw),
+];
+
+fn unify(args: &mut dyn Args) -> Result<bool, Error> {
+    args.unify_args(0, 1, false)
+}
+
+fn unify_with_occurs_check(args: &mut dyn Args) -> Result<bool, Error> {
+    args.unify_args(0, 1, true)
+}
+
+fn not_unifiable(args: &mut dyn Args) -> Result<bool, Error> {
+    Ok(!args.unifiable(0, 1)?)
+}
+
+/// The largest arity of a term built by `functor/3`.
+const MAX_ARITY: usize = 255;
+
+/// `functor(Term, Name, Arity)`. Raises a representation error if asked to
+/// build a term with more than `MAX_ARITY` arguments.
+fn functor(args: &mut dyn Args) -> Result<bool, Error> {
+    match args.get(0)? {
+        Term::Anonymous | Term::Variable(_) => {}
+        Term::Structure(Structure(name, subterms)) => {
+            return Ok(args.unify(1, &atom(name))?
+                && args.unify(2, &Term::Integer(subterms.len().into()))?);
+        }
+        term => {
+            return Ok(args.unify(1, &term)?
+                && args.unify(2, &Term::Integer(0.into()))?);
+        }
+    }
+
+    let name = args.get(1)?;
+    let arity = args.get_integer(2)?;
+    let too_big = arity > BigInt::from(MAX_ARITY);
+    let term = match (name, arity.to_usize()) {
+        (Term::Anonymous, _) | (Term::Variable(_), _) => {
+            return Err(PrologError::Instantiation.into())
+        }
+        (name @ Term::Structure(_), _) if as_atom(&name).is_none() => {
+            return Err(PrologError::Type("atomic".into(), name).into())
+        }
+        _ if too_big => {
+            let limit = "max_arity".into();
+            return Err(PrologError::Representation(limit).into());
+        }
+        (_, None) => return Ok(false),
+        (name, Some(0)) => name,
+        (Term::Structure(Structure(name, _)), Some(n)) => {
+            Term::Structure(Structure(name, vec![Term::Anonymous; n]))
+        }
+        (name, Some(_)) => {
+            return Err(PrologError::Type("atom".into(), name).into())
+        }
+    };
+    args.unify(0, &term)
+}
+
+/// `arg(N, Term, Arg)`.
+fn arg(args: &mut dyn Args) -> Result<bool, Error> {
+    let n = args.get_integer(0)?.to_usize();
+    let mut subterms = match args.get(1)? {
+        Term::Anonymous | Term::Variable(_) => {
+            return Err(PrologError::Instantiation.into())
+        }
+        Term::Structure(Structure(_, ref subterms)) if !subterms.is_empty() => {
+            subterms.clone()
+        }
+        term => return Err(PrologError::Type("compound".into(), term).into()),
+    };
+    match n {
+        Some(n) if n >= 1 && n <= subterms.len() => {
+            let arg = subterms.swap_remove(n - 1);
+            args.unify(2, &arg)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// `Term =.. List`.
+fn univ(args: &mut dyn Args) -> Result<bool, Error> {
+    match args.get(0)? {
+        Term::Anonymous | Term::Variable(_) => {}
+        Term::Structure(Structure(name, subterms)) => {
+            let items = Some(atom(name)).into_iter().chain(subterms);
+            return args.unify(1, &Term::list(items));
+        }
+        term => return args.unify(1, &Term::list(vec![term])),
+    }
+
+    let list = args.get(1)?;
+    let mut items = match proper_list(&list) {
+        Some(items) => items.into_iter().cloned().collect::<Vec<_>>(),
+        None => return Err(PrologError::Instantiation.into()),
+    };
+    if items.is_empty() {
+        return Err(PrologError::Instantiation.into());
+    }
+    let name = items.remove(0);
+    let term = match name {
+        Term::Anonymous | Term::Variable(_) => {
+            return Err(PrologError::Instantiation.into())
+        }
+        ref name if items.is_empty() => name.clone(),
+        Term::Structure(Structure(name, ref subterms))
+            if subterms.is_empty() =>
+        {
+            Term::Structure(Structure(name, items))
+        }
+        name => return Err(PrologError::Type("atom".into(), name).into()),
+    };
+    args.unify(0, &term)
+}
+
+/// `copy_term(Term, Copy)`.
+fn copy_term(args: &mut dyn Args) -> Result<bool, Error> {
+    fn rename(term: &Term, names: &mut HashMap<Variable, Variable>) -> Term {
+        match *term {
+            Term::Structure(Structure(name, ref subterms)) => {
+                let subterms = subterms.iter().map(|t| rename(t, names));
+                Term::Structure(Structure(name, subterms.collect()))
+            }
+            Term::Variable(var) => {
+                let n = names.len();
+                let var = *names.entry(var).or_insert_with(|| {
+                    Variable::from_str(format!("_C{}", n)).unwrap()
+                });
+                Term::Variable(var)
+            }
+            ref term => term.clone(),
+        }
+    }
+
+    let copy = rename(&args.get(0)?, &mut HashMap::new());
+    args.unify(1, &copy)
+}
+
+/// `Result is Expr`.
+fn is(args: &mut dyn Args) -> Result<bool, Error> {
+    let value = eval(&args.get(1)?)?;
+    args.unify(0, &value.to_term())
+}
+
+/// `throw(Ball)`. The ball is copied, so undoing bindings while unwinding
+/// to the catcher does not change it.
+fn throw(args: &mut dyn Args) -> Result<bool, Error> {
+    match args.get(0)? {
+        Term::Anonymous | Term::Variable(_) => {
+            Err(PrologError::Instantiation.into())
+        }
+        ball => Err(Thrown(ball).into()),
+    }
+}
+
+/// Evaluates both arguments, and checks the ordering of their values.
+fn compare<F>(args: &mut dyn Args, check: F) -> Result<bool, Error>
+where
+    F: FnOnce(::std::cmp::Ordering) -> bool,
+{
+    let lhs = eval(&args.get(0)?)?;
+    let rhs = eval(&args.get(1)?)?;
+    Ok(check(lhs.compare(&rhs)))
+}
+
+fn atom(name: Atom) -> Term {
+    Term::Structure(Structure(name, vec![]))
+}
+
+/// Returns the atom a term is, if it is one.
+pub fn as_atom(term: &Term) -> Option<Atom> {
+    match *term {
+        Term::Structure(Structure(name, ref args)) if args.is_empty() => {
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+fn is_var(term: &Term) -> bool {
+    match *term {
+        Term::Anonymous | Term::Variable(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the elements of a list, if it is a proper list.
+pub fn proper_list(mut term: &Term) -> Option<Vec<&Term>> {
+    let mut items = Vec::new();
+    loop {
+        match *term {
+            Term::Structure(Structure(name, ref args))
+                if name.as_ref() == "." && args.len() == 2 =>
+            {
+                items.push(&args[0]);
+                term = &args[1];
+            }
+            Term::Structure(Structure(name, ref args))
+                if name.as_ref() == "[]" && args.is_empty() =>
+            {
+                return Some(items)
+            }
+            _ => return None,
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use failure::Error;
+
+use common::{Atom, HeapCell, Structure, Term};
+
+/// An error raised by a built-in predicate, as classified by section 7.12.2
+/// of the ISO standard.
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum PrologError {
+    /// An argument was an unbound variable, but needed to be instantiated.
+    Instantiation,
+
+    /// An argument had the wrong type. Holds the type that was expected, such
+    /// as `integer` or `evaluable`, and the argument itself.
+    Type(Atom, Term),
+
+    /// An arithmetic function was undefined for its arguments. Holds the kind
+    /// of error, such as `zero_divisor`.
+    Evaluation(Atom),
+
+    /// Something that was referred to does not exist. Holds the kind of
+    /// thing, such as `procedure`, and the reference to it.
+    Existence(Atom, Term),
+
+    /// An implementation-defined limit was exceeded. Holds the limit, such
+    /// as `max_arity`.
+    Representation(Atom),
+}
+
+impl PrologError {
+    /// Returns the term describing the error, which is the first argument of
+    /// the `error/2` term ISO Prolog would throw for it.
+    pub fn formal(&self) -> Term {
+        let (name, args) = match *self {
+            PrologError::Instantiation => ("instantiation_error", vec![]),
+            PrologError::Type(ty, ref culprit) => {
+                ("type_error", vec![atom_term(ty), culprit.clone()])
+            }
+            PrologError::Evaluation(kind) => {
+                ("evaluation_error", vec![atom_term(kind)])
+            }
+            PrologError::Existence(kind, ref culprit) => {
+                ("existence_error", vec![atom_term(kind), culprit.clone()])
+            }
+            PrologError::Representation(limit) => {
+                ("representation_error", vec![atom_term(limit)])
+            }
+        };
+        Term::Structure(Structure(name.into(), args))
+    }
+
+    /// Returns the `error(Formal, Context)` term that is thrown for the
+    /// error. The context is left unbound.
+    pub fn to_term(&self) -> Term {
+        let args = vec![self.formal(), Term::Anonymous];
+        Term::Structure(Structure("error".into(), args))
+    }
+}
+
+impl Display for PrologError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        Display::fmt(&self.formal(), fmt)
+    }
+}
+
+/// A term thrown by `throw/1`. Reaching the host means no `catch/3` caught
+/// it.
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub struct Thrown(pub Term);
+
+impl Thrown {
+    /// Returns the term an error is thrown as, if Prolog code can catch it.
+    /// Only thrown terms and errors raised by built-in predicates can be
+    /// caught; errors in the machine itself cannot.
+    pub fn ball(err: &Error) -> Option<Term> {
+        if let Some(Thrown(ball)) = err.downcast_ref() {
+            Some(ball.clone())
+        } else {
+            err.downcast_ref().map(PrologError::to_term)
+        }
+    }
+}
+
+impl Display for Thrown {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Uncaught exception: {}", self.0)
+    }
+}
+
+/// The error yielded by a query's iterator when the query is interrupted.
+#[derive(Clone, Copy, Debug, Fail, PartialEq)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("Interrupted")
+    }
+}
+
+/// An error in the state of a machine, caused by malformed code or a
+/// corrupted heap rather than by the program being run.
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum MachineError {
+    /// A cell dereferenced to a cell that cannot be operated on. Holds the
+    /// cell and a description of the operation, such as the instruction.
+    InvalidDeref(HeapCell, String),
+
+    /// A cell that should have held a functor did not. Holds its address and
+    /// contents.
+    BadFunctorCell(usize, HeapCell),
+
+    /// A register was read before anything was written to it.
+    RegisterOutOfRange(usize),
+
+    /// A heap address was past the top of the heap.
+    HeapOverflow(usize),
+
+    /// A stack index was past the top of the stack.
+    StackOverflow(usize),
+
+    /// A stack cell that should have held a control value held a term.
+    /// Holds its index and contents.
+    BadControlCell(usize, HeapCell),
+
+    /// A stack cell that should have held a term held a control value. Holds
+    /// its index and contents.
+    BadValueCell(usize, usize),
+
+    /// The continuation point did not follow a call instruction. Holds the
+    /// continuation point.
+    BadContinuation(usize),
+
+    /// The instruction pointer was past the end of the loaded code.
+    IpOutOfRange(usize),
+
+    /// An integer cell referred to an entry past the end of the machine's
+    /// table of big integers.
+    BigIntOutOfRange(usize),
+}
+
+impl Display for MachineError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            MachineError::InvalidDeref(cell, ref op) => {
+                write!(fmt, "Invalid deref to {:?} in {}", cell, op)
+            }
+            MachineError::BadFunctorCell(addr, cell) => write!(
+                fmt,
+                "Expecting {} to be a functor, found {:?}",
+                addr, cell
+            ),
+            MachineError::RegisterOutOfRange(n) => {
+                write!(fmt, "Register {} is out of range", n)
+            }
+            MachineError::HeapOverflow(addr) => {
+                write!(fmt, "Heap address {} is out of range", addr)
+            }
+            MachineError::StackOverflow(i) => {
+                write!(fmt, "Stack index {} is out of range", i)
+            }
+            MachineError::BadControlCell(i, cell) => write!(
+                fmt,
+                "Expecting {} to be a control value, found {:?}",
+                i, cell
+            ),
+            MachineError::BadValueCell(i, n) => write!(
+                fmt,
+                "Expecting {} to be a value, found control value {}",
+                i, n
+            ),
+            MachineError::BadContinuation(cp) => {
+                write!(fmt, "Continuation {} does not follow a call", cp)
+            }
+            MachineError::IpOutOfRange(p) => {
+                write!(fmt, "Instruction pointer {} is out of range", p)
+            }
+            MachineError::BigIntOutOfRange(i) => {
+                write!(fmt, "Big integer {} is out of range", i)
+            }
+        }
+    }
+}
+
+fn atom_term(atom: Atom) -> Term {
+    Term::Structure(Structure(atom, vec![]))
+}
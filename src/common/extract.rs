@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+
+use failure::Error;
+
+use common::{Atom, BigInts, Functor, HeapCell, MachineError, PrologError,
+             Structure, Term, Variable};
+
+/// Read access to the cells of a machine's memory, which terms can be
+/// extracted from.
+pub trait Cells {
+    /// Gets the cell stored at the given address.
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError>;
+
+    /// Returns the table holding the big integers the cells refer to.
+    fn big_ints(&self) -> &BigInts;
+
+    /// Follows a chain of references to the cell at its end. An unbound
+    /// variable derefs to a reference to itself.
+    fn deref_cell(&self, mut cell: HeapCell) -> Result<HeapCell, MachineError> {
+        while let HeapCell::Ref(addr) = cell {
+            let next = self.get(addr)?;
+            if next == cell {
+                break;
+            }
+            cell = next;
+        }
+        Ok(cell)
+    }
+
+    /// Returns whether the unbound variable at the given address occurs in
+    /// the term a cell refers to, like `Term::contains` does for terms. Each
+    /// compound term is only visited once, so cyclic terms are walked in
+    /// finite time.
+    fn occurs(&self, var: usize, cell: HeapCell) -> Result<bool, MachineError> {
+        let mut cells = vec![cell];
+        let mut visited = HashSet::new();
+        while let Some(cell) = cells.pop() {
+            let cell = self.deref_cell(cell)?;
+            match cell {
+                HeapCell::Str(_) | HeapCell::Lis(_)
+                    if !visited.insert(cell) => {}
+                HeapCell::Ref(a) if a == var => return Ok(true),
+                HeapCell::Str(a) => match self.get(a)? {
+                    HeapCell::Functor(Functor(_, arity)) => {
+                        for i in 1..(arity + 1) {
+                            cells.push(self.get(a + i)?);
+                        }
+                    }
+                    cell => return Err(MachineError::BadFunctorCell(a, cell)),
+                },
+                HeapCell::Lis(a) => {
+                    cells.push(self.get(a)?);
+                    cells.push(self.get(a + 1)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Extracts terms from a machine's memory.
+///
+/// Unification without the occurs check can bind a variable to a term that
+/// contains it, leaving a cycle on the heap. Each such cyclic subterm is
+/// extracted as a variable named like `_S1`, and the variable's value is kept
+/// as an extra binding, so `X = f(Y), Y = g(Y)` gives `X = f(_S1)` where
+/// `_S1 = g(_S1)`. A term that is the value of a query variable is named
+/// after that variable instead, so `X = f(X)` gives just `X = f(X)`.
+pub struct Extractor<'a, C: 'a + ?Sized> {
+    cells: &'a C,
+
+    /// The names of unbound variables, by address. Others are named `_N`,
+    /// after their address `N`.
+    names: HashMap<usize, Variable>,
+
+    /// The variables standing for cyclic subterms, by the cell that refers to
+    /// the subterm.
+    cycles: HashMap<HeapCell, Variable>,
+
+    /// The cells of the compound terms part way through being extracted,
+    /// which a term contains itself if it refers back to.
+    open: HashSet<HeapCell>,
+
+    /// The values of the variables in `cycles` that are not query variables.
+    bindings: Vec<(Variable, Term)>,
+
+    /// The number of variables named like `_S1` so far.
+    num_cycles: usize,
+}
+
+/// A compound term part way through being extracted.
+struct Frame {
+    cell: HeapCell,
+    atom: Atom,
+
+    /// The addresses of the arguments that are left to extract, last first.
+    args: Vec<usize>,
+
+    /// The arguments that have been extracted.
+    subterms: Vec<Term>,
+}
+
+impl<'a, C: 'a + Cells + ?Sized> Extractor<'a, C> {
+    /// Creates an Extractor, given the names of unbound variables.
+    pub fn new(cells: &'a C, names: HashMap<usize, Variable>) -> Self {
+        Extractor {
+            cells,
+            names,
+            cycles: HashMap::new(),
+            open: HashSet::new(),
+            bindings: Vec::new(),
+            num_cycles: 0,
+        }
+    }
+
+    /// Extracts the term a cell refers to. If the cell is the value of a
+    /// query variable, its name should be given, so that a cyclic term can
+    /// refer back to it.
+    ///
+    /// This does not recurse, so deeply nested terms and long lists are fine.
+    pub fn extract(
+        &mut self,
+        cell: HeapCell,
+        var: Option<Variable>,
+    ) -> Result<Term, Error> {
+        let mut frames: Vec<Frame> = Vec::new();
+        self.open.clear();
+        let mut term = self.start(cell, &mut frames, var)?;
+        loop {
+            if let Some(term) = term.take() {
+                match frames.last_mut() {
+                    Some(frame) => frame.subterms.push(term),
+                    None => return Ok(term),
+                }
+            }
+            // There is always a frame here, since the term would have been
+            // returned otherwise.
+            let arg = frames.last_mut().and_then(|frame| frame.args.pop());
+            term = match arg {
+                Some(addr) => {
+                    let cell = self.cells.get(addr)?;
+                    self.start(cell, &mut frames, var)?
+                }
+                None => {
+                    let frame = frames.pop().unwrap();
+                    self.open.remove(&frame.cell);
+                    let term =
+                        Term::Structure(Structure(frame.atom, frame.subterms));
+                    let is_root = frames.is_empty();
+                    match self.cycles.get(&frame.cell) {
+                        Some(&name) if is_root && var == Some(name) => {
+                            Some(term)
+                        }
+                        Some(&name) => {
+                            self.bindings.push((name, term));
+                            Some(Term::Variable(name))
+                        }
+                        None => Some(term),
+                    }
+                }
+            };
+        }
+    }
+
+    /// Like `extract`, but raises a type error if the term is cyclic, since
+    /// built-in predicates can't work with its finite representation.
+    pub fn extract_acyclic(&mut self, cell: HeapCell) -> Result<Term, Error> {
+        let term = self.extract(cell, None)?;
+        match (term, self.bindings.pop()) {
+            (term, None) => Ok(term),
+            // The term itself was cyclic, so it was replaced by a variable.
+            (Term::Variable(_), Some((_, term))) | (term, Some(_)) => {
+                Err(PrologError::Type("acyclic_term".into(), term).into())
+            }
+        }
+    }
+
+    /// Returns the values of the variables standing for cyclic subterms.
+    pub fn into_bindings(self) -> Vec<(Variable, Term)> {
+        self.bindings
+    }
+
+    /// Starts extracting the term a cell refers to. Returns the term if it
+    /// can be extracted at once; otherwise, pushes a frame for it.
+    fn start(
+        &mut self,
+        cell: HeapCell,
+        frames: &mut Vec<Frame>,
+        var: Option<Variable>,
+    ) -> Result<Option<Term>, Error> {
+        let cell = self.cells.deref_cell(cell)?;
+        let (atom, args) = match cell {
+            HeapCell::Con(atom) => {
+                return Ok(Some(Term::Structure(Structure(atom, vec![]))))
+            }
+            HeapCell::Float(f) => return Ok(Some(Term::Float(f))),
+            HeapCell::Functor(_) => {
+                let op = "extract".to_string();
+                return Err(MachineError::InvalidDeref(cell, op).into());
+            }
+            HeapCell::Int(n) => {
+                let n = self.cells.big_ints().value(n)?;
+                return Ok(Some(Term::Integer(n)));
+            }
+            HeapCell::Ref(n) => {
+                let name = self.names.get(&n).cloned().unwrap_or_else(|| {
+                    Variable::from_str(format!("_{}", n)).unwrap()
+                });
+                return Ok(Some(Term::Variable(name)));
+            }
+            HeapCell::Lis(a) => (".".into(), vec![a + 1, a]),
+            HeapCell::Str(a) => {
+                let Functor(atom, arity) = match self.cells.get(a)? {
+                    HeapCell::Functor(f) => f,
+                    cell => {
+                        return Err(MachineError::BadFunctorCell(a, cell).into())
+                    }
+                };
+                (atom, (1..(arity + 1)).rev().map(|i| a + i).collect())
+            }
+        };
+
+        if let Some(&name) = self.cycles.get(&cell) {
+            return Ok(Some(Term::Variable(name)));
+        } else if self.open.contains(&cell) {
+            // The term contains itself. If it is the value of the query
+            // variable, the variable can stand for it.
+            let name = match var {
+                Some(var) if frames[0].cell == cell => var,
+                _ => {
+                    self.num_cycles += 1;
+                    let name = format!("_S{}", self.num_cycles);
+                    Variable::from_str(name).unwrap()
+                }
+            };
+            self.cycles.insert(cell, name);
+            return Ok(Some(Term::Variable(name)));
+        }
+
+        self.open.insert(cell);
+        frames.push(Frame {
+            cell,
+            atom,
+            args,
+            subterms: Vec::new(),
+        });
+        Ok(None)
+    }
+}
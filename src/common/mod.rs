@@ -1,53 +1,130 @@
 //! Common code used by multiple chapters.
 
+pub mod arith;
+pub mod builtins;
 mod env;
+mod error;
+mod extract;
+mod number;
+mod operators;
 pub mod parsers;
 #[cfg(test)]
 mod tests;
+mod writer;
 
 use std::borrow::Cow;
 use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use nom::{Err as NomErr, IError, IResult, Needed};
+use nom::{Err as NomErr, ErrorKind, IError, IResult, Needed};
+use num_bigint::BigInt;
 use regex::Regex;
 use symbol::Symbol;
 
 pub use self::env::Env;
+pub use self::error::{Interrupted, MachineError, PrologError, Thrown};
+pub use self::extract::{Cells, Extractor};
+pub use self::number::{BigInts, Float, Int};
+pub use self::operators::{Op, OpTable, OpType, DEFAULT_OPS};
+pub use self::writer::{TermWriter, Written};
 
 /// An error while parsing.
 #[derive(Clone, Debug, Fail, PartialEq)]
-pub enum ParseError {
-    /// An error, possibly with the byte index at which it occurred.
-    Error(Option<usize>),
+pub struct ParseError {
+    /// The kind of error.
+    pub kind: ParseErrorKind,
+
+    /// Where in the input the error occurred, if known. Incomplete input is
+    /// reported at its end.
+    pub location: Option<Location>,
+
+    /// The file the input was read from, if any.
+    pub file: Option<PathBuf>,
+}
+
+/// The kinds of `ParseError`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// A syntax error, possibly with the kind of error nom reported, which
+    /// describes what was expected.
+    Error(Option<ErrorKind>),
 
     /// An error which can be resolved by adding more input.
     Incomplete(Needed),
 }
 
+/// A position in the input to a parser.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+    /// The byte index.
+    pub offset: usize,
+
+    /// The line number, starting at 1.
+    pub line: usize,
+
+    /// The column number in characters, starting at 1.
+    pub column: usize,
+
+    /// The text of the line, without its line ending.
+    pub text: String,
+}
+
+impl Location {
+    /// Finds the line and column of a byte index in the input.
+    pub fn new(src: &str, offset: usize) -> Location {
+        let before = &src[..offset];
+        let line_start = before.rfind('\n').map(|n| n + 1).unwrap_or(0);
+        let line_end = src[offset..]
+            .find('\n')
+            .map(|n| offset + n)
+            .unwrap_or(src.len());
+        Location {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            text: src[line_start..line_end].trim_end_matches('\r').to_owned(),
+        }
+    }
+}
+
 impl ParseError {
     /// Creates a ParseError from a `nom::IError` and the input.
     pub fn from_ierror(err: IError<&str>, src: &str) -> ParseError {
-        fn find_position(err: NomErr<&str>) -> Option<&str> {
+        /// Finds the kind of an error and the input it occurred at, looking
+        /// for the first nested error with a position if it has none.
+        fn find_position(
+            err: NomErr<&str>,
+        ) -> (Option<ErrorKind>, Option<&str>) {
             match err {
-                NomErr::Code(_) => None,
-                NomErr::Node(_, errs) => {
+                NomErr::Code(kind) => (Some(kind), None),
+                NomErr::Node(kind, errs) => {
                     for err in errs {
-                        if let Some(pos) = find_position(err) {
-                            return Some(pos);
+                        if let (kind, Some(pos)) = find_position(err) {
+                            return (kind, Some(pos));
                         }
                     }
-                    None
+                    (Some(kind), None)
                 }
-                NomErr::Position(_, pos) => Some(pos),
-                NomErr::NodePosition(_, pos, _) => Some(pos),
+                NomErr::Position(kind, pos) => (Some(kind), Some(pos)),
+                NomErr::NodePosition(kind, pos, _) => (Some(kind), Some(pos)),
             }
         }
 
-        match err {
-            IError::Incomplete(needed) => ParseError::Incomplete(needed),
-            IError::Error(err) => ParseError::Error(
-                find_position(err).map(|pos| src.len() - pos.len()),
-            ),
+        let (kind, offset) = match err {
+            IError::Incomplete(needed) => {
+                (ParseErrorKind::Incomplete(needed), Some(src.len()))
+            }
+            IError::Error(err) => {
+                let (kind, pos) = find_position(err);
+                let offset = pos.map(|pos| src.len() - pos.len());
+                (ParseErrorKind::Error(kind), offset)
+            }
+        };
+        ParseError {
+            kind,
+            location: offset.map(|offset| Location::new(src, offset)),
+            file: None,
         }
     }
 
@@ -59,29 +136,85 @@ impl ParseError {
         let res = match res {
             IResult::Done("", val) => Ok(val),
             IResult::Done(pos, _) => {
-                return Err(ParseError::Error(Some(src.len() - pos.len())));
+                let code = parsers::Expected::EndOfInput.code();
+                let err = NomErr::Position(ErrorKind::Custom(code), pos);
+                Err(IError::Error(err))
             }
             IResult::Incomplete(needed) => Err(IError::Incomplete(needed)),
             IResult::Error(err) => Err(IError::Error(err)),
         };
         res.map_err(|err| ParseError::from_ierror(err, src))
     }
+
+    /// Sets the file the input was read from.
+    pub fn in_file<P: Into<PathBuf>>(self, file: P) -> ParseError {
+        ParseError {
+            file: Some(file.into()),
+            ..self
+        }
+    }
+
+    /// Returns whether the error can be resolved by adding more input.
+    pub fn is_incomplete(&self) -> bool {
+        match self.kind {
+            ParseErrorKind::Incomplete(_) => true,
+            ParseErrorKind::Error(_) => false,
+        }
+    }
 }
 
+/// Displays the error like rustc does, with the line it occurred on and a
+/// caret under where in the line it occurred.
 impl Display for ParseError {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        match *self {
-            ParseError::Error(Some(n)) => {
-                write!(fmt, "Parse error at character {}", n)
+        match self.kind {
+            ParseErrorKind::Error(Some(ErrorKind::Custom(code))) => {
+                match parsers::Expected::from_code(code) {
+                    Some(expected) => write!(fmt, "expected {}", expected)?,
+                    None => fmt.write_str("syntax error")?,
+                }
+            }
+            ParseErrorKind::Error(Some(ref kind)) => {
+                write!(fmt, "syntax error: {}", kind.description())?
+            }
+            ParseErrorKind::Error(None) => fmt.write_str("syntax error")?,
+            ParseErrorKind::Incomplete(_) if self.file.is_some() => {
+                fmt.write_str("unexpected end of file")?
             }
-            ParseError::Error(None) => fmt.write_str("Parse error"),
-            ParseError::Incomplete(Needed::Unknown) => {
-                fmt.write_str("Incomplete input")
+            ParseErrorKind::Incomplete(_) => {
+                fmt.write_str("unexpected end of input")?
             }
-            ParseError::Incomplete(Needed::Size(size)) => {
-                write!(fmt, "Incomplete input ({} characters needed)", size)
+        }
+
+        let loc = match self.location {
+            Some(ref loc) => loc,
+            None => {
+                return match self.file {
+                    Some(ref file) => write!(fmt, "\n --> {}", file.display()),
+                    None => Ok(()),
+                }
             }
+        };
+        let gutter = " ".repeat(loc.line.to_string().len());
+        if let Some(ref file) = self.file {
+            write!(
+                fmt,
+                "\n{}--> {}:{}:{}",
+                gutter,
+                file.display(),
+                loc.line,
+                loc.column
+            )?;
         }
+        // Tabs are kept before the caret, so that it lines up.
+        let indent: String = loc.text
+            .chars()
+            .take(loc.column - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(fmt, "\n{} |", gutter)?;
+        write!(fmt, "\n{} | {}", loc.line, loc.text)?;
+        write!(fmt, "\n{} | {}^", gutter, indent)
     }
 }
 
@@ -95,48 +228,71 @@ impl AsRef<str> for Atom {
     }
 }
 
+impl Atom {
+    /// Returns whether the atom consists entirely of symbol characters, like
+    /// `=..` or `\+`.
+    pub fn is_symbolic(&self) -> bool {
+        let atom = self.0.as_str();
+        !atom.is_empty() && atom.chars().all(is_symbol_char)
+    }
+
+    /// Writes the atom surrounded by quotes, escaping it as needed.
+    fn write_quoted<W: Write>(&self, fmt: &mut W) -> FmtResult {
+        fmt.write_char('\'')?;
+        for ch in self.0.as_str().chars() {
+            if is_quoted_plain_char(ch) {
+                fmt.write_char(ch)?;
+            } else {
+                match ch {
+                    '\x07' => fmt.write_str("\\a")?,
+                    '\x08' => fmt.write_str("\\b")?,
+                    '\x1b' => fmt.write_str("\\e")?,
+                    '\x0c' => fmt.write_str("\\f")?,
+                    '\n' => fmt.write_str("\\n")?,
+                    '\r' => fmt.write_str("\\r")?,
+                    '\t' => fmt.write_str("\\t")?,
+                    '\x0b' => fmt.write_str("\\v")?,
+                    '\\' => fmt.write_str("\\\\")?,
+                    '\'' => fmt.write_str("\\'")?,
+                    '"' => fmt.write_str("\\\"")?,
+                    _ => {
+                        let n = ch as u32;
+                        if n < 0x10000 {
+                            write!(fmt, "\\u{:04x}", n)?;
+                        } else {
+                            write!(fmt, "\\U{:08x}", n)?;
+                        }
+                    }
+                }
+            }
+        }
+        fmt.write_char('\'')
+    }
+}
+
 fn is_quoted_plain_char(ch: char) -> bool {
     (' ' <= ch && ch <= '~') && (ch != '\'' && ch != '\\')
 }
 
+fn is_symbol_char(ch: char) -> bool {
+    "+-*/\\^<>=~:.?@#&$".contains(ch)
+}
+
 impl Display for Atom {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         lazy_static! {
-            static ref PLAIN: Regex = Regex::new("^[a-z0-9][a-zA-Z_0-9]*$").unwrap();
+            static ref PLAIN: Regex = Regex::new("^[a-z][a-zA-Z_0-9]*$").unwrap();
         }
         let atom = self.0.as_str();
-        if PLAIN.is_match(atom) {
+        // A lone `.` would end the clause, and `/*` starts a comment in most
+        // Prologs, so those are quoted even though they are symbolic.
+        let symbolic =
+            self.is_symbolic() && atom != "." && !atom.starts_with("/*");
+        let solo = atom == "!" || atom == ";" || atom == "[]";
+        if PLAIN.is_match(atom) || symbolic || solo {
             fmt.write_str(atom)
         } else {
-            fmt.write_char('\'')?;
-            for ch in atom.chars() {
-                if is_quoted_plain_char(ch) {
-                    fmt.write_char(ch)?;
-                } else {
-                    match ch {
-                        '\x07' => fmt.write_str("\\a")?,
-                        '\x08' => fmt.write_str("\\b")?,
-                        '\x1b' => fmt.write_str("\\e")?,
-                        '\x0c' => fmt.write_str("\\f")?,
-                        '\n' => fmt.write_str("\\n")?,
-                        '\r' => fmt.write_str("\\r")?,
-                        '\t' => fmt.write_str("\\t")?,
-                        '\x0b' => fmt.write_str("\\v")?,
-                        '\\' => fmt.write_str("\\\\")?,
-                        '\'' => fmt.write_str("\\'")?,
-                        '"' => fmt.write_str("\\\"")?,
-                        _ => {
-                            let n = ch as u32;
-                            if n < 0x10000 {
-                                write!(fmt, "\\u{:04x}", n)?;
-                            } else {
-                                write!(fmt, "\\U{:08x}", n)?;
-                            }
-                        }
-                    }
-                }
-            }
-            fmt.write_char('\'')
+            self.write_quoted(fmt)
         }
     }
 }
@@ -163,9 +319,30 @@ impl From<String> for Atom {
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Functor(pub Atom, pub usize);
 
+impl Functor {
+    /// Returns whether this is the functor of a list cell, `'.'/2`.
+    pub fn is_list(self) -> bool {
+        self.1 == 2 && (self.0).0.as_str() == "."
+    }
+
+    /// Returns the predicate indicator for the functor, which is the term
+    /// `Name/Arity`.
+    pub fn indicator(self) -> Term {
+        let name = Term::Structure(Structure(self.0, vec![]));
+        let arity = Term::Integer(self.1.into());
+        Term::Structure(Structure("/".into(), vec![name, arity]))
+    }
+}
+
 impl Display for Functor {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        write!(fmt, "{}/{}", self.0, self.1)
+        // A symbolic name would run into the slash, so it is always quoted.
+        if self.0.is_symbolic() {
+            self.0.write_quoted(fmt)?;
+        } else {
+            Display::fmt(&self.0, fmt)?;
+        }
+        write!(fmt, "/{}", self.1)
     }
 }
 
@@ -213,6 +390,12 @@ pub enum Term {
     /// affect other anonymous variables in the term.
     Anonymous,
 
+    /// A floating-point number.
+    Float(Float),
+
+    /// An integer, which may be arbitrarily large.
+    Integer(BigInt),
+
     /// A structure literal.
     Structure(Structure),
 
@@ -231,24 +414,31 @@ impl Term {
             return true;
         }
         match *self {
-            Term::Anonymous => false,
             Term::Structure(ref s) => s.1.iter().any(|t| t.contains(sub)),
             Term::Variable(ref v) => match *sub {
-                Term::Anonymous => false,
                 Term::Variable(ref v2) => v == v2,
-                Term::Structure(_) => false,
+                _ => false,
             },
+            _ => false,
         }
     }
+
+    /// Creates a proper list holding the given terms.
+    pub fn list<I>(items: I) -> Term
+    where
+        I: IntoIterator<Item = Term>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        let nil = Term::Structure(Structure("[]".into(), vec![]));
+        items.into_iter().rev().fold(nil, |tail, head| {
+            Term::Structure(Structure(".".into(), vec![head, tail]))
+        })
+    }
 }
 
 impl Display for Term {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        match *self {
-            Term::Anonymous => fmt.write_char('_'),
-            Term::Structure(ref s) => Display::fmt(s, fmt),
-            Term::Variable(ref v) => Display::fmt(v, fmt),
-        }
+        Display::fmt(&TermWriter::new().term(self), fmt)
     }
 }
 
@@ -265,22 +455,7 @@ impl Structure {
 
 impl Display for Structure {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        Display::fmt(&self.0, fmt)?;
-        if self.1.is_empty() {
-            Ok(())
-        } else {
-            fmt.write_char('(')?;
-            let mut first = true;
-            for arg in &self.1 {
-                if first {
-                    first = false;
-                } else {
-                    fmt.write_str(", ")?;
-                }
-                Display::fmt(arg, fmt)?;
-            }
-            fmt.write_char(')')
-        }
+        Display::fmt(&TermWriter::new().structure(self), fmt)
     }
 }
 
@@ -294,30 +469,103 @@ pub struct Clause(pub Structure, pub Vec<Structure>);
 
 impl Display for Clause {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        let &Clause(ref hd, ref tl) = self;
-        Display::fmt(hd, fmt)?;
-        if !tl.is_empty() {
-            let mut first = true;
-            for term in tl {
-                if first {
-                    fmt.write_str(" :-\n    ")?;
-                    first = false;
-                } else {
-                    fmt.write_str(",\n    ")?;
-                }
-                Display::fmt(term, fmt)?;
+        Display::fmt(&TermWriter::new().clause(self), fmt)
+    }
+}
+
+/// A constant, which is stored directly in a heap cell rather than being
+/// built out of a functor cell.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Constant {
+    /// An atom, which is a structure with no arguments.
+    Atom(Atom),
+
+    /// A floating-point number.
+    Float(Float),
+
+    /// An integer.
+    Int(Int),
+}
+
+impl Constant {
+    /// Returns the constant a term is, if it is an atom or a number. Big
+    /// integers are added to the given table.
+    pub fn from_term(
+        term: &Term,
+        big_ints: &mut BigInts,
+    ) -> Option<Constant> {
+        match *term {
+            Term::Float(f) => Some(Constant::Float(f)),
+            Term::Integer(ref n) => {
+                Some(Constant::Int(big_ints.int(n.clone())))
+            }
+            Term::Structure(Structure(atom, ref args)) if args.is_empty() => {
+                Some(Constant::Atom(atom))
             }
+            _ => None,
+        }
+    }
+
+    /// Returns the constant stored in a heap cell, if it holds one.
+    pub fn from_cell(cell: HeapCell) -> Option<Constant> {
+        match cell {
+            HeapCell::Con(atom) => Some(Constant::Atom(atom)),
+            HeapCell::Float(f) => Some(Constant::Float(f)),
+            HeapCell::Int(n) => Some(Constant::Int(n)),
+            _ => None,
+        }
+    }
+
+    /// Returns the heap cell that stores the constant.
+    pub fn cell(self) -> HeapCell {
+        match self {
+            Constant::Atom(atom) => HeapCell::Con(atom),
+            Constant::Float(f) => HeapCell::Float(f),
+            Constant::Int(n) => HeapCell::Int(n),
+        }
+    }
+
+    /// Converts the constant to a term, looking up big integers in the
+    /// given table.
+    pub fn to_term(self, big_ints: &BigInts) -> Result<Term, MachineError> {
+        Ok(match self {
+            Constant::Atom(atom) => Term::Structure(Structure(atom, vec![])),
+            Constant::Float(f) => Term::Float(f),
+            Constant::Int(n) => Term::Integer(big_ints.value(n)?),
+        })
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Constant::Atom(atom) => Display::fmt(&atom, fmt),
+            Constant::Float(f) => Display::fmt(&f, fmt),
+            Constant::Int(n) => Display::fmt(&n, fmt),
         }
-        fmt.write_char('.')
     }
 }
 
 /// A single cell on the heap.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum HeapCell {
+    /// A constant, which is a structure with no arguments stored directly in
+    /// the cell.
+    Con(Atom),
+
+    /// A floating-point number.
+    Float(Float),
+
     /// A functor.
     Functor(Functor),
 
+    /// An integer.
+    Int(Int),
+
+    /// A list reference, which points to a pair of cells holding the head
+    /// and tail of a `'.'/2` structure.
+    Lis(usize),
+
     /// A reference to another cell.
     Ref(usize),
 
@@ -333,4 +581,67 @@ impl HeapCell {
             _ => false,
         }
     }
+
+    /// Returns whether the given cell is a constant, which is an atom or a
+    /// number.
+    pub fn is_constant(self) -> bool {
+        Constant::from_cell(self).is_some()
+    }
+}
+
+/// What a machine does when a procedure with no clauses is called, as set by
+/// the `unknown` flag of ISO Prolog.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Unknown {
+    /// Raise an existence error. This is the default.
+    #[default]
+    Error,
+
+    /// Fail.
+    Fail,
+
+    /// Print a warning to stderr, then fail. The warning is printed rather
+    /// than logged, so that it is seen whatever the log level is.
+    Warning,
+}
+
+impl Unknown {
+    /// Handles a call to an unknown procedure. Returns the error to raise, if
+    /// there is one; otherwise, the call fails.
+    pub fn call(self, functor: Functor) -> Result<(), PrologError> {
+        match self {
+            Unknown::Error => Err(PrologError::Existence(
+                "procedure".into(),
+                functor.indicator(),
+            )),
+            Unknown::Fail => Ok(()),
+            Unknown::Warning => {
+                eprintln!("Warning: Unknown procedure {}", functor);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for Unknown {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(match *self {
+            Unknown::Error => "error",
+            Unknown::Fail => "fail",
+            Unknown::Warning => "warning",
+        })
+    }
+}
+
+impl FromStr for Unknown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Unknown, String> {
+        match s {
+            "error" => Ok(Unknown::Error),
+            "fail" => Ok(Unknown::Fail),
+            "warning" => Ok(Unknown::Warning),
+            _ => Err(format!("Invalid value for the unknown flag: {}", s)),
+        }
+    }
 }
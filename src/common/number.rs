@@ -0,0 +1,155 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::hash::{Hash, Hasher};
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use common::MachineError;
+
+/// An integer, as stored in a heap cell.
+///
+/// Integers that fit in an `i64` are stored directly. Larger ones are kept in
+/// the machine's `BigInts` table and stored as their index in it, so that
+/// cells can stay `Copy`. An integer is only ever `Big` if it does not fit in
+/// an `i64`, and the table holds each value once, so the derived equality
+/// compares values. The derived order does not.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Int {
+    /// An integer that fits in an `i64`.
+    Small(i64),
+
+    /// A larger integer, by its index in the machine's `BigInts`.
+    Big(usize),
+}
+
+impl From<i64> for Int {
+    fn from(n: i64) -> Int {
+        Int::Small(n)
+    }
+}
+
+impl Display for Int {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Int::Small(n) => Display::fmt(&n, fmt),
+            Int::Big(i) => write!(fmt, "<big integer {}>", i),
+        }
+    }
+}
+
+/// The integers too large for an `i64` that a machine's code and heap refer
+/// to. Those added while running a query are discarded when the machine is
+/// reset, along with the query's code and heap.
+#[derive(Debug, Default)]
+pub struct BigInts {
+    ints: Vec<BigInt>,
+    indices: HashMap<BigInt, usize>,
+
+    /// The number of integers that belong to the program, and are kept when
+    /// the table is reset.
+    kept: usize,
+}
+
+impl BigInts {
+    /// Constructs a new, empty BigInts table.
+    pub fn new() -> BigInts {
+        Default::default()
+    }
+
+    /// Returns an integer as it is stored in a cell, adding it to the table
+    /// if it is too large for an `i64` and not there already.
+    pub fn int(&mut self, n: BigInt) -> Int {
+        if let Some(n) = n.to_i64() {
+            return Int::Small(n);
+        }
+        if let Some(&i) = self.indices.get(&n) {
+            return Int::Big(i);
+        }
+        let i = self.ints.len();
+        self.ints.push(n.clone());
+        self.indices.insert(n, i);
+        Int::Big(i)
+    }
+
+    /// Returns the value of an integer stored in a cell.
+    pub fn value(&self, n: Int) -> Result<BigInt, MachineError> {
+        match n {
+            Int::Small(n) => Ok(n.into()),
+            Int::Big(i) => match self.ints.get(i) {
+                Some(n) => Ok(n.clone()),
+                None => Err(MachineError::BigIntOutOfRange(i)),
+            },
+        }
+    }
+
+    /// Marks the integers in the table as belonging to the program, so that
+    /// resetting the table keeps them.
+    pub fn keep(&mut self) {
+        self.kept = self.ints.len();
+    }
+
+    /// Discards the integers added since the table was last kept.
+    pub fn reset(&mut self) {
+        for n in self.ints.drain(self.kept..) {
+            self.indices.remove(&n);
+        }
+    }
+}
+
+/// A floating-point number.
+///
+/// Unlike an `f64`, floats are totally ordered, so that they can be stored in
+/// terms. Two floats are only equal if they have the same bits, so `0.0` and
+/// `-0.0` are different terms.
+#[derive(Clone, Copy, Debug)]
+pub struct Float(pub f64);
+
+impl PartialEq for Float {
+    fn eq(&self, other: &Float) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for Float {}
+
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Float) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Float {
+    fn cmp(&self, other: &Float) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for Float {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state)
+    }
+}
+
+impl Display for Float {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let f = self.0;
+        if f.is_nan() {
+            fmt.write_str("1.5NaN")
+        } else if f.is_infinite() {
+            fmt.write_str(if f < 0.0 { "-1.0Inf" } else { "1.0Inf" })
+        } else {
+            // Debug formatting gives the shortest representation that reads
+            // back as the same float, but leaves out the fraction before an
+            // exponent, which Prolog requires.
+            let s = format!("{:?}", f);
+            match s.find('e') {
+                Some(i) if !s[..i].contains('.') => {
+                    write!(fmt, "{}.0{}", &s[..i], &s[i..])
+                }
+                _ => fmt.write_str(&s),
+            }
+        }
+    }
+}
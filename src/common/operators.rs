@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use common::Atom;
+
+/// The type of an operator, which determines whether it is prefix, infix, or
+/// postfix, and how it associates.
+///
+/// An `x` stands for an argument whose priority must be strictly less than
+/// the operator's, and a `y` for an argument whose priority may be equal to
+/// it. For example, `-` is `yfx`, so `a - b - c` is `(a - b) - c`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OpType {
+    /// A non-associative infix operator.
+    XFX,
+
+    /// A right-associative infix operator.
+    XFY,
+
+    /// A left-associative infix operator.
+    YFX,
+
+    /// An associative prefix operator.
+    FY,
+
+    /// A non-associative prefix operator.
+    FX,
+
+    /// A non-associative postfix operator.
+    XF,
+
+    /// An associative postfix operator.
+    YF,
+}
+
+impl OpType {
+    /// Returns the operator type with the given name, such as `xfx`.
+    pub fn from_name(name: &str) -> Option<OpType> {
+        match name {
+            "xfx" => Some(OpType::XFX),
+            "xfy" => Some(OpType::XFY),
+            "yfx" => Some(OpType::YFX),
+            "fy" => Some(OpType::FY),
+            "fx" => Some(OpType::FX),
+            "xf" => Some(OpType::XF),
+            "yf" => Some(OpType::YF),
+            _ => None,
+        }
+    }
+}
+
+impl Display for OpType {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(match *self {
+            OpType::XFX => "xfx",
+            OpType::XFY => "xfy",
+            OpType::YFX => "yfx",
+            OpType::FY => "fy",
+            OpType::FX => "fx",
+            OpType::XF => "xf",
+            OpType::YF => "yf",
+        })
+    }
+}
+
+/// An operator definition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Op {
+    /// The priority of the operator, from 1 to 1200. Operators with a lower
+    /// priority bind more tightly.
+    pub priority: u32,
+
+    /// The type of the operator.
+    pub op_type: OpType,
+}
+
+impl Op {
+    /// Returns the maximum priorities of the left and right arguments of the
+    /// operator. Prefix operators have no left argument, and postfix
+    /// operators no right argument; the missing side is given as 0.
+    pub fn arg_priorities(self) -> (u32, u32) {
+        let p = self.priority;
+        match self.op_type {
+            OpType::XFX => (p - 1, p - 1),
+            OpType::XFY => (p - 1, p),
+            OpType::YFX => (p, p - 1),
+            OpType::FY => (0, p),
+            OpType::FX => (0, p - 1),
+            OpType::XF => (p - 1, 0),
+            OpType::YF => (p, 0),
+        }
+    }
+}
+
+/// A table of operators, used when reading and writing terms.
+///
+/// An atom may be defined as a prefix operator and as an infix or postfix
+/// operator at the same time, which is why there are three separate tables.
+#[derive(Clone, Debug)]
+pub struct OpTable {
+    prefix: HashMap<Atom, Op>,
+    infix: HashMap<Atom, Op>,
+    postfix: HashMap<Atom, Op>,
+}
+
+impl OpTable {
+    /// Creates an OpTable with no operators in it.
+    pub fn empty() -> OpTable {
+        OpTable {
+            prefix: HashMap::new(),
+            infix: HashMap::new(),
+            postfix: HashMap::new(),
+        }
+    }
+
+    /// Creates an OpTable with the operators defined by the ISO standard.
+    pub fn new() -> OpTable {
+        let mut table = OpTable::empty();
+        for &(priority, op_type, names) in ISO_OPERATORS {
+            for &name in names {
+                table.add(priority, op_type, name.into());
+            }
+        }
+        table
+    }
+
+    /// Defines an operator, replacing any existing definition of the same
+    /// kind (prefix, infix, or postfix) for the atom. A priority of 0 removes
+    /// the definition instead.
+    pub fn add(&mut self, priority: u32, op_type: OpType, name: Atom) {
+        let table = match op_type {
+            OpType::XFX | OpType::XFY | OpType::YFX => &mut self.infix,
+            OpType::FY | OpType::FX => &mut self.prefix,
+            OpType::XF | OpType::YF => &mut self.postfix,
+        };
+        if priority == 0 {
+            table.remove(&name);
+        } else {
+            table.insert(name, Op { priority, op_type });
+        }
+    }
+
+    /// Returns the definition of the atom as a prefix operator, if any.
+    pub fn prefix(&self, name: Atom) -> Option<Op> {
+        self.prefix.get(&name).cloned()
+    }
+
+    /// Returns the definition of the atom as an infix operator, if any.
+    pub fn infix(&self, name: Atom) -> Option<Op> {
+        self.infix.get(&name).cloned()
+    }
+
+    /// Returns the definition of the atom as a postfix operator, if any.
+    pub fn postfix(&self, name: Atom) -> Option<Op> {
+        self.postfix.get(&name).cloned()
+    }
+}
+
+impl Default for OpTable {
+    fn default() -> OpTable {
+        OpTable::new()
+    }
+}
+
+lazy_static! {
+    /// A table of the ISO default operators, for when no other table is
+    /// given.
+    pub static ref DEFAULT_OPS: OpTable = OpTable::new();
+}
+
+/// The default operators, from table 7 of the ISO standard.
+const ISO_OPERATORS: &[(u32, OpType, &[&str])] = &[
+    (1200, OpType::XFX, &[":-", "-->"]),
+    (1200, OpType::FX, &[":-", "?-"]),
+    (1100, OpType::XFY, &[";"]),
+    (1050, OpType::XFY, &["->"]),
+    (1000, OpType::XFY, &[","]),
+    (900, OpType::FY, &["\\+"]),
+    (
+        700,
+        OpType::XFX,
+        &[
+            "=", "\\=", "==", "\\==", "@<", "@>", "@=<", "@>=", "=..", "is",
+            "=:=", "=\\=", "<", ">", "=<", ">=",
+        ],
+    ),
+    (500, OpType::YFX, &["+", "-", "/\\", "\\/", "xor"]),
+    (
+        400,
+        OpType::YFX,
+        &["*", "/", "//", "rem", "mod", "div", "<<", ">>"],
+    ),
+    (200, OpType::XFX, &["**"]),
+    (200, OpType::XFY, &["^"]),
+    (200, OpType::FY, &["-", "+", "\\"]),
+];
@@ -1,15 +1,21 @@
-//! Nom parsers for various syntactic elements.
+//! Parsers for various syntactic elements.
 //!
-//! Currently does not support:
-//!
-//!  - Infix operators
+//! The smaller elements, such as atoms and variables, are parsed with nom.
+//! Terms are read with a hand-written operator precedence parser, which is
+//! driven by an `OpTable`. The parsers which read terms come in two forms:
+//! one which takes the table to use, and one which uses the ISO default
+//! operators.
 
 use std::char;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::str::FromStr;
 
-use nom::{digit, hex_digit, multispace, IResult, Needed};
+use nom::{digit, hex_digit, multispace, ErrorKind, IError, IResult, Needed};
+use num_bigint::BigInt;
+use num_traits::{Num, ToPrimitive};
 
-use common::{Atom, Clause, Functor, Structure, Term, Variable};
+use common::{is_symbol_char, Atom, Clause, Float, Functor, OpTable, OpType,
+             ParseError, Structure, Term, Variable, DEFAULT_OPS};
 
 macro_rules! from_str {
     ($($(#[$meta:meta])* $parser:ident => $ty:ty),*$(,)*) => {
@@ -32,6 +38,9 @@ from_str! {
     /// Parses a Functor.
     functor => Functor,
 
+    /// Parses a Structure.
+    structure => Structure,
+
     /// Parses a Term.
     term => Term,
 }
@@ -54,21 +63,20 @@ macro_rules! remove_whitespace_and_comments {
 
 named_attr!(
     #[doc = "Parses an `Atom`."],
-    pub atom(&str) -> Atom, remove_whitespace_and_comments!(alt!(
-    map!(
-        delimited!(tag_s!("'"), many0!(atom_quoted_char), tag_s!("'")),
-        |cs| Atom::from(cs.into_iter().filter_map(|x| x).collect::<String>())
-    ) |
-    map!(unquoted_atom, |cs| cs.into())
-)));
+    pub atom(&str) -> Atom, remove_whitespace_and_comments!(name));
 
-named_attr!(
-    #[doc = "Parses a `Clause`."],
-    pub clause(&str) -> Clause, remove_whitespace_and_comments!(do_parse!(
-    clause: alt!(clause_rule | clause_fact) >>
-    tag_s!(".") >>
-    ( clause )
-)));
+/// Parses a `Clause`, using the default operators.
+pub fn clause(input: &str) -> IResult<&str, Clause> {
+    clause_with_ops(input, &DEFAULT_OPS)
+}
+
+/// Parses a `Clause`, using the given operators.
+pub fn clause_with_ops<'a>(
+    input: &'a str,
+    ops: &OpTable,
+) -> IResult<&'a str, Clause> {
+    to_iresult(Reader { ops }.clause(input))
+}
 
 named_attr!(
     #[doc = "Parses a `Functor`."],
@@ -79,39 +87,130 @@ named_attr!(
     ( Functor(atom, arity) )
 )));
 
-named_attr!(
-    #[doc = "Parses a series of `Clause`s."],
-    pub program(&str) -> Vec<Clause>,
-    remove_whitespace_and_comments!(many0!(clause)));
+/// Parses a series of `Clause`s, starting with the default operators.
+pub fn program(input: &str) -> (Vec<Clause>, Vec<ParseError>) {
+    program_with_ops(input, &mut OpTable::new())
+}
 
-named_attr!(
-    #[doc = "Parses a query, which is a conjunctive list of `Structure`s."],
-    pub query(&str) -> Vec<Structure>, remove_whitespace_and_comments!(do_parse!(
-    structures: separated_list!(tag_s!(","), structure) >>
-    tag_s!(".") >>
-    ( structures )
-)));
+/// Parses a series of `Clause`s, starting with the given operators.
+///
+/// Directives, which are clauses of the form `:- Goal.`, are run as they are
+/// read. The only directive currently supported is `op/3`, which changes the
+/// operator table for the rest of the program; any others are ignored with a
+/// warning.
+///
+/// A syntax error doesn't stop the parse: the rest of the clause it is in is
+/// skipped, up to its end token, and reading carries on from the next one.
+/// The clauses that were read are returned along with every error.
+pub fn program_with_ops(
+    src: &str,
+    ops: &mut OpTable,
+) -> (Vec<Clause>, Vec<ParseError>) {
+    let mut clauses = Vec::new();
+    let mut errors = Vec::new();
+    let mut input = src;
+    loop {
+        input = skip_layout(input);
+        if input.is_empty() {
+            return (clauses, errors);
+        }
+        let res = Reader { ops }.sentence(input);
+        let (rest, term) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                errors.push(to_parse_error(err, src));
+                match err.and_then(|(pos, _)| skip_clause(pos)) {
+                    Some(rest) => {
+                        input = rest;
+                        continue;
+                    }
+                    None => return (clauses, errors),
+                }
+            }
+        };
+        let expected = match directive_goal(&term) {
+            Some(goal) if run_directive(goal, ops) => None,
+            Some(_) => Some(Expected::Directive),
+            None => match into_clause(term) {
+                Some(clause) => {
+                    clauses.push(clause);
+                    None
+                }
+                None => Some(Expected::Clause),
+            },
+        };
+        if let Some(expected) = expected {
+            errors.push(to_parse_error(Some((input, expected)), src));
+        }
+        input = rest;
+    }
+}
 
-named_attr!(
-    #[doc = "Parses a `Structure`."],
-    pub structure(&str) -> Structure, remove_whitespace_and_comments!(do_parse!(
-    atom: atom >>
-    subterms: opt!(complete!(delimited!(
-        tag_s!("("),
-        separated_list!(tag_s!(","), term),
-        tag_s!(")")))) >>
-    ( Structure(atom, subterms.unwrap_or_else(Vec::new)) )
-)));
+/// Skips to just after the next end token, to carry on reading after a syntax
+/// error. Characters that don't start a valid token are skipped one by one.
+/// Returns `None` if the input ends first.
+fn skip_clause(mut input: &str) -> Option<&str> {
+    loop {
+        match token(input) {
+            Ok((rest, Token::End)) => return Some(rest),
+            Ok((rest, _)) => input = rest,
+            Err(Some((pos, _))) => {
+                let mut chars = pos.chars();
+                chars.next()?;
+                input = chars.as_str();
+            }
+            Err(None) => return None,
+        }
+    }
+}
 
-named_attr!(
-    #[doc = "Parses a `Term`."],
-    pub term(&str) -> Term, remove_whitespace_and_comments!(alt!(
-    map!(variable, |s| if s == "_" {
-        Term::Anonymous
-    } else {
-        Term::Variable(Variable::from_str(s).unwrap())
-    }) | map!(structure, Term::Structure)
-)));
+/// Parses a query, which is a conjunction of `Structure`s, using the default
+/// operators.
+pub fn query(input: &str) -> IResult<&str, Vec<Structure>> {
+    query_with_ops(input, &DEFAULT_OPS)
+}
+
+/// Parses a query, which is a conjunction of `Structure`s, using the given
+/// operators.
+pub fn query_with_ops<'a>(
+    input: &'a str,
+    ops: &OpTable,
+) -> IResult<&'a str, Vec<Structure>> {
+    to_iresult(Reader { ops }.query(input))
+}
+
+/// Parses a `Structure`, using the default operators.
+pub fn structure(input: &str) -> IResult<&str, Structure> {
+    structure_with_ops(input, &DEFAULT_OPS)
+}
+
+/// Parses a `Structure`, using the given operators.
+pub fn structure_with_ops<'a>(
+    input: &'a str,
+    ops: &OpTable,
+) -> IResult<&'a str, Structure> {
+    let input = skip_layout(input);
+    to_iresult(Reader { ops }.term(input, 1200).and_then(|(rest, (t, _))| {
+        match t {
+            Term::Structure(s) => Ok((rest, s)),
+            _ => Err(Some((input, Expected::Callable))),
+        }
+    }))
+}
+
+/// Parses a `Term`, using the default operators.
+pub fn term(input: &str) -> IResult<&str, Term> {
+    term_with_ops(input, &DEFAULT_OPS)
+}
+
+/// Parses a `Term`, using the given operators.
+pub fn term_with_ops<'a>(
+    input: &'a str,
+    ops: &OpTable,
+) -> IResult<&'a str, Term> {
+    let res = Reader { ops }.term(input, 1200);
+    to_iresult(res.map(|(rest, (term, _))| (rest, term)))
+}
 
 named_attr!(
     #[doc = "Parses a valid `Variable`."],
@@ -120,9 +219,627 @@ named_attr!(
     take_while_s!(is_plain_char)
 ))));
 
+// The term reader.
+
+/// The result of one of the parts of the term reader. An error holds the
+/// input at which it occurred and what was expected there, or `None` if more
+/// input is needed.
+type ReadResult<'a, T> = Result<(&'a str, T), Option<(&'a str, Expected)>>;
+
+/// What the term reader expected when it found a syntax error. These are
+/// reported as `nom::ErrorKind::Custom` errors, with the code from `code`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Expected {
+    /// A term.
+    Term,
+
+    /// An operator, or the end of the clause or query.
+    End,
+
+    /// An operator, or a closing parenthesis.
+    CloseParen,
+
+    /// An operator, or a closing bracket after the tail of a list.
+    CloseBracket,
+
+    /// An operator, or what comes after an argument of a compound term.
+    ArgSeparator,
+
+    /// An operator, or what comes after an element of a list.
+    ListSeparator,
+
+    /// A clause, rather than a term that isn't one, such as a number.
+    Clause,
+
+    /// A supported directive with valid arguments.
+    Directive,
+
+    /// A term that can be a goal: an atom or compound term.
+    Callable,
+
+    /// A valid token.
+    Token,
+
+    /// A number.
+    Number,
+
+    /// The end of the input, rather than anything after what was parsed.
+    EndOfInput,
+}
+
+impl Expected {
+    /// Returns the error code this is reported with.
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns what an error code says was expected, if it is one returned
+    /// by `code`.
+    pub fn from_code(code: u32) -> Option<Expected> {
+        [
+            Expected::Term,
+            Expected::End,
+            Expected::CloseParen,
+            Expected::CloseBracket,
+            Expected::ArgSeparator,
+            Expected::ListSeparator,
+            Expected::Clause,
+            Expected::Directive,
+            Expected::Callable,
+            Expected::Token,
+            Expected::Number,
+            Expected::EndOfInput,
+        ].iter()
+            .cloned()
+            .find(|expected| expected.code() == code)
+    }
+}
+
+impl Display for Expected {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(match *self {
+            Expected::Term => "a term",
+            Expected::End => "an operator or `.`",
+            Expected::CloseParen => "an operator or `)`",
+            Expected::CloseBracket => "an operator or `]`",
+            Expected::ArgSeparator => "an operator, `,` or `)`",
+            Expected::ListSeparator => "an operator, `,`, `|` or `]`",
+            Expected::Clause => "a clause",
+            Expected::Directive => "a valid `op/3` directive",
+            Expected::Callable => "an atom or compound term",
+            Expected::Token => "a valid token",
+            Expected::Number => "a number",
+            Expected::EndOfInput => "the end of the input",
+        })
+    }
+}
+
+/// Converts a `ReadResult` to a `nom::IResult`.
+fn to_iresult<'a, T>(res: ReadResult<'a, T>) -> IResult<&'a str, T> {
+    match res {
+        Ok((rest, val)) => IResult::Done(rest, val),
+        Err(Some((pos, expected))) => {
+            let kind = ErrorKind::Custom(expected.code());
+            IResult::Error(error_position!(kind, pos))
+        }
+        Err(None) => IResult::Incomplete(Needed::Unknown),
+    }
+}
+
+/// Converts an error from the term reader to a `ParseError`, given the whole
+/// input.
+fn to_parse_error(err: Option<(&str, Expected)>, src: &str) -> ParseError {
+    let err = match err {
+        Some((pos, expected)) => {
+            let kind = ErrorKind::Custom(expected.code());
+            IError::Error(error_position!(kind, pos))
+        }
+        None => IError::Incomplete(Needed::Unknown),
+    };
+    ParseError::from_ierror(err, src)
+}
+
+/// A single token, as read by the term reader.
+#[derive(Clone, Debug, PartialEq)]
+enum Token<'a> {
+    /// A name, and whether it is immediately followed by an opening
+    /// parenthesis, which makes it the functor of a compound term.
+    Name(Atom, bool),
+
+    /// A variable name.
+    Variable(&'a str),
+
+    /// An integer.
+    Int(BigInt),
+
+    /// A floating-point number.
+    Float(f64),
+
+    /// A punctuation character, such as a parenthesis or comma.
+    Punct(char),
+
+    /// A double-quoted string, which is read as a list of character codes.
+    Str(String),
+
+    /// The end of a clause or query, which is a `.` followed by whitespace,
+    /// a comment, or the end of the input.
+    End,
+}
+
+/// Reads the next token, skipping any whitespace and comments before it.
+fn token<'a>(input: &'a str) -> ReadResult<'a, Token<'a>> {
+    let input = skip_layout(input);
+    let mut chars = input.chars();
+    let ch = match chars.next() {
+        Some(ch) => ch,
+        None => return Err(None),
+    };
+    let after = chars.as_str();
+
+    let layout_next = after
+        .chars()
+        .next()
+        .is_none_or(|ch| ch.is_whitespace() || ch == '%');
+    if ch == '.' && layout_next {
+        Ok((after, Token::End))
+    } else if ch.is_ascii_digit() {
+        number(input)
+    } else if is_variable_start_char(ch) {
+        let n = input.find(|ch| !is_plain_char(ch)).unwrap_or(input.len());
+        Ok((&input[n..], Token::Variable(&input[..n])))
+    } else if "()[]{},|".contains(ch) {
+        Ok((after, Token::Punct(ch)))
+    } else if ch == '"' {
+        match string(input) {
+            IResult::Done(rest, s) => Ok((rest, Token::Str(s))),
+            IResult::Incomplete(_) => Err(None),
+            IResult::Error(_) => Err(Some((input, Expected::Token))),
+        }
+    } else {
+        match name(input) {
+            IResult::Done(rest, atom) => {
+                Ok((rest, Token::Name(atom, rest.starts_with('('))))
+            }
+            IResult::Incomplete(_) => Err(None),
+            IResult::Error(_) => Err(Some((input, Expected::Token))),
+        }
+    }
+}
+
+/// Reads a number token. Besides decimal integers and floats, this reads
+/// hexadecimal integers like `0xff`, and character codes like `0'a`.
+fn number<'a>(input: &'a str) -> ReadResult<'a, Token<'a>> {
+    if let Some(rest) = input.strip_prefix("0'") {
+        if let Some(rest) = rest.strip_prefix("''") {
+            return Ok((rest, Token::Int(BigInt::from('\'' as u32))));
+        }
+        return match atom_quoted_char(rest) {
+            IResult::Done(rest, Some(ch)) => {
+                Ok((rest, Token::Int(BigInt::from(ch as u32))))
+            }
+            IResult::Incomplete(_) => Err(None),
+            _ => Err(Some((input, Expected::Token))),
+        };
+    }
+    if let Some(digits) = input.strip_prefix("0x") {
+        let n = digits_len(digits, 16);
+        if n > 0 {
+            let val = BigInt::from_str_radix(&digits[..n], 16).unwrap();
+            return Ok((&digits[n..], Token::Int(val)));
+        }
+    }
+
+    let int_len = digits_len(input, 10);
+    let after = &input[int_len..];
+    let frac_len = after.strip_prefix('.').map_or(0, |s| digits_len(s, 10));
+    if frac_len == 0 {
+        let val = input[..int_len].parse().unwrap();
+        return Ok((after, Token::Int(val)));
+    }
+
+    // An exponent is only part of the float if it has digits.
+    let mut len = int_len + 1 + frac_len;
+    if let Some(exp) = input[len..].strip_prefix(['e', 'E']) {
+        let sign_len = if exp.starts_with(['+', '-']) { 1 } else { 0 };
+        let exp_len = digits_len(&exp[sign_len..], 10);
+        if exp_len > 0 {
+            len += 1 + sign_len + exp_len;
+        }
+    }
+    let val = input[..len]
+        .parse()
+        .map_err(|_| Some((input, Expected::Number)))?;
+    Ok((&input[len..], Token::Float(val)))
+}
+
+/// Reads the next token, which must be the given one. If it isn't, the error
+/// says what else could have come before it.
+fn expect<'a>(
+    input: &'a str,
+    token_expected: Token,
+    expected: Expected,
+) -> ReadResult<'a, ()> {
+    match token(input)? {
+        (rest, tok) if tok == token_expected => Ok((rest, ())),
+        _ => Err(Some((skip_layout(input), expected))),
+    }
+}
+
+/// An operator precedence parser for terms.
+struct Reader<'o> {
+    ops: &'o OpTable,
+}
+
+impl<'o> Reader<'o> {
+    /// Reads a clause, which is a term followed by an end token.
+    fn clause<'a>(&self, input: &'a str) -> ReadResult<'a, Clause> {
+        let input = skip_layout(input);
+        let (rest, term) = self.sentence(input)?;
+        match into_clause(term) {
+            Some(clause) => Ok((rest, clause)),
+            None => Err(Some((input, Expected::Clause))),
+        }
+    }
+
+    /// Reads a query, which is a conjunction of goals followed by an end
+    /// token. The conjunction may be empty.
+    fn query<'a>(&self, input: &'a str) -> ReadResult<'a, Vec<Structure>> {
+        if let Ok((rest, Token::End)) = token(input) {
+            return Ok((rest, Vec::new()));
+        }
+        let (rest, term) = self.sentence(input)?;
+        let mut goals = Vec::new();
+        flatten_conjunction(term, &mut goals);
+        Ok((rest, goals))
+    }
+
+    /// Reads a term followed by an end token.
+    fn sentence<'a>(&self, input: &'a str) -> ReadResult<'a, Term> {
+        let (rest, (term, _)) = self.term(input, 1200)?;
+        let (rest, ()) = expect(rest, Token::End, Expected::End)?;
+        Ok((rest, term))
+    }
+
+    /// Reads a term whose priority is at most `max`, returning it along with
+    /// its priority.
+    fn term<'a>(
+        &self,
+        input: &'a str,
+        max: u32,
+    ) -> ReadResult<'a, (Term, u32)> {
+        let (mut input, (mut left, mut priority)) = self.primary(input, max)?;
+        while let Ok((rest, tok)) = token(input) {
+            // Only an unquoted comma is the comma operator, but quotes
+            // aren't kept in tokens, so a comma name is never treated as
+            // one.
+            let name = match tok {
+                Token::Name(name, _) if name.as_ref() != "," => name,
+                Token::Punct(',') => Atom::from(","),
+                _ => break,
+            };
+
+            if let Some(op) = self.ops.infix(name) {
+                let (left_max, right_max) = op.arg_priorities();
+                if op.priority <= max && priority <= left_max {
+                    let (rest, (right, _)) = self.term(rest, right_max)?;
+                    left = Term::Structure(Structure(name, vec![left, right]));
+                    priority = op.priority;
+                    input = rest;
+                    continue;
+                }
+            }
+            if let Some(op) = self.ops.postfix(name) {
+                let (left_max, _) = op.arg_priorities();
+                if op.priority <= max && priority <= left_max {
+                    left = Term::Structure(Structure(name, vec![left]));
+                    priority = op.priority;
+                    input = rest;
+                    continue;
+                }
+            }
+            break;
+        }
+        Ok((input, (left, priority)))
+    }
+
+    /// Reads a term that is not the application of an infix or postfix
+    /// operator.
+    fn primary<'a>(
+        &self,
+        input: &'a str,
+        max: u32,
+    ) -> ReadResult<'a, (Term, u32)> {
+        match token(input)? {
+            (rest, Token::Variable("_")) => Ok((rest, (Term::Anonymous, 0))),
+            (rest, Token::Variable(name)) => {
+                let var = Variable::from_str(name).unwrap();
+                Ok((rest, (Term::Variable(var), 0)))
+            }
+            (rest, Token::Int(n)) => Ok((rest, (Term::Integer(n), 0))),
+            (rest, Token::Float(f)) => Ok((rest, (Term::Float(Float(f)), 0))),
+            (rest, Token::Name(name, false))
+                if name.as_ref() == "-"
+                    && rest.starts_with(|ch: char| ch.is_ascii_digit()) =>
+            {
+                // A minus sign right before a number makes it negative.
+                let (rest, term) = match number(rest)? {
+                    (rest, Token::Int(n)) => (rest, Term::Integer(-n)),
+                    (rest, Token::Float(f)) => (rest, Term::Float(Float(-f))),
+                    _ => unreachable!(),
+                };
+                Ok((rest, (term, 0)))
+            }
+            (rest, Token::Punct('(')) => {
+                let (rest, (term, _)) = self.term(rest, 1200)?;
+                let (rest, ()) =
+                    expect(rest, Token::Punct(')'), Expected::CloseParen)?;
+                Ok((rest, (term, 0)))
+            }
+            (rest, Token::Name(name, true)) => {
+                let (rest, args) = self.arguments(&rest[1..])?;
+                Ok((rest, (Term::Structure(Structure(name, args)), 0)))
+            }
+            (rest, Token::Name(name, false)) => self.prefix(rest, name, max),
+            (rest, Token::Punct('[')) => match token(rest)? {
+                (rest, Token::Punct(']')) => {
+                    let nil = Term::Structure(Structure("[]".into(), vec![]));
+                    Ok((rest, (nil, 0)))
+                }
+                _ => {
+                    let (rest, list) = self.list(rest)?;
+                    Ok((rest, (list, 0)))
+                }
+            },
+            (rest, Token::Str(s)) => {
+                let codes = s.chars()
+                    .map(|ch| Term::Integer(BigInt::from(ch as u32)))
+                    .collect();
+                Ok((rest, (make_list(codes, None), 0)))
+            }
+            _ => Err(Some((skip_layout(input), Expected::Term))),
+        }
+    }
+
+    /// Reads the arguments of a compound term, after the opening
+    /// parenthesis.
+    fn arguments<'a>(&self, input: &'a str) -> ReadResult<'a, Vec<Term>> {
+        let mut args = Vec::new();
+        let mut input = match token(input)? {
+            (rest, Token::Punct(')')) => return Ok((rest, args)),
+            _ => input,
+        };
+        loop {
+            let (rest, (arg, _)) = self.term(input, 999)?;
+            args.push(arg);
+            match token(rest)? {
+                (rest, Token::Punct(',')) => input = rest,
+                (rest, Token::Punct(')')) => return Ok((rest, args)),
+                _ => {
+                    let expected = Expected::ArgSeparator;
+                    return Err(Some((skip_layout(rest), expected)));
+                }
+            }
+        }
+    }
+
+    /// Reads the elements and tail of a non-empty list, after the opening
+    /// bracket.
+    fn list<'a>(&self, mut input: &'a str) -> ReadResult<'a, Term> {
+        let mut elems = Vec::new();
+        loop {
+            let (rest, (elem, _)) = self.term(input, 999)?;
+            elems.push(elem);
+            match token(rest)? {
+                (rest, Token::Punct(',')) => input = rest,
+                (rest, Token::Punct('|')) => {
+                    let (rest, (tail, _)) = self.term(rest, 999)?;
+                    let close = Token::Punct(']');
+                    let (rest, ()) =
+                        expect(rest, close, Expected::CloseBracket)?;
+                    return Ok((rest, make_list(elems, Some(tail))));
+                }
+                (rest, Token::Punct(']')) => {
+                    return Ok((rest, make_list(elems, None)))
+                }
+                _ => {
+                    let expected = Expected::ListSeparator;
+                    return Err(Some((skip_layout(rest), expected)));
+                }
+            }
+        }
+    }
+
+    /// Reads the operand of a prefix operator, if the name is one and it is
+    /// applied to something. Otherwise, the name is just an atom.
+    fn prefix<'a>(
+        &self,
+        input: &'a str,
+        name: Atom,
+        max: u32,
+    ) -> ReadResult<'a, (Term, u32)> {
+        let atom = Term::Structure(Structure(name, Vec::new()));
+        let op = match self.ops.prefix(name) {
+            Some(op) if op.priority <= max => op,
+            _ => return Ok((input, (atom, 0))),
+        };
+        let (_, arg_max) = op.arg_priorities();
+
+        // The operator is an operand itself if the next token can't start a
+        // term, or is an infix or postfix operator that can't be read as a
+        // prefix operator here, as in `- = x`.
+        let is_atom = match token(input) {
+            Err(_) | Ok((_, Token::End)) => true,
+            Ok((_, Token::Punct(ch))) => ch != '(' && ch != '[' && ch != '{',
+            Ok((_, Token::Name(next, false))) if next.as_ref() != "," => {
+                (self.ops.infix(next).is_some()
+                    || self.ops.postfix(next).is_some())
+                    && self.ops
+                        .prefix(next)
+                        .is_none_or(|op| op.priority > arg_max)
+            }
+            Ok(_) => false,
+        };
+        if is_atom {
+            return Ok((input, (atom, 0)));
+        }
+
+        let (rest, (arg, _)) = self.term(input, arg_max)?;
+        let term = Term::Structure(Structure(name, vec![arg]));
+        Ok((rest, (term, op.priority)))
+    }
+}
+
+/// Builds a list out of `'.'/2` structures from its elements and tail. If
+/// there is no tail, the list ends with `[]`.
+fn make_list(elems: Vec<Term>, tail: Option<Term>) -> Term {
+    let nil = || Term::Structure(Structure("[]".into(), Vec::new()));
+    elems.into_iter().rev().fold(tail.unwrap_or_else(nil), |tail, head| {
+        Term::Structure(Structure(".".into(), vec![head, tail]))
+    })
+}
+
+/// Converts a term to a clause, if it is a valid one. Directives are not
+/// valid clauses.
+fn into_clause(term: Term) -> Option<Clause> {
+    if directive_goal(&term).is_some() {
+        return None;
+    }
+    match term {
+        Term::Structure(Structure(name, mut args))
+            if name.as_ref() == ":-" && args.len() == 2 =>
+        {
+            let body = args.pop().unwrap();
+            match args.pop().unwrap() {
+                Term::Structure(head) => {
+                    let mut goals = Vec::new();
+                    flatten_conjunction(body, &mut goals);
+                    Some(Clause(head, goals))
+                }
+                _ => None,
+            }
+        }
+        Term::Structure(head) => Some(Clause(head, Vec::new())),
+        _ => None,
+    }
+}
+
+/// Returns the goal of a directive, if the term is one.
+fn directive_goal(term: &Term) -> Option<&Term> {
+    match *term {
+        Term::Structure(Structure(name, ref args))
+            if name.as_ref() == ":-" && args.len() == 1 =>
+        {
+            Some(&args[0])
+        }
+        _ => None,
+    }
+}
+
+/// Runs a directive, returning whether it was valid.
+fn run_directive(goal: &Term, ops: &mut OpTable) -> bool {
+    match *goal {
+        Term::Structure(Structure(name, ref args))
+            if name.as_ref() == "op" && args.len() == 3 =>
+        {
+            let priority = match args[0] {
+                Term::Integer(ref p) => p.to_u32().filter(|&p| p <= 1200),
+                _ => None,
+            };
+            let op_type = constant(&args[1])
+                .and_then(|t| OpType::from_name(t.as_ref()));
+            let names = op_names(&args[2]);
+            match (priority, op_type, names) {
+                (Some(priority), Some(op_type), Some(names)) => {
+                    for name in names {
+                        ops.add(priority, op_type, name);
+                    }
+                    true
+                }
+                _ => false,
+            }
+        }
+        _ => {
+            warn!("Ignoring unsupported directive {}", goal);
+            true
+        }
+    }
+}
+
+/// Returns the atom a term consists of, if it is a constant.
+fn constant(term: &Term) -> Option<Atom> {
+    match *term {
+        Term::Structure(Structure(atom, ref args)) if args.is_empty() => {
+            Some(atom)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the operator names given as the third argument of `op/3`, which
+/// is either a single atom or a list of them. The comma may not be
+/// redefined.
+fn op_names(term: &Term) -> Option<Vec<Atom>> {
+    let mut names = Vec::new();
+    let mut term = term;
+    loop {
+        match *term {
+            Term::Structure(Structure(atom, ref args))
+                if atom.as_ref() == "." && args.len() == 2 =>
+            {
+                names.push(constant(&args[0])?);
+                term = &args[1];
+            }
+            _ => {
+                let atom = constant(term)?;
+                if atom.as_ref() != "[]" || names.is_empty() {
+                    names.push(atom);
+                }
+                break;
+            }
+        }
+    }
+    if names.iter().any(|name| name.as_ref() == ",") {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+/// Splits a term into the goals of the conjunction it represents. Variables
+/// are wrapped in `call/1`, as in ISO Prolog.
+fn flatten_conjunction(term: Term, goals: &mut Vec<Structure>) {
+    match term {
+        Term::Structure(Structure(name, mut args))
+            if name.as_ref() == "," && args.len() == 2 =>
+        {
+            let right = args.pop().unwrap();
+            let left = args.pop().unwrap();
+            flatten_conjunction(left, goals);
+            flatten_conjunction(right, goals);
+        }
+        Term::Structure(s) => goals.push(s),
+        var => goals.push(Structure(atom!(call), vec![var])),
+    }
+}
+
 // Smaller primitives.
 
+named!(string(&str) -> String, map!(
+    delimited!(tag_s!("\""), many0!(string_char), tag_s!("\"")),
+    |cs| cs.into_iter().flatten().collect()
+));
+
 named!(atom_quoted_char(&str) -> Option<char>, alt_complete!(
+    quoted_escape |
+    map!(verify!(one_char, |ch| ch != '\\' && ch != '\''), Some)
+));
+
+named!(string_char(&str) -> Option<char>, alt_complete!(
+    quoted_escape |
+    map!(verify!(one_char, |ch| ch != '\\' && ch != '"'), Some)
+));
+
+named!(quoted_escape(&str) -> Option<char>, alt_complete!(
     value!(Some('\x07'), tag_s!("\\a")) |
     value!(Some('\x08'), tag_s!("\\b")) |
     do_parse!(tag_s!("\\c") >> multispace >> ( None )) |
@@ -143,8 +860,7 @@ named!(atom_quoted_char(&str) -> Option<char>, alt_complete!(
     value!(Some('\\'), tag_s!("\\\\")) |
     value!(Some('\''), tag_s!("\\'")) |
     value!(Some('"'), tag_s!("\\\"")) |
-    value!(Some('`'), tag_s!("\\`")) |
-    map!(verify!(one_char, |ch| ch != '\\' && ch != '\''), Some)
+    value!(Some('`'), tag_s!("\\`"))
 ));
 
 named!(atom_quoted_hex_escape(&str) -> char, map_opt!(alt!(
@@ -168,13 +884,16 @@ named!(unquoted_atom(&str) -> &str, recognize!(tuple!(
     take_while_s!(is_plain_char)
 )));
 
-named!(clause_fact(&str) -> Clause, map!(structure, |hd| Clause(hd, vec![])));
-
-named!(clause_rule(&str) -> Clause, do_parse!(
-    hd: structure >>
-    tag_s!(":-") >>
-    tl: separated_list!(tag_s!(","), structure) >>
-    ( Clause(hd, tl) )
+named_attr!(
+    #[doc = "Parses an atom, without any surrounding whitespace."],
+    name(&str) -> Atom, alt!(
+    map!(
+        delimited!(tag_s!("'"), many0!(atom_quoted_char), tag_s!("'")),
+        |cs| Atom::from(cs.into_iter().flatten().collect::<String>())
+    ) |
+    map!(unquoted_atom, |cs| cs.into()) |
+    map!(take_while1_s!(is_symbol_char), |cs| cs.into()) |
+    map!(alt!(tag_s!("!") | tag_s!(";")), |cs| cs.into())
 ));
 
 // Helper functions.
@@ -187,7 +906,7 @@ fn all_hex_digits(s: &str) -> bool {
 }
 
 fn is_atom_start_char(ch: char) -> bool {
-    ('a' <= ch && ch <= 'z') || ('0' <= ch && ch <= '9')
+    'a' <= ch && ch <= 'z'
 }
 
 fn is_variable_start_char(ch: char) -> bool {
@@ -199,6 +918,22 @@ fn is_plain_char(ch: char) -> bool {
         || ('0' <= ch && ch <= '9') || ch == '_'
 }
 
+/// Returns the length of the run of digits in the given radix at the start of
+/// the input.
+fn digits_len(input: &str, radix: u32) -> usize {
+    input
+        .find(|ch: char| !ch.is_digit(radix))
+        .unwrap_or(input.len())
+}
+
+/// Skips any whitespace and comments at the start of the input.
+fn skip_layout(input: &str) -> &str {
+    match whitespace_or_comment(input) {
+        IResult::Done(rest, _) => rest,
+        _ => input,
+    }
+}
+
 fn one_char(input: &str) -> IResult<&str, char> {
     let mut iter = input.chars();
     match iter.next() {
@@ -1,5 +1,10 @@
-use common::{Clause, Functor, Structure, Term};
-use common::parsers::{atom, clause, functor, query, term, variable};
+use nom::{ErrorKind, IResult, Needed};
+
+use common::{Clause, Float, Functor, OpTable, OpType, ParseErrorKind,
+             PrologError, Structure, Term, TermWriter};
+use common::arith::{eval, Number};
+use common::parsers::{atom, clause, functor, program, program_with_ops, query,
+                      term, term_with_ops, variable, Expected};
 use test_utils::example_query_term;
 
 mod prop;
@@ -24,12 +29,12 @@ parse_tests! {
     atom("asdf", atom!(asdf));
     atom("'Asdf'", atom!(Asdf));
     atom("''", "".into());
-    atom("12", "12".into());
     atom("'asdf'", atom!(asdf));
     atom("foo_bar", atom!(foo_bar));
     atom("'foo bar'", "foo bar".into());
     atom("'Hello, world!'", "Hello, world!".into());
     atom("'HELLO\\nWORLD'", "HELLO\nWORLD".into());
+    atom("!", "!".into());
 
     variable("X", "X");
     variable("Foo", "Foo");
@@ -38,13 +43,29 @@ parse_tests! {
 
     functor("asdf/2", functor!(asdf/2));
     functor("''/0", Functor("".into(), 0));
-    functor("12/3", Functor("12".into(), 3));
+    functor("'12'/3", Functor("12".into(), 3));
     functor("qwerty/123456", functor!(qwerty/123456));
 
     term("_", Term::Anonymous);
     term("X", Term::Variable(variable!("X")));
-    term("123", Term::Structure(Structure("123".into(), vec![])));
-    term("123()", Term::Structure(Structure("123".into(), vec![])));
+    term("123", Term::Integer(123.into()));
+    term("'123'", Term::Structure(Structure("123".into(), vec![])));
+    term("-7", Term::Integer((-7).into()));
+    term("123456789012345678901234567890",
+        Term::Integer("123456789012345678901234567890".parse().unwrap()));
+    term("0xff", Term::Integer(255.into()));
+    term("0'a", Term::Integer(97.into()));
+    term("0'\\n", Term::Integer(10.into()));
+    term("1.5", Term::Float(Float(1.5)));
+    term("-2.5e-3", Term::Float(Float(-2.5e-3)));
+    term("1.0E10", Term::Float(Float(1.0e10)));
+    term("- 1", Term::Structure(Structure("-".into(), vec![
+        Term::Integer(1.into()),
+    ])));
+    term("1 - 1", Term::Structure(Structure("-".into(), vec![
+        Term::Integer(1.into()),
+        Term::Integer(1.into()),
+    ])));
     term("''()", Term::Structure(Structure("".into(), vec![])));
     term("a_b(c(D, E), F, _, '')", Term::Structure(Structure(atom!(a_b), vec![
         Term::Structure(Structure(atom!(c), vec![
@@ -57,6 +78,72 @@ parse_tests! {
     ])));
     term("p(Z, h(Z, W), f(W))", example_query_term());
 
+    term("X = Y", Term::Structure(Structure("=".into(), vec![
+        Term::Variable(variable!("X")),
+        Term::Variable(variable!("Y")),
+    ])));
+    term("a + b * c", Term::Structure(Structure("+".into(), vec![
+        Term::Structure(Structure(atom!(a), vec![])),
+        Term::Structure(Structure("*".into(), vec![
+            Term::Structure(Structure(atom!(b), vec![])),
+            Term::Structure(Structure(atom!(c), vec![])),
+        ])),
+    ])));
+    term("a - b - c", Term::Structure(Structure("-".into(), vec![
+        Term::Structure(Structure("-".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+            Term::Structure(Structure(atom!(b), vec![])),
+        ])),
+        Term::Structure(Structure(atom!(c), vec![])),
+    ])));
+    term("(a , b) ; c", Term::Structure(Structure(";".into(), vec![
+        Term::Structure(Structure(",".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+            Term::Structure(Structure(atom!(b), vec![])),
+        ])),
+        Term::Structure(Structure(atom!(c), vec![])),
+    ])));
+    term("- X", Term::Structure(Structure("-".into(), vec![
+        Term::Variable(variable!("X")),
+    ])));
+    term("- - a", Term::Structure(Structure("-".into(), vec![
+        Term::Structure(Structure("-".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+        ])),
+    ])));
+    term("f(-, a)", Term::Structure(Structure(atom!(f), vec![
+        Term::Structure(Structure("-".into(), vec![])),
+        Term::Structure(Structure(atom!(a), vec![])),
+    ])));
+    term("- = a", Term::Structure(Structure("=".into(), vec![
+        Term::Structure(Structure("-".into(), vec![])),
+        Term::Structure(Structure(atom!(a), vec![])),
+    ])));
+    term("\\+ a = b", Term::Structure(Structure("\\+".into(), vec![
+        Term::Structure(Structure("=".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+            Term::Structure(Structure(atom!(b), vec![])),
+        ])),
+    ])));
+    term("[]", Term::Structure(Structure("[]".into(), vec![])));
+    term("[ ]", Term::Structure(Structure("[]".into(), vec![])));
+    term("[a, b | T]", Term::Structure(Structure(".".into(), vec![
+        Term::Structure(Structure(atom!(a), vec![])),
+        Term::Structure(Structure(".".into(), vec![
+            Term::Structure(Structure(atom!(b), vec![])),
+            Term::Variable(variable!("T")),
+        ])),
+    ])));
+    term("[X]", Term::Structure(Structure(".".into(), vec![
+        Term::Variable(variable!("X")),
+        Term::Structure(Structure("[]".into(), vec![])),
+    ])));
+    term("\"a\"", Term::Structure(Structure(".".into(), vec![
+        Term::Integer(97.into()),
+        Term::Structure(Structure("[]".into(), vec![])),
+    ])));
+    term("\"\"", Term::Structure(Structure("[]".into(), vec![])));
+
     query(".", vec![]);
     query("true.", vec![ Structure(atom!(true), vec![]) ]);
     query("fail.", vec![ Structure(atom!(fail), vec![]) ]);
@@ -64,14 +151,14 @@ parse_tests! {
     query("append(cons(1, nil), X, cons(1, cons(2, nil))).", vec![
         Structure(atom!(append), vec![
             Term::Structure(Structure(atom!(cons), vec![
-                Term::Structure(Structure("1".into(), vec![])),
+                Term::Integer(1.into()),
                 Term::Structure(Structure(atom!(nil), vec![])),
             ])),
             Term::Variable(variable!("X")),
             Term::Structure(Structure(atom!(cons), vec![
-                Term::Structure(Structure("1".into(), vec![])),
+                Term::Integer(1.into()),
                 Term::Structure(Structure(atom!(cons), vec![
-                    Term::Structure(Structure("2".into(), vec![])),
+                    Term::Integer(2.into()),
                     Term::Structure(Structure(atom!(nil), vec![])),
                 ])),
             ])),
@@ -115,4 +202,276 @@ parse_tests! {
                 Term::Variable(variable!("L3")),
             ]),
         ]));
+    clause("max(X, Y, X) :- geq(X, Y), !.",
+        Clause(Structure(atom!(max), vec![
+            Term::Variable(variable!("X")),
+            Term::Variable(variable!("Y")),
+            Term::Variable(variable!("X")),
+        ]), vec![
+            Structure(atom!(geq), vec![
+                Term::Variable(variable!("X")),
+                Term::Variable(variable!("Y")),
+            ]),
+            Structure("!".into(), vec![]),
+        ]));
+    clause("p(A) :- A = f(B), q(B).",
+        Clause(Structure(atom!(p), vec![
+            Term::Variable(variable!("A")),
+        ]), vec![
+            Structure("=".into(), vec![
+                Term::Variable(variable!("A")),
+                Term::Structure(Structure(atom!(f), vec![
+                    Term::Variable(variable!("B")),
+                ])),
+            ]),
+            Structure(atom!(q), vec![
+                Term::Variable(variable!("B")),
+            ]),
+        ]));
+    clause("p :- X, q.",
+        Clause(Structure(atom!(p), vec![]), vec![
+            Structure(atom!(call), vec![Term::Variable(variable!("X"))]),
+            Structure(atom!(q), vec![]),
+        ]));
+}
+
+#[test]
+fn operator_table() {
+    let mut ops = OpTable::new();
+    assert_eq!(
+        term_with_ops("a === b", &ops),
+        IResult::Done(
+            " === b",
+            Term::Structure(Structure(atom!(a), vec![]))
+        ),
+    );
+
+    ops.add(700, OpType::XFX, "===".into());
+    assert_eq!(
+        term_with_ops("a === b", &ops),
+        IResult::Done("", Term::Structure(Structure("===".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+            Term::Structure(Structure(atom!(b), vec![])),
+        ]))),
+    );
+
+    ops.add(0, OpType::YFX, "-".into());
+    assert_eq!(
+        term_with_ops("- a", &ops),
+        IResult::Done("", Term::Structure(Structure("-".into(), vec![
+            Term::Structure(Structure(atom!(a), vec![])),
+        ]))),
+    );
+    assert_eq!(
+        term_with_ops("a - b", &ops),
+        IResult::Done(" - b", Term::Structure(Structure(atom!(a), vec![]))),
+    );
+}
+
+#[test]
+fn op_directives() {
+    let src = ":- op(700, xfx, ===).\n\
+               :- op(200, xfy, '.'(^^, '.'(@@, '[]'))).\n\
+               eq(A, B) :- A === B.\n\
+               p(a ^^ b @@ c).\n";
+    let mut ops = OpTable::new();
+    assert_eq!(
+        program_with_ops(src, &mut ops),
+        (
+            vec![
+                Clause::parse("eq(A, B) :- '==='(A, B).").unwrap(),
+                Clause::parse("p('^^'(a, '@@'(b, c))).").unwrap(),
+            ],
+            vec![]
+        )
+    );
+    assert!(ops.infix("===".into()).is_some());
+
+    let src = "p.\n:- op(1201, xfx, ===).\n";
+    let (clauses, errors) = program(src);
+    assert_eq!(clauses, vec![Clause::parse("p.").unwrap()]);
+    assert_eq!(
+        errors[0].to_string(),
+        "expected a valid `op/3` directive\n\
+         \x20 |\n\
+         2 | :- op(1201, xfx, ===).\n\
+         \x20 | ^"
+    );
+}
+
+#[test]
+fn program_error_recovery() {
+    let src = "p(a b).\n\
+               q(a).\n\
+               r('x. y', \"a. b\" c).\n\
+               3.\n\
+               :- op(700, xfx, ===).\n\
+               s(a === b.\n\
+               t(a === b).\n\
+               u(";
+    let (clauses, errors) = program(src);
+    assert_eq!(
+        clauses,
+        vec![
+            Clause::parse("q(a).").unwrap(),
+            Clause::parse("t('==='(a, b)).").unwrap(),
+        ]
+    );
+    let errors = errors
+        .into_iter()
+        .map(|err| {
+            let loc = err.location.unwrap();
+            (loc.line, loc.column, err.kind)
+        })
+        .collect::<Vec<_>>();
+    let expected = |expected: Expected| {
+        ParseErrorKind::Error(Some(ErrorKind::Custom(expected.code())))
+    };
+    assert_eq!(
+        errors,
+        vec![
+            (1, 5, expected(Expected::ArgSeparator)),
+            (3, 18, expected(Expected::ArgSeparator)),
+            (4, 1, expected(Expected::Clause)),
+            (6, 10, expected(Expected::ArgSeparator)),
+            (8, 3, ParseErrorKind::Incomplete(Needed::Unknown)),
+        ]
+    );
+}
+
+#[test]
+fn parse_error_locations() {
+    let src = "p(a).\nq(a,\n\tb c).\n";
+    let err = program(src).1.remove(0).in_file("test.pl");
+    let loc = err.location.clone().unwrap();
+    assert_eq!((loc.offset, loc.line, loc.column), (14, 3, 4));
+    assert_eq!(
+        err.to_string(),
+        "expected an operator, `,` or `)`\n\
+         \x20--> test.pl:3:4\n\
+         \x20 |\n\
+         3 | \tb c).\n\
+         \x20 | \t  ^"
+    );
+
+    let src = "p(a).\nq(\"a\n";
+    let err = program(src).1.remove(0).in_file("test.pl");
+    assert!(err.is_incomplete());
+    assert_eq!(
+        err.to_string(),
+        "unexpected end of file\n\
+         \x20--> test.pl:3:1\n\
+         \x20 |\n\
+         3 | \n\
+         \x20 | ^"
+    );
+
+    let err = Term::parse("f(a) g").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "expected the end of the input\n\
+         \x20 |\n\
+         1 | f(a) g\n\
+         \x20 |     ^"
+    );
+}
+
+#[test]
+fn incomplete_terms() {
+    assert!(query("foo(X").is_incomplete());
+    assert!(query("X = a").is_incomplete());
+    assert!(query("X = ").is_incomplete());
+    assert!(query("X = a.").is_done());
+}
+
+#[test]
+fn writes_operators() {
+    let cases = [
+        ("'='(X, f(Y))", "X = f(Y)"),
+        ("'+'(a, '*'(b, c))", "a + b * c"),
+        ("'*'('+'(a, b), c)", "(a + b) * c"),
+        ("'-'('-'(a, b), c)", "a - b - c"),
+        ("'-'(a, '-'(b, c))", "a - (b - c)"),
+        ("'-'(a)", "- a"),
+        ("'-'('-')", "- (-)"),
+        ("f(',', '-', ','(a, b))", "f(',', -, (a, b))"),
+        ("':-'(p, ','(q, ';'(r, s)))", "p :- q, (r ; s)"),
+        ("'\\\\+'(is(X, '+'(Y, 1)))", "\\+ X is Y + 1"),
+        ("'-'(1)", "- 1"),
+        ("'-'(1, -1)", "1 - -1"),
+        ("f(0x10, 1.0e20, 0'a, '1')", "f(16, 1.0e20, 97, '1')"),
+    ];
+    for &(src, expected) in &cases {
+        let term = Term::parse(src).unwrap();
+        assert_eq!(term.to_string(), expected);
+    }
+
+    let term = Term::parse("'.'(a, '.'('B c', '.'(X, Y)))").unwrap();
+    assert_eq!(term.to_string(), "[a, 'B c', X | Y]");
+    let writer = TermWriter::new().quoted(false);
+    assert_eq!(writer.term(&term).to_string(), "[a, B c, X | Y]");
+    let writer = TermWriter::new().lists(false);
+    assert_eq!(
+        writer.term(&term).to_string(),
+        "'.'(a, '.'('B c', '.'(X, Y)))"
+    );
+    let term = Term::parse("f([], [[]], '[]'(a))").unwrap();
+    assert_eq!(term.to_string(), "f([], [[]], '[]'(a))");
+
+    let term = Term::parse("a = b + c").unwrap();
+    let writer = TermWriter::new().ignore_ops(true);
+    assert_eq!(writer.term(&term).to_string(), "=(a, +(b, c))");
+    let writer = TermWriter::new().priority(699);
+    assert_eq!(writer.term(&term).to_string(), "(a = b + c)");
+
+    let clause = Clause::parse("p(X) :- X = (-).").unwrap();
+    assert_eq!(clause.to_string(), "p(X) :-\n    X = (-).");
+    let clause = Clause::parse("'-' :- a, !.").unwrap();
+    assert_eq!(clause.to_string(), "- :-\n    a,\n    !.");
+    let clause = Clause::parse("p(=).").unwrap();
+    assert_eq!(clause.to_string(), "p(=).");
+    let clause = Clause::parse("(=).").unwrap();
+    assert_eq!(clause.to_string(), "= .");
+}
+
+#[test]
+fn evaluates_arithmetic() {
+    let eval_str = |s| eval(&Term::parse(s).unwrap());
+    let int = |n: i64| Ok(Number::Int(n.into()));
+    assert_eq!(eval_str("1 + 2 * 3"), int(7));
+    assert_eq!(eval_str("- (3 - 5)"), int(2));
+    assert_eq!(eval_str("7 // 2"), int(3));
+    assert_eq!(eval_str("-7 // 2"), int(-3));
+    assert_eq!(eval_str("-7 mod 2"), int(1));
+    assert_eq!(eval_str("7 mod -2"), int(-1));
+    assert_eq!(eval_str("-7 rem 2"), int(-1));
+    assert_eq!(eval_str("abs(-4) + min(2, 3) + max(2, 3)"), int(9));
+    assert_eq!(eval_str("7 / 2"), Ok(Number::Float(3.5)));
+    assert_eq!(eval_str("1 + 0.5"), Ok(Number::Float(1.5)));
+    assert_eq!(
+        eval_str("9223372036854775807 + 1"),
+        Ok(Number::Int("9223372036854775808".parse().unwrap()))
+    );
+
+    assert_eq!(eval_str("X + 1"), Err(PrologError::Instantiation));
+    assert_eq!(
+        eval_str("foo + 1"),
+        Err(PrologError::Type(
+            "evaluable".into(),
+            Term::parse("foo/0").unwrap()
+        ))
+    );
+    assert_eq!(
+        eval_str("1.5 mod 2"),
+        Err(PrologError::Type("integer".into(), Term::Float(Float(1.5))))
+    );
+    assert_eq!(
+        eval_str("1 // 0"),
+        Err(PrologError::Evaluation("zero_divisor".into()))
+    );
+    assert_eq!(
+        PrologError::Type("evaluable".into(), Term::parse("foo/0").unwrap())
+            .to_string(),
+        "type_error(evaluable, foo / 0)"
+    );
 }
@@ -1,5 +1,6 @@
 use common::{Atom, Clause, Functor, Term, Variable};
-use test_utils::{arb_atom, arb_clause, arb_functor, arb_term, arb_variable};
+use test_utils::{arb_atom, arb_clause, arb_functor, arb_op_term, arb_term,
+                 arb_variable};
 
 proptest! {
     #[test]
@@ -31,6 +32,13 @@ proptest! {
         assert_eq!(term, &term2);
     }
 
+    #[test]
+    fn parse_tostring_for_operator_term(ref term in arb_op_term(5)) {
+        let term_str = term.to_string();
+        let term2 = Term::parse(&term_str).expect("Failed to parse term");
+        assert_eq!(term, &term2);
+    }
+
     #[test]
     fn parse_tostring_for_clause(ref clause in arb_clause(5, 3, 3)) {
         let clause_str = clause.to_string();
@@ -0,0 +1,331 @@
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+
+use common::{is_symbol_char, Atom, Clause, OpTable, Structure, Term,
+             DEFAULT_OPS};
+
+/// Writes terms as text, with a choice of options. This is what the
+/// `Display` impls for `Term`, `Structure`, and `Clause` use, with the
+/// default options.
+///
+/// By default, operators from the ISO default table are written as
+/// operators, with only the parentheses needed to read the term back in the
+/// same way, lists are written with list syntax, and atoms are quoted only
+/// when needed.
+#[derive(Clone, Copy, Debug)]
+pub struct TermWriter<'o> {
+    ops: &'o OpTable,
+    ignore_ops: bool,
+    lists: bool,
+    priority: u32,
+    quoted: bool,
+}
+
+impl TermWriter<'static> {
+    /// Creates a TermWriter with the default options.
+    pub fn new() -> TermWriter<'static> {
+        TermWriter {
+            ops: &DEFAULT_OPS,
+            ignore_ops: false,
+            lists: true,
+            priority: 1200,
+            quoted: true,
+        }
+    }
+}
+
+impl Default for TermWriter<'static> {
+    fn default() -> TermWriter<'static> {
+        TermWriter::new()
+    }
+}
+
+impl<'o> TermWriter<'o> {
+    /// Uses the given operator table.
+    pub fn ops<'p>(self, ops: &'p OpTable) -> TermWriter<'p> {
+        TermWriter {
+            ops,
+            ignore_ops: self.ignore_ops,
+            lists: self.lists,
+            priority: self.priority,
+            quoted: self.quoted,
+        }
+    }
+
+    /// Sets whether operators are written in canonical form, like `+(a, b)`.
+    pub fn ignore_ops(self, ignore_ops: bool) -> TermWriter<'o> {
+        TermWriter { ignore_ops, ..self }
+    }
+
+    /// Sets whether `'.'/2` structures are written as lists, like
+    /// `[a, b | T]`.
+    pub fn lists(self, lists: bool) -> TermWriter<'o> {
+        TermWriter { lists, ..self }
+    }
+
+    /// Sets the priority of the context the term is written in. Terms with a
+    /// higher priority are parenthesized. For example, the value in `X = Y`
+    /// should be written with a priority of 699.
+    pub fn priority(self, priority: u32) -> TermWriter<'o> {
+        TermWriter { priority, ..self }
+    }
+
+    /// Sets whether atoms are quoted when needed to read them back in.
+    pub fn quoted(self, quoted: bool) -> TermWriter<'o> {
+        TermWriter { quoted, ..self }
+    }
+
+    /// Returns a value which displays the term with these options.
+    pub fn term<'a>(&'a self, term: &'a Term) -> Written<'a, 'o> {
+        Written(self, Item::Term(term))
+    }
+
+    /// Returns a value which displays the structure with these options.
+    pub fn structure<'a>(&'a self, s: &'a Structure) -> Written<'a, 'o> {
+        Written(self, Item::Structure(s))
+    }
+
+    /// Returns a value which displays the clause with these options. The
+    /// priority option is ignored.
+    pub fn clause<'a>(&'a self, clause: &'a Clause) -> Written<'a, 'o> {
+        Written(self, Item::Clause(clause))
+    }
+
+    fn write_clause<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        clause: &Clause,
+    ) -> FmtResult {
+        let Clause(ref hd, ref tl) = *clause;
+        self.write_structure(out, hd, 1199)?;
+        let mut first = true;
+        for goal in tl {
+            if first {
+                out.write_str(" :-\n    ")?;
+                first = false;
+            } else {
+                out.write_str(",\n    ")?;
+            }
+            self.write_structure(out, goal, 999)?;
+        }
+        // A symbolic atom right before the end would absorb the dot.
+        if out.last.is_some_and(is_symbol_char) {
+            out.write_char(' ')?;
+        }
+        out.write_char('.')
+    }
+
+    fn write_term<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        term: &Term,
+        max: u32,
+    ) -> FmtResult {
+        match *term {
+            Term::Anonymous => out.write_char('_'),
+            Term::Float(f) => write!(out, "{}", f),
+            Term::Integer(ref n) => write!(out, "{}", n),
+            Term::Structure(ref s) => self.write_structure(out, s, max),
+            Term::Variable(ref v) => write!(out, "{}", v),
+        }
+    }
+
+    /// Writes the operand of an operator. An operator as an operand is
+    /// parenthesized, so it isn't read as being applied to what's next to
+    /// it.
+    fn write_operand<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        term: &Term,
+        max: u32,
+    ) -> FmtResult {
+        match *term {
+            Term::Structure(Structure(atom, ref args))
+                if args.is_empty() && self.is_op(atom) =>
+            {
+                out.write_char('(')?;
+                self.write_atom(out, atom)?;
+                out.write_char(')')
+            }
+            _ => self.write_term(out, term, max),
+        }
+    }
+
+    fn write_structure<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        s: &Structure,
+        max: u32,
+    ) -> FmtResult {
+        let Structure(name, ref args) = *s;
+        if self.lists && name.as_ref() == "." && args.len() == 2 {
+            return self.write_list(out, s);
+        }
+        if !self.ignore_ops {
+            match args.len() {
+                1 => if let Some(op) = self.ops.prefix(name) {
+                    let (_, arg_max) = op.arg_priorities();
+                    return self.parenthesize(out, op.priority > max, |out| {
+                        self.write_atom(out, name)?;
+                        out.write_char(' ')?;
+                        self.write_operand(out, &args[0], arg_max)
+                    });
+                } else if let Some(op) = self.ops.postfix(name) {
+                    let (arg_max, _) = op.arg_priorities();
+                    return self.parenthesize(out, op.priority > max, |out| {
+                        self.write_operand(out, &args[0], arg_max)?;
+                        out.write_char(' ')?;
+                        self.write_atom(out, name)
+                    });
+                },
+                2 => if let Some(op) = self.ops.infix(name) {
+                    let (left_max, right_max) = op.arg_priorities();
+                    return self.parenthesize(out, op.priority > max, |out| {
+                        self.write_operand(out, &args[0], left_max)?;
+                        if name.as_ref() == "," {
+                            out.write_str(", ")?;
+                        } else {
+                            out.write_char(' ')?;
+                            self.write_atom(out, name)?;
+                            out.write_char(' ')?;
+                        }
+                        self.write_operand(out, &args[1], right_max)
+                    });
+                },
+                _ => {}
+            }
+        }
+
+        if self.quoted && name.as_ref() == "[]" && !args.is_empty() {
+            // `[]` is only an atom on its own, not as a functor.
+            name.write_quoted(out)?;
+        } else {
+            self.write_atom(out, name)?;
+        }
+        if !args.is_empty() {
+            out.write_char('(')?;
+            let mut first = true;
+            for arg in args {
+                if first {
+                    first = false;
+                } else {
+                    out.write_str(", ")?;
+                }
+                self.write_term(out, arg, 999)?;
+            }
+            out.write_char(')')?;
+        }
+        Ok(())
+    }
+
+    fn write_list<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        mut s: &Structure,
+    ) -> FmtResult {
+        out.write_char('[')?;
+        loop {
+            self.write_term(out, &s.1[0], 999)?;
+            match s.1[1] {
+                Term::Structure(ref tail)
+                    if tail.0.as_ref() == "." && tail.1.len() == 2 =>
+                {
+                    out.write_str(", ")?;
+                    s = tail;
+                }
+                Term::Structure(Structure(nil, ref args))
+                    if nil.as_ref() == "[]" && args.is_empty() =>
+                {
+                    break
+                }
+                ref tail => {
+                    out.write_str(" | ")?;
+                    self.write_term(out, tail, 999)?;
+                    break;
+                }
+            }
+        }
+        out.write_char(']')
+    }
+
+    fn write_atom<W: Write>(
+        &self,
+        out: &mut Output<W>,
+        atom: Atom,
+    ) -> FmtResult {
+        if self.quoted {
+            write!(out, "{}", atom)
+        } else {
+            out.write_str(atom.as_ref())
+        }
+    }
+
+    fn parenthesize<W, F>(
+        &self,
+        out: &mut Output<W>,
+        parens: bool,
+        write: F,
+    ) -> FmtResult
+    where
+        W: Write,
+        F: FnOnce(&mut Output<W>) -> FmtResult,
+    {
+        if parens {
+            out.write_char('(')?;
+            write(out)?;
+            out.write_char(')')
+        } else {
+            write(out)
+        }
+    }
+
+    fn is_op(&self, atom: Atom) -> bool {
+        self.ops.prefix(atom).is_some() || self.ops.infix(atom).is_some()
+            || self.ops.postfix(atom).is_some()
+    }
+}
+
+/// A term, structure, or clause to be displayed by a `TermWriter`.
+#[derive(Clone, Copy, Debug)]
+enum Item<'a> {
+    Clause(&'a Clause),
+    Structure(&'a Structure),
+    Term(&'a Term),
+}
+
+/// A value which displays an item using a `TermWriter`.
+#[derive(Clone, Copy, Debug)]
+pub struct Written<'a, 'o: 'a>(&'a TermWriter<'o>, Item<'a>);
+
+impl<'a, 'o> Display for Written<'a, 'o> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let Written(writer, item) = *self;
+        let mut out = Output {
+            inner: fmt,
+            last: None,
+        };
+        match item {
+            Item::Clause(clause) => writer.write_clause(&mut out, clause),
+            Item::Structure(s) => {
+                writer.write_structure(&mut out, s, writer.priority)
+            }
+            Item::Term(term) => {
+                writer.write_term(&mut out, term, writer.priority)
+            }
+        }
+    }
+}
+
+/// A wrapper around a writer which remembers the last character written.
+struct Output<W> {
+    inner: W,
+    last: Option<char>,
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write_str(&mut self, s: &str) -> FmtResult {
+        if let Some(ch) = s.chars().next_back() {
+            self.last = Some(ch);
+        }
+        self.inner.write_str(s)
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet};
 
-use common::{Structure, Term, Variable};
+use common::{BigInts, Constant, Functor, Structure, Term, Variable};
 
 use super::flatten::{flatten, FlatTerm};
 use super::super::{Instruction, Location};
@@ -10,10 +10,12 @@ use super::super::{Instruction, Location};
 /// the head is `None`, compile as a query. Otherwise, compiles as a rule.
 ///
 /// Note that if compiled as a query, all variables are marked as permanent and
-/// the stack frame is not deallocated after running the code.
+/// the stack frame is not deallocated after running the code. Big integers
+/// are added to the given table.
 pub fn compile(
     head: Option<&Structure>,
     body: &[Structure],
+    big_ints: &mut BigInts,
 ) -> (Vec<Instruction>, Vec<Variable>) {
     let vars = find_variables(head, body);
     let permanent: Vec<Variable> = if head.is_some() {
@@ -24,140 +26,292 @@ pub fn compile(
     } else {
         vars.into_iter().map(|p| p.0).collect()
     };
-    //let permanent_map =
-    //permanent.iter().enumerate().map(|(i, &v)| (v, i)).collect();
-
-    let mut code = vec![Instruction::Allocate(permanent.len())];
-    //let mut seen_vars = HashSet::new();
-
-    //let mut vars = HashMap::new();
-    // TODO: Clean this up.
-    let body = if let Some(head) = head {
-        /*
-        compile_head(
-            &mut code,
-            &mut seen_vars,
-            &mut vars,
-            &permanent_map,
-            &head,
-        );
-        compile_body(
-            &mut code,
-            &mut seen_vars,
-            &mut vars,
-            &permanent_map,
-            &body[0],
-        );
-        */
-        &body[1..]
+    let permanent_map = permanent
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| (v, i))
+        .collect::<HashMap<_, _>>();
+
+    let mut compiler = Compiler {
+        code: vec![Instruction::Allocate(permanent.len())],
+        seen: HashSet::new(),
+        vars: HashMap::new(),
+        permanent: permanent_map,
+        big_ints,
+    };
+
+    // Temporary registers used by the head must not be clobbered by the
+    // argument registers of the first goal, so they start after both.
+    let mut next_reg = if let Some(head) = head {
+        let base = body.first().map_or(0, |s| s.1.len()).max(head.1.len());
+        compiler.compile_head(base, head)
     } else {
-        body
+        0
     };
-    for s in body {
-        //vars.clear();
-        // compile_body(&mut code, &mut seen_vars, &mut vars, &permanent_map, s);
+
+    for (i, s) in body.iter().enumerate() {
+        if i > 0 {
+            // Temporary variables never live across a call, so each goal
+            // after the first can start allocating registers from scratch.
+            compiler.vars.retain(|_, loc| match *loc {
+                Location::Local(_) => true,
+                Location::Register(_) => false,
+            });
+            next_reg = 0;
+        }
+        compiler.compile_body(next_reg, s);
     }
-    if head.is_none() {
-        code.push(Instruction::Deallocate);
+    if head.is_some() {
+        compiler.code.push(Instruction::Deallocate);
     }
 
-    (code, permanent)
+    (compiler.code, permanent)
+}
+
+/// The state of the compiler while compiling a single rule or query.
+struct Compiler<'a> {
+    /// The code emitted so far.
+    code: Vec<Instruction>,
+
+    /// The variables for which code has been emitted.
+    seen: HashSet<Variable>,
+
+    /// The locations assigned to variables.
+    vars: HashMap<Variable, Location>,
+
+    /// The environment slots of the permanent variables.
+    permanent: HashMap<Variable, usize>,
+
+    /// The table that big integers in the clause are added to.
+    big_ints: &'a mut BigInts,
 }
 
-fn compile_head(
-    code: &mut Vec<Instruction>,
-    seen: &mut HashSet<usize>,
-    vars: &mut HashMap<Variable, Location>,
-    permanent: &HashMap<Variable, usize>,
-    s: &Structure,
-) {
-    for (i, f) in flatten(s).into_iter().enumerate() {
-        match f {
-            FlatTerm::Functor(a, is) => unimplemented!(),
-            FlatTerm::Ref(n) => unimplemented!(),
-            FlatTerm::Variable(Some(v)) => unimplemented!(),
-            FlatTerm::Variable(None) => unimplemented!(),
+impl<'a> Compiler<'a> {
+    /// Compiles the head of a rule, returning the first register that is not
+    /// used by the head's code.
+    fn compile_head(&mut self, base: usize, s: &Structure) -> usize {
+        let flat = flatten(s, self.big_ints);
+        let arity = s.1.len();
+
+        // Assign a location to each slot in the flattened term. Argument
+        // slots stay in the argument registers, permanent variables go into
+        // the environment, and everything else is renumbered to start at
+        // `base`.
+        let permanent = &self.permanent;
+        let locs = flat.iter()
+            .enumerate()
+            .map(|(i, f)| match *f {
+                _ if i < arity => Location::Register(i),
+                FlatTerm::Variable(Some(v)) if permanent.contains_key(&v) => {
+                    Location::Local(permanent[&v])
+                }
+                _ => Location::Register(base + i - arity),
+            })
+            .collect::<Vec<_>>();
+        for (i, f) in flat.iter().enumerate() {
+            if let FlatTerm::Variable(Some(v)) = *f {
+                self.vars.insert(v, locs[i]);
+            }
+        }
+
+        let code = &mut self.code;
+        let mut seen_slots = HashSet::new();
+        for (i, f) in flat.iter().enumerate() {
+            match *f {
+                FlatTerm::Functor(a, ref js) => {
+                    let functor = Functor(a, js.len());
+                    code.push(if functor.is_list() {
+                        Instruction::GetList(locs[i])
+                    } else {
+                        Instruction::GetStructure(functor, locs[i])
+                    });
+                    for &j in js {
+                        code.push(if let FlatTerm::Constant(c) = flat[j] {
+                            Instruction::UnifyConstant(c)
+                        } else if seen_slots.insert(j) {
+                            Instruction::UnifyVariable(locs[j])
+                        } else {
+                            Instruction::UnifyValue(locs[j])
+                        });
+                    }
+                }
+                FlatTerm::Ref(j) => {
+                    code.push(if seen_slots.insert(j) {
+                        Instruction::GetVariable(locs[j], i)
+                    } else {
+                        Instruction::GetValue(locs[j], i)
+                    });
+                }
+                FlatTerm::Constant(c) => if i < arity {
+                    code.push(Instruction::GetConstant(c, locs[i]));
+                },
+                FlatTerm::Variable(_) => {
+                    /* No code needs be emitted here. */
+                }
+            }
+        }
+        self.seen.extend(self.vars.keys().cloned());
+
+        if flat.len() > arity {
+            base + flat.len() - arity
+        } else {
+            base
         }
     }
-}
 
-fn compile_body(
-    code: &mut Vec<Instruction>,
-    seen_vars: &mut HashSet<Variable>,
-    vars: &mut HashMap<Variable, usize>,
-    permanent: &HashMap<Variable, usize>,
-    s: &Structure,
-) {
-    for (i, t) in s.1.iter().enumerate() {
-        match *t {
-            Term::Anonymous => unimplemented!(),
-            Term::Structure(Structure(a, ref ts)) => unimplemented!(),
-            Term::Variable(v) => {
-                let seen = !seen_vars.insert(v);
-                if let Some(&loc) = permanent.get(&v) {
-                    let loc = Location::Local(loc);
-                    code.push(if seen {
+    /// Compiles a single goal in the body of a rule or query, including the
+    /// call to it.
+    fn compile_body(&mut self, next_reg: usize, s: &Structure) {
+        let mut next_reg = next_reg.max(s.1.len());
+        for (i, t) in s.1.iter().enumerate() {
+            match *t {
+                Term::Anonymous => {
+                    let loc = Location::Register(next_reg);
+                    next_reg += 1;
+                    self.code.push(Instruction::PutVariable(loc, i));
+                }
+                Term::Float(_) | Term::Integer(_) => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    let loc = Location::Register(i);
+                    self.code.push(Instruction::PutConstant(c, loc));
+                }
+                Term::Structure(ref s) => {
+                    let loc = Location::Register(i);
+                    self.compile_structure(&mut next_reg, loc, s);
+                }
+                Term::Variable(v) => {
+                    let loc = self.variable_location(&mut next_reg, v);
+                    self.code.push(if self.seen.insert(v) {
+                        Instruction::PutVariable(loc, i)
+                    } else {
                         Instruction::PutValue(loc, i)
+                    });
+                }
+            }
+        }
+        self.code.push(Instruction::Call(s.functor()));
+    }
+
+    /// Compiles code to build a structure on the heap, storing its address in
+    /// the given location. Subterms are built before the structures
+    /// containing them.
+    fn compile_structure(
+        &mut self,
+        next_reg: &mut usize,
+        loc: Location,
+        s: &Structure,
+    ) {
+        let subterm_locs = s.1
+            .iter()
+            .map(|t| match *t {
+                Term::Structure(ref sub) => {
+                    let loc = Location::Register(*next_reg);
+                    *next_reg += 1;
+                    self.compile_structure(next_reg, loc, sub);
+                    Some(loc)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        self.code.push(if s.functor().is_list() {
+            Instruction::PutList(loc)
+        } else {
+            Instruction::PutStructure(s.functor(), loc)
+        });
+        for (t, sub_loc) in s.1.iter().zip(subterm_locs) {
+            match *t {
+                Term::Anonymous => {
+                    let loc = Location::Register(*next_reg);
+                    *next_reg += 1;
+                    self.code.push(Instruction::SetVariable(loc));
+                }
+                Term::Float(_) | Term::Integer(_) => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    self.code.push(Instruction::SetConstant(c));
+                }
+                Term::Structure(_) => {
+                    self.code.push(Instruction::SetValue(sub_loc.unwrap()));
+                }
+                Term::Variable(v) => {
+                    let loc = self.variable_location(next_reg, v);
+                    self.code.push(if self.seen.insert(v) {
+                        Instruction::SetVariable(loc)
                     } else {
-                        Instruction::PutVariable(loc, i)
+                        Instruction::SetValue(loc)
                     });
-                } else {
-                    unimplemented!()
                 }
             }
         }
     }
-    code.push(Instruction::Call(s.functor()));
+
+    /// Returns the location of a variable, allocating a register for it if it
+    /// is a temporary variable that has not yet been given one.
+    fn variable_location(
+        &mut self,
+        next_reg: &mut usize,
+        v: Variable,
+    ) -> Location {
+        let permanent = &self.permanent;
+        *self.vars.entry(v).or_insert_with(|| {
+            if let Some(&n) = permanent.get(&v) {
+                Location::Local(n)
+            } else {
+                let loc = Location::Register(*next_reg);
+                *next_reg += 1;
+                loc
+            }
+        })
+    }
 }
 
+/// Finds the variables in a clause, in order of first occurrence, along with
+/// the number of chunks each occurs in. The head and the first goal of a rule
+/// form a single chunk.
 fn find_variables(
     head: Option<&Structure>,
     body: &[Structure],
-) -> HashMap<Variable, usize> {
-    fn add_structure_variables(
-        vars: &mut HashMap<Variable, usize>,
-        s: &Structure,
+) -> Vec<(Variable, usize)> {
+    fn add_chunk_variables(
+        vars: &mut Vec<(Variable, usize)>,
+        chunk: &[&Structure],
     ) {
-        let mut set = HashSet::new();
-        for t in &s.1 {
-            find_term_variables(&mut set, t);
+        let mut chunk_vars = Vec::new();
+        for s in chunk {
+            for t in &s.1 {
+                find_term_variables(&mut chunk_vars, t);
+            }
         }
-        for var in set {
-            *vars.entry(var).or_insert(0) += 1;
+        for var in chunk_vars {
+            if let Some(entry) = vars.iter_mut().find(|e| e.0 == var) {
+                entry.1 += 1;
+                continue;
+            }
+            vars.push((var, 1));
         }
     }
 
-    fn find_term_variables(vars: &mut HashSet<Variable>, t: &Term) {
+    fn find_term_variables(vars: &mut Vec<Variable>, t: &Term) {
         match *t {
-            Term::Anonymous => {}
-            Term::Variable(var) => {
-                vars.insert(var);
-            }
+            Term::Anonymous | Term::Float(_) | Term::Integer(_) => {}
+            Term::Variable(var) => if !vars.contains(&var) {
+                vars.push(var);
+            },
             Term::Structure(Structure(_, ref ts)) => for t in ts {
                 find_term_variables(vars, t);
             },
         }
     }
 
-    let mut vars = HashMap::new();
+    let mut vars = Vec::new();
+    let mut goals = body.iter();
     if let Some(head) = head {
-        let mut set = HashSet::new();
-        for t in &head.1 {
-            find_term_variables(&mut set, t);
-        }
-        for t in &body[0].1 {
-            find_term_variables(&mut set, t);
-        }
-        for var in set {
-            *vars.entry(var).or_insert(0) += 1;
-        }
-        for s in &body[1..] {
-            add_structure_variables(&mut vars, s);
-        }
-    } else {
-        for s in body {
-            add_structure_variables(&mut vars, s);
-        }
+        let mut chunk = vec![head];
+        chunk.extend(goals.next());
+        add_chunk_variables(&mut vars, &chunk);
+    }
+    for s in goals {
+        add_chunk_variables(&mut vars, &[s]);
     }
     vars
 }
@@ -192,6 +346,7 @@ mod tests {
                     ],
                 ),
             ],
+            &mut BigInts::new(),
         );
         assert_eq!(
             code,
@@ -212,4 +367,53 @@ mod tests {
             )
         )
     }
+
+    #[test]
+    fn compiles_nested_body_structures() {
+        // ?- p(f(X, g(Y)), X).
+        let code = compile(
+            None,
+            &[
+                Structure(
+                    atom!(p),
+                    vec![
+                        Term::Structure(Structure(
+                            atom!(f),
+                            vec![
+                                Term::Variable(variable!("X")),
+                                Term::Structure(Structure(
+                                    atom!(g),
+                                    vec![Term::Variable(variable!("Y"))],
+                                )),
+                            ],
+                        )),
+                        Term::Variable(variable!("X")),
+                    ],
+                ),
+            ],
+            &mut BigInts::new(),
+        );
+        assert_eq!(
+            code,
+            (
+                vec![
+                    Instruction::Allocate(2),
+                    Instruction::PutStructure(
+                        functor!(g / 1),
+                        Location::Register(2),
+                    ),
+                    Instruction::SetVariable(Location::Local(1)),
+                    Instruction::PutStructure(
+                        functor!(f / 2),
+                        Location::Register(0),
+                    ),
+                    Instruction::SetVariable(Location::Local(0)),
+                    Instruction::SetValue(Location::Register(2)),
+                    Instruction::PutValue(Location::Local(0), 1),
+                    Instruction::Call(functor!(p / 2)),
+                ],
+                vec![variable!("X"), variable!("Y")]
+            )
+        )
+    }
 }
@@ -1,23 +1,33 @@
 use std::collections::HashSet;
 
-use common::{Functor, Structure};
+use common::{BigInts, Functor, Structure};
 
 use super::flatten::{flatten, FlatTerm};
 use super::super::{Instruction, Location};
 
-/// Compiles a fact into a series of instructions.
-pub fn compile(fact: &Structure) -> Vec<Instruction> {
+/// Compiles a fact into a series of instructions. Big integers in it are
+/// added to the given table.
+pub fn compile(
+    fact: &Structure,
+    big_ints: &mut BigInts,
+) -> Vec<Instruction> {
     let mut code = Vec::new();
     let mut seen = HashSet::new();
-    for (i, flat) in flatten(fact).into_iter().enumerate() {
-        match flat {
-            FlatTerm::Functor(a, js) => {
-                code.push(Instruction::GetStructure(
-                    Functor(a, js.len()),
-                    Location::Register(i),
-                ));
-                for j in js {
-                    if seen.contains(&j) {
+    let flat = flatten(fact, big_ints);
+    for (i, f) in flat.iter().enumerate() {
+        match *f {
+            FlatTerm::Functor(a, ref js) => {
+                let functor = Functor(a, js.len());
+                let loc = Location::Register(i);
+                code.push(if functor.is_list() {
+                    Instruction::GetList(loc)
+                } else {
+                    Instruction::GetStructure(functor, loc)
+                });
+                for &j in js {
+                    if let FlatTerm::Constant(c) = flat[j] {
+                        code.push(Instruction::UnifyConstant(c));
+                    } else if seen.contains(&j) {
                         code.push(Instruction::UnifyValue(
                             Location::Register(j),
                         ));
@@ -31,8 +41,15 @@ pub fn compile(fact: &Structure) -> Vec<Instruction> {
             }
             FlatTerm::Ref(j) => {
                 assert!(j > i);
-                code.push(Instruction::GetValue(Location::Register(j), i));
+                code.push(if seen.insert(j) {
+                    Instruction::GetVariable(Location::Register(j), i)
+                } else {
+                    Instruction::GetValue(Location::Register(j), i)
+                });
             }
+            FlatTerm::Constant(c) => if i < fact.1.len() {
+                code.push(Instruction::GetConstant(c, Location::Register(i)));
+            },
             FlatTerm::Variable(_) => { /* No code needs be emitted here. */ }
         }
     }
@@ -48,7 +65,7 @@ mod tests {
     #[test]
     fn flattens_example_program() {
         assert_eq!(
-            flatten(&example_program()),
+            flatten(&example_program(), &mut BigInts::new()),
             vec![
                 FlatTerm::Functor(atom!(f), vec![3]), // f(X)
                 FlatTerm::Functor(atom!(h), vec![4, 5]), // h(Y, f(a))
@@ -64,7 +81,7 @@ mod tests {
     #[test]
     fn compiles_example_program() {
         assert_eq!(
-            compile(&example_program()),
+            compile(&example_program(), &mut BigInts::new()),
             vec![
                 Instruction::GetStructure(
                     functor!(f / 1),
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
-use common::{Atom, Structure, Term, Variable};
+use common::{Atom, BigInts, Constant, Structure, Term, Variable};
 
 /// A type for flattened terms.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum FlatTerm {
+    Constant(Constant),
     Functor(Atom, Vec<usize>),
     Ref(usize),
     Variable(Option<Variable>),
@@ -15,6 +16,7 @@ pub enum FlatTerm {
 fn flatten_term_to(
     flattened: &mut Vec<Option<FlatTerm>>,
     vars: &mut HashMap<Variable, usize>,
+    big_ints: &mut BigInts,
     i: usize,
     term: &Term,
 ) {
@@ -22,25 +24,37 @@ fn flatten_term_to(
         Term::Anonymous => {
             flattened[i] = Some(FlatTerm::Variable(None));
         }
+        Term::Float(_) | Term::Integer(_) => {
+            let c = Constant::from_term(term, big_ints).unwrap();
+            flattened[i] = Some(FlatTerm::Constant(c));
+        }
         Term::Structure(Structure(f, ref ts)) => {
             let mut is = Vec::new();
-            let n = flattened.len();
-            for _ in 0..ts.len() {
-                flattened.push(None);
-            }
-            for (j, t) in ts.iter().enumerate() {
+            let mut subterms = Vec::new();
+            for t in ts {
                 match *t {
                     Term::Variable(ref v) if vars.contains_key(v) => {
                         is.push(vars[v]);
                     }
+                    Term::Variable(v) => {
+                        let n = flattened.len();
+                        flattened.push(Some(FlatTerm::Variable(Some(v))));
+                        vars.insert(v, n);
+                        is.push(n);
+                    }
                     _ => {
-                        let i = n + j;
-                        flatten_term_to(flattened, vars, i, t);
-                        is.push(i);
+                        // Push a placeholder.
+                        let n = flattened.len();
+                        flattened.push(None);
+                        subterms.push((n, t));
+                        is.push(n);
                     }
                 }
             }
             flattened[i] = Some(FlatTerm::Functor(f, is));
+            for (n, t) in subterms {
+                flatten_term_to(flattened, vars, big_ints, n, t);
+            }
         }
         Term::Variable(v) => {
             assert!(!vars.contains_key(&v));
@@ -50,17 +64,21 @@ fn flatten_term_to(
     }
 }
 
-/// Flattens a structure to the form needed for fact compilation.
-pub fn flatten(structure: &Structure) -> Vec<FlatTerm> {
+/// Flattens a structure to the form needed for fact compilation. Big integers
+/// in it are added to the given table.
+pub fn flatten(
+    structure: &Structure,
+    big_ints: &mut BigInts,
+) -> Vec<FlatTerm> {
     // Initially contains placeholders for argument registers.
     let mut flattened = vec![None; structure.1.len()];
     let mut vars = HashMap::new();
 
-    // The first pass flattens functor arguments only.
+    // The first pass flattens functor and number arguments only.
     for (i, term) in structure.1.iter().enumerate() {
         match *term {
-            Term::Structure(_) => {
-                flatten_term_to(&mut flattened, &mut vars, i, term)
+            Term::Float(_) | Term::Integer(_) | Term::Structure(_) => {
+                flatten_term_to(&mut flattened, &mut vars, big_ints, i, term)
             }
             _ => { /* Wait for the second pass. */ }
         }
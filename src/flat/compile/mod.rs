@@ -6,27 +6,32 @@ use std::collections::HashMap;
 
 use failure::Error;
 
-use common::{Clause, Functor, Structure, Variable};
+use common::{BigInts, Clause, Functor, Structure, Variable};
 
 use super::control::Instruction;
 use self::clause::compile as compile_clause_helper;
 use self::fact::compile as compile_fact;
 
-/// Compiles a single clause in a program into a series of instructions.
-fn compile_clause(clause: &Clause) -> Vec<Instruction> {
+/// Compiles a single clause in a program into a series of instructions. Big
+/// integers in it are added to the given table.
+pub fn compile_clause(
+    clause: &Clause,
+    big_ints: &mut BigInts,
+) -> Vec<Instruction> {
     let Clause(ref head, ref body) = *clause;
 
     if body.is_empty() {
-        compile_fact(head)
+        compile_fact(head, big_ints)
     } else {
-        compile_clause_helper(Some(head), body).0
+        compile_clause_helper(Some(head), body, big_ints).0
     }
 }
 
 /// Compiles a program into a series of instructions. Also returns a list of
-/// labels.
+/// labels. Big integers in the program are added to the given table.
 pub fn compile_program(
     program: &[Clause],
+    big_ints: &mut BigInts,
 ) -> Result<(Vec<Instruction>, HashMap<Functor, usize>), Error> {
     let mut code = Vec::new();
     let mut labels = HashMap::new();
@@ -37,7 +42,7 @@ pub fn compile_program(
             labels.insert(clause.0.functor(), addr).is_none(),
             "M2 doesn't support disjunctions"
         );
-        code.extend(compile_clause(clause));
+        code.extend(compile_clause(clause, big_ints));
     }
     assert_eq!(program.len(), labels.len());
 
@@ -45,14 +50,19 @@ pub fn compile_program(
 }
 
 /// Compiles a query into a series of instructions. Also returns a list of
-/// variable assignments.
-pub fn compile_query(query: &[Structure]) -> (Vec<Instruction>, Vec<Variable>) {
-    compile_clause_helper(None, query)
+/// variable assignments. Big integers in the query are added to the given
+/// table.
+pub fn compile_query(
+    query: &[Structure],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, Vec<Variable>) {
+    compile_clause_helper(None, query, big_ints)
 }
 
 #[cfg(test)]
 mod tests {
     use common::Term;
+    use flat::Location;
     use super::*;
 
     #[test]
@@ -74,7 +84,7 @@ mod tests {
                     ],
                 ),
                 Structure(
-                    atom!(q),
+                    atom!(r),
                     vec![
                         Term::Variable(variable!("Z")),
                         Term::Variable(variable!("Y")),
@@ -83,16 +93,16 @@ mod tests {
             ],
         );
         assert_eq!(
-            compile_clause(&program),
+            compile_clause(&program, &mut BigInts::new()),
             vec![
                 Instruction::Allocate(2),
-                // get_variable X3, A1
-                // get_variable Y1, A2
-                // put_value X3, A1
-                // put_variable Y2, A2
+                Instruction::GetVariable(Location::Register(2), 0),
+                Instruction::GetVariable(Location::Local(0), 1),
+                Instruction::PutValue(Location::Register(2), 0),
+                Instruction::PutVariable(Location::Local(1), 1),
                 Instruction::Call(functor!(q / 2)),
-                // put_value Y2, A1
-                // put_value Y1, A2
+                Instruction::PutValue(Location::Local(1), 0),
+                Instruction::PutValue(Location::Local(0), 1),
                 Instruction::Call(functor!(r / 2)),
                 Instruction::Deallocate,
             ],
@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-use common::Functor;
+use common::{Constant, Functor};
 
 /// A local address, which corresponds to either a register or an entry on the
 /// local stack.
@@ -35,22 +35,60 @@ pub enum Instruction {
     /// the machine in write mode, which constructs the term on the heap.
     GetStructure(Functor, Location),
 
+    /// Like `GetStructure`, but for a list cell, which has no functor cell.
+    GetList(Location),
+
+    /// Unifies the value in the given location with a constant.
+    GetConstant(Constant, Location),
+
+    /// Unifies the value in the given location with the value in the numbered
+    /// argument register.
     GetValue(Location, usize),
+
+    /// Stores the value in the numbered argument register into the given
+    /// location.
     GetVariable(Location, usize),
 
     /// Places the functor part of a structure onto the heap, storing its
     /// address in the location given by the second argument.
     PutStructure(Functor, Location),
 
+    /// Places a list cell onto the heap, storing its address in the given
+    /// location. The head and tail of the list are placed on the heap after
+    /// it.
+    PutList(Location),
+
+    /// Places a constant onto the heap, storing its address in the given
+    /// location.
+    PutConstant(Constant, Location),
+
+    /// Copies the value in the given location into the numbered argument
+    /// register.
     PutValue(Location, usize),
+
+    /// Places an unbound variable cell onto the heap, storing its address in
+    /// both the given location and the numbered argument register.
     PutVariable(Location, usize),
 
+    /// Places a copy of the value in the given location onto the heap.
+    SetValue(Location),
+
+    /// Places a constant onto the heap.
+    SetConstant(Constant),
+
+    /// Places an unbound variable cell onto the heap, storing its address in
+    /// the given location.
+    SetVariable(Location),
+
     /// Attempts to unify a value. See `Machine::unify`.
     UnifyValue(Location),
 
     /// Attempts to unify a variable.
     UnifyVariable(Location),
 
+    /// Attempts to unify a constant.
+    UnifyConstant(Constant),
+
     /// Calls the code for the fact with the given functor, with support for
     /// faster leaf calls.
     Call(Functor),
@@ -71,6 +109,10 @@ impl Display for Instruction {
             Instruction::GetStructure(f, reg) => {
                 write!(fmt, "get_structure {}, {}", f, reg)
             }
+            Instruction::GetList(loc) => write!(fmt, "get_list {}", loc),
+            Instruction::GetConstant(c, loc) => {
+                write!(fmt, "get_constant {}, {}", c, loc)
+            }
             Instruction::GetValue(loc, reg) => {
                 write!(fmt, "get_value {}, {}", loc, reg)
             }
@@ -81,6 +123,10 @@ impl Display for Instruction {
             Instruction::PutStructure(f, reg) => {
                 write!(fmt, "put_structure {}, {}", f, reg)
             }
+            Instruction::PutList(loc) => write!(fmt, "put_list {}", loc),
+            Instruction::PutConstant(c, loc) => {
+                write!(fmt, "put_constant {}, {}", c, loc)
+            }
             Instruction::PutValue(loc, reg) => {
                 write!(fmt, "put_value {}, {}", loc, reg)
             }
@@ -88,10 +134,17 @@ impl Display for Instruction {
                 write!(fmt, "put_variable {}, {}", loc, reg)
             }
 
+            Instruction::SetValue(r) => write!(fmt, "set_value {}", r),
+            Instruction::SetVariable(r) => write!(fmt, "set_variable {}", r),
+            Instruction::SetConstant(c) => write!(fmt, "set_constant {}", c),
+
             Instruction::UnifyValue(r) => write!(fmt, "unify_value {}", r),
             Instruction::UnifyVariable(r) => {
                 write!(fmt, "unify_variable {}", r)
             }
+            Instruction::UnifyConstant(c) => {
+                write!(fmt, "unify_constant {}", c)
+            }
 
             Instruction::Call(f) => write!(fmt, "call {}", f),
             Instruction::Proceed => fmt.write_str("proceed"),
@@ -5,188 +5,391 @@ mod compile;
 mod control;
 mod store;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use failure::Error;
 
-use common::{Clause, Functor, HeapCell, Structure, Term, Variable};
+use common::{BigInts, Cells, Clause, Constant, Extractor, Functor, HeapCell,
+             Interrupted, MachineError, Structure, Term, Unknown, Variable};
+use common::builtins::{record_variables, wam_only, Args, Builtin, Builtins};
 
 pub use self::control::{Instruction, Location};
-pub use self::compile::{compile_program, compile_query};
-use self::store::{Heap, Registers};
+pub use self::compile::{compile_clause, compile_program, compile_query};
+use self::store::{Heap, Registers, Stack};
 
 /// An abstract machine for M<sub>2</sub>.
+///
+/// Goals can't be called from terms, so `call/1` and `catch/3` are not
+/// supported, and calling either raises an error that ends the query, unless
+/// the program defines it.
 #[derive(Debug)]
 pub struct Machine {
     /// All stored code.
     code: Vec<Instruction>,
 
+    /// The address at which the code for the current query starts. Everything
+    /// before it is program code.
+    query_start: usize,
+
     /// All code labels.
     labels: HashMap<Functor, usize>,
 
+    /// The built-in predicates, which calls check before user code.
+    builtins: Builtins,
+
+    /// What to do when a procedure with no clauses is called.
+    unknown: Unknown,
+
+    /// Whether unification fails rather than binding a variable to a term
+    /// that contains it.
+    occurs_check: bool,
+
+    /// Set from outside the machine to interrupt the running query.
+    interrupt: Arc<AtomicBool>,
+
     /// The instruction pointer.
     p: usize,
 
     /// The stored instruction pointer, aka the continuation point.
     cp: usize,
 
+    /// The index of the current environment frame on the stack.
+    e: usize,
+
     /// The unification pointer.
     s: usize,
 
     /// Whether unification failed.
     fail: bool,
 
+    /// An error raised by a built-in predicate, which ends the query.
+    error: Option<Error>,
+
     /// Whether the machine is in write mode.
     write_mode: bool,
 
     /// The registers.
     registers: Registers,
 
-    /// The stack.
-    stack: Vec<usize>,
+    /// The stack, which holds environment frames. Each frame consists of the
+    /// previous value of `e`, the previous value of `cp`, and the contents of
+    /// the permanent variables, in that order.
+    stack: Stack,
 
     /// The heap.
     heap: Heap,
+
+    /// The addresses of the variables bound while `\=` checks whether its
+    /// arguments unify, so that the bindings can be undone. M2 has no
+    /// backtracking, so bindings are otherwise never recorded.
+    trail: Option<Vec<usize>>,
 }
 
 impl Machine {
     /// Compiles a set of clauses into a program.
     pub fn new(program: &[Clause]) -> Result<Machine, Error> {
-        compile_program(program).map(|(c, l)| Machine::with_code(c, l))
+        let mut big_ints = BigInts::new();
+        let (code, labels) = compile_program(program, &mut big_ints)?;
+        Ok(Machine::with_code(code, labels, big_ints))
     }
 
-    /// Creates a new Machine containing the given code and labels.
+    /// Creates a new Machine containing the given code and labels, and the
+    /// big integers the code refers to.
     pub fn with_code(
         code: Vec<Instruction>,
         labels: HashMap<Functor, usize>,
+        big_ints: BigInts,
     ) -> Machine {
         Machine {
+            query_start: code.len(),
             code,
             labels,
+            builtins: Builtins::new(),
+            unknown: Unknown::default(),
+            occurs_check: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
             p: 0,
             cp: 0,
+            e: 0,
             s: 0,
             fail: false,
+            error: None,
             write_mode: false,
             registers: Registers::new(),
-            stack: Vec::new(),
-            heap: Heap::new(),
+            stack: Stack::new(),
+            heap: Heap::new(big_ints),
+            trail: None,
         }
     }
 
-    /// Resets the state of the machine.
+    /// Resets the state of the machine, unloading any query code.
     pub fn reset(&mut self) {
+        self.code.truncate(self.query_start);
         self.p = 0;
         self.cp = 0;
+        self.e = 0;
         self.fail = false;
+        self.error = None;
         self.write_mode = false;
         self.registers.reset();
-        self.stack.clear();
+        self.stack.reset();
         self.heap.reset();
+        self.trail = None;
     }
 
-    /// Runs a single instruction. Returns whether unification just succeeded.
-    pub fn run_instruction(&mut self, instr: Instruction) -> bool {
+    /// Runs a single instruction. Returns whether the query just succeeded,
+    /// which is signalled by control reaching the end of the loaded code.
+    pub fn run_instruction(
+        &mut self,
+        instr: Instruction,
+    ) -> Result<bool, MachineError> {
         trace!("{}", instr);
+        match instr {
+            Instruction::Call(_)
+            | Instruction::Proceed
+            | Instruction::Deallocate => {}
+            _ => self.p += 1,
+        }
         match instr {
             Instruction::GetStructure(functor, loc) => {
-                let addr = self.heap.deref(self.read(loc));
-                match self.heap[addr] {
+                let addr = self.heap.deref(self.read(loc)?)?;
+                match self.heap.get(addr)? {
                     HeapCell::Ref(_) => {
                         let n = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
                         self.heap.alloc(HeapCell::Functor(functor));
-                        self.heap.bind(addr, n);
+                        self.heap.bind(addr, n)?;
                         self.write_mode = true;
                     }
-                    HeapCell::Str(a) => match self.heap[a] {
-                        HeapCell::Functor(f) if f == functor => {
-                            self.s = a + 1;
-                            self.write_mode = false;
-                        }
-                        _ => {
-                            self.s = 0;
-                            self.fail = true;
-                        }
-                    },
-                    _ => panic!("Invalid deref in {}", instr),
+                    HeapCell::Str(a)
+                        if self.heap.get_functor(a)? == functor =>
+                    {
+                        self.s = a + 1;
+                        self.write_mode = false;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s = 0;
+                        self.fail = true;
+                    }
                 }
-                false
+            }
+
+            Instruction::GetList(loc) => {
+                let addr = self.heap.deref(self.read(loc)?)?;
+                match self.heap.get(addr)? {
+                    HeapCell::Ref(_) => {
+                        let n = self.heap.alloc_with(|n| HeapCell::Lis(n + 1));
+                        self.heap.bind(addr, n)?;
+                        self.write_mode = true;
+                    }
+                    HeapCell::Lis(a) => {
+                        self.s = a;
+                        self.write_mode = false;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s = 0;
+                        self.fail = true;
+                    }
+                }
+            }
+
+            Instruction::GetConstant(c, loc) => {
+                let addr = self.read(loc)?;
+                self.unify_constant(addr, c)?;
             }
 
             Instruction::PutStructure(functor, loc) => {
                 let n = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
                 self.heap.alloc(HeapCell::Functor(functor));
-                self.write(loc, n);
-                false
+                self.write(loc, n)?;
+            }
+            Instruction::PutList(loc) => {
+                let n = self.heap.alloc_with(|n| HeapCell::Lis(n + 1));
+                self.write(loc, n)?;
+            }
+            Instruction::PutConstant(c, loc) => {
+                let addr = self.heap.alloc(c.cell());
+                self.write(loc, addr)?;
+            }
+            Instruction::GetValue(loc, reg) => {
+                let a1 = self.read(loc)?;
+                let a2 = self.registers.get(reg)?;
+                self.unify(a1, a2)?;
+            }
+            Instruction::GetVariable(loc, reg) => {
+                let addr = self.registers.get(reg)?;
+                self.write(loc, addr)?;
+            }
+
+            Instruction::PutValue(loc, reg) => {
+                self.registers[reg] = self.read(loc)?;
+            }
+            Instruction::PutVariable(loc, reg) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.write(loc, addr)?;
+                self.registers[reg] = addr;
+            }
+
+            Instruction::SetValue(loc) => {
+                let val = self.heap.get(self.read(loc)?)?;
+                self.heap.alloc(val);
+            }
+            Instruction::SetVariable(loc) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.write(loc, addr)?;
+            }
+            Instruction::SetConstant(c) => {
+                self.heap.alloc(c.cell());
             }
 
             Instruction::UnifyValue(loc) => {
                 if self.write_mode {
-                    let val = self.heap[self.read(loc)];
+                    let val = self.heap.get(self.read(loc)?)?;
                     self.heap.alloc(val);
                 } else {
-                    let a1 = self.read(loc);
+                    let a1 = self.read(loc)?;
                     let a2 = self.s;
-                    self.unify(a1, a2);
+                    self.unify(a1, a2)?;
                 }
                 self.s += 1;
-                false
             }
             Instruction::UnifyVariable(loc) => {
                 let addr = if self.write_mode {
-                    self.heap.alloc_with(|n| HeapCell::Ref(n))
+                    self.heap.alloc_with(HeapCell::Ref)
                 } else {
                     self.s
                 };
-                self.write(loc, addr);
+                self.write(loc, addr)?;
+                self.s += 1;
+            }
+            Instruction::UnifyConstant(c) => {
+                if self.write_mode {
+                    self.heap.alloc(c.cell());
+                } else {
+                    let addr = self.s;
+                    self.unify_constant(addr, c)?;
+                }
                 self.s += 1;
-                false
             }
 
-            Instruction::Call(ref f) => {
-                self.cp = self.p + 1;
-                self.p = self.labels[f];
-                false
+            Instruction::Call(f) => {
+                if let Some(builtin) = self.builtins.get(f) {
+                    self.call_builtin(builtin);
+                    self.p += 1;
+                } else if let Some(&addr) = self.labels.get(&f) {
+                    self.cp = self.p + 1;
+                    self.p = addr;
+                } else {
+                    self.call_unknown(f);
+                }
             }
             Instruction::Proceed => {
                 self.p = self.cp;
-                false
             }
 
-            i => unimplemented!("instruction not implemented: {}", i),
+            Instruction::Allocate(n) => {
+                let e = self.stack.next_index();
+                self.stack.push(self.e);
+                self.stack.push(self.cp);
+                for _ in 0..n {
+                    self.stack.push(usize::MAX);
+                }
+                self.e = e;
+            }
+            Instruction::Deallocate => {
+                let e = self.e;
+                self.p = self.stack.get(e + 1)?;
+                self.cp = self.stack.get(e + 1)?;
+                self.e = self.stack.get(e)?;
+                self.stack.truncate(e);
+            }
         }
+        Ok(!self.fail && self.error.is_none() && self.p == self.code.len())
     }
 
     /// Reads a value from the given location. Returns a heap address.
-    pub fn read(&self, loc: Location) -> usize {
+    pub fn read(&self, loc: Location) -> Result<usize, MachineError> {
         match loc {
-            Location::Register(n) => self.registers[n],
-            Location::Local(n) => unimplemented!("index local"),
+            Location::Register(n) => self.registers.get(n),
+            Location::Local(n) => self.stack.get(self.e + n + 2),
         }
     }
 
     /// Runs a single instruction, based on the current instruction pointer.
-    /// Returns whether unification just succeeded.
-    pub fn step(&mut self) -> bool {
-        let instr = if let Some(instr) = self.code.get(self.p) {
-            *instr
-        } else {
-            panic!("ip out of bounds")
+    /// Returns whether the query just succeeded.
+    pub fn step(&mut self) -> Result<bool, MachineError> {
+        match self.code.get(self.p) {
+            Some(&instr) => self.run_instruction(instr),
+            None => Err(MachineError::IpOutOfRange(self.p)),
+        }
+    }
+
+    /// Calls a built-in predicate on the argument registers.
+    fn call_builtin(&mut self, builtin: Builtin) {
+        let mut args = BuiltinArgs {
+            machine: self,
+            vars: HashMap::new(),
         };
-        self.run_instruction(instr)
+        let res = match builtin {
+            Builtin::Det(predicate) => predicate(&mut args),
+            // M2 cannot backtrack, so only the first solution is used.
+            Builtin::NonDet(predicate) => {
+                predicate(&mut args).and_then(|mut solutions| {
+                    match solutions.next() {
+                        Some(terms) => args.unify_all(&terms?),
+                        None => Ok(false),
+                    }
+                })
+            }
+        };
+        match res {
+            Ok(true) => {}
+            Ok(false) => self.fail = true,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /// Handles a call to a procedure that has no clauses, as directed by the
+    /// `unknown` flag. Calls to `call/1` and `catch/3` raise an error
+    /// instead.
+    fn call_unknown(&mut self, f: Functor) {
+        if let Some(err) = wam_only(f) {
+            self.error = Some(err);
+            return;
+        }
+        match self.unknown.call(f) {
+            Ok(()) => self.fail = true,
+            Err(err) => self.error = Some(err.into()),
+        }
     }
 
     /// Performs unification between two heap terms.
-    fn unify(&mut self, a1: usize, a2: usize) {
+    fn unify(&mut self, a1: usize, a2: usize) -> Result<(), MachineError> {
         let mut pdl = vec![a1, a2];
+        // The pairs of compound terms already unified, so that cyclic terms
+        // do not send unification round in circles.
+        let mut seen = HashSet::new();
         while !pdl.is_empty() && !self.fail {
-            let d1 = self.heap.deref(pdl.pop().unwrap());
-            let d2 = self.heap.deref(pdl.pop().unwrap());
+            let d1 = self.heap.deref(pdl.pop().unwrap())?;
+            let d2 = self.heap.deref(pdl.pop().unwrap())?;
             if d1 != d2 {
-                match (self.heap[d1], self.heap[d2]) {
+                match (self.heap.get(d1)?, self.heap.get(d2)?) {
                     (HeapCell::Str(v1), HeapCell::Str(v2)) => {
-                        let f1 = self.heap.get_functor(v1);
-                        let f2 = self.heap.get_functor(v2);
+                        if !seen.insert((v1, v2)) {
+                            continue;
+                        }
+                        let f1 = self.heap.get_functor(v1)?;
+                        let f2 = self.heap.get_functor(v2)?;
                         if f1 == f2 {
                             for i in 1..(f1.1 + 1) {
                                 pdl.push(v1 + i);
@@ -194,21 +397,192 @@ impl Machine {
                             }
                         } else {
                             self.fail = true;
-                            return;
+                            return Ok(());
+                        }
+                    }
+                    (HeapCell::Lis(v1), HeapCell::Lis(v2)) => {
+                        if !seen.insert((v1, v2)) {
+                            continue;
+                        }
+                        for i in 0..2 {
+                            pdl.push(v1 + i);
+                            pdl.push(v2 + i);
+                        }
+                    }
+                    (HeapCell::Ref(_), _) | (_, HeapCell::Ref(_)) => {
+                        if self.occurs_check
+                            && (self.heap.occurs(d1, HeapCell::Ref(d2))?
+                                || self.heap.occurs(d2, HeapCell::Ref(d1))?)
+                        {
+                            self.fail = true;
+                            return Ok(());
                         }
+                        let addr = self.heap.bind(d1, d2)?;
+                        if let Some(ref mut trail) = self.trail {
+                            trail.push(addr);
+                        }
+                    }
+                    (c1, c2) if c1.is_constant() && c1 == c2 => {}
+                    _ => {
+                        self.fail = true;
+                        return Ok(());
                     }
-                    _ => self.heap.bind(d1, d2),
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Returns whether two heap terms unify, undoing any bindings made to
+    /// find out.
+    fn unifiable(
+        &mut self,
+        a1: usize,
+        a2: usize,
+    ) -> Result<bool, MachineError> {
+        self.trail = Some(Vec::new());
+        let res = self.unify(a1, a2);
+        let undone = self
+            .trail
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .try_for_each(|addr| self.heap.unbind(addr));
+        res?;
+        undone?;
+        Ok(!mem::replace(&mut self.fail, false))
+    }
+
+    /// Places a term onto the heap, returning its address. Variables are
+    /// looked up in the given map, and ones not in it are added to it as new
+    /// unbound variables.
+    fn put_term(
+        &mut self,
+        term: &Term,
+        vars: &mut HashMap<Variable, usize>,
+    ) -> Result<usize, MachineError> {
+        Ok(match *term {
+            Term::Anonymous => self.heap.alloc_with(HeapCell::Ref),
+            Term::Variable(var) => {
+                if let Some(&addr) = vars.get(&var) {
+                    return Ok(addr);
+                }
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                vars.insert(var, addr);
+                addr
+            }
+            // Atoms are stored as functors of arity 0, as compiled code does.
+            Term::Structure(ref s) => {
+                let mut cells = Vec::with_capacity(s.1.len());
+                for t in &s.1 {
+                    let addr = self.put_term(t, vars)?;
+                    cells.push(self.heap.get(addr)?);
+                }
+                let functor = s.functor();
+                let addr = if functor.is_list() {
+                    self.heap.alloc_with(|n| HeapCell::Lis(n + 1))
+                } else {
+                    let addr = self.heap.alloc_with(|n| HeapCell::Str(n + 1));
+                    self.heap.alloc(HeapCell::Functor(functor));
+                    addr
+                };
+                for cell in cells {
+                    self.heap.alloc(cell);
+                }
+                addr
+            }
+            _ => {
+                let c = Constant::from_term(term, &mut self.heap.big_ints);
+                self.heap.alloc(c.unwrap().cell())
+            }
+        })
+    }
+
+    /// Unifies the value at the given address with a constant.
+    fn unify_constant(
+        &mut self,
+        addr: usize,
+        c: Constant,
+    ) -> Result<(), MachineError> {
+        let addr = self.heap.deref(addr)?;
+        match self.heap.get(addr)? {
+            HeapCell::Ref(_) => {
+                let n = self.heap.alloc(c.cell());
+                self.heap.bind(addr, n)?;
+            }
+            cell if cell == c.cell() => {}
+            _ => self.fail = true,
+        }
+        Ok(())
     }
 
     /// Writes a heap address to the given location.
-    pub fn write(&mut self, loc: Location, addr: usize) {
+    pub fn write(
+        &mut self,
+        loc: Location,
+        addr: usize,
+    ) -> Result<(), MachineError> {
         match loc {
             Location::Register(n) => self.registers[n] = addr,
-            Location::Local(n) => unimplemented!("index local"),
+            Location::Local(n) => self.stack.set(self.e + n + 2, addr)?,
         }
+        Ok(())
+    }
+}
+
+/// The arguments to a call to a built-in predicate.
+struct BuiltinArgs<'a> {
+    machine: &'a mut Machine,
+    vars: HashMap<Variable, usize>,
+}
+
+impl<'a> Args for BuiltinArgs<'a> {
+    fn get(&mut self, n: usize) -> Result<Term, Error> {
+        let addr = self.machine.registers.get(n)?;
+        let term = self.machine.heap.extract_term(addr)?;
+        record_variables(&term, &mut self.vars);
+        Ok(term)
+    }
+
+    fn unify(&mut self, n: usize, term: &Term) -> Result<bool, Error> {
+        let addr = self.machine.put_term(term, &mut self.vars)?;
+        let arg = self.machine.registers.get(n)?;
+        self.machine.unify(arg, addr)?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unify_with_occurs_check(
+        &mut self,
+        n: usize,
+        term: &Term,
+    ) -> Result<bool, Error> {
+        let occurs_check = mem::replace(&mut self.machine.occurs_check, true);
+        let res = self.unify(n, term);
+        self.machine.occurs_check = occurs_check;
+        res
+    }
+
+    fn unify_args(
+        &mut self,
+        a: usize,
+        b: usize,
+        occurs_check: bool,
+    ) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        let occurs_check = self.machine.occurs_check || occurs_check;
+        let occurs_check =
+            mem::replace(&mut self.machine.occurs_check, occurs_check);
+        let res = self.machine.unify(a, b);
+        self.machine.occurs_check = occurs_check;
+        res?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unifiable(&mut self, a: usize, b: usize) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        Ok(self.machine.unifiable(a, b)?)
     }
 }
 
@@ -219,32 +593,111 @@ impl ::Machine for Machine {
     ) -> Box<'a + Iterator<Item = Result<HashMap<Variable, Term>, Error>>> {
         self.reset();
 
-        let (query_code, vars) = compile_query(&query);
-
-        for instr in query_code {
-            assert!(!self.run_instruction(instr));
-            assert!(!self.fail);
-        }
+        // The query is loaded after the program, and its continuation is the
+        // end of the code; reaching it means the query succeeded.
+        let (query_code, vars) =
+            compile_query(&query, &mut self.heap.big_ints);
+        self.p = self.code.len();
+        self.code.extend(query_code);
+        self.cp = self.code.len();
 
         Box::new(MachineIter {
             machine: self,
-            _vars: vars,
+            vars,
+            done: false,
         })
     }
+
+    fn load_program(&mut self, program: &[Clause]) -> Result<(), Error> {
+        let mut big_ints = BigInts::new();
+        let (code, labels) = compile_program(program, &mut big_ints)?;
+        self.reset();
+        self.query_start = code.len();
+        self.code = code;
+        self.labels = labels;
+        self.heap = Heap::new(big_ints);
+        Ok(())
+    }
+
+    fn builtins_mut(&mut self) -> Option<&mut Builtins> {
+        Some(&mut self.builtins)
+    }
+
+    fn set_unknown(&mut self, unknown: Unknown) {
+        self.unknown = unknown;
+    }
+
+    fn set_occurs_check(&mut self, occurs_check: bool) {
+        self.occurs_check = occurs_check;
+    }
+
+    fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt;
+    }
 }
 
 struct MachineIter<'a> {
     machine: &'a mut Machine,
-    _vars: Vec<Variable>,
+    vars: Vec<Variable>,
+    done: bool,
+}
+
+impl<'a> MachineIter<'a> {
+    /// Reads the values of the query variables out of the query's environment
+    /// frame.
+    fn extract_bindings(&self) -> Result<HashMap<Variable, Term>, Error> {
+        let machine = &*self.machine;
+        let addrs = (0..self.vars.len())
+            .map(|i| machine.read(Location::Local(i)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Unbound variables are named after the last query variable that
+        // refers to them, so `'='(X, Y)` gives `X = Y` and `Y = Y`.
+        let mut names = HashMap::new();
+        for (&var, &addr) in self.vars.iter().zip(&addrs) {
+            let addr = machine.heap.deref(addr)?;
+            if machine.heap.get(addr)?.is_ref() {
+                names.insert(addr, var);
+            }
+        }
+
+        let mut extractor = Extractor::new(&machine.heap, names);
+        let mut bindings = self
+            .vars
+            .iter()
+            .zip(addrs)
+            .map(|(&var, addr)| {
+                let cell = machine.heap.get(addr)?;
+                Ok((var, extractor.extract(cell, Some(var))?))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        bindings.extend(extractor.into_bindings());
+        Ok(bindings)
+    }
 }
 
 impl<'a> Iterator for MachineIter<'a> {
     type Item = Result<HashMap<Variable, Term>, Error>;
 
     fn next(&mut self) -> Option<Result<HashMap<Variable, Term>, Error>> {
+        if self.done {
+            return None;
+        }
+
+        // M2 has no backtracking, so a query has at most one solution.
+        self.done = true;
         loop {
-            if self.machine.step() {
-                unimplemented!("unification succeeded")
+            if self.machine.interrupt.swap(false, Ordering::SeqCst) {
+                self.done = false;
+                return Some(Err(Interrupted.into()));
+            }
+            match self.machine.step() {
+                Ok(true) => return Some(self.extract_bindings()),
+                Ok(false) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+            if let Some(err) = self.machine.error.take() {
+                return Some(Err(err));
             } else if self.machine.fail {
                 return None;
             }
@@ -258,6 +711,152 @@ mod tests {
     use super::*;
     use test_utils::{example_program, example_query};
 
+    #[test]
+    fn environments_preserve_permanent_variables() {
+        let mut machine =
+            Machine::with_code(Vec::new(), HashMap::new(), BigInts::new());
+        let run = |machine: &mut Machine, instr| {
+            machine.run_instruction(instr).expect("Machine error");
+        };
+        run(&mut machine, Instruction::Allocate(1));
+        run(&mut machine, Instruction::PutVariable(Location::Local(0), 0));
+        let y = machine.read(Location::Local(0)).unwrap();
+
+        machine.cp = 7;
+        run(&mut machine, Instruction::Allocate(2));
+        run(&mut machine, Instruction::PutVariable(Location::Local(0), 0));
+        run(&mut machine, Instruction::PutVariable(Location::Local(1), 1));
+        assert_ne!(machine.read(Location::Local(0)).unwrap(), y);
+        run(&mut machine, Instruction::Deallocate);
+
+        assert_eq!(machine.read(Location::Local(0)).unwrap(), y);
+        assert_eq!(machine.p, 7);
+        assert_eq!(machine.cp, 7);
+    }
+
+    #[test]
+    fn reports_machine_errors() {
+        let mut machine =
+            Machine::with_code(Vec::new(), HashMap::new(), BigInts::new());
+        assert_eq!(
+            machine.run_instruction(Instruction::GetValue(
+                Location::Register(0),
+                1,
+            )),
+            Err(MachineError::RegisterOutOfRange(0))
+        );
+        assert_eq!(machine.step(), Err(MachineError::IpOutOfRange(1)));
+
+        // A structure cell that points to itself, rather than a functor.
+        machine.reset();
+        let a = Constant::Atom(atom!(a));
+        machine
+            .run_instruction(Instruction::PutConstant(a, Location::Register(0)))
+            .unwrap();
+        machine.heap[0] = HeapCell::Str(0);
+        assert_eq!(
+            machine.run_instruction(Instruction::GetStructure(
+                functor!(f / 1),
+                Location::Register(0),
+            )),
+            Err(MachineError::BadFunctorCell(0, HeapCell::Str(0)))
+        );
+        let err = machine.heap.extract_term(0).unwrap_err();
+        assert_eq!(
+            err.downcast::<MachineError>().unwrap(),
+            MachineError::BadFunctorCell(0, HeapCell::Str(0))
+        );
+        machine.heap[0] = HeapCell::Functor(functor!(f / 1));
+        let err = machine.heap.extract_term(0).unwrap_err();
+        assert_eq!(
+            err.downcast::<MachineError>().unwrap(),
+            MachineError::InvalidDeref(
+                HeapCell::Functor(functor!(f / 1)),
+                "extract".to_string(),
+            )
+        );
+
+        // A permanent variable past the end of its environment, and an
+        // environment that was never allocated.
+        machine.reset();
+        let allocate = Instruction::Allocate(1);
+        assert_eq!(machine.run_instruction(allocate), Ok(false));
+        assert_eq!(
+            machine.run_instruction(Instruction::PutValue(
+                Location::Local(1),
+                0,
+            )),
+            Err(MachineError::StackOverflow(3))
+        );
+        machine.reset();
+        assert_eq!(
+            machine.run_instruction(Instruction::Deallocate),
+            Err(MachineError::StackOverflow(1))
+        );
+    }
+
+    #[test]
+    fn works_for_conjunctive_program() {
+        let program = vec![
+            Clause::parse("'='(X, X).").unwrap(),
+            Clause::parse("eq2(X, Z) :- '='(X, Y), '='(Y, Z).").unwrap(),
+        ];
+        let mut machine =
+            Machine::new(&program).expect("Couldn't build machine");
+        let matches = machine
+            .run_query(vec![
+                Structure::parse("eq2(f(A), f(g(B)))").unwrap(),
+            ])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            matches,
+            vec![
+                vec![
+                    (
+                        variable!("A"),
+                        Term::Structure(Structure(
+                            atom!(g),
+                            vec![Term::Variable(variable!("B"))],
+                        )),
+                    ),
+                    (variable!("B"), Term::Variable(variable!("B"))),
+                ].into_iter()
+                    .collect(),
+            ]
+        );
+
+        let matches = machine
+            .run_query(vec![Structure::parse("eq2(a, b)").unwrap()])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(matches, vec![]);
+    }
+
+    #[test]
+    fn keeps_program_if_loading_fails() {
+        let mut machine = Machine::new(&[Clause::parse("p(a).").unwrap()])
+            .expect("Couldn't build machine");
+        let run = |machine: &mut Machine| {
+            machine
+                .run_query(vec![Structure::parse("p(X)").unwrap()])
+                .map(|r| r.map(|mut r| r.remove(&variable!("X")).unwrap()))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query")
+        };
+
+        // M2 only supports one clause per functor.
+        let program = vec![
+            Clause::parse("p(b).").unwrap(),
+            Clause::parse("p(c).").unwrap(),
+        ];
+        assert!(machine.load_program(&program).is_err());
+        assert_eq!(run(&mut machine), vec![Term::parse("a").unwrap()]);
+
+        machine.load_program(&program[..1]).unwrap();
+        assert_eq!(run(&mut machine), vec![Term::parse("b").unwrap()]);
+    }
+
     #[test]
     fn works_for_example_program() {
         let program = vec![Clause(example_program(), vec![])];
@@ -300,4 +899,90 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn calls_builtins() {
+        let program = vec![
+            Clause::parse("wrap(X, Y) :- Y = f(X), nonvar(Y).").unwrap(),
+            Clause::parse("shape(T, N, A) :- functor(T, N, A).").unwrap(),
+        ];
+        let mut machine =
+            Machine::new(&program).expect("Couldn't build machine");
+        machine
+            .builtins_mut()
+            .unwrap()
+            .register(functor!(always / 1), |_| Ok(true));
+        let matches = machine
+            .run_query(vec![
+                Structure::parse("wrap(a, W)").unwrap(),
+                Structure::parse("shape(W, N, A)").unwrap(),
+                Structure::parse("always(W)").unwrap(),
+            ])
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(
+            matches,
+            vec![
+                vec![
+                    (variable!("W"), Term::parse("f(a)").unwrap()),
+                    (variable!("N"), Term::parse("f").unwrap()),
+                    (variable!("A"), Term::Integer(1.into())),
+                ].into_iter()
+                    .collect(),
+            ]
+        );
+    }
+
+    #[test]
+    fn unifies_terms_on_the_heap() {
+        let program = vec![
+            Clause::parse("q(a).").unwrap(),
+            Clause::parse("p(X) :- q(X).").unwrap(),
+        ];
+        let mut machine =
+            Machine::new(&program).expect("Couldn't build machine");
+        let mut count = |occurs_check, query: &[&str]| {
+            machine.set_occurs_check(occurs_check);
+            let query = query
+                .iter()
+                .map(|s| Structure::parse(s).unwrap())
+                .collect();
+            let results = machine
+                .run_query(query)
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query");
+            results.len()
+        };
+        assert_eq!(count(false, &["X = a", "p(X)"]), 1);
+        assert_eq!(count(false, &["q(X)", "X = a"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = X"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = f(Y)", "X = Y"]), 1);
+        assert_eq!(count(false, &["f(A, f(A)) \\= f(f(A), A)"]), 0);
+        assert_eq!(count(true, &["f(A, f(A)) \\= f(f(A), A)"]), 1);
+        assert_eq!(count(false, &["f(X, X) \\= f(a, b)", "var(X)"]), 1);
+    }
+
+    #[test]
+    fn reports_unknown_procedures() {
+        let program = vec![Clause::parse("p(X) :- q(X).").unwrap()];
+        let mut machine =
+            Machine::new(&program).expect("Couldn't build machine");
+        let err = machine
+            .run_query(vec![Structure::parse("p(X)").unwrap()])
+            .next()
+            .expect("Query failed")
+            .expect_err("Query didn't raise an error");
+        assert_eq!(err.to_string(), "existence_error(procedure, q / 1)");
+    }
+
+    #[test]
+    fn rejects_calls_to_goals() {
+        let mut machine = Machine::new(&[]).expect("Couldn't build machine");
+        let err = machine
+            .run_query(vec![Structure::parse("call(true)").unwrap()])
+            .next()
+            .expect("Query failed")
+            .expect_err("Query didn't raise an error");
+        assert_eq!(err.to_string(), "call/1 is only supported by the WAM");
+    }
 }
@@ -3,16 +3,27 @@ use std::ops::{Index, IndexMut};
 
 use failure::Error;
 
-use common::{Functor, HeapCell, Structure, Term, Variable};
+use common::{BigInts, Cells, Extractor, Functor, HeapCell, MachineError, Term};
 
 /// The heap, aka the global stack.
 #[derive(Debug)]
-pub struct Heap(Vec<HeapCell>);
+pub struct Heap {
+    cells: Vec<HeapCell>,
+
+    /// The big integers that integer cells, and the constants in the
+    /// machine's code, refer to.
+    pub big_ints: BigInts,
+}
 
 impl Heap {
-    /// Constructs a new, empty Heap.
-    pub fn new() -> Heap {
-        Heap(Vec::new())
+    /// Constructs a new, empty Heap. The given big integers are the
+    /// program's, and are kept when the heap is reset.
+    pub fn new(mut big_ints: BigInts) -> Heap {
+        big_ints.keep();
+        Heap {
+            cells: Vec::new(),
+            big_ints,
+        }
     }
 
     /// Allocates a single heap cell whose value is not address-dependent.
@@ -25,84 +36,92 @@ impl Heap {
     /// at is passed to the function. Returns the address the cell was
     /// allocated at.
     pub fn alloc_with<F: FnOnce(usize) -> HeapCell>(&mut self, f: F) -> usize {
-        let n = self.0.len();
-        self.0.push(f(n));
+        let n = self.cells.len();
+        self.cells.push(f(n));
         n
     }
 
     /// Binds one term to another. At least one given address must deref to a
-    /// self-referential (unbound) `Ref` cell.
-    pub fn bind(&mut self, a: usize, b: usize) {
-        let da = self.deref(a);
-        let db = self.deref(b);
-        if self[da].is_ref() {
-            self.0[da] = HeapCell::Ref(db);
+    /// self-referential (unbound) `Ref` cell. Returns the address of the cell
+    /// that was bound.
+    pub fn bind(&mut self, a: usize, b: usize) -> Result<usize, MachineError> {
+        let da = self.deref(a)?;
+        let db = self.deref(b)?;
+        if self.get(da)?.is_ref() {
+            self.cells[da] = HeapCell::Ref(db);
+            Ok(da)
         } else {
-            assert!(self[db].is_ref());
-            self.0[db] = HeapCell::Ref(da);
+            match self.get(db)? {
+                HeapCell::Ref(_) => {
+                    self.cells[db] = HeapCell::Ref(da);
+                    Ok(db)
+                }
+                cell => {
+                    let op = "bind".to_string();
+                    Err(MachineError::InvalidDeref(cell, op))
+                }
+            }
         }
     }
 
     /// Derefs an address, resolving any `Ref` cells.
-    pub fn deref(&self, addr: usize) -> usize {
-        match self[addr] {
-            HeapCell::Ref(a) if a != addr => self.deref(a),
-            _ => addr,
+    pub fn deref(&self, mut addr: usize) -> Result<usize, MachineError> {
+        loop {
+            match self.get(addr)? {
+                HeapCell::Ref(a) if a != addr => addr = a,
+                _ => return Ok(addr),
+            }
         }
     }
 
-    /// Extracts a Term from the given HeapCell, which may be from a register.
-    /// If it is not, the heap index of the cell should be passed in as the
-    /// last argument.
-    pub fn extract_term(
-        &self,
-        idx: usize,
-        names: Option<&HashMap<usize, Variable>>,
-    ) -> Result<Term, Error> {
-        match self[idx] {
-            HeapCell::Functor(f) => {
-                bail!("Found functor data {} where a term was expected", f)
-            }
-            HeapCell::Ref(n) => if idx == n {
-                let var = names
-                    .and_then(|names| names.get(&n).map(|v| v.clone()))
-                    .unwrap_or_else(|| {
-                        Variable::from_str(format!("_{}", n)).unwrap()
-                    });
-                Ok(Term::Variable(var))
-            } else {
-                self.extract_term(n, names)
-            },
-            HeapCell::Str(f_idx) => {
-                let Functor(atom, arity) = match self[f_idx] {
-                    HeapCell::Functor(f) => f,
-                    cell => {
-                        bail!("Found {:?} where functor was expected", cell)
-                    }
-                };
-                let mut subterms = vec![];
-                for i in 0..arity {
-                    subterms.push(self.extract_term(f_idx + i + 1, names)?);
-                }
-                Ok(Term::Structure(Structure(atom, subterms)))
-            }
+    /// Extracts the term at the given address, naming unbound variables
+    /// after their addresses. Raises a type error if the term is cyclic.
+    pub fn extract_term(&self, addr: usize) -> Result<Term, Error> {
+        Extractor::new(self, HashMap::new()).extract_acyclic(self.get(addr)?)
+    }
+
+    /// Gets the cell stored at the given address.
+    pub fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        self.cells
+            .get(addr)
+            .cloned()
+            .ok_or(MachineError::HeapOverflow(addr))
+    }
+
+    /// Gets the functor stored at the given address.
+    pub fn get_functor(&self, addr: usize) -> Result<Functor, MachineError> {
+        match self.get(addr)? {
+            HeapCell::Functor(f) => Ok(f),
+            cell => Err(MachineError::BadFunctorCell(addr, cell)),
         }
     }
 
-    /// Gets the functor stored at the given address. If a functor is not
-    /// stored at the address, will panic.
-    pub fn get_functor(&self, addr: usize) -> Functor {
-        match self[addr] {
-            HeapCell::Functor(f) => f,
-            cell => {
-                panic!("Expecting {} to be a functor, found {:?}", addr, cell)
+    /// Clears the heap, discarding the big integers added since the program
+    /// was loaded.
+    pub fn reset(&mut self) {
+        self.cells.clear();
+        self.big_ints.reset();
+    }
+
+    /// Resets the cell at the given address to an unbound variable.
+    pub fn unbind(&mut self, addr: usize) -> Result<(), MachineError> {
+        match self.cells.get_mut(addr) {
+            Some(cell) => {
+                *cell = HeapCell::Ref(addr);
+                Ok(())
             }
+            None => Err(MachineError::HeapOverflow(addr)),
         }
     }
+}
+
+impl Cells for Heap {
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        Heap::get(self, addr)
+    }
 
-    /// Clears the heap.
-    pub fn reset(&mut self) {
-        self.0.clear();
+    fn big_ints(&self) -> &BigInts {
+        &self.big_ints
     }
 }
 
@@ -110,13 +129,13 @@ impl Index<usize> for Heap {
     type Output = HeapCell;
 
     fn index(&self, i: usize) -> &HeapCell {
-        &self.0[i]
+        &self.cells[i]
     }
 }
 
 impl IndexMut<usize> for Heap {
     fn index_mut(&mut self, i: usize) -> &mut HeapCell {
-        &mut self.0[i]
+        &mut self.cells[i]
     }
 }
 
@@ -129,6 +148,14 @@ impl Registers {
         Registers(Vec::new())
     }
 
+    /// Reads the value of a register.
+    pub fn get(&self, i: usize) -> Result<usize, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::RegisterOutOfRange(i))
+    }
+
     /// Resets all the registers.
     pub fn reset(&mut self) {
         self.0.clear();
@@ -146,8 +173,59 @@ impl Index<usize> for Registers {
 impl IndexMut<usize> for Registers {
     fn index_mut(&mut self, i: usize) -> &mut usize {
         while self.0.len() <= i {
-            self.0.push(::std::usize::MAX);
+            self.0.push(usize::MAX);
         }
         &mut self.0[i]
     }
 }
+
+/// The stack, whose cells hold heap addresses and control values.
+#[derive(Debug)]
+pub struct Stack(Vec<usize>);
+
+impl Stack {
+    /// Constructs a new, empty Stack.
+    pub fn new() -> Stack {
+        Stack(Vec::new())
+    }
+
+    /// Gets the value stored at the given index.
+    pub fn get(&self, i: usize) -> Result<usize, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::StackOverflow(i))
+    }
+
+    /// Returns the index that will be used by the next push, which is where
+    /// a new environment frame starts.
+    pub fn next_index(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Pushes a value onto the top of the stack.
+    pub fn push(&mut self, value: usize) {
+        self.0.push(value);
+    }
+
+    /// Clears the stack.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+
+    /// Writes the value at the given index.
+    pub fn set(&mut self, i: usize, value: usize) -> Result<(), MachineError> {
+        match self.0.get_mut(i) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(MachineError::StackOverflow(i)),
+        }
+    }
+
+    /// Discards every cell at or above the given index.
+    pub fn truncate(&mut self, n: usize) {
+        self.0.truncate(n);
+    }
+}
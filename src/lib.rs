@@ -14,6 +14,8 @@ extern crate lazy_static;
 extern crate log;
 #[macro_use]
 extern crate nom;
+extern crate num_bigint;
+extern crate num_traits;
 #[cfg(test)]
 #[macro_use]
 extern crate proptest;
@@ -27,15 +29,20 @@ mod macros;
 #[macro_use]
 mod test_utils;
 
+pub mod backtracking;
 pub mod common;
 pub mod flat;
 pub mod unification;
+pub mod wam;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 use failure::Error;
 
-use common::{Structure, Term, Variable};
+use common::{Clause, Structure, Term, Unknown, Variable};
+use common::builtins::Builtins;
 
 /// A trait for an abstract machine based on CESK semantics.
 pub trait Machine {
@@ -44,4 +51,32 @@ pub trait Machine {
         &'a mut self,
         query: Vec<Structure>,
     ) -> Box<'a + Iterator<Item = Result<HashMap<Variable, Term>, Error>>>;
+
+    /// Replaces the loaded program, keeping the machine's settings and
+    /// built-in predicates. If the program can't be compiled, the old one is
+    /// kept.
+    fn load_program(&mut self, program: &[Clause]) -> Result<(), Error>;
+
+    /// Returns the table of built-in predicates, with which host code can
+    /// register foreign predicates for queries to call. Machines that have no
+    /// calls return `None`.
+    fn builtins_mut(&mut self) -> Option<&mut Builtins> {
+        None
+    }
+
+    /// Sets what happens when a query calls a procedure that has no clauses.
+    /// Machines that have no calls ignore this.
+    fn set_unknown(&mut self, _unknown: Unknown) {}
+
+    /// Sets whether unification performs the occurs check, failing rather
+    /// than binding a variable to a term that contains it, which would create
+    /// a cyclic term. Off by default.
+    fn set_occurs_check(&mut self, occurs_check: bool);
+
+    /// Sets a flag that interrupts the running query when it becomes true,
+    /// such as from a signal handler. The query's iterator then clears the
+    /// flag and yields an `Interrupted` error; asking it for another solution
+    /// resumes the query where it stopped. Machines whose queries always
+    /// finish ignore this.
+    fn set_interrupt(&mut self, _interrupt: Arc<AtomicBool>) {}
 }
@@ -1,6 +1,6 @@
 use proptest::prelude::*;
 
-use common::{Atom, Clause, Functor, Structure, Term, Variable};
+use common::{Atom, Clause, Float, Functor, Structure, Term, Variable};
 
 macro_rules! parse_tests {
     ($($parser:ident($input:expr, $expected:expr);)*) => {
@@ -140,3 +140,28 @@ pub fn arb_clause(
         })
         .boxed()
 }
+
+/// Like `arb_term`, but the atoms are mostly operators from the default
+/// table, to test that they are written and read back correctly.
+pub fn arb_op_term(max_depth: usize) -> BoxedStrategy<Term> {
+    let atoms = vec![
+        ",", ";", "->", ":-", "\\+", "=", "is", "-", "+", "*", "**", "^",
+        "\\", "a", "[]", ".",
+    ];
+    let atom = prop::sample::select(atoms).prop_map(Atom::from);
+    prop_oneof![
+        Just(Term::Anonymous),
+        arb_variable().prop_map(Term::Variable),
+        atom.clone()
+            .prop_map(|atom| Term::Structure(Structure(atom, Vec::new()))),
+        any::<i64>().prop_map(|n| Term::Integer(n.into())),
+        prop::num::f64::NORMAL.prop_map(|f| Term::Float(Float(f))),
+    ].prop_recursive(max_depth as u32, 64, 3, move |inner| {
+        atom.clone().prop_flat_map(move |atom| {
+            prop::collection::vec(inner.clone(), 1..4).prop_map(
+                move |subterms| Term::Structure(Structure(atom, subterms)),
+            )
+        })
+    })
+        .boxed()
+}
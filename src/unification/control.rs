@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
-use common::Functor;
+use common::{Constant, Functor};
 
 /// A single M<sub>0</sub> instruction.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -9,6 +9,11 @@ pub enum Instruction {
     /// address in the register numbered by the second argument.
     PutStructure(Functor, usize),
 
+    /// Places a list cell onto the heap, storing its address in the register
+    /// numbered by the argument. The head and tail of the list are placed on
+    /// the heap after it.
+    PutList(usize),
+
     /// Places an unbound variable cell onto the heap, storing its address in
     /// the register numbered by the argument.
     SetVariable(usize),
@@ -17,6 +22,9 @@ pub enum Instruction {
     /// the argument onto the heap.
     SetValue(usize),
 
+    /// Places a constant onto the heap.
+    SetConstant(Constant),
+
     /// Inspects the value pointed to by the numbered register in preparation
     /// for unification with a functor.
     ///
@@ -27,11 +35,17 @@ pub enum Instruction {
     /// the machine in write mode, which constructs the term on the heap.
     GetStructure(Functor, usize),
 
+    /// Like `GetStructure`, but for a list cell.
+    GetList(usize),
+
     /// Attempts to unify a variable.
     UnifyVariable(usize),
 
     /// Attempts to unify a value. See `Machine::unify`.
     UnifyValue(usize),
+
+    /// Attempts to unify a constant.
+    UnifyConstant(Constant),
 }
 
 impl Display for Instruction {
@@ -40,16 +54,22 @@ impl Display for Instruction {
             Instruction::PutStructure(f, r) => {
                 write!(fmt, "put_structure {}, {}", f, r)
             }
+            Instruction::PutList(r) => write!(fmt, "put_list {}", r),
             Instruction::SetVariable(r) => write!(fmt, "set_variable {}", r),
             Instruction::SetValue(r) => write!(fmt, "set_value {}", r),
+            Instruction::SetConstant(c) => write!(fmt, "set_constant {}", c),
 
             Instruction::GetStructure(f, r) => {
                 write!(fmt, "get_structure {}, {}", f, r)
             }
+            Instruction::GetList(r) => write!(fmt, "get_list {}", r),
             Instruction::UnifyVariable(r) => {
                 write!(fmt, "unify_variable {}", r)
             }
             Instruction::UnifyValue(r) => write!(fmt, "unify_value {}", r),
+            Instruction::UnifyConstant(c) => {
+                write!(fmt, "unify_constant {}", c)
+            }
         }
     }
 }
@@ -1,5 +1,7 @@
 use std::ops::{Index, IndexMut};
 
+use common::MachineError;
+
 /// The register store. Access the values in the registers by indexing.
 #[derive(Debug)]
 pub struct Env {
@@ -12,6 +14,14 @@ impl Env {
         Env { regs: Vec::new() }
     }
 
+    /// Reads the value of a register.
+    pub fn get(&self, n: usize) -> Result<usize, MachineError> {
+        self.regs
+            .get(n)
+            .cloned()
+            .ok_or(MachineError::RegisterOutOfRange(n))
+    }
+
     /// Clears all the values from the registers.
     pub fn clear(&mut self) {
         self.regs.clear()
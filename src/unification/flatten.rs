@@ -1,4 +1,4 @@
-use common::{Atom, Env, Structure, Term, Variable};
+use common::{Atom, BigInts, Constant, Env, Structure, Term, Variable};
 
 /// A breadth-first search to flatten a term.
 ///
@@ -7,6 +7,7 @@ use common::{Atom, Env, Structure, Term, Variable};
 fn flatten_term_onto<'t>(
     regs: &mut Vec<FlatTermValue>,
     env: &mut Env<Variable, usize>,
+    big_ints: &mut BigInts,
     i: usize,
     term: &'t Term,
 ) {
@@ -14,6 +15,10 @@ fn flatten_term_onto<'t>(
         Term::Anonymous => {
             regs[i] = FlatTermValue::Variable(None);
         }
+        Term::Float(_) | Term::Integer(_) => {
+            let c = Constant::from_term(term, big_ints).unwrap();
+            regs[i] = FlatTermValue::Constant(c);
+        }
         Term::Structure(Structure(f, ref ts)) => {
             let mut is = Vec::new();
             let mut subterms = Vec::new();
@@ -40,7 +45,7 @@ fn flatten_term_onto<'t>(
             }
             regs[i] = FlatTermValue::Structure(f, is);
             for (i, t) in subterms {
-                flatten_term_onto(regs, env, i, t);
+                flatten_term_onto(regs, env, big_ints, i, t);
             }
         }
         Term::Variable(var) => {
@@ -52,6 +57,10 @@ fn flatten_term_onto<'t>(
 /// A value in a flattened term.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum FlatTermValue {
+    /// A number, which is stored directly in the heap cell of the structure
+    /// that contains it.
+    Constant(Constant),
+
     /// A structure.
     Structure(Atom, Vec<usize>),
 
@@ -64,12 +73,13 @@ pub enum FlatTermValue {
 pub struct FlatTerm(pub Vec<FlatTermValue>);
 
 impl FlatTerm {
-    /// Converts a Term into a flattened form.
-    pub fn flatten(term: &Term) -> FlatTerm {
+    /// Converts a Term into a flattened form. Big integers in it are added
+    /// to the given table.
+    pub fn flatten(term: &Term, big_ints: &mut BigInts) -> FlatTerm {
         // We start with a placeholder.
         let mut regs = vec![FlatTermValue::Variable(None)];
         let mut env = Env::new();
-        flatten_term_onto(&mut regs, &mut env, 0, term);
+        flatten_term_onto(&mut regs, &mut env, big_ints, 0, term);
         FlatTerm(regs)
     }
 }
@@ -83,7 +93,7 @@ mod tests {
     #[test]
     fn flattens_example_query_term() {
         assert_eq!(
-            FlatTerm::flatten(&example_query_term()),
+            FlatTerm::flatten(&example_query_term(), &mut BigInts::new()),
             FlatTerm(vec![
                 FlatTermValue::Structure(atom!(p), vec![1, 2, 3]),
                 FlatTermValue::Variable(Some(variable!("Z"))),
@@ -97,12 +107,15 @@ mod tests {
     #[test]
     fn flattens_simple_terms() {
         assert_eq!(
-            FlatTerm::flatten(&Term::Anonymous),
+            FlatTerm::flatten(&Term::Anonymous, &mut BigInts::new()),
             FlatTerm(vec![FlatTermValue::Variable(None)])
         );
 
         assert_eq!(
-            FlatTerm::flatten(&Term::Variable(variable!("X"))),
+            FlatTerm::flatten(
+                &Term::Variable(variable!("X")),
+                &mut BigInts::new(),
+            ),
             FlatTerm(vec![FlatTermValue::Variable(Some(variable!("X")))])
         );
 
@@ -113,7 +126,7 @@ mod tests {
                     Term::Variable(variable!("X")),
                     Term::Variable(variable!("X")),
                 ]
-            ))),
+            )), &mut BigInts::new()),
             FlatTerm(vec![
                 FlatTermValue::Structure(atom!(foo), vec![1, 1]),
                 FlatTermValue::Variable(Some(variable!("X"))),
@@ -124,7 +137,7 @@ mod tests {
     proptest! {
         #[test]
         fn flatten_term_doesnt_crash(ref term in arb_term(5, 5)) {
-            FlatTerm::flatten(term);
+            FlatTerm::flatten(term, &mut BigInts::new());
         }
     }
 }
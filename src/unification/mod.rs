@@ -12,7 +12,8 @@ use std::iter::{empty, once};
 
 use failure::Error;
 
-use common::{HeapCell, Structure, Term, Variable};
+use common::{BigInts, Cells, Clause, Extractor, HeapCell, MachineError,
+             Structure, Term, Variable};
 
 pub use self::control::Instruction;
 pub use self::env::Env;
@@ -34,13 +35,18 @@ pub struct Machine {
 
     /// The store, which is the heap (aka the global stack).
     pub s: Store,
+
+    /// Whether unification fails rather than binding a variable to a term
+    /// that contains it.
+    pub occurs_check: bool,
 }
 
 impl Machine {
     /// Creates a new Machine, given the term to unify against.
     pub fn new(program: &Term) -> Machine {
         let mut machine = Machine::empty();
-        machine.c = compile_program(&program);
+        machine.c = compile_program(&program, &mut machine.s.big_ints);
+        machine.s.big_ints.keep();
         machine
     }
 
@@ -50,11 +56,15 @@ impl Machine {
             c: Vec::new(),
             e: Env::new(),
             s: Store::new(),
+            occurs_check: false,
         }
     }
 
     /// Runs an instruction.
-    pub fn run_instruction(&mut self, instr: Instruction) {
+    pub fn run_instruction(
+        &mut self,
+        instr: Instruction,
+    ) -> Result<(), MachineError> {
         trace!("{}", instr);
         match instr {
             Instruction::PutStructure(functor, reg) => {
@@ -62,26 +72,32 @@ impl Machine {
                 self.s.push(HeapCell::Functor(functor));
                 self.e[reg] = n;
             }
+            Instruction::PutList(reg) => {
+                self.e[reg] = self.s.push_with(|n| HeapCell::Lis(n + 1));
+            }
             Instruction::SetVariable(reg) => {
                 let n = self.s.push_with(|n| HeapCell::Ref(n));
                 self.e[reg] = n;
             }
             Instruction::SetValue(reg) => {
-                let cell = self.s.get(self.e[reg]);
+                let cell = self.s.get(self.e.get(reg)?)?;
                 self.s.push(cell);
             }
+            Instruction::SetConstant(c) => {
+                self.s.push(c.cell());
+            }
 
             Instruction::GetStructure(functor, reg) => {
-                let addr = self.s.deref(self.e[reg]);
-                match self.s.get(addr) {
+                let addr = self.s.deref(self.e.get(reg)?)?;
+                match self.s.get(addr)? {
                     HeapCell::Ref(_) => {
                         let n = self.s.push_with(|n| HeapCell::Str(n + 1));
                         self.s.push(HeapCell::Functor(functor));
-                        self.s.bind(addr, n);
+                        self.s.bind(addr, n)?;
                         self.s.mode = Mode::Write;
                     }
                     HeapCell::Str(a) => {
-                        if self.s.get_functor(a) == functor {
+                        if self.s.get_functor(a)? == functor {
                             self.s.s = a + 1;
                             self.s.mode = Mode::Read;
                         } else {
@@ -89,7 +105,36 @@ impl Machine {
                             self.s.fail = true;
                         }
                     }
-                    _ => panic!("Invalid deref in {}", instr),
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s.s = 0;
+                        self.s.fail = true;
+                    }
+                }
+            }
+            Instruction::GetList(reg) => {
+                let addr = self.s.deref(self.e.get(reg)?)?;
+                match self.s.get(addr)? {
+                    HeapCell::Ref(_) => {
+                        let n = self.s.push_with(|n| HeapCell::Lis(n + 1));
+                        self.s.bind(addr, n)?;
+                        self.s.mode = Mode::Write;
+                    }
+                    HeapCell::Lis(a) => {
+                        self.s.s = a;
+                        self.s.mode = Mode::Read;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => {
+                        self.s.s = 0;
+                        self.s.fail = true;
+                    }
                 }
             }
             Instruction::UnifyVariable(reg) => {
@@ -106,18 +151,38 @@ impl Machine {
             Instruction::UnifyValue(reg) => {
                 match self.s.mode {
                     Mode::Read => {
-                        let a1 = self.e[reg];
+                        let a1 = self.e.get(reg)?;
                         let a2 = self.s.s;
-                        self.unify(a1, a2);
+                        self.unify(a1, a2)?;
                     }
                     Mode::Write => {
-                        let val = self.s.get(self.e[reg]);
+                        let val = self.s.get(self.e.get(reg)?)?;
                         self.s.push(val);
                     }
                 }
                 self.s.s += 1;
             }
+            Instruction::UnifyConstant(c) => {
+                match self.s.mode {
+                    Mode::Read => {
+                        let addr = self.s.deref(self.s.s)?;
+                        match self.s.get(addr)? {
+                            HeapCell::Ref(_) => {
+                                let n = self.s.push(c.cell());
+                                self.s.bind(addr, n)?;
+                            }
+                            cell if cell == c.cell() => {}
+                            _ => self.s.fail = true,
+                        }
+                    }
+                    Mode::Write => {
+                        self.s.push(c.cell());
+                    }
+                }
+                self.s.s += 1;
+            }
         }
+        Ok(())
     }
 
     /// Unifies the values at the two addresses.
@@ -125,16 +190,16 @@ impl Machine {
     /// This operates as a breadth-first-search, checking (and recursively
     /// searching) through any functors that unify, and simply `bind`ing any
     /// variables.
-    fn unify(&mut self, a1: usize, a2: usize) {
+    fn unify(&mut self, a1: usize, a2: usize) -> Result<(), MachineError> {
         let mut pdl = vec![a1, a2];
         while !pdl.is_empty() && !self.s.fail {
-            let d1 = self.s.deref(pdl.pop().unwrap());
-            let d2 = self.s.deref(pdl.pop().unwrap());
+            let d1 = self.s.deref(pdl.pop().unwrap())?;
+            let d2 = self.s.deref(pdl.pop().unwrap())?;
             if d1 != d2 {
-                match (self.s.get(d1), self.s.get(d2)) {
+                match (self.s.get(d1)?, self.s.get(d2)?) {
                     (HeapCell::Str(v1), HeapCell::Str(v2)) => {
-                        let f1 = self.s.get_functor(v1);
-                        let f2 = self.s.get_functor(v2);
+                        let f1 = self.s.get_functor(v1)?;
+                        let f2 = self.s.get_functor(v2)?;
                         if f1 == f2 {
                             for i in 1..(f1.1 + 1) {
                                 pdl.push(v1 + i);
@@ -142,13 +207,63 @@ impl Machine {
                             }
                         } else {
                             self.s.fail = true;
-                            return;
+                            return Ok(());
+                        }
+                    }
+                    (HeapCell::Lis(v1), HeapCell::Lis(v2)) => {
+                        for i in 0..2 {
+                            pdl.push(v1 + i);
+                            pdl.push(v2 + i);
+                        }
+                    }
+                    (HeapCell::Ref(_), _) | (_, HeapCell::Ref(_)) => {
+                        if self.occurs_check
+                            && (self.s.occurs(d1, HeapCell::Ref(d2))?
+                                || self.s.occurs(d2, HeapCell::Ref(d1))?)
+                        {
+                            self.s.fail = true;
+                            return Ok(());
                         }
+                        self.s.bind(d1, d2)?
+                    }
+                    (c1, c2) if c1.is_constant() && c1 == c2 => {}
+                    _ => {
+                        self.s.fail = true;
+                        return Ok(());
                     }
-                    _ => self.s.bind(d1, d2),
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl Machine {
+    /// Reads the values of the query variables out of their registers.
+    fn extract_bindings(
+        &self,
+        vars: HashMap<Variable, usize>,
+    ) -> Result<HashMap<Variable, Term>, Error> {
+        // Unbound variables are named after a query variable that refers to
+        // them.
+        let mut names = HashMap::new();
+        for (&var, &reg) in &vars {
+            let addr = self.s.deref(self.e.get(reg)?)?;
+            if self.s.get(addr)?.is_ref() {
+                names.insert(addr, var);
+            }
+        }
+
+        let mut extractor = Extractor::new(&self.s, names);
+        let mut bindings = vars
+            .into_iter()
+            .map(|(var, reg)| {
+                let cell = self.s.get(self.e.get(reg)?)?;
+                Ok((var, extractor.extract(cell, Some(var))?))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        bindings.extend(extractor.into_bindings());
+        Ok(bindings)
     }
 }
 
@@ -167,26 +282,37 @@ impl ::Machine for Machine {
         self.e.clear();
         self.s.reset();
 
-        let (query_code, vars) = compile_query(&query);
+        let (query_code, vars) = compile_query(&query, &mut self.s.big_ints);
 
         for instr in query_code.into_iter().chain(self.c.clone()) {
-            self.run_instruction(instr);
+            if let Err(err) = self.run_instruction(instr) {
+                return Box::new(once(Err(err.into())));
+            }
             if self.s.fail {
                 return Box::new(empty());
             }
         }
 
-        let mut names = HashMap::new();
-        Box::new(once(
-            vars.into_iter()
-                .map(|(var, reg)| {
-                    self.s.extract_term(self.e[reg], Some(&names)).map(|val| {
-                        names.insert(self.s.deref(self.e[reg]), var);
-                        (var, val)
-                    })
-                })
-                .collect(),
-        ))
+        Box::new(once(self.extract_bindings(vars)))
+    }
+
+    fn load_program(&mut self, program: &[Clause]) -> Result<(), Error> {
+        let Clause(head, body) = match program {
+            [clause] => clause.clone(),
+            _ => bail!("M0 only supports one clause in the program."),
+        };
+        if !body.is_empty() {
+            bail!("M0 doesn't support implications.");
+        }
+        self.s.big_ints = BigInts::new();
+        let head = Term::Structure(head);
+        self.c = compile_program(&head, &mut self.s.big_ints);
+        self.s.big_ints.keep();
+        Ok(())
+    }
+
+    fn set_occurs_check(&mut self, occurs_check: bool) {
+        self.occurs_check = occurs_check;
     }
 }
 
@@ -199,11 +325,12 @@ mod tests {
 
     fn compile_query_roundtrip(term: Term) -> Term {
         let mut machine = Machine::empty();
-        for instr in compile_query(&term).0 {
-            machine.run_instruction(instr);
+        let query_code = compile_query(&term, &mut machine.s.big_ints).0;
+        for instr in query_code {
+            machine.run_instruction(instr).expect("Machine error");
             assert!(!machine.s.fail);
         }
-        machine.s.extract_term(machine.e[0], None).unwrap()
+        machine.s.extract_term(machine.e[0]).unwrap()
     }
 
     proptest!{
@@ -1,6 +1,6 @@
 use std::collections::HashSet;
 
-use common::{Functor, Term};
+use common::{BigInts, Functor, Term};
 
 use super::control::Instruction;
 use super::flatten::{FlatTerm, FlatTermValue};
@@ -12,7 +12,11 @@ fn compile(
 ) -> Instruction {
     let new = seen.insert(reg);
     if let Some(functor) = functor {
-        Instruction::GetStructure(functor, reg)
+        if functor.is_list() {
+            Instruction::GetList(reg)
+        } else {
+            Instruction::GetStructure(functor, reg)
+        }
     } else if new {
         Instruction::UnifyVariable(reg)
     } else {
@@ -20,17 +24,24 @@ fn compile(
     }
 }
 
-/// Compiles a "program" (a term to unify against) into instructions.
-pub fn compile_program(term: &Term) -> Vec<Instruction> {
-    let flat = FlatTerm::flatten(term).0;
+/// Compiles a "program" (a term to unify against) into instructions. Big
+/// integers in it are added to the given table.
+pub fn compile_program(
+    term: &Term,
+    big_ints: &mut BigInts,
+) -> Vec<Instruction> {
+    let flat = FlatTerm::flatten(term, big_ints).0;
     let mut seen = HashSet::with_capacity(flat.len());
     let mut code = Vec::new();
 
-    for (i, v) in flat.into_iter().enumerate() {
-        if let FlatTermValue::Structure(f, args) = v {
+    for (i, v) in flat.iter().enumerate() {
+        if let FlatTermValue::Structure(f, ref args) = *v {
             code.push(compile(&mut seen, i, Some(Functor(f, args.len()))));
-            for arg in args {
-                code.push(compile(&mut seen, arg, None));
+            for &arg in args {
+                code.push(match flat[arg] {
+                    FlatTermValue::Constant(c) => Instruction::UnifyConstant(c),
+                    _ => compile(&mut seen, arg, None),
+                });
             }
         }
     }
@@ -46,7 +57,7 @@ mod tests {
     #[test]
     fn compiles_example_term() {
         assert_eq!(
-            compile_program(&example_program_term()),
+            compile_program(&example_program_term(), &mut BigInts::new()),
             vec![
                 Instruction::GetStructure(functor!(p / 3), 0),
                 Instruction::UnifyVariable(1),
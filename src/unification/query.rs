@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet};
 
-use common::{Functor, Term, Variable};
+use common::{BigInts, Functor, Term, Variable};
 
 use super::control::Instruction;
 use super::flatten::{FlatTerm, FlatTermValue};
@@ -12,7 +12,11 @@ fn compile(
 ) -> Instruction {
     let new = seen.insert(reg);
     if let Some(functor) = functor {
-        Instruction::PutStructure(functor, reg)
+        if functor.is_list() {
+            Instruction::PutList(reg)
+        } else {
+            Instruction::PutStructure(functor, reg)
+        }
     } else if new {
         Instruction::SetVariable(reg)
     } else {
@@ -32,14 +36,24 @@ fn compile_visitor(
     if let Some(val) = flats[current].take() {
         match val {
             FlatTermValue::Structure(a, ref args) => {
+                let constants = args.iter()
+                    .map(|&arg| match flats[arg] {
+                        Some(FlatTermValue::Constant(c)) => Some(c),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
                 for &arg in args {
                     compile_visitor(code, seen, flats, vars, arg);
                 }
                 code.push(compile(seen, current, Some(Functor(a, args.len()))));
-                for &arg in args {
-                    code.push(compile(seen, arg, None));
+                for (&arg, c) in args.iter().zip(constants) {
+                    code.push(match c {
+                        Some(c) => Instruction::SetConstant(c),
+                        None => compile(seen, arg, None),
+                    });
                 }
             }
+            FlatTermValue::Constant(_) => {}
             FlatTermValue::Variable(Some(var)) => {
                 let overwrote = vars.insert(var, current);
                 assert!(overwrote.is_none());
@@ -50,11 +64,13 @@ fn compile_visitor(
 }
 
 /// Compiles a term into instructions that will construct the term on the
-/// heap, storing the root into the given register number.
+/// heap, storing the root into the given register number. Big integers in it
+/// are added to the given table.
 pub fn compile_query(
     term: &Term,
+    big_ints: &mut BigInts,
 ) -> (Vec<Instruction>, HashMap<Variable, usize>) {
-    let mut flat = FlatTerm::flatten(&term)
+    let mut flat = FlatTerm::flatten(&term, big_ints)
         .0
         .into_iter()
         .map(Some)
@@ -89,7 +105,7 @@ mod tests {
     #[test]
     fn compiles_example_term() {
         assert_eq!(
-            compile_query(&example_query_term()),
+            compile_query(&example_query_term(), &mut BigInts::new()),
             (
                 vec![
                     Instruction::PutStructure(functor!(h / 2), 2),
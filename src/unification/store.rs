@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use failure::Error;
 
-use common::{Functor, HeapCell, Structure, Term, Variable};
+use common::{BigInts, Cells, Extractor, Functor, HeapCell, MachineError, Term};
 
 /// The heap, as well as some "small" data that are not in the numbered
 /// registers.
@@ -10,6 +10,10 @@ use common::{Functor, HeapCell, Structure, Term, Variable};
 pub struct Store {
     heap: Vec<HeapCell>,
 
+    /// The big integers that integer cells, and the constants in the
+    /// machine's code, refer to.
+    pub big_ints: BigInts,
+
     /// Whether unification has failed.
     pub fail: bool,
 
@@ -27,32 +31,34 @@ impl Store {
             fail: false,
             mode: Mode::Write,
             heap: Vec::new(),
+            big_ints: BigInts::new(),
             s: 0,
         }
     }
 
     /// Returns the address a cell derefs to.
-    pub fn deref(&self, addr: usize) -> usize {
-        match self.get(addr) {
-            HeapCell::Ref(a) if a != addr => self.deref(a),
-            _ => addr,
+    pub fn deref(&self, mut addr: usize) -> Result<usize, MachineError> {
+        loop {
+            match self.get(addr)? {
+                HeapCell::Ref(a) if a != addr => addr = a,
+                _ => return Ok(addr),
+            }
         }
     }
 
-    /// Retrieves the value of a heap cell. Panics if the address is out of
-    /// bounds.
-    pub fn get(&self, addr: usize) -> HeapCell {
-        self.heap[addr]
+    /// Retrieves the value of a heap cell.
+    pub fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        self.heap
+            .get(addr)
+            .cloned()
+            .ok_or(MachineError::HeapOverflow(addr))
     }
 
     /// Given the address of a functor cell, returns the functor stored in it.
-    /// Panics if given an address that doesn't point to a functor.
-    pub fn get_functor(&self, addr: usize) -> Functor {
-        match self.get(addr) {
-            HeapCell::Functor(f) => f,
-            cell => {
-                panic!("Expecting {} to be a functor, found {:?}", addr, cell)
-            }
+    pub fn get_functor(&self, addr: usize) -> Result<Functor, MachineError> {
+        match self.get(addr)? {
+            HeapCell::Functor(f) => Ok(f),
+            cell => Err(MachineError::BadFunctorCell(addr, cell)),
         }
     }
 
@@ -81,61 +87,47 @@ impl Store {
     }
 
     /// Resets the heap to its initial state, without ceding its allocation.
+    /// The big integers added since the program was loaded are discarded.
     pub fn reset(&mut self) {
         self.fail = false;
         self.heap.clear();
+        self.big_ints.reset();
         self.s = 0;
     }
 
     /// Binds one term to another. At least one given address must deref to a
     /// self-referential (unbound) `Ref` cell.
-    pub fn bind(&mut self, a: usize, b: usize) {
-        let da = self.deref(a);
-        let db = self.deref(b);
-        if self.get(da).is_ref() {
+    pub fn bind(&mut self, a: usize, b: usize) -> Result<(), MachineError> {
+        let da = self.deref(a)?;
+        let db = self.deref(b)?;
+        if self.get(da)?.is_ref() {
             self.heap[da] = HeapCell::Ref(db);
         } else {
-            assert!(self.get(db).is_ref());
-            self.heap[db] = HeapCell::Ref(da);
-        }
-    }
-
-    /// Extracts a Term from the given HeapCell, which may be from a register.
-    /// If it is not, the heap index of the cell should be passed in as the
-    /// last argument.
-    pub fn extract_term(
-        &self,
-        idx: usize,
-        names: Option<&HashMap<usize, Variable>>,
-    ) -> Result<Term, Error> {
-        match self.heap[idx] {
-            HeapCell::Functor(f) => {
-                bail!("Found functor data {} where a term was expected", f)
-            }
-            HeapCell::Ref(n) => if idx == n {
-                let var = names
-                    .and_then(|names| names.get(&n).map(|v| v.clone()))
-                    .unwrap_or_else(|| {
-                        Variable::from_str(format!("_{}", n)).unwrap()
-                    });
-                Ok(Term::Variable(var))
-            } else {
-                self.extract_term(n, names)
-            },
-            HeapCell::Str(f_idx) => {
-                let Functor(atom, arity) = match self.heap[f_idx] {
-                    HeapCell::Functor(f) => f,
-                    cell => {
-                        bail!("Found {:?} where functor was expected", cell)
-                    }
-                };
-                let mut subterms = vec![];
-                for i in 0..arity {
-                    subterms.push(self.extract_term(f_idx + i + 1, names)?);
+            match self.get(db)? {
+                HeapCell::Ref(_) => self.heap[db] = HeapCell::Ref(da),
+                cell => {
+                    let op = "bind".to_string();
+                    return Err(MachineError::InvalidDeref(cell, op));
                 }
-                Ok(Term::Structure(Structure(atom, subterms)))
             }
         }
+        Ok(())
+    }
+
+    /// Extracts the term at the given address, naming unbound variables
+    /// after their addresses. Raises a type error if the term is cyclic.
+    pub fn extract_term(&self, addr: usize) -> Result<Term, Error> {
+        Extractor::new(self, HashMap::new()).extract_acyclic(self.get(addr)?)
+    }
+}
+
+impl Cells for Store {
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        Store::get(self, addr)
+    }
+
+    fn big_ints(&self) -> &BigInts {
+        &self.big_ints
     }
 }
 
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use common::{Atom, BigInts, Clause, Functor, Structure, Term, Variable};
+
+use super::super::Instruction;
+use super::{extend_program, SwitchTable};
+
+/// Compiles a goal passed to `call/1` that is made of control constructs into
+/// procedures appended to the given code, adding their labels and big
+/// integers. Returns the functor of the procedure that runs the goal.
+///
+/// The procedure's first argument is the choice point that a cut in the goal
+/// discards back to, and the rest are the variables in the goal, in order of
+/// first occurrence. The goal must not contain the variable `Cut`.
+pub fn compile_call(
+    code: &mut Vec<Instruction>,
+    labels: &mut HashMap<Functor, usize>,
+    tables: &mut Vec<SwitchTable>,
+    big_ints: &mut BigInts,
+    goal: &Term,
+) -> Functor {
+    let mut compiler = CallCompiler {
+        name: format!("$call{}", code.len()),
+        cut: Term::Variable(variable!("Cut")),
+        clauses: Vec::new(),
+    };
+    let body = compiler.body(goal);
+    let head = compiler.add_procedure(goal, vec![body]);
+    extend_program(code, labels, tables, big_ints, &compiler.clauses);
+    head.functor()
+}
+
+/// Returns whether goals with the given functor are control constructs,
+/// which `compile_call` compiles rather than calling a procedure for.
+pub fn is_control(Functor(name, arity): Functor) -> bool {
+    matches!(
+        (name.as_ref(), arity),
+        ("!", 0) | ("\\+", 1) | (",", 2) | (";", 2) | ("->", 2)
+    )
+}
+
+/// The state used while compiling a goal for `call/1`.
+struct CallCompiler {
+    /// The prefix of the names of the procedures, which is unique to the
+    /// goal.
+    name: String,
+
+    /// The variable holding the choice point a cut discards back to, which
+    /// every procedure takes as its first argument. Disjunctions and
+    /// if-then-elses get procedures of their own, so passing it along lets a
+    /// cut in them cut through the whole goal.
+    cut: Term,
+
+    /// The clauses of the procedures compiled so far.
+    clauses: Vec<Clause>,
+}
+
+impl CallCompiler {
+    /// Adds a procedure with a clause for each of the given bodies, returning
+    /// a goal that calls it on the variables in the given goal.
+    fn add_procedure(
+        &mut self,
+        goal: &Term,
+        bodies: Vec<Vec<Structure>>,
+    ) -> Structure {
+        let mut vars = Vec::new();
+        term_variables(goal, &mut vars);
+        let mut args = vec![self.cut.clone()];
+        args.extend(vars.into_iter().map(Term::Variable));
+
+        // Procedures for inner goals are added before the procedures using
+        // them, so the number of clauses so far is unique to each one.
+        let name = format!("{}_{}", self.name, self.clauses.len());
+        let head = Structure(Atom::from(name), args);
+        for body in bodies {
+            self.clauses.push(Clause(head.clone(), body));
+        }
+        head
+    }
+
+    /// Compiles a goal into the goals of a clause body.
+    fn body(&mut self, goal: &Term) -> Vec<Structure> {
+        let mut body = Vec::new();
+        self.compile_goal(goal, &mut body);
+        body
+    }
+
+    /// Compiles a goal, adding the goals it becomes to the given clause body.
+    fn compile_goal(&mut self, goal: &Term, body: &mut Vec<Structure>) {
+        let (name, args) = match *goal {
+            Term::Structure(Structure(name, ref args)) => (name, args),
+            _ => return body.push(call(goal)),
+        };
+        match (name.as_ref(), args.len()) {
+            (",", 2) => {
+                self.compile_goal(&args[0], body);
+                self.compile_goal(&args[1], body);
+            }
+            ("!", 0) => {
+                body.push(Structure("$cut".into(), vec![self.cut.clone()]))
+            }
+            (";", 2) => {
+                let first = match args[0] {
+                    Term::Structure(Structure(name, ref ite))
+                        if name.as_ref() == "->" && ite.len() == 2 =>
+                    {
+                        self.if_then(&ite[0], &ite[1])
+                    }
+                    ref goal => self.body(goal),
+                };
+                let second = self.body(&args[1]);
+                body.push(self.add_procedure(goal, vec![first, second]));
+            }
+            ("->", 2) => {
+                let first = self.if_then(&args[0], &args[1]);
+                body.push(self.add_procedure(goal, vec![first]));
+            }
+            ("\\+", 1) => {
+                let fail = Term::Structure(Structure("fail".into(), vec![]));
+                let first = self.if_then(&args[0], &fail);
+                body.push(self.add_procedure(goal, vec![first, vec![]]));
+            }
+            _ => body.push(call(goal)),
+        }
+    }
+
+    /// Compiles the body of a clause for an if-then-else. A cut in the
+    /// condition is local to it, and the cut after it commits to the branch.
+    fn if_then(&mut self, cond: &Term, then: &Term) -> Vec<Structure> {
+        let mut body = vec![call(cond), Structure("!".into(), Vec::new())];
+        self.compile_goal(then, &mut body);
+        body
+    }
+}
+
+/// Returns a goal calling the given goal with `call/1`.
+fn call(goal: &Term) -> Structure {
+    Structure("call".into(), vec![goal.clone()])
+}
+
+/// Adds the variables in a term to the list, in order of first occurrence.
+fn term_variables(term: &Term, vars: &mut Vec<Variable>) {
+    match *term {
+        Term::Variable(var) if !vars.contains(&var) => vars.push(var),
+        Term::Structure(ref s) => for t in &s.1 {
+            term_variables(t, vars);
+        },
+        _ => {}
+    }
+}
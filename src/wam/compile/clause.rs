@@ -0,0 +1,600 @@
+use std::collections::{HashMap, HashSet};
+
+use common::{BigInts, Constant, Structure, Term, Variable};
+use flat::Location;
+
+use super::super::Instruction;
+
+/// Compiles a query or clause into a series of instructions and a list of the
+/// permanent variables, in the order of their environment slots. If the head
+/// is `None`, compiles as a query. Otherwise, compiles as a clause.
+///
+/// Note that if compiled as a query, all named variables are permanent, every
+/// call keeps all of them alive, and the environment is not deallocated after
+/// running the code, so that the bindings can be read out of it.
+///
+/// Big integers are added to the given table.
+pub fn compile(
+    head: Option<&Structure>,
+    body: &[Structure],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, Vec<Variable>) {
+    let (order, info) = find_variables(head, body);
+    let is_query = head.is_none();
+
+    // Permanent variables are ordered by the last goal they appear in, so
+    // that the environment can be trimmed as they stop being needed.
+    let mut permanent = if is_query {
+        order.clone()
+    } else {
+        order
+            .iter()
+            .cloned()
+            .filter(|v| info[v].chunks.len() > 1)
+            .collect::<Vec<_>>()
+    };
+    if !is_query {
+        permanent.sort_by(|a, b| {
+            info[b].last_chunk().cmp(&info[a].last_chunk())
+        });
+    }
+
+    let void = if is_query {
+        HashSet::new()
+    } else {
+        order
+            .into_iter()
+            .filter(|v| info[v].occurrences == 1)
+            .collect()
+    };
+
+    // A cut after the first call can't use the B0 register, since the call
+    // overwrites it, so the level to cut back to is saved in an extra
+    // permanent variable after the others. Trimming is held off until the
+    // last such cut.
+    let num_calls = body.iter().filter(|s| !is_cut(s)).count();
+    let last_deep_cut = body.iter()
+        .enumerate()
+        .skip_while(|&(_, s)| is_cut(s))
+        .filter(|&(_, s)| is_cut(s))
+        .map(|(i, _)| i)
+        .last();
+    let level = last_deep_cut.map(|_| permanent.len());
+    let env_size = permanent.len() + level.map_or(0, |_| 1);
+
+    let mut compiler = Compiler {
+        code: Vec::new(),
+        info,
+        permanent: permanent
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| (v, i))
+            .collect(),
+        void,
+        is_query,
+        locs: HashMap::new(),
+        seen: HashSet::new(),
+        in_structure: HashSet::new(),
+        unsafe_vars: HashSet::new(),
+        next_reg: 0,
+        big_ints,
+    };
+
+    // A chain rule doesn't need an environment, since nothing needs to
+    // survive its only call.
+    let allocate = is_query || num_calls > 1 || level.is_some();
+    if allocate {
+        compiler.code.push(Instruction::Allocate(env_size));
+    }
+    if let Some(level) = level {
+        compiler.code.push(Instruction::GetLevel(level));
+    }
+
+    if let Some(head) = head {
+        // Temporary registers used by the head must not be clobbered by the
+        // argument registers of the first goal, so they start after both.
+        compiler.next_reg = body.iter()
+            .find(|s| !is_cut(s))
+            .map_or(0, |s| s.1.len())
+            .max(head.1.len());
+        compiler.compile_head(head);
+    }
+
+    let mut chunk = 0;
+    for (i, s) in body.iter().enumerate() {
+        if is_cut(s) {
+            compiler.code.push(match level {
+                Some(level) if chunk > 0 => Instruction::Cut(level),
+                _ => Instruction::NeckCut,
+            });
+            continue;
+        }
+
+        compiler.compile_goal(chunk, s);
+        let functor = s.functor();
+        if is_query {
+            compiler.code.push(Instruction::Call(functor, env_size));
+        } else if i == body.len() - 1 {
+            if allocate {
+                compiler.code.push(Instruction::Deallocate);
+            }
+            compiler.code.push(Instruction::Execute(functor));
+        } else if last_deep_cut.map_or(false, |j| j > i) {
+            compiler.code.push(Instruction::Call(functor, env_size));
+        } else {
+            let live = permanent
+                .iter()
+                .filter(|v| compiler.info[v].last_chunk() > chunk)
+                .count();
+            compiler.code.push(Instruction::Call(functor, live));
+        }
+
+        // Temporary variables never live across a call, so the next goal can
+        // start allocating registers from scratch.
+        compiler.locs.retain(|_, loc| match *loc {
+            Location::Local(_) => true,
+            Location::Register(_) => false,
+        });
+        compiler.next_reg = 0;
+        chunk += 1;
+    }
+
+    // A clause whose body doesn't end in a call to be executed returns
+    // directly.
+    if !is_query && body.last().map_or(true, is_cut) {
+        if allocate {
+            compiler.code.push(Instruction::Deallocate);
+        }
+        compiler.code.push(Instruction::Proceed);
+    }
+
+    (compiler.code, permanent)
+}
+
+/// Information about how a variable is used in a clause.
+#[derive(Debug)]
+struct VariableInfo {
+    /// The number of times the variable occurs.
+    occurrences: usize,
+
+    /// The chunks the variable occurs in, in increasing order. Each call ends
+    /// a chunk, so the head and the first goal form chunk 0, and every other
+    /// goal is in its own chunk along with any cuts before it.
+    chunks: Vec<usize>,
+}
+
+impl VariableInfo {
+    /// Returns the last chunk the variable occurs in.
+    fn last_chunk(&self) -> usize {
+        *self.chunks.last().unwrap()
+    }
+}
+
+/// Finds the variables in a clause, in order of first occurrence, along with
+/// information about where they occur.
+fn find_variables(
+    head: Option<&Structure>,
+    body: &[Structure],
+) -> (Vec<Variable>, HashMap<Variable, VariableInfo>) {
+    fn find_term_variables(
+        order: &mut Vec<Variable>,
+        info: &mut HashMap<Variable, VariableInfo>,
+        chunk: usize,
+        t: &Term,
+    ) {
+        match *t {
+            Term::Anonymous | Term::Float(_) | Term::Integer(_) => {}
+            Term::Variable(var) => {
+                let entry = info.entry(var).or_insert_with(|| {
+                    order.push(var);
+                    VariableInfo {
+                        occurrences: 0,
+                        chunks: Vec::new(),
+                    }
+                });
+                entry.occurrences += 1;
+                if entry.chunks.last() != Some(&chunk) {
+                    entry.chunks.push(chunk);
+                }
+            }
+            Term::Structure(Structure(_, ref ts)) => for t in ts {
+                find_term_variables(order, info, chunk, t);
+            },
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut info = HashMap::new();
+    for t in head.iter().flat_map(|s| &s.1) {
+        find_term_variables(&mut order, &mut info, 0, t);
+    }
+    let mut chunk = 0;
+    for s in body {
+        for t in &s.1 {
+            find_term_variables(&mut order, &mut info, chunk, t);
+        }
+        if !is_cut(s) {
+            chunk += 1;
+        }
+    }
+    (order, info)
+}
+
+/// Returns whether a goal is a cut.
+fn is_cut(s: &Structure) -> bool {
+    s.1.is_empty() && s.0.as_ref() == "!"
+}
+
+/// Returns whether a structure should be compiled as a list cell.
+fn is_list(s: &Structure) -> bool {
+    s.1.len() == 2 && s.0.as_ref() == "."
+}
+
+/// The state of the compiler while compiling a single clause or query.
+struct Compiler<'a> {
+    /// The code emitted so far.
+    code: Vec<Instruction>,
+
+    /// Information about every variable in the clause.
+    info: HashMap<Variable, VariableInfo>,
+
+    /// The environment slots of the permanent variables.
+    permanent: HashMap<Variable, usize>,
+
+    /// The variables that occur only once, and so need not be stored
+    /// anywhere.
+    void: HashSet<Variable>,
+
+    /// Whether a query is being compiled.
+    is_query: bool,
+
+    /// The locations assigned to variables.
+    locs: HashMap<Variable, Location>,
+
+    /// The variables for which code has been emitted.
+    seen: HashSet<Variable>,
+
+    /// The variables whose first occurrence is inside a structure, and so
+    /// are known to live on the heap.
+    in_structure: HashSet<Variable>,
+
+    /// The permanent variables whose first occurrence is as an argument to a
+    /// goal, and so may be left pointing into a discarded environment.
+    unsafe_vars: HashSet<Variable>,
+
+    /// The next free temporary register.
+    next_reg: usize,
+
+    /// The table that big integers in the clause are added to.
+    big_ints: &'a mut BigInts,
+}
+
+impl<'a> Compiler<'a> {
+    /// Returns whether a term needs no code to be stored, because it is an
+    /// anonymous variable or a variable that occurs only once.
+    fn is_void(&self, t: &Term) -> bool {
+        match *t {
+            Term::Anonymous => true,
+            Term::Variable(v) => self.void.contains(&v),
+            _ => false,
+        }
+    }
+
+    /// Allocates a fresh temporary register.
+    fn fresh_register(&mut self) -> usize {
+        let reg = self.next_reg;
+        self.next_reg += 1;
+        reg
+    }
+
+    /// Returns the location of a variable, allocating a register for it if it
+    /// is a temporary variable that has not yet been given one.
+    fn location(&mut self, v: Variable) -> Location {
+        if let Some(&loc) = self.locs.get(&v) {
+            return loc;
+        }
+        let loc = if let Some(&n) = self.permanent.get(&v) {
+            Location::Local(n)
+        } else {
+            Location::Register(self.fresh_register())
+        };
+        self.locs.insert(v, loc);
+        loc
+    }
+
+    /// Compiles the head of a clause. Structures are unified breadth-first,
+    /// with nested structures being loaded into temporary registers.
+    fn compile_head(&mut self, head: &Structure) {
+        let mut queue = Vec::new();
+        for (i, t) in head.1.iter().enumerate() {
+            match *t {
+                _ if self.is_void(t) => {}
+                Term::Variable(v) => {
+                    let loc = self.location(v);
+                    self.code.push(if self.seen.insert(v) {
+                        Instruction::GetVariable(loc, i)
+                    } else {
+                        Instruction::GetValue(loc, i)
+                    });
+                }
+                Term::Structure(ref s) if !s.1.is_empty() => {
+                    self.compile_get(i, s, &mut queue)
+                }
+                Term::Anonymous => unreachable!(),
+                _ => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    self.code.push(Instruction::GetConstant(c, i));
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < queue.len() {
+            let (reg, s) = queue[i];
+            self.compile_get(reg, s, &mut queue);
+            i += 1;
+        }
+    }
+
+    /// Compiles the unification of the term in a register with a structure,
+    /// adding any nested structures to the queue.
+    fn compile_get<'t>(
+        &mut self,
+        reg: usize,
+        s: &'t Structure,
+        queue: &mut Vec<(usize, &'t Structure)>,
+    ) {
+        self.code.push(if is_list(s) {
+            Instruction::GetList(reg)
+        } else {
+            Instruction::GetStructure(s.functor(), reg)
+        });
+        let mut voids = 0;
+        for t in &s.1 {
+            if self.is_void(t) {
+                voids += 1;
+                continue;
+            } else if voids > 0 {
+                self.code.push(Instruction::UnifyVoid(voids));
+                voids = 0;
+            }
+
+            match *t {
+                Term::Variable(v) => {
+                    let loc = self.location(v);
+                    self.code.push(if self.seen.insert(v) {
+                        self.in_structure.insert(v);
+                        Instruction::UnifyVariable(loc)
+                    } else if self.in_structure.contains(&v) {
+                        Instruction::UnifyValue(loc)
+                    } else {
+                        Instruction::UnifyLocalValue(loc)
+                    });
+                }
+                Term::Structure(ref sub) if !sub.1.is_empty() => {
+                    let reg = self.fresh_register();
+                    let loc = Location::Register(reg);
+                    self.code.push(Instruction::UnifyVariable(loc));
+                    queue.push((reg, sub));
+                }
+                Term::Anonymous => unreachable!(),
+                _ => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    self.code.push(Instruction::UnifyConstant(c));
+                }
+            }
+        }
+        if voids > 0 {
+            self.code.push(Instruction::UnifyVoid(voids));
+        }
+    }
+
+    /// Compiles the arguments of the goal in the given chunk, not including
+    /// the call itself.
+    fn compile_goal(&mut self, chunk: usize, s: &Structure) {
+        self.next_reg = self.next_reg.max(s.1.len());
+        for (i, t) in s.1.iter().enumerate() {
+            match *t {
+                _ if self.is_void(t) => {
+                    let loc = Location::Register(self.fresh_register());
+                    self.code.push(Instruction::PutVariable(loc, i));
+                }
+                Term::Variable(v) => {
+                    let loc = self.location(v);
+                    let instr = if self.seen.insert(v) {
+                        if !self.is_query && self.permanent.contains_key(&v) {
+                            self.unsafe_vars.insert(v);
+                        }
+                        Instruction::PutVariable(loc, i)
+                    } else {
+                        match loc {
+                            Location::Local(n)
+                                if self.unsafe_vars.contains(&v)
+                                    && self.info[&v].last_chunk() == chunk =>
+                            {
+                                Instruction::PutUnsafeValue(n, i)
+                            }
+                            _ => Instruction::PutValue(loc, i),
+                        }
+                    };
+                    self.code.push(instr);
+                }
+                Term::Structure(ref s) if !s.1.is_empty() => {
+                    self.compile_put(i, s)
+                }
+                Term::Anonymous => unreachable!(),
+                _ => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    self.code.push(Instruction::PutConstant(c, i));
+                }
+            }
+        }
+    }
+
+    /// Compiles code to build a structure on the heap, storing it in the
+    /// given register. Subterms are built before the structures containing
+    /// them.
+    fn compile_put(&mut self, reg: usize, s: &Structure) {
+        let sub_regs = s.1
+            .iter()
+            .map(|t| match *t {
+                Term::Structure(ref sub) if !sub.1.is_empty() => {
+                    let sub_reg = self.fresh_register();
+                    self.compile_put(sub_reg, sub);
+                    Some(sub_reg)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        self.code.push(if is_list(s) {
+            Instruction::PutList(reg)
+        } else {
+            Instruction::PutStructure(s.functor(), reg)
+        });
+        let mut voids = 0;
+        for (t, sub_reg) in s.1.iter().zip(sub_regs) {
+            if self.is_void(t) {
+                voids += 1;
+                continue;
+            } else if voids > 0 {
+                self.code.push(Instruction::SetVoid(voids));
+                voids = 0;
+            }
+
+            match *t {
+                Term::Variable(v) => {
+                    let loc = self.location(v);
+                    self.code.push(if self.seen.insert(v) {
+                        self.in_structure.insert(v);
+                        Instruction::SetVariable(loc)
+                    } else if self.in_structure.contains(&v) {
+                        Instruction::SetValue(loc)
+                    } else {
+                        Instruction::SetLocalValue(loc)
+                    });
+                }
+                Term::Structure(ref sub) if !sub.1.is_empty() => {
+                    let loc = Location::Register(sub_reg.unwrap());
+                    self.code.push(Instruction::SetValue(loc));
+                }
+                Term::Anonymous => unreachable!(),
+                _ => {
+                    let c = Constant::from_term(t, self.big_ints).unwrap();
+                    self.code.push(Instruction::SetConstant(c));
+                }
+            }
+        }
+        if voids > 0 {
+            self.code.push(Instruction::SetVoid(voids));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::Clause;
+    use super::*;
+
+    fn compile_clause(src: &str) -> (Vec<Instruction>, Vec<Variable>) {
+        let Clause(head, body) = Clause::parse(src).unwrap();
+        compile(Some(&head), &body, &mut BigInts::new())
+    }
+
+    #[test]
+    fn trims_environment() {
+        let (code, perms) = compile_clause(
+            "p(X, Y, Z) :- q(U, V, W), r(Y, Z, U), s(U, W), t(X, V).",
+        );
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Allocate(6),
+                Instruction::GetVariable(Location::Local(0), 0),
+                Instruction::GetVariable(Location::Local(4), 1),
+                Instruction::GetVariable(Location::Local(5), 2),
+                Instruction::PutVariable(Location::Local(2), 0),
+                Instruction::PutVariable(Location::Local(1), 1),
+                Instruction::PutVariable(Location::Local(3), 2),
+                Instruction::Call(functor!(q / 3), 6),
+                Instruction::PutValue(Location::Local(4), 0),
+                Instruction::PutValue(Location::Local(5), 1),
+                Instruction::PutValue(Location::Local(2), 2),
+                Instruction::Call(functor!(r / 3), 4),
+                Instruction::PutUnsafeValue(2, 0),
+                Instruction::PutUnsafeValue(3, 1),
+                Instruction::Call(functor!(s / 2), 2),
+                Instruction::PutValue(Location::Local(0), 0),
+                Instruction::PutUnsafeValue(1, 1),
+                Instruction::Deallocate,
+                Instruction::Execute(functor!(t / 2)),
+            ]
+        );
+        assert_eq!(
+            perms,
+            vec![
+                variable!("X"),
+                variable!("V"),
+                variable!("U"),
+                variable!("W"),
+                variable!("Y"),
+                variable!("Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_constants_lists_and_voids() {
+        let (code, perms) =
+            compile_clause("p(a, '.'(X, _), _) :- q(X, '.'(b, '[]')).");
+        assert_eq!(
+            code,
+            vec![
+                Instruction::GetConstant(Constant::Atom(atom!(a)), 0),
+                Instruction::GetList(1),
+                Instruction::UnifyVariable(Location::Register(3)),
+                Instruction::UnifyVoid(1),
+                Instruction::PutValue(Location::Register(3), 0),
+                Instruction::PutList(1),
+                Instruction::SetConstant(Constant::Atom(atom!(b))),
+                Instruction::SetConstant(Constant::Atom("[]".into())),
+                Instruction::Execute(functor!(q / 2)),
+            ]
+        );
+        assert!(perms.is_empty());
+    }
+
+    #[test]
+    fn compiles_cuts() {
+        let (code, _) = compile_clause("p(X) :- !, q(X), !, r.");
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Allocate(1),
+                Instruction::GetLevel(0),
+                Instruction::GetVariable(Location::Register(1), 0),
+                Instruction::NeckCut,
+                Instruction::PutValue(Location::Register(1), 0),
+                Instruction::Call(functor!(q / 1), 1),
+                Instruction::Cut(0),
+                Instruction::Deallocate,
+                Instruction::Execute(functor!(r / 0)),
+            ]
+        );
+
+        let (code, _) = compile_clause("p(X) :- q(X), !.");
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Allocate(1),
+                Instruction::GetLevel(0),
+                Instruction::GetVariable(Location::Register(1), 0),
+                Instruction::PutValue(Location::Register(1), 0),
+                Instruction::Call(functor!(q / 1), 1),
+                Instruction::Cut(0),
+                Instruction::Deallocate,
+                Instruction::Proceed,
+            ]
+        );
+    }
+}
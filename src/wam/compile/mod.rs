@@ -0,0 +1,344 @@
+mod call;
+mod clause;
+
+use std::collections::HashMap;
+
+use common::{BigInts, Clause, Constant, Functor, HeapCell, Structure, Term,
+             Variable};
+
+use super::control::Instruction;
+pub use self::call::{compile_call, is_control};
+use self::clause::compile;
+
+/// A table used by a switch instruction, mapping the constants or functors
+/// it dispatches on to the addresses of their code.
+pub type SwitchTable = HashMap<HeapCell, usize>;
+
+/// Compiles a single clause in a program into a series of instructions. Big
+/// integers in it are added to the given table.
+pub fn compile_clause(
+    clause: &Clause,
+    big_ints: &mut BigInts,
+) -> Vec<Instruction> {
+    let Clause(ref head, ref body) = *clause;
+    compile(Some(head), body, big_ints).0
+}
+
+/// Compiles a program into a series of instructions. Also returns a list of
+/// labels, and the switch tables used by the indexing instructions. Big
+/// integers in the program are added to the given table.
+///
+/// Clauses with the same functor are grouped into a single procedure, in the
+/// order they appear in the program. See `compile_procedure` for how the
+/// clauses of a procedure are chained together.
+pub fn compile_program(
+    program: &[Clause],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, HashMap<Functor, usize>, Vec<SwitchTable>) {
+    let mut code = Vec::new();
+    let mut labels = HashMap::new();
+    let mut tables = Vec::new();
+    extend_program(&mut code, &mut labels, &mut tables, big_ints, program);
+    (code, labels, tables)
+}
+
+/// Compiles a program onto the end of existing code, adding to its labels,
+/// switch tables and big integers.
+fn extend_program(
+    code: &mut Vec<Instruction>,
+    labels: &mut HashMap<Functor, usize>,
+    tables: &mut Vec<SwitchTable>,
+    big_ints: &mut BigInts,
+    program: &[Clause],
+) {
+    let mut procedures: Vec<(Functor, Vec<&Clause>)> = Vec::new();
+    for clause in program {
+        let functor = clause.0.functor();
+        if let Some(procedure) =
+            procedures.iter_mut().find(|&&mut (f, _)| f == functor)
+        {
+            procedure.1.push(clause);
+            continue;
+        }
+        procedures.push((functor, vec![clause]));
+    }
+
+    for (functor, clauses) in procedures {
+        labels.insert(functor, code.len());
+        compile_procedure(code, tables, big_ints, &clauses);
+    }
+}
+
+/// Compiles the clauses of a single procedure.
+///
+/// The clauses are split into groups, where each group is either a single
+/// clause whose first argument is a variable, or a run of clauses whose first
+/// arguments are not variables. The groups are chained together with choice
+/// point instructions, and groups of more than one clause are indexed on
+/// their first argument, so that only the clauses which could match are
+/// tried.
+fn compile_procedure(
+    code: &mut Vec<Instruction>,
+    tables: &mut Vec<SwitchTable>,
+    big_ints: &mut BigInts,
+    clauses: &[&Clause],
+) {
+    let mut groups: Vec<(bool, Vec<&Clause>)> = Vec::new();
+    for &clause in clauses {
+        let indexed = first_argument(clause).is_some_and(is_indexable);
+        match groups.last_mut() {
+            Some(&mut (true, ref mut group)) if indexed => group.push(clause),
+            _ => groups.push((indexed, vec![clause])),
+        }
+    }
+
+    compile_alternatives(code, &groups, |code, (_, group)| {
+        if group.len() == 1 {
+            code.extend(compile_clause(group[0], big_ints));
+        } else {
+            compile_indexed_group(code, tables, big_ints, group);
+        }
+    });
+}
+
+/// Compiles a group of clauses whose first arguments are all constants or
+/// structures, dispatching on the first argument register.
+fn compile_indexed_group(
+    code: &mut Vec<Instruction>,
+    tables: &mut Vec<SwitchTable>,
+    big_ints: &mut BigInts,
+    group: &[&Clause],
+) {
+    let switch = code.len();
+    code.push(Instruction::SwitchOnTerm(switch, None, None, None));
+
+    // If the first argument is unbound, every clause is tried in order.
+    let var = code.len();
+    let addrs = compile_alternatives(code, group, |code, clause| {
+        code.extend(compile_clause(clause, big_ints))
+    });
+
+    let mut constants: Vec<(HeapCell, Vec<usize>)> = Vec::new();
+    let mut lists = Vec::new();
+    let mut structures: Vec<(HeapCell, Vec<usize>)> = Vec::new();
+    for (clause, addr) in group.iter().zip(addrs) {
+        let arg = first_argument(clause).unwrap();
+        let (entries, key) = match (Constant::from_term(arg, big_ints), arg) {
+            (Some(c), _) => (&mut constants, c.cell()),
+            (None, Term::Structure(s)) if s.functor().is_list() => {
+                lists.push(addr);
+                continue;
+            }
+            (None, Term::Structure(s)) => {
+                (&mut structures, HeapCell::Functor(s.functor()))
+            }
+            _ => unreachable!(),
+        };
+        if let Some(entry) = entries.iter_mut().find(|e| e.0 == key) {
+            entry.1.push(addr);
+            continue;
+        }
+        entries.push((key, vec![addr]));
+    }
+
+    let con = compile_switch(
+        code,
+        tables,
+        constants,
+        Instruction::SwitchOnConstant,
+    );
+    let lis = if lists.is_empty() {
+        None
+    } else {
+        Some(compile_try_chain(code, &lists))
+    };
+    let str = compile_switch(
+        code,
+        tables,
+        structures,
+        Instruction::SwitchOnStructure,
+    );
+    code[switch] = Instruction::SwitchOnTerm(var, con, lis, str);
+}
+
+/// Compiles a series of alternatives, chaining them together with
+/// `TryMeElse`, `RetryMeElse`, and `TrustMe` if there is more than one.
+/// Returns the address of the code for each alternative, after its choice
+/// point instruction.
+fn compile_alternatives<T, F>(
+    code: &mut Vec<Instruction>,
+    items: &[T],
+    mut compile_item: F,
+) -> Vec<usize>
+where
+    F: FnMut(&mut Vec<Instruction>, &T),
+{
+    let last = items.len() - 1;
+    let mut prev = None;
+    let mut addrs = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        if last > 0 {
+            // The previous alternative's choice point instruction gets
+            // pointed at this one's. Only the first alternative's is a
+            // `TryMeElse`; the ones after it are `RetryMeElse`.
+            let here = code.len();
+            if let Some(prev) = prev {
+                code[prev] = if i == 1 {
+                    Instruction::TryMeElse(here)
+                } else {
+                    Instruction::RetryMeElse(here)
+                };
+            }
+            code.push(if i == 0 {
+                Instruction::TryMeElse(here)
+            } else if i < last {
+                Instruction::RetryMeElse(here)
+            } else {
+                Instruction::TrustMe
+            });
+            prev = Some(here);
+        }
+        addrs.push(code.len());
+        compile_item(code, item);
+    }
+    addrs
+}
+
+/// Compiles a switch instruction for the given keys and the addresses of the
+/// clauses that match them, returning its address. If there is only one key,
+/// the switch is skipped, and the clauses are jumped to directly.
+fn compile_switch<F: FnOnce(usize) -> Instruction>(
+    code: &mut Vec<Instruction>,
+    tables: &mut Vec<SwitchTable>,
+    entries: Vec<(HeapCell, Vec<usize>)>,
+    make_switch: F,
+) -> Option<usize> {
+    match entries.len() {
+        0 => None,
+        1 => Some(compile_try_chain(code, &entries[0].1)),
+        _ => {
+            let table = entries
+                .into_iter()
+                .map(|(f, addrs)| (f, compile_try_chain(code, &addrs)))
+                .collect();
+            let addr = code.len();
+            code.push(make_switch(tables.len()));
+            tables.push(table);
+            Some(addr)
+        }
+    }
+}
+
+/// Compiles a chain of `Try`, `Retry`, and `Trust` instructions that tries
+/// each of the given clause addresses in turn, returning its address. If
+/// there is only one clause, no chain is needed, and its address is returned.
+fn compile_try_chain(code: &mut Vec<Instruction>, addrs: &[usize]) -> usize {
+    if addrs.len() == 1 {
+        return addrs[0];
+    }
+
+    let start = code.len();
+    let last = addrs.len() - 1;
+    for (i, &addr) in addrs.iter().enumerate() {
+        code.push(if i == 0 {
+            Instruction::Try(addr)
+        } else if i < last {
+            Instruction::Retry(addr)
+        } else {
+            Instruction::Trust(addr)
+        });
+    }
+    start
+}
+
+/// Returns the first argument in the head of a clause, if it has one.
+fn first_argument(clause: &Clause) -> Option<&Term> {
+    (clause.0).1.first()
+}
+
+/// Returns whether a term can be indexed on, which is whether it is a
+/// constant or a structure, as opposed to a variable.
+fn is_indexable(term: &Term) -> bool {
+    !matches!(*term, Term::Anonymous | Term::Variable(_))
+}
+
+/// Compiles a query into a series of instructions. Also returns a list of
+/// variable assignments. Big integers in the query are added to the given
+/// table.
+pub fn compile_query(
+    query: &[Structure],
+    big_ints: &mut BigInts,
+) -> (Vec<Instruction>, Vec<Variable>) {
+    compile(None, query, big_ints)
+}
+
+#[cfg(test)]
+mod tests {
+    use flat::Location;
+    use super::*;
+
+    #[test]
+    fn indexes_first_arguments() {
+        let program = vec![
+            Clause::parse("p(a).").unwrap(),
+            Clause::parse("p(f(X)).").unwrap(),
+            Clause::parse("p(b).").unwrap(),
+            Clause::parse("p(X).").unwrap(),
+        ];
+        assert_eq!(
+            compile_program(&program, &mut BigInts::new()),
+            (
+                vec![
+                    Instruction::TryMeElse(13),
+                    Instruction::SwitchOnTerm(2, Some(12), None, Some(6)),
+                    Instruction::TryMeElse(5),
+                    Instruction::GetConstant(Constant::Atom(atom!(a)), 0),
+                    Instruction::Proceed,
+                    Instruction::RetryMeElse(9),
+                    Instruction::GetStructure(functor!(f / 1), 0),
+                    Instruction::UnifyVoid(1),
+                    Instruction::Proceed,
+                    Instruction::TrustMe,
+                    Instruction::GetConstant(Constant::Atom(atom!(b)), 0),
+                    Instruction::Proceed,
+                    Instruction::SwitchOnConstant(0),
+                    Instruction::TrustMe,
+                    Instruction::Proceed,
+                ],
+                vec![(functor!(p / 1), 0)].into_iter().collect(),
+                vec![
+                    vec![
+                        (HeapCell::Con(atom!(a)), 3),
+                        (HeapCell::Con(atom!(b)), 10),
+                    ].into_iter()
+                        .collect(),
+                ],
+            )
+        );
+    }
+
+    #[test]
+    fn compiles_queries() {
+        let query = vec![
+            Structure::parse("p(X, f(Y, _))").unwrap(),
+            Structure::parse("q(Y, a)").unwrap(),
+        ];
+        assert_eq!(
+            compile_query(&query, &mut BigInts::new()),
+            (
+                vec![
+                    Instruction::Allocate(2),
+                    Instruction::PutVariable(Location::Local(0), 0),
+                    Instruction::PutStructure(functor!(f / 2), 1),
+                    Instruction::SetVariable(Location::Local(1)),
+                    Instruction::SetVoid(1),
+                    Instruction::Call(functor!(p / 2), 2),
+                    Instruction::PutValue(Location::Local(1), 0),
+                    Instruction::PutConstant(Constant::Atom(atom!(a)), 1),
+                    Instruction::Call(functor!(q / 2), 2),
+                ],
+                vec![variable!("X"), variable!("Y")],
+            )
+        );
+    }
+}
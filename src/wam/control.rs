@@ -0,0 +1,269 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use common::{Constant, Functor};
+use flat::Location;
+
+/// A single WAM instruction.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// Places an unbound variable into the given location and the numbered
+    /// argument register. If the location is a permanent variable, the
+    /// variable lives in the environment; otherwise, it lives on the heap.
+    PutVariable(Location, usize),
+
+    /// Copies the value in the given location into the numbered argument
+    /// register.
+    PutValue(Location, usize),
+
+    /// Copies the value in the numbered permanent variable into the numbered
+    /// argument register, first moving it to the heap if it is an unbound
+    /// variable that would be discarded along with the current environment.
+    PutUnsafeValue(usize, usize),
+
+    /// Places the functor part of a structure onto the heap, storing a
+    /// reference to it in the numbered register.
+    PutStructure(Functor, usize),
+
+    /// Stores a reference to a list cell about to be built on the heap in the
+    /// numbered register.
+    PutList(usize),
+
+    /// Stores a constant in the numbered register.
+    PutConstant(Constant, usize),
+
+    /// Stores the value in the numbered argument register into the given
+    /// location.
+    GetVariable(Location, usize),
+
+    /// Unifies the value in the given location with the value in the numbered
+    /// argument register.
+    GetValue(Location, usize),
+
+    /// Inspects the value in the numbered register in preparation for
+    /// unification with a functor.
+    ///
+    /// If the value is the same functor, unification proceeds with the
+    /// machine in read mode, which unifies with each argument.
+    ///
+    /// If the value is an unbound variable, unification proceeds with the
+    /// machine in write mode, which constructs the term on the heap.
+    GetStructure(Functor, usize),
+
+    /// Like `GetStructure`, but for a list cell.
+    GetList(usize),
+
+    /// Unifies the value in the numbered register with a constant.
+    GetConstant(Constant, usize),
+
+    /// Places an unbound variable cell onto the heap, storing a reference to
+    /// it in the given location.
+    SetVariable(Location),
+
+    /// Places a copy of the value in the given location onto the heap.
+    SetValue(Location),
+
+    /// Like `SetValue`, but moves an unbound variable in the environment to
+    /// the heap instead of copying a reference to it.
+    SetLocalValue(Location),
+
+    /// Places a constant onto the heap.
+    SetConstant(Constant),
+
+    /// Places the given number of unbound variable cells onto the heap.
+    SetVoid(usize),
+
+    /// Attempts to unify a variable.
+    UnifyVariable(Location),
+
+    /// Attempts to unify a value.
+    UnifyValue(Location),
+
+    /// Like `UnifyValue`, but in write mode, moves an unbound variable in the
+    /// environment to the heap instead of copying a reference to it.
+    UnifyLocalValue(Location),
+
+    /// Attempts to unify a constant.
+    UnifyConstant(Constant),
+
+    /// Skips the given number of arguments in read mode, or places that many
+    /// unbound variable cells onto the heap in write mode.
+    UnifyVoid(usize),
+
+    /// Allocates an environment with the given number of permanent variables.
+    Allocate(usize),
+
+    /// Discards the current environment, restoring the previous one and its
+    /// continuation point.
+    Deallocate,
+
+    /// Calls the procedure with the given functor. The second argument is the
+    /// number of permanent variables still needed after the call; any others
+    /// may be trimmed from the environment.
+    Call(Functor, usize),
+
+    /// Jumps to the procedure with the given functor, keeping the current
+    /// continuation point. Used for the last goal in a rule.
+    Execute(Functor),
+
+    /// Returns to the continuation point.
+    Proceed,
+
+    /// Creates a choice point whose next alternative clause is at the given
+    /// address, then proceeds with the following instruction.
+    TryMeElse(usize),
+
+    /// Restores the state saved in the current choice point, updates its next
+    /// alternative clause to the given address, and proceeds with the
+    /// following instruction.
+    RetryMeElse(usize),
+
+    /// Restores the state saved in the current choice point, discards it, and
+    /// proceeds with the following instruction.
+    TrustMe,
+
+    /// Discards any choice points created since the current procedure was
+    /// called. Only valid before the first call in a clause.
+    NeckCut,
+
+    /// Stores the choice point to cut back to in the numbered permanent
+    /// variable.
+    GetLevel(usize),
+
+    /// Discards any choice points created since the choice point stored in
+    /// the numbered permanent variable.
+    Cut(usize),
+
+    /// Creates a choice point whose next alternative is the following
+    /// instruction, then jumps to the given address.
+    Try(usize),
+
+    /// Restores the state saved in the current choice point, updates its next
+    /// alternative to the following instruction, and jumps to the given
+    /// address.
+    Retry(usize),
+
+    /// Restores the state saved in the current choice point, discards it, and
+    /// jumps to the given address.
+    Trust(usize),
+
+    /// Marks the goal of a `catch/3` as having exited, so that the `catch/3`
+    /// no longer catches errors. The numbered permanent variable holds the
+    /// variable that is bound to mark it.
+    ExitCatch(usize),
+
+    /// Jumps based on the type of the first argument register: to the first
+    /// address if it is an unbound variable, the second if it is a constant,
+    /// the third if it is a list, and the fourth if it is a structure. If the
+    /// address for the type is `None`, no clause can match, and the machine
+    /// backtracks.
+    SwitchOnTerm(usize, Option<usize>, Option<usize>, Option<usize>),
+
+    /// Jumps to the address that the numbered switch table gives for the
+    /// constant in the first argument register, backtracking if there is
+    /// none. The table is keyed by the cell holding the constant.
+    SwitchOnConstant(usize),
+
+    /// Jumps to the address that the numbered switch table gives for the
+    /// functor of the structure in the first argument register, backtracking
+    /// if there is none. The table is keyed by the functor cell.
+    SwitchOnStructure(usize),
+}
+
+impl Display for Instruction {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Instruction::PutVariable(loc, reg) => {
+                write!(fmt, "put_variable {}, A{}", loc, reg)
+            }
+            Instruction::PutValue(loc, reg) => {
+                write!(fmt, "put_value {}, A{}", loc, reg)
+            }
+            Instruction::PutUnsafeValue(n, reg) => {
+                write!(fmt, "put_unsafe_value Y{}, A{}", n, reg)
+            }
+            Instruction::PutStructure(f, reg) => {
+                write!(fmt, "put_structure {}, X{}", f, reg)
+            }
+            Instruction::PutList(reg) => write!(fmt, "put_list X{}", reg),
+            Instruction::PutConstant(c, reg) => {
+                write!(fmt, "put_constant {}, X{}", c, reg)
+            }
+
+            Instruction::GetVariable(loc, reg) => {
+                write!(fmt, "get_variable {}, A{}", loc, reg)
+            }
+            Instruction::GetValue(loc, reg) => {
+                write!(fmt, "get_value {}, A{}", loc, reg)
+            }
+            Instruction::GetStructure(f, reg) => {
+                write!(fmt, "get_structure {}, X{}", f, reg)
+            }
+            Instruction::GetList(reg) => write!(fmt, "get_list X{}", reg),
+            Instruction::GetConstant(c, reg) => {
+                write!(fmt, "get_constant {}, X{}", c, reg)
+            }
+
+            Instruction::SetVariable(loc) => {
+                write!(fmt, "set_variable {}", loc)
+            }
+            Instruction::SetValue(loc) => write!(fmt, "set_value {}", loc),
+            Instruction::SetLocalValue(loc) => {
+                write!(fmt, "set_local_value {}", loc)
+            }
+            Instruction::SetConstant(c) => write!(fmt, "set_constant {}", c),
+            Instruction::SetVoid(n) => write!(fmt, "set_void {}", n),
+
+            Instruction::UnifyVariable(loc) => {
+                write!(fmt, "unify_variable {}", loc)
+            }
+            Instruction::UnifyValue(loc) => {
+                write!(fmt, "unify_value {}", loc)
+            }
+            Instruction::UnifyLocalValue(loc) => {
+                write!(fmt, "unify_local_value {}", loc)
+            }
+            Instruction::UnifyConstant(c) => {
+                write!(fmt, "unify_constant {}", c)
+            }
+            Instruction::UnifyVoid(n) => write!(fmt, "unify_void {}", n),
+
+            Instruction::Allocate(n) => write!(fmt, "allocate {}", n),
+            Instruction::Deallocate => fmt.write_str("deallocate"),
+            Instruction::Call(f, n) => write!(fmt, "call {}, {}", f, n),
+            Instruction::Execute(f) => write!(fmt, "execute {}", f),
+            Instruction::Proceed => fmt.write_str("proceed"),
+
+            Instruction::TryMeElse(l) => write!(fmt, "try_me_else {}", l),
+            Instruction::RetryMeElse(l) => {
+                write!(fmt, "retry_me_else {}", l)
+            }
+            Instruction::TrustMe => fmt.write_str("trust_me"),
+            Instruction::NeckCut => fmt.write_str("neck_cut"),
+            Instruction::GetLevel(n) => write!(fmt, "get_level Y{}", n),
+            Instruction::Cut(n) => write!(fmt, "cut Y{}", n),
+
+            Instruction::Try(l) => write!(fmt, "try {}", l),
+            Instruction::Retry(l) => write!(fmt, "retry {}", l),
+            Instruction::Trust(l) => write!(fmt, "trust {}", l),
+
+            Instruction::ExitCatch(n) => write!(fmt, "exit_catch Y{}", n),
+
+            Instruction::SwitchOnTerm(v, c, l, s) => {
+                write!(fmt, "switch_on_term {}", v)?;
+                for &label in &[c, l, s] {
+                    match label {
+                        Some(label) => write!(fmt, ", {}", label)?,
+                        None => fmt.write_str(", fail")?,
+                    }
+                }
+                Ok(())
+            }
+            Instruction::SwitchOnConstant(t) => {
+                write!(fmt, "switch_on_constant T{}", t)
+            }
+            Instruction::SwitchOnStructure(t) => {
+                write!(fmt, "switch_on_structure T{}", t)
+            }
+        }
+    }
+}
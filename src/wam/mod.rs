@@ -0,0 +1,2077 @@
+//! The complete Warren Abstract Machine, with constants, lists, anonymous
+//! variables, last call optimization, and environment trimming.
+
+mod compile;
+mod control;
+mod store;
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use failure::Error;
+
+use common::{BigInts, Cells, Clause, Constant, Extractor, Functor, HeapCell,
+             Int, Interrupted, MachineError, PrologError, Structure, Term,
+             Thrown, Unknown, Variable};
+use common::builtins::{record_variables, Args, Builtin, Builtins, Redo};
+use flat::Location;
+
+pub use self::control::Instruction;
+pub use self::compile::{compile_clause, compile_program, compile_query};
+use self::compile::{compile_call, is_control, SwitchTable};
+use self::store::{Heap, Registers, Stack, StackCell, STACK_BASE};
+
+/// The value of the `e` and `b` registers when there is no environment or
+/// choice point, respectively.
+const NONE: usize = usize::MAX;
+
+/// The next alternative of a choice point created by a nondeterministic
+/// built-in predicate, whose further solutions come from Rust rather than
+/// code.
+const REDO: usize = usize::MAX;
+
+/// The next alternative of the choice point created by a call to `catch/3`,
+/// known as a catch frame. It has no alternatives of its own, and only
+/// serves to restore the state at the call when an error is caught.
+const CATCH: usize = usize::MAX - 1;
+
+/// An abstract machine for the full WAM.
+#[derive(Debug)]
+pub struct Machine {
+    /// All stored code.
+    code: Vec<Instruction>,
+
+    /// The address at which the code for the current query starts. Everything
+    /// before it is program code.
+    query_start: usize,
+
+    /// The address just after the code for the current query, which the
+    /// query continues to once it succeeds. The procedures compiled for goals
+    /// passed to `call/1` are placed after the instruction there.
+    query_end: usize,
+
+    /// The procedures compiled for goals passed to `call/1`, by the control
+    /// constructs the goals are made of.
+    calls: HashMap<Term, Functor>,
+
+    /// All code labels.
+    labels: HashMap<Functor, usize>,
+
+    /// The address of the code `catch/3` runs after creating its catch
+    /// frame.
+    catch: usize,
+
+    /// The built-in predicates, which calls check before user code.
+    builtins: Builtins,
+
+    /// What to do when a procedure with no clauses is called.
+    unknown: Unknown,
+
+    /// Whether unification fails rather than binding a variable to a term
+    /// that contains it.
+    occurs_check: bool,
+
+    /// Set from outside the machine to interrupt the running query.
+    interrupt: Arc<AtomicBool>,
+
+    /// The remaining solutions of calls to nondeterministic built-in
+    /// predicates, by the index of the choice point each call created.
+    redos: HashMap<usize, Redo>,
+
+    /// The tables used by `SwitchOnConstant` and `SwitchOnStructure`.
+    switch_tables: Vec<SwitchTable>,
+
+    /// The big integers that integer cells, and the constants in the code,
+    /// refer to.
+    big_ints: BigInts,
+
+    /// The instruction pointer.
+    p: usize,
+
+    /// The stored instruction pointer, aka the continuation point.
+    cp: usize,
+
+    /// The index of the current environment frame on the stack.
+    e: usize,
+
+    /// The index of the most recent choice point on the stack.
+    b: usize,
+
+    /// The value of `b` when the current procedure was called, which is the
+    /// choice point that a cut discards back to.
+    b0: usize,
+
+    /// The heap address at the time the most recent choice point was created.
+    /// Bindings to variables below it must be trailed.
+    hb: usize,
+
+    /// Whether every binding is trailed, which `\=` sets so that it can undo
+    /// the bindings it makes.
+    trail_all: bool,
+
+    /// The unification pointer.
+    s: usize,
+
+    /// The arity of the most recently called procedure, which is the number
+    /// of argument registers a choice point needs to save.
+    num_args: usize,
+
+    /// Whether unification failed, and there were no choice points left to
+    /// backtrack to.
+    fail: bool,
+
+    /// An error raised by a built-in predicate, which ends the query.
+    error: Option<Error>,
+
+    /// Whether the machine is in write mode.
+    write_mode: bool,
+
+    /// The registers.
+    registers: Registers,
+
+    /// The stack, which holds both environment frames and choice points.
+    ///
+    /// An environment frame consists of the previous value of `e`, the
+    /// previous value of `cp`, and the contents of the permanent variables,
+    /// in that order. The number of permanent variables is not stored;
+    /// instead, it is read from the call instruction before the continuation
+    /// point, which allows the frame to shrink as variables stop being used.
+    ///
+    /// A choice point consists of the number of saved arguments, the saved
+    /// arguments, and the saved values of `e`, `cp`, `b`, the address of the
+    /// next alternative clause, the trail pointer, and the heap pointer, in
+    /// that order.
+    stack: Stack,
+
+    /// The trail, which holds the addresses of bindings to undo when
+    /// backtracking.
+    trail: Vec<usize>,
+
+    /// The heap.
+    heap: Heap,
+}
+
+impl Machine {
+    /// Compiles a set of clauses into a program.
+    pub fn new(program: &[Clause]) -> Machine {
+        let mut big_ints = BigInts::new();
+        let (code, labels, switch_tables) =
+            compile_program(program, &mut big_ints);
+        Machine::with_code(code, labels, switch_tables, big_ints)
+    }
+
+    /// Creates a new Machine containing the given code, labels, and switch
+    /// tables, and the big integers the code refers to.
+    pub fn with_code(
+        code: Vec<Instruction>,
+        labels: HashMap<Functor, usize>,
+        switch_tables: Vec<SwitchTable>,
+        big_ints: BigInts,
+    ) -> Machine {
+        let mut machine = Machine {
+            query_start: 0,
+            query_end: 0,
+            calls: HashMap::new(),
+            code: Vec::new(),
+            labels: HashMap::new(),
+            catch: 0,
+            builtins: Builtins::new(),
+            unknown: Unknown::default(),
+            occurs_check: false,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            redos: HashMap::new(),
+            switch_tables: Vec::new(),
+            big_ints: BigInts::new(),
+            p: 0,
+            cp: 0,
+            e: NONE,
+            b: NONE,
+            b0: NONE,
+            hb: 0,
+            trail_all: false,
+            s: 0,
+            num_args: 0,
+            fail: false,
+            error: None,
+            write_mode: false,
+            registers: Registers::new(),
+            stack: Stack::new(),
+            trail: Vec::new(),
+            heap: Heap::new(),
+        };
+        machine.load_code(code, labels, switch_tables, big_ints);
+        machine
+    }
+
+    /// Replaces the loaded code, labels, switch tables, and big integers,
+    /// discarding any query.
+    fn load_code(
+        &mut self,
+        mut code: Vec<Instruction>,
+        labels: HashMap<Functor, usize>,
+        switch_tables: Vec<SwitchTable>,
+        mut big_ints: BigInts,
+    ) {
+        // The rest of `catch/3`, which calls the goal in A1, and saves the
+        // variable in A4 that marks whether the goal has exited.
+        let catch = code.len();
+        code.extend(vec![
+            Instruction::Allocate(1),
+            Instruction::GetVariable(Location::Local(0), 3),
+            Instruction::Call(functor!(call / 1), 1),
+            Instruction::ExitCatch(0),
+            Instruction::Deallocate,
+            Instruction::Proceed,
+        ]);
+
+        self.reset();
+        self.query_start = code.len();
+        self.query_end = code.len();
+        self.code = code;
+        self.labels = labels;
+        self.catch = catch;
+        self.switch_tables = switch_tables;
+        big_ints.keep();
+        self.big_ints = big_ints;
+    }
+
+    /// Resets the state of the machine, unloading any query code.
+    pub fn reset(&mut self) {
+        self.code.truncate(self.query_start);
+        self.query_end = self.query_start;
+
+        // The procedures compiled for `call/1` go with the query. They are
+        // never indexed, since their first argument is always a variable, so
+        // they add no switch tables.
+        let query_start = self.query_start;
+        self.labels.retain(|_, &mut addr| addr < query_start);
+        self.calls.clear();
+        self.p = 0;
+        self.cp = 0;
+        self.e = NONE;
+        self.b = NONE;
+        self.b0 = NONE;
+        self.hb = 0;
+        self.num_args = 0;
+        self.fail = false;
+        self.error = None;
+        self.write_mode = false;
+        self.registers.reset();
+        self.stack.reset();
+        self.trail.clear();
+        self.heap.reset();
+        self.big_ints.reset();
+        self.redos.clear();
+    }
+
+    /// Runs a single instruction. Returns whether the query just succeeded,
+    /// which is signalled by control reaching the end of the query's code.
+    pub fn run_instruction(
+        &mut self,
+        instr: Instruction,
+    ) -> Result<bool, MachineError> {
+        trace!("{}", instr);
+        match instr {
+            Instruction::Call(_, _)
+            | Instruction::Execute(_)
+            | Instruction::Proceed
+            | Instruction::Try(_)
+            | Instruction::Retry(_)
+            | Instruction::Trust(_)
+            | Instruction::SwitchOnTerm(_, _, _, _)
+            | Instruction::SwitchOnConstant(_)
+            | Instruction::SwitchOnStructure(_) => {}
+            _ => self.p += 1,
+        }
+        match instr {
+            Instruction::PutVariable(Location::Register(n), reg) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.registers[n] = HeapCell::Ref(addr);
+                self.registers[reg] = HeapCell::Ref(addr);
+            }
+            Instruction::PutVariable(Location::Local(n), reg) => {
+                let addr = STACK_BASE + self.e + n + 2;
+                let cell = StackCell::Value(HeapCell::Ref(addr));
+                self.stack.set(self.e + n + 2, cell)?;
+                self.registers[reg] = HeapCell::Ref(addr);
+            }
+            Instruction::PutValue(loc, reg) => {
+                self.registers[reg] = self.read(loc)?;
+            }
+            Instruction::PutUnsafeValue(n, reg) => {
+                let cell = self.deref_cell(self.read(Location::Local(n))?)?;
+                self.registers[reg] = match cell {
+                    HeapCell::Ref(a) if a >= STACK_BASE + self.e => {
+                        // The variable lives in the environment that is about
+                        // to be discarded, so it gets moved to the heap.
+                        let h = self.heap.alloc_with(HeapCell::Ref);
+                        self.bind(a, HeapCell::Ref(h))?;
+                        HeapCell::Ref(h)
+                    }
+                    cell => cell,
+                };
+            }
+            Instruction::PutStructure(functor, reg) => {
+                let addr = self.heap.alloc(HeapCell::Functor(functor));
+                self.registers[reg] = HeapCell::Str(addr);
+            }
+            Instruction::PutList(reg) => {
+                self.registers[reg] = HeapCell::Lis(self.heap.next_addr());
+            }
+            Instruction::PutConstant(c, reg) => {
+                self.registers[reg] = c.cell();
+            }
+
+            Instruction::GetVariable(loc, reg) => {
+                let cell = self.registers.get(reg)?;
+                self.write(loc, cell)?;
+            }
+            Instruction::GetValue(loc, reg) => {
+                let c1 = self.read(loc)?;
+                let c2 = self.registers.get(reg)?;
+                self.unify(c1, c2)?;
+            }
+            Instruction::GetStructure(functor, reg) => {
+                match self.deref_cell(self.registers.get(reg)?)? {
+                    HeapCell::Ref(a) => {
+                        let addr = self.heap.alloc(HeapCell::Functor(functor));
+                        self.bind(a, HeapCell::Str(addr))?;
+                        self.write_mode = true;
+                    }
+                    HeapCell::Str(a) => {
+                        if self.heap.get_functor(a)? == functor {
+                            self.s = a + 1;
+                            self.write_mode = false;
+                        } else {
+                            self.fail = true;
+                        }
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => self.fail = true,
+                }
+            }
+            Instruction::GetList(reg) => {
+                match self.deref_cell(self.registers.get(reg)?)? {
+                    HeapCell::Ref(a) => {
+                        let addr = self.heap.next_addr();
+                        self.bind(a, HeapCell::Lis(addr))?;
+                        self.write_mode = true;
+                    }
+                    HeapCell::Lis(a) => {
+                        self.s = a;
+                        self.write_mode = false;
+                    }
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => self.fail = true,
+                }
+            }
+            Instruction::GetConstant(c, reg) => {
+                let cell = self.registers.get(reg)?;
+                self.unify_constant(cell, c)?;
+            }
+
+            Instruction::SetVariable(loc) => {
+                let addr = self.heap.alloc_with(HeapCell::Ref);
+                self.write(loc, HeapCell::Ref(addr))?;
+            }
+            Instruction::SetValue(loc) => {
+                let cell = self.read(loc)?;
+                self.heap.alloc(cell);
+            }
+            Instruction::SetLocalValue(loc) => {
+                let cell = self.read(loc)?;
+                self.push_local_value(cell)?;
+            }
+            Instruction::SetConstant(c) => {
+                self.heap.alloc(c.cell());
+            }
+            Instruction::SetVoid(n) => for _ in 0..n {
+                self.heap.alloc_with(HeapCell::Ref);
+            },
+
+            Instruction::UnifyVariable(loc) => {
+                let cell = if self.write_mode {
+                    HeapCell::Ref(self.heap.alloc_with(HeapCell::Ref))
+                } else {
+                    self.heap.get(self.s)?
+                };
+                self.write(loc, cell)?;
+                self.s += 1;
+            }
+            Instruction::UnifyValue(loc) => {
+                let cell = self.read(loc)?;
+                if self.write_mode {
+                    self.heap.alloc(cell);
+                } else {
+                    let s = self.heap.get(self.s)?;
+                    self.unify(cell, s)?;
+                }
+                self.s += 1;
+            }
+            Instruction::UnifyLocalValue(loc) => {
+                let cell = self.read(loc)?;
+                if self.write_mode {
+                    self.push_local_value(cell)?;
+                } else {
+                    let s = self.heap.get(self.s)?;
+                    self.unify(cell, s)?;
+                }
+                self.s += 1;
+            }
+            Instruction::UnifyConstant(c) => {
+                if self.write_mode {
+                    self.heap.alloc(c.cell());
+                } else {
+                    let s = self.heap.get(self.s)?;
+                    self.unify_constant(s, c)?;
+                }
+                self.s += 1;
+            }
+            Instruction::UnifyVoid(n) => if self.write_mode {
+                for _ in 0..n {
+                    self.heap.alloc_with(HeapCell::Ref);
+                }
+            } else {
+                self.s += n;
+            },
+
+            Instruction::Allocate(n) => {
+                let e = self.stack_top()?;
+                self.stack.truncate(e);
+                self.stack.push(StackCell::Control(self.e));
+                self.stack.push(StackCell::Control(self.cp));
+                for i in 0..n {
+                    let addr = STACK_BASE + e + i + 2;
+                    self.stack.push(StackCell::Value(HeapCell::Ref(addr)));
+                }
+                self.e = e;
+            }
+            Instruction::Deallocate => {
+                let e = self.e;
+                self.cp = self.stack.control(e + 1)?;
+                self.e = self.stack.control(e)?;
+            }
+            Instruction::Call(f, _) => {
+                let next = self.p + 1;
+                self.call(f, next)?;
+            }
+            Instruction::Execute(f) => {
+                let next = self.cp;
+                self.call(f, next)?;
+            }
+            Instruction::Proceed => {
+                self.p = self.cp;
+            }
+
+            Instruction::TryMeElse(l) => self.push_choice_point(l)?,
+            Instruction::RetryMeElse(l) => {
+                let n = self.restore_choice_point()?;
+                self.stack.set(self.b + n + 4, StackCell::Control(l))?;
+            }
+            Instruction::TrustMe => self.pop_choice_point()?,
+            Instruction::NeckCut => {
+                let b0 = self.b0;
+                self.cut(b0)?;
+            }
+            Instruction::GetLevel(n) => {
+                let b0 = StackCell::Control(self.b0);
+                self.stack.set(self.e + n + 2, b0)?;
+            }
+            Instruction::Cut(n) => {
+                let b0 = self.stack.control(self.e + n + 2)?;
+                self.cut(b0)?;
+            }
+
+            Instruction::Try(l) => {
+                let next = self.p + 1;
+                self.push_choice_point(next)?;
+                self.p = l;
+            }
+            Instruction::Retry(l) => {
+                let n = self.restore_choice_point()?;
+                let next = StackCell::Control(self.p + 1);
+                self.stack.set(self.b + n + 4, next)?;
+                self.p = l;
+            }
+            Instruction::Trust(l) => {
+                self.pop_choice_point()?;
+                self.p = l;
+            }
+
+            Instruction::ExitCatch(n) => {
+                let flag = self.stack.value(self.e + n + 2)?;
+                let (b, m) = (self.b, self.stack.control(self.b)?);
+                if self.stack.control(b + m + 4)? == CATCH
+                    && self.stack.value(b + 4)? == flag
+                {
+                    // The goal left no choice points, so its catch frame is
+                    // the most recent one, and can be discarded.
+                    let prev = self.stack.control(b + m + 3)?;
+                    self.cut(prev)?;
+                } else if let HeapCell::Ref(a) = self.deref_cell(flag)? {
+                    self.bind(a, HeapCell::Con(atom!(true)))?;
+                }
+            }
+
+            Instruction::SwitchOnTerm(v, c, l, s) => {
+                let label = match self.deref_cell(self.registers.get(0)?)? {
+                    HeapCell::Ref(_) => Some(v),
+                    HeapCell::Lis(_) => l,
+                    HeapCell::Str(_) => s,
+                    cell @ HeapCell::Functor(_) => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                    _ => c,
+                };
+                self.jump_to(label);
+            }
+            Instruction::SwitchOnConstant(t) => {
+                let label = match self.deref_cell(self.registers.get(0)?)? {
+                    cell if cell.is_constant() => {
+                        self.switch_tables[t].get(&cell).cloned()
+                    }
+                    cell => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                };
+                self.jump_to(label);
+            }
+            Instruction::SwitchOnStructure(t) => {
+                let label = match self.deref_cell(self.registers.get(0)?)? {
+                    HeapCell::Str(a) => {
+                        let cell = HeapCell::Functor(self.heap.get_functor(a)?);
+                        self.switch_tables[t].get(&cell).cloned()
+                    }
+                    cell => {
+                        let op = instr.to_string();
+                        return Err(MachineError::InvalidDeref(cell, op));
+                    }
+                };
+                self.jump_to(label);
+            }
+        }
+        if self.fail {
+            self.backtrack()?;
+        }
+        Ok(self.succeeded())
+    }
+
+    /// Returns whether the query has succeeded, which is signalled by control
+    /// reaching the end of the query's code.
+    fn succeeded(&self) -> bool {
+        !self.fail && self.error.is_none() && self.p == self.query_end
+    }
+
+    /// Reads the cell in the given location.
+    pub fn read(&self, loc: Location) -> Result<HeapCell, MachineError> {
+        match loc {
+            Location::Register(n) => self.registers.get(n),
+            Location::Local(n) => self.stack.value(self.e + n + 2),
+        }
+    }
+
+    /// Runs a single instruction, based on the current instruction pointer.
+    /// Returns whether the query just succeeded.
+    pub fn step(&mut self) -> Result<bool, MachineError> {
+        match self.code.get(self.p) {
+            Some(&instr) => self.run_instruction(instr),
+            None => Err(MachineError::IpOutOfRange(self.p)),
+        }
+    }
+
+    /// Resumes execution at the next alternative clause of the most recent
+    /// choice point. If there are no choice points, the machine fails.
+    pub fn backtrack(&mut self) -> Result<(), MachineError> {
+        loop {
+            if self.b == NONE {
+                self.fail = true;
+                return Ok(());
+            }
+            self.fail = false;
+            let n = self.stack.control(self.b)?;
+            let next = self.stack.control(self.b + n + 4)?;
+            if next == CATCH {
+                let prev = self.stack.control(self.b + n + 3)?;
+                self.set_b(prev)?;
+                continue;
+            } else if next != REDO {
+                self.p = next;
+                return Ok(());
+            }
+            self.restore_choice_point()?;
+            if self.redo()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes a cell to the given location.
+    pub fn write(
+        &mut self,
+        loc: Location,
+        cell: HeapCell,
+    ) -> Result<(), MachineError> {
+        match loc {
+            Location::Register(n) => self.registers[n] = cell,
+            Location::Local(n) => {
+                self.stack.set(self.e + n + 2, StackCell::Value(cell))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds the unbound variable at the given address to a cell, trailing
+    /// the binding if it would need to be undone on backtracking.
+    fn bind(
+        &mut self,
+        addr: usize,
+        cell: HeapCell,
+    ) -> Result<(), MachineError> {
+        match self.get(addr)? {
+            HeapCell::Ref(a) if a == addr => {}
+            cell => {
+                let op = "bind".to_string();
+                return Err(MachineError::InvalidDeref(cell, op));
+            }
+        }
+        self.set(addr, cell)?;
+        let trail = if self.trail_all {
+            true
+        } else if addr < STACK_BASE {
+            addr < self.hb
+        } else {
+            self.b != NONE && addr < STACK_BASE + self.b
+        };
+        if trail {
+            self.trail.push(addr);
+        }
+        Ok(())
+    }
+
+    /// Discards every choice point newer than the given one.
+    fn cut(&mut self, b0: usize) -> Result<(), MachineError> {
+        // NONE is the oldest possible choice point, despite its value.
+        let as_option = |b| if b == NONE { None } else { Some(b) };
+        if as_option(self.b) > as_option(b0) {
+            self.set_b(b0)?;
+            self.redos.retain(|&b, _| as_option(b) <= as_option(b0));
+        }
+        Ok(())
+    }
+
+    /// Extracts the term a cell refers to, naming unbound variables after
+    /// their addresses. Raises a type error if the term is cyclic.
+    fn extract_term(&self, cell: HeapCell) -> Result<Term, Error> {
+        Extractor::new(self, HashMap::new()).extract_acyclic(cell)
+    }
+
+    /// Calls a built-in predicate with the given arity on the argument
+    /// registers, continuing at the given address if it succeeds.
+    fn call_builtin(&mut self, builtin: Builtin, arity: usize, next: usize) {
+        let mut args = BuiltinArgs {
+            machine: self,
+            vars: HashMap::new(),
+        };
+        match builtin {
+            Builtin::Det(predicate) => match predicate(&mut args) {
+                Ok(true) => self.p = next,
+                Ok(false) => self.fail = true,
+                Err(err) => self.throw(err),
+            },
+            Builtin::NonDet(predicate) => match predicate(&mut args) {
+                Ok(solutions) => {
+                    let vars = args.vars;
+                    self.cp = next;
+                    self.num_args = arity;
+                    if let Err(err) = self.push_choice_point(REDO) {
+                        self.error = Some(err.into());
+                        return;
+                    }
+                    self.redos.insert(self.b, Redo { solutions, vars });
+
+                    // The first solution is tried the same way as the rest,
+                    // by backtracking into the choice point.
+                    self.fail = true;
+                }
+                Err(err) => self.throw(err),
+            },
+        }
+    }
+
+    /// Places a term onto the heap, returning a cell referring to it.
+    /// Variables are looked up in the given map, and ones not in it are added
+    /// to it as new unbound variables.
+    fn put_term(
+        &mut self,
+        term: &Term,
+        vars: &mut HashMap<Variable, usize>,
+    ) -> Result<HeapCell, MachineError> {
+        Ok(match *term {
+            Term::Anonymous => HeapCell::Ref(self.heap.alloc_with(HeapCell::Ref)),
+            Term::Variable(var) => match vars.get(&var).cloned() {
+                Some(addr) => match self.deref_cell(HeapCell::Ref(addr))? {
+                    HeapCell::Ref(a) if a >= STACK_BASE => {
+                        // The heap may not refer to the stack, so the
+                        // variable is moved to the heap.
+                        let h = self.heap.alloc_with(HeapCell::Ref);
+                        self.bind(a, HeapCell::Ref(h))?;
+                        vars.insert(var, h);
+                        HeapCell::Ref(h)
+                    }
+                    cell => cell,
+                },
+                None => {
+                    let addr = self.heap.alloc_with(HeapCell::Ref);
+                    vars.insert(var, addr);
+                    HeapCell::Ref(addr)
+                }
+            },
+            Term::Structure(ref s) if !s.1.is_empty() => {
+                let cells = s.1
+                    .iter()
+                    .map(|t| self.put_term(t, vars))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let functor = s.functor();
+                let cell = if functor.is_list() {
+                    HeapCell::Lis(self.heap.next_addr())
+                } else {
+                    HeapCell::Str(self.heap.alloc(HeapCell::Functor(functor)))
+                };
+                for cell in cells {
+                    self.heap.alloc(cell);
+                }
+                cell
+            }
+            _ => Constant::from_term(term, &mut self.big_ints).unwrap().cell(),
+        })
+    }
+
+    /// Reads the cell at the given address, which may be on the heap or the
+    /// stack.
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        if addr < STACK_BASE {
+            self.heap.get(addr)
+        } else {
+            self.stack.value(addr - STACK_BASE)
+        }
+    }
+
+    /// Jumps to the given address, or fails if there is none.
+    fn jump_to(&mut self, label: Option<usize>) {
+        match label {
+            Some(label) => self.p = label,
+            None => self.fail = true,
+        }
+    }
+
+    /// Restores the state saved in the current choice point, then discards
+    /// it.
+    fn pop_choice_point(&mut self) -> Result<(), MachineError> {
+        let n = self.restore_choice_point()?;
+        let b = self.stack.control(self.b + n + 3)?;
+        self.set_b(b)
+    }
+
+    /// Places a term onto the heap, first moving it to the heap if it is an
+    /// unbound variable in the environment.
+    fn push_local_value(&mut self, cell: HeapCell) -> Result<(), MachineError> {
+        match self.deref_cell(cell)? {
+            HeapCell::Ref(a) if a >= STACK_BASE => {
+                let h = self.heap.alloc_with(HeapCell::Ref);
+                self.bind(a, HeapCell::Ref(h))?;
+            }
+            cell => {
+                self.heap.alloc(cell);
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a choice point whose next alternative is at the given address.
+    fn push_choice_point(&mut self, next: usize) -> Result<(), MachineError> {
+        let b = self.stack_top()?;
+        self.stack.truncate(b);
+        self.stack.push(StackCell::Control(self.num_args));
+        for i in 0..self.num_args {
+            let arg = self.registers.get(i)?;
+            self.stack.push(StackCell::Value(arg));
+        }
+        for &n in &[
+            self.e,
+            self.cp,
+            self.b,
+            next,
+            self.trail.len(),
+            self.heap.next_addr(),
+        ] {
+            self.stack.push(StackCell::Control(n));
+        }
+        self.b = b;
+        self.hb = self.heap.next_addr();
+        Ok(())
+    }
+
+    /// Tries the next solution of the nondeterministic built-in predicate
+    /// whose choice point is the most recent one, discarding the choice point
+    /// once there are none left. Returns false if the machine needs to
+    /// backtrack further.
+    fn redo(&mut self) -> Result<bool, MachineError> {
+        let b = self.b;
+        let next = self.redos.get_mut(&b).and_then(|r| r.solutions.next());
+        let res = match next {
+            Some(Ok(terms)) => {
+                let vars = self.redos[&b].vars.clone();
+                BuiltinArgs { machine: self, vars }.unify_all(&terms)
+            }
+            Some(Err(err)) => Err(err),
+            None => {
+                self.redos.remove(&b);
+                let n = self.stack.control(b)?;
+                let prev = self.stack.control(b + n + 3)?;
+                self.set_b(prev)?;
+                return Ok(false);
+            }
+        };
+        match res {
+            Ok(true) => self.p = self.cp,
+            Ok(false) => return Ok(false),
+            Err(err) => self.throw(err),
+        }
+        Ok(true)
+    }
+
+    /// Restores the argument registers, environment, continuation, trail,
+    /// and heap saved in the current choice point. Returns the number of
+    /// saved arguments.
+    fn restore_choice_point(&mut self) -> Result<usize, MachineError> {
+        let b = self.b;
+        let n = self.stack.control(b)?;
+        for i in 0..n {
+            self.registers[i] = self.stack.value(b + i + 1)?;
+        }
+        self.e = self.stack.control(b + n + 1)?;
+        self.cp = self.stack.control(b + n + 2)?;
+        let tr = self.stack.control(b + n + 5)?;
+        self.unwind_trail(tr)?;
+        self.heap.truncate(self.stack.control(b + n + 6)?);
+        self.hb = self.heap.next_addr();
+        Ok(n)
+    }
+
+    /// Makes the given choice point the most recent one.
+    fn set_b(&mut self, b: usize) -> Result<(), MachineError> {
+        self.b = b;
+        self.hb = if b == NONE {
+            0
+        } else {
+            let n = self.stack.control(b)?;
+            self.stack.control(b + n + 6)?
+        };
+        Ok(())
+    }
+
+    /// Writes the cell at the given address, which may be on the heap or the
+    /// stack.
+    fn set(&mut self, addr: usize, cell: HeapCell) -> Result<(), MachineError> {
+        if addr < STACK_BASE {
+            self.heap.set(addr, cell)
+        } else {
+            self.stack.set(addr - STACK_BASE, StackCell::Value(cell))
+        }
+    }
+
+    /// Returns the index of the first stack slot not used by either the
+    /// current environment or the most recent choice point.
+    ///
+    /// The size of the current environment is taken from the call
+    /// instruction before the continuation point, so permanent variables
+    /// which are no longer needed are not kept.
+    fn stack_top(&self) -> Result<usize, MachineError> {
+        let e_top = if self.e == NONE {
+            0
+        } else {
+            match self.cp.checked_sub(1).and_then(|p| self.code.get(p)) {
+                Some(&Instruction::Call(_, n)) => self.e + n + 2,
+                _ => return Err(MachineError::BadContinuation(self.cp)),
+            }
+        };
+        let b_top = if self.b == NONE {
+            0
+        } else {
+            self.b + self.stack.control(self.b)? + 7
+        };
+        Ok(max(e_top, b_top))
+    }
+
+    /// Handles a call to a procedure that has no clauses, as directed by the
+    /// `unknown` flag.
+    fn call_unknown(&mut self, f: Functor) {
+        match self.unknown.call(f) {
+            Ok(()) => self.fail = true,
+            Err(err) => self.throw(err.into()),
+        }
+    }
+
+    /// Calls the procedure with the given functor on the argument registers,
+    /// continuing at the given address if it succeeds.
+    fn call(&mut self, f: Functor, next: usize) -> Result<(), MachineError> {
+        if f == functor!(call / 1) {
+            self.call_goal(next)?;
+        } else if f == functor!(catch / 3) {
+            // The catch frame saves a fresh variable alongside the arguments,
+            // which is bound once the goal exits. Backtracking into the goal
+            // unbinds it again, since the catch/3 is only active while its
+            // goal is running.
+            let flag = self.heap.alloc_with(HeapCell::Ref);
+            self.registers[3] = HeapCell::Ref(flag);
+            self.cp = next;
+            self.num_args = 4;
+            self.push_choice_point(CATCH)?;
+            self.p = self.catch;
+        } else if f == Functor("$cut".into(), 1) {
+            // The procedures compiled for `call/1` cut back to the choice
+            // point the goal was called at, which is passed to them.
+            if let HeapCell::Int(Int::Small(b)) =
+                self.deref_cell(self.registers.get(0)?)?
+            {
+                self.cut(b as usize)?;
+            }
+            self.p = next;
+        } else if let Some(builtin) = self.builtins.get(f) {
+            self.call_builtin(builtin, f.1, next);
+        } else if let Some(&addr) = self.labels.get(&f) {
+            self.cp = next;
+            self.b0 = self.b;
+            self.num_args = f.1;
+            self.p = addr;
+        } else {
+            self.call_unknown(f);
+        }
+        Ok(())
+    }
+
+    /// Calls the goal in the first argument register, as `call/1` does.
+    fn call_goal(&mut self, next: usize) -> Result<(), MachineError> {
+        let goal = self.deref_cell(self.registers.get(0)?)?;
+        if self.is_control(goal)? {
+            return self.call_control(goal, next);
+        }
+        let f = match goal {
+            HeapCell::Con(atom) => Functor(atom, 0),
+            HeapCell::Str(a) => {
+                let f = self.heap.get_functor(a)?;
+                for i in 0..f.1 {
+                    self.registers[i] = self.heap.get(a + i + 1)?;
+                }
+                f
+            }
+            HeapCell::Ref(_) => {
+                self.throw(PrologError::Instantiation.into());
+                return Ok(());
+            }
+            cell => {
+                match self.extract_term(cell) {
+                    Ok(goal) => {
+                        let err = PrologError::Type("callable".into(), goal);
+                        self.throw(err.into());
+                    }
+                    Err(err) => self.error = Some(err),
+                }
+                return Ok(());
+            }
+        };
+        self.call(f, next)
+    }
+
+    /// Calls a goal made of the control constructs `,/2`, `;/2`, `->/2`,
+    /// `\+/1`, and `!/0`. The goal is compiled into procedures the first
+    /// time goals made of the same control constructs are called, which are
+    /// passed the goals the control constructs combine.
+    fn call_control(
+        &mut self,
+        goal: HeapCell,
+        next: usize,
+    ) -> Result<(), MachineError> {
+        let mut goals = Vec::new();
+        let mut open = HashSet::new();
+        let control = self.split_control(goal, &mut goals, &mut open)?;
+        let f = match self.calls.get(&control).cloned() {
+            Some(f) => f,
+            None => {
+                let f = compile_call(
+                    &mut self.code,
+                    &mut self.labels,
+                    &mut self.switch_tables,
+                    &mut self.big_ints,
+                    &control,
+                );
+                self.calls.insert(control, f);
+                f
+            }
+        };
+        self.registers[0] = HeapCell::Int(Int::Small(self.b as i64));
+        for (i, goal) in goals.into_iter().enumerate() {
+            self.registers[i + 1] = goal;
+        }
+        self.call(f, next)
+    }
+
+    /// Returns whether a goal is a control construct.
+    fn is_control(&self, goal: HeapCell) -> Result<bool, MachineError> {
+        Ok(match goal {
+            HeapCell::Con(atom) => is_control(Functor(atom, 0)),
+            HeapCell::Str(a) => is_control(self.heap.get_functor(a)?),
+            _ => false,
+        })
+    }
+
+    /// Splits a goal into the control constructs it is made of, returned as a
+    /// term, and the goals they combine, which are added to `goals` and
+    /// replaced by variables named after their index.
+    ///
+    /// `open` holds the structures being split, so that a cyclic goal is
+    /// only split as far as its first repeated structure, which is left to
+    /// be called on its own.
+    fn split_control(
+        &self,
+        goal: HeapCell,
+        goals: &mut Vec<HeapCell>,
+        open: &mut HashSet<usize>,
+    ) -> Result<Term, MachineError> {
+        let goal = self.deref_cell(goal)?;
+        match goal {
+            HeapCell::Con(atom) if self.is_control(goal)? => {
+                return Ok(Term::Structure(Structure(atom, Vec::new())));
+            }
+            HeapCell::Str(a) if self.is_control(goal)? && open.insert(a) => {
+                let Functor(name, arity) = self.heap.get_functor(a)?;
+                let mut args = Vec::with_capacity(arity);
+                for i in 1..(arity + 1) {
+                    let arg = self.heap.get(a + i)?;
+                    args.push(self.split_control(arg, goals, open)?);
+                }
+                open.remove(&a);
+                return Ok(Term::Structure(Structure(name, args)));
+            }
+            _ => {}
+        }
+        let var = Variable::from_str(format!("G{}", goals.len())).unwrap();
+        goals.push(goal);
+        Ok(Term::Variable(var))
+    }
+
+    /// Throws an error to the innermost active `catch/3` whose catcher
+    /// unifies with it. If there is none, or the error cannot be caught, the
+    /// error ends the query.
+    fn throw(&mut self, err: Error) {
+        let ball = match Thrown::ball(&err) {
+            Some(ball) => ball,
+            None => {
+                self.error = Some(err);
+                return;
+            }
+        };
+        match self.unwind_to_catcher(&ball) {
+            Ok(true) => {}
+            Ok(false) => self.error = Some(err),
+            Err(err) => self.error = Some(err.into()),
+        }
+    }
+
+    /// Discards choice points until reaching an active catch frame whose
+    /// catcher unifies with the ball, then restores the state saved in it and
+    /// calls the recovery goal. Returns false if there is no such frame.
+    fn unwind_to_catcher(&mut self, ball: &Term) -> Result<bool, MachineError> {
+        while self.b != NONE {
+            let b = self.b;
+            let n = self.stack.control(b)?;
+            let prev = self.stack.control(b + n + 3)?;
+            let active = self.stack.control(b + n + 4)? == CATCH
+                && self.deref_cell(self.stack.value(b + 4)?)?.is_ref();
+            if !active {
+                self.cut(prev)?;
+                continue;
+            }
+
+            self.restore_choice_point()?;
+            self.cut(prev)?;
+            let ball = self.put_term(ball, &mut HashMap::new())?;
+            let catcher = self.registers.get(1)?;
+            self.unify(catcher, ball)?;
+            if self.fail {
+                // The bindings made are undone when restoring the next catch
+                // frame.
+                self.fail = false;
+                continue;
+            }
+            self.registers[0] = self.registers.get(2)?;
+            let next = self.cp;
+            self.call_goal(next)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Performs unification between two terms.
+    fn unify(
+        &mut self,
+        c1: HeapCell,
+        c2: HeapCell,
+    ) -> Result<(), MachineError> {
+        let mut pdl = vec![c1, c2];
+        // The pairs of compound terms already unified, so that cyclic terms
+        // do not send unification round in circles.
+        let mut seen = HashSet::new();
+        while !pdl.is_empty() && !self.fail {
+            let d1 = self.deref_cell(pdl.pop().unwrap())?;
+            let d2 = self.deref_cell(pdl.pop().unwrap())?;
+            if d1 == d2 {
+                continue;
+            }
+            match (d1, d2) {
+                (HeapCell::Ref(a1), HeapCell::Ref(a2)) => {
+                    // The newer variable is always bound to the older one.
+                    if a1 < a2 {
+                        self.bind(a2, d1)?;
+                    } else {
+                        self.bind(a1, d2)?;
+                    }
+                }
+                (HeapCell::Ref(a), cell) | (cell, HeapCell::Ref(a)) => {
+                    if self.occurs_check && self.occurs(a, cell)? {
+                        self.fail = true;
+                    } else {
+                        self.bind(a, cell)?;
+                    }
+                }
+                (HeapCell::Lis(v1), HeapCell::Lis(v2)) => {
+                    if !seen.insert((v1, v2)) {
+                        continue;
+                    }
+                    for i in 0..2 {
+                        pdl.push(self.heap.get(v1 + i)?);
+                        pdl.push(self.heap.get(v2 + i)?);
+                    }
+                }
+                (HeapCell::Str(v1), HeapCell::Str(v2)) => {
+                    if !seen.insert((v1, v2)) {
+                        continue;
+                    }
+                    let f1 = self.heap.get_functor(v1)?;
+                    let f2 = self.heap.get_functor(v2)?;
+                    if f1 == f2 {
+                        for i in 1..(f1.1 + 1) {
+                            pdl.push(self.heap.get(v1 + i)?);
+                            pdl.push(self.heap.get(v2 + i)?);
+                        }
+                    } else {
+                        self.fail = true;
+                    }
+                }
+                _ => self.fail = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Unifies a term with a constant.
+    fn unify_constant(
+        &mut self,
+        cell: HeapCell,
+        c: Constant,
+    ) -> Result<(), MachineError> {
+        match self.deref_cell(cell)? {
+            HeapCell::Ref(a) => self.bind(a, c.cell())?,
+            cell if cell == c.cell() => {}
+            _ => self.fail = true,
+        }
+        Ok(())
+    }
+
+    /// Returns whether two terms unify, undoing any bindings made to find
+    /// out.
+    fn unifiable(
+        &mut self,
+        c1: HeapCell,
+        c2: HeapCell,
+    ) -> Result<bool, MachineError> {
+        let trail_all = mem::replace(&mut self.trail_all, true);
+        let tr = self.trail.len();
+        let res = self.unify(c1, c2);
+        let undone = self.unwind_trail(tr);
+        self.trail_all = trail_all;
+        res?;
+        undone?;
+        Ok(!mem::replace(&mut self.fail, false))
+    }
+
+    /// Undoes the bindings recorded in the trail above the given trail
+    /// pointer.
+    fn unwind_trail(&mut self, tr: usize) -> Result<(), MachineError> {
+        let addrs = self.trail.drain(tr..).collect::<Vec<_>>();
+        for addr in addrs {
+            self.set(addr, HeapCell::Ref(addr))?;
+        }
+        Ok(())
+    }
+}
+
+/// The arguments to a call to a built-in predicate.
+struct BuiltinArgs<'a> {
+    machine: &'a mut Machine,
+    vars: HashMap<Variable, usize>,
+}
+
+impl<'a> Args for BuiltinArgs<'a> {
+    fn get(&mut self, n: usize) -> Result<Term, Error> {
+        let cell = self.machine.registers.get(n)?;
+        let term = self.machine.extract_term(cell)?;
+        record_variables(&term, &mut self.vars);
+        Ok(term)
+    }
+
+    fn unify(&mut self, n: usize, term: &Term) -> Result<bool, Error> {
+        let cell = self.machine.put_term(term, &mut self.vars)?;
+        let arg = self.machine.registers.get(n)?;
+        self.machine.unify(arg, cell)?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unify_with_occurs_check(
+        &mut self,
+        n: usize,
+        term: &Term,
+    ) -> Result<bool, Error> {
+        let occurs_check = mem::replace(&mut self.machine.occurs_check, true);
+        let res = self.unify(n, term);
+        self.machine.occurs_check = occurs_check;
+        res
+    }
+
+    fn unify_args(
+        &mut self,
+        a: usize,
+        b: usize,
+        occurs_check: bool,
+    ) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        let occurs_check = self.machine.occurs_check || occurs_check;
+        let occurs_check =
+            mem::replace(&mut self.machine.occurs_check, occurs_check);
+        let res = self.machine.unify(a, b);
+        self.machine.occurs_check = occurs_check;
+        res?;
+        Ok(!self.machine.fail)
+    }
+
+    fn unifiable(&mut self, a: usize, b: usize) -> Result<bool, Error> {
+        let a = self.machine.registers.get(a)?;
+        let b = self.machine.registers.get(b)?;
+        Ok(self.machine.unifiable(a, b)?)
+    }
+}
+
+impl Cells for Machine {
+    fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        Machine::get(self, addr)
+    }
+
+    fn big_ints(&self) -> &BigInts {
+        &self.big_ints
+    }
+}
+
+impl ::Machine for Machine {
+    fn run_query<'a>(
+        &'a mut self,
+        query: Vec<Structure>,
+    ) -> Box<'a + Iterator<Item = Result<HashMap<Variable, Term>, Error>>> {
+        self.reset();
+
+        // Control constructs in the query are called with `call/1`, which
+        // compiles them; a cut is left as it is, and cuts the query.
+        let query = query
+            .into_iter()
+            .map(|goal| {
+                if is_control(goal.functor()) && !goal.1.is_empty() {
+                    Structure("call".into(), vec![Term::Structure(goal)])
+                } else {
+                    goal
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The query is loaded after the program, and its continuation is the
+        // address just after it; reaching it means the query succeeded. The
+        // instruction there is never run, and only keeps the procedures
+        // compiled for `call/1` from starting at that address.
+        let (query_code, vars) = compile_query(&query, &mut self.big_ints);
+        self.p = self.code.len();
+        self.code.extend(query_code);
+        self.query_end = self.code.len();
+        self.code.push(Instruction::Proceed);
+        self.cp = self.query_end;
+
+        Box::new(MachineIter {
+            machine: self,
+            vars,
+            started: false,
+            done: false,
+        })
+    }
+
+    fn load_program(&mut self, program: &[Clause]) -> Result<(), Error> {
+        let mut big_ints = BigInts::new();
+        let (code, labels, switch_tables) =
+            compile_program(program, &mut big_ints);
+        self.load_code(code, labels, switch_tables, big_ints);
+        Ok(())
+    }
+
+    fn builtins_mut(&mut self) -> Option<&mut Builtins> {
+        Some(&mut self.builtins)
+    }
+
+    fn set_unknown(&mut self, unknown: Unknown) {
+        self.unknown = unknown;
+    }
+
+    fn set_occurs_check(&mut self, occurs_check: bool) {
+        self.occurs_check = occurs_check;
+    }
+
+    fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt;
+    }
+}
+
+struct MachineIter<'a> {
+    machine: &'a mut Machine,
+    vars: Vec<Variable>,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> MachineIter<'a> {
+    /// Reads the values of the query variables out of the query's environment
+    /// frame, which is always the first thing on the stack.
+    fn extract_bindings(&self) -> Result<HashMap<Variable, Term>, Error> {
+        let machine = &*self.machine;
+        let cells = (0..self.vars.len())
+            .map(|i| machine.stack.value(i + 2))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Unbound variables are named after the last query variable that
+        // refers to them, so `'='(X, Y)` gives `X = Y` and `Y = Y`.
+        let mut names = HashMap::new();
+        for (&var, &cell) in self.vars.iter().zip(&cells) {
+            if let HeapCell::Ref(addr) = machine.deref_cell(cell)? {
+                names.insert(addr, var);
+            }
+        }
+
+        let mut extractor = Extractor::new(machine, names);
+        let mut bindings = self
+            .vars
+            .iter()
+            .zip(cells)
+            .map(|(&var, cell)| {
+                Ok((var, extractor.extract(cell, Some(var))?))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        bindings.extend(extractor.into_bindings());
+        Ok(bindings)
+    }
+}
+
+impl<'a> Iterator for MachineIter<'a> {
+    type Item = Result<HashMap<Variable, Term>, Error>;
+
+    fn next(&mut self) -> Option<Result<HashMap<Variable, Term>, Error>> {
+        if self.done {
+            return None;
+        }
+
+        // After the first solution, further solutions are found by
+        // backtracking into the most recent choice point.
+        if self.started {
+            if let Err(err) = self.machine.backtrack() {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        }
+        self.started = true;
+
+        // Backtracking into a nondeterministic built-in predicate may find
+        // the next solution without running any instructions.
+        while !self.machine.fail {
+            if let Some(err) = self.machine.error.take() {
+                self.done = true;
+                return Some(Err(err));
+            } else if self.machine.succeeded() {
+                return Some(self.extract_bindings());
+            } else if self.machine.interrupt.swap(false, Ordering::SeqCst) {
+                // The next call picks up where this one stopped, rather than
+                // backtracking.
+                self.started = false;
+                return Some(Err(Interrupted.into()));
+            }
+            if let Err(err) = self.machine.step() {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use Machine as MachineTrait;
+    use common::{parsers, Atom, ParseError};
+    use super::*;
+
+    fn run(program: &[&str], query: &str) -> Vec<HashMap<Variable, Term>> {
+        let program = program
+            .iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let results = machine
+            .run_query(vec![Structure::parse(query).unwrap()])
+            .take(10)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        results
+    }
+
+    fn constant(name: &str) -> Term {
+        Term::Structure(Structure(Atom::from(name), vec![]))
+    }
+
+    fn list(items: Vec<Term>) -> Term {
+        items.into_iter().rev().fold(constant("[]"), |tail, head| {
+            Term::Structure(Structure(".".into(), vec![head, tail]))
+        })
+    }
+
+    #[test]
+    fn appends_lists() {
+        let program = [
+            "append('[]', L, L).",
+            "append('.'(H, T), L, '.'(H, R)) :- append(T, L, R).",
+        ];
+        let results = run(
+            &program,
+            "append(X, Y, '.'(a, '.'(b, '[]')))",
+        );
+        let results = results
+            .into_iter()
+            .map(|r| (r[&variable!("X")].clone(), r[&variable!("Y")].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                (list(vec![]), list(vec![constant("a"), constant("b")])),
+                (list(vec![constant("a")]), list(vec![constant("b")])),
+                (list(vec![constant("a"), constant("b")]), list(vec![])),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_trimmed_variables_alive() {
+        // Y and Z are trimmed from the environment of p before the calls to
+        // q and r, so their bindings must have been moved to the heap.
+        let program = [
+            "p(X) :- q(Y), r(Z, Y), s(f(Z), X).",
+            "q(a).",
+            "r(b, a).",
+            "s(A, A).",
+        ];
+        assert_eq!(
+            run(&program, "p(R)"),
+            vec![
+                vec![(
+                    variable!("R"),
+                    Term::Structure(Structure(
+                        atom!(f),
+                        vec![constant("b")],
+                    )),
+                )].into_iter()
+                    .collect(),
+            ]
+        );
+    }
+
+    #[test]
+    fn indexing_avoids_choice_points() {
+        let program = [
+            "evalBool(false, false).",
+            "evalBool(true, true).",
+            "evalBool(and(X, Y), true) :-
+                evalBool(X, true),
+                evalBool(Y, true).",
+            "evalBool(not(X), true) :- evalBool(X, false).",
+            "evalBool(not(X), false) :- evalBool(X, true).",
+        ].iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let results = machine
+            .run_query(vec![
+                Structure::parse("evalBool(and(true, and(true, true)), R)")
+                    .unwrap(),
+            ])
+            .next()
+            .expect("Query failed")
+            .expect("Failed to run query");
+        assert_eq!(
+            results,
+            vec![(variable!("R"), constant("true"))].into_iter().collect()
+        );
+        assert_eq!(machine.b, NONE);
+    }
+
+    #[test]
+    fn cut_discards_choice_points() {
+        let program = [
+            "member(a).",
+            "member(b).",
+            "member(c).",
+            "first(X) :- member(X), !.",
+            "first(z).",
+            "all(X) :- !, member(X).",
+            "all(z).",
+        ];
+        let xs = |results: Vec<HashMap<Variable, Term>>| {
+            results
+                .into_iter()
+                .map(|mut r| r.remove(&variable!("X")).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(xs(run(&program, "first(X)")), vec![constant("a")]);
+        assert_eq!(
+            xs(run(&program, "all(X)")),
+            vec![constant("a"), constant("b"), constant("c")]
+        );
+    }
+
+    #[test]
+    fn unifies_numbers() {
+        let program = [
+            "digit(0, zero).",
+            "digit(1, one).",
+            "digit(1.0, float).",
+            "digit(123456789012345678901234567890, big).",
+            "pair(X, p(X, 2.5)).",
+        ];
+        let name = |query| {
+            run(&program, query)
+                .into_iter()
+                .map(|mut r| r.remove(&variable!("N")).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(name("digit(1, N)"), vec![constant("one")]);
+        assert_eq!(name("digit(1.0, N)"), vec![constant("float")]);
+        assert_eq!(
+            name("digit(123456789012345678901234567890, N)"),
+            vec![constant("big")]
+        );
+        assert_eq!(name("digit(2, N)"), vec![]);
+        assert_eq!(
+            name("pair(-1, N)"),
+            vec![Term::parse("p(-1, 2.5)").unwrap()]
+        );
+    }
+
+    #[test]
+    fn discards_big_integers_with_the_query() {
+        let program = [Clause::parse("big(123456789012345678901234567890).")
+            .unwrap()];
+        let mut machine = Machine::new(&program);
+        let query = ["big(X)", "Y is X * X", "Z = 98765432109876543210"]
+            .iter()
+            .map(|s| Structure::parse(s).unwrap())
+            .collect();
+        let results = machine
+            .run_query(query)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let n = |s: &str| Term::parse(s).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0][&variable!("Y")],
+            n("15241578753238836750495351562536198787501905199875019052100")
+        );
+        assert_eq!(results[0][&variable!("Z")], n("98765432109876543210"));
+
+        // Only the program's integer is kept.
+        machine.reset();
+        let big = |i| Constant::Int(Int::Big(i)).to_term(&machine.big_ints);
+        assert_eq!(big(0), Ok(n("123456789012345678901234567890")));
+        assert_eq!(big(1), Err(MachineError::BigIntOutOfRange(1)));
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        let program = [
+            "len('[]', 0).",
+            "len('.'(_, T), N) :- len(T, M), N is M + 1.",
+            "between(L, H, L) :- L =< H.",
+            "between(L, H, X) :- L < H, M is L + 1, between(M, H, X).",
+        ];
+        let ns = |query| {
+            run(&program, query)
+                .into_iter()
+                .map(|mut r| r.remove(&variable!("N")).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ns("len('.'(a, '.'(b, '[]')), N)"),
+            vec![Term::Integer(2.into())]
+        );
+        assert_eq!(
+            ns("between(1, 3, N)"),
+            (1..4).map(|n| Term::Integer(n.into())).collect::<Vec<_>>()
+        );
+
+        let program = Vec::new();
+        let mut machine = Machine::new(&program);
+        let query = vec![Structure::parse("is(X, '+'(Y, 1))").unwrap()];
+        let err = machine
+            .run_query(query)
+            .next()
+            .expect("Query failed")
+            .expect_err("Query didn't raise an error");
+        assert_eq!(err.to_string(), "instantiation_error");
+    }
+
+    #[test]
+    fn calls_builtins() {
+        let program = ["p(X) :- var(X), X = a.", "p(b)."];
+        let x = |query| {
+            run(&program, query)
+                .into_iter()
+                .map(|mut r| r.remove(&variable!("X")).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let parse = |s| Term::parse(s).unwrap();
+        assert_eq!(x("p(X)"), vec![constant("a"), constant("b")]);
+        assert_eq!(x("X = f(Y, Y)"), vec![parse("f(Y, Y)")]);
+        assert_eq!(x("f(X, b) \\= f(a, b)"), vec![]);
+        assert_eq!(x("f(X, b) \\= f(a, X)"), vec![parse("X")]);
+        assert_eq!(x("arg(2, f(a, g(Y)), X)"), vec![parse("g(Y)")]);
+        assert_eq!(x("f(a, Y) =.. X"), vec![parse("[f, a, Y]")]);
+        assert_eq!(x("X =.. [g, a]"), vec![parse("g(a)")]);
+        assert_eq!(x("atom(f(X))"), vec![]);
+        match x("functor(X, g, 2)").remove(0) {
+            Term::Structure(Structure(name, args)) => {
+                assert_eq!(name, atom!(g));
+                assert_eq!(args.len(), 2);
+                assert_ne!(args[0], args[1]);
+            }
+            term => panic!("Expected a structure, found {}", term),
+        }
+        match x("copy_term(f(Y, Y, Z), X)").remove(0) {
+            Term::Structure(Structure(_, args)) => {
+                assert_eq!(args[0], args[1]);
+                assert_ne!(args[0], args[2]);
+                assert_ne!(args[0], Term::Variable(variable!("Y")));
+            }
+            term => panic!("Expected a structure, found {}", term),
+        }
+        assert_eq!(x("fail"), vec![]);
+    }
+
+    #[test]
+    fn calls_foreign_predicates() {
+        let people = Rc::new(vec![("alice", 30), ("bob", 25), ("carol", 35)]);
+        let program = [
+            "adult(P) :- person(P, A), A >= 30.",
+            "first(P) :- person(P, _), !.",
+        ].iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        {
+            let builtins = machine.builtins_mut().unwrap();
+            let store = people.clone();
+            builtins.register_nondet(functor!(person / 2), move |args| {
+                let name = match args.get(0)? {
+                    Term::Variable(_) => None,
+                    _ => Some(args.get_atom(0)?),
+                };
+                let solutions = store
+                    .iter()
+                    .filter(|p| name.iter().all(|n| n.as_ref() == p.0))
+                    .map(|&(n, age)| {
+                        Ok(vec![constant(n), Term::Integer(age.into())])
+                    })
+                    .collect::<Vec<_>>();
+                Ok(Box::new(solutions.into_iter()))
+            });
+            let store = people.clone();
+            builtins.register(functor!(age / 2), move |args| {
+                let name = args.get_atom(0)?;
+                match store.iter().find(|p| name.as_ref() == p.0) {
+                    Some(&(_, age)) => {
+                        args.unify(1, &Term::Integer(age.into()))
+                    }
+                    None => Ok(false),
+                }
+            });
+        }
+
+        let mut run = |query, var| {
+            machine
+                .run_query(vec![Structure::parse(query).unwrap()])
+                .map(|r| {
+                    r.map(|mut r| r.remove(&variable!(var)).unwrap())
+                        .map_err(|err| err.to_string())
+                })
+                .collect::<Vec<_>>()
+        };
+        let int = |n: i32| Ok(Term::Integer(n.into()));
+        assert_eq!(run("person(P, A)", "A"), vec![int(30), int(25), int(35)]);
+        assert_eq!(run("person(bob, A)", "A"), vec![int(25)]);
+        assert_eq!(
+            run("adult(P)", "P"),
+            vec![Ok(constant("alice")), Ok(constant("carol"))]
+        );
+        assert_eq!(run("first(P)", "P"), vec![Ok(constant("alice"))]);
+        assert_eq!(run("age(carol, A)", "A"), vec![int(35)]);
+        assert_eq!(run("age(dave, A)", "A"), vec![]);
+        assert_eq!(
+            run("age(P, A)", "A"),
+            vec![Err("instantiation_error".to_string())]
+        );
+        assert_eq!(
+            run("person(1, A)", "A"),
+            vec![Err("type_error(atom, 1)".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_procedures() {
+        let program = vec![Clause::parse("p(X) :- q(X).").unwrap()];
+        let mut machine = Machine::new(&program);
+        let run = |machine: &mut Machine| {
+            machine
+                .run_query(vec![Structure::parse("p(X)").unwrap()])
+                .map(|r| r.map_err(|err| err.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            run(&mut machine),
+            vec![Err("existence_error(procedure, q / 1)".to_string())]
+        );
+        machine.set_unknown(Unknown::Fail);
+        assert_eq!(run(&mut machine), vec![]);
+    }
+
+    #[test]
+    fn reports_machine_errors() {
+        let mut machine = Machine::with_code(
+            Vec::new(),
+            HashMap::new(),
+            Vec::new(),
+            BigInts::new(),
+        );
+        assert_eq!(
+            machine.run_instruction(Instruction::GetValue(
+                Location::Register(0),
+                1,
+            )),
+            Err(MachineError::RegisterOutOfRange(0))
+        );
+        let end = machine.code.len();
+        machine.p = end;
+        assert_eq!(machine.step(), Err(MachineError::IpOutOfRange(end)));
+
+        // A structure cell that points to a constant, rather than a functor.
+        machine.reset();
+        let addr = machine.heap.alloc(HeapCell::Con(atom!(a)));
+        machine.registers[0] = HeapCell::Str(addr);
+        assert_eq!(
+            machine.run_instruction(Instruction::GetStructure(
+                functor!(f / 1),
+                0,
+            )),
+            Err(MachineError::BadFunctorCell(addr, HeapCell::Con(atom!(a))))
+        );
+
+        // A cut whose level is a permanent variable, rather than a saved
+        // choice point, and an environment whose continuation isn't a call.
+        machine.reset();
+        let allocate = Instruction::Allocate(1);
+        assert_eq!(machine.run_instruction(allocate), Ok(false));
+        assert_eq!(
+            machine.run_instruction(Instruction::Cut(0)),
+            Err(MachineError::BadControlCell(2, HeapCell::Ref(STACK_BASE + 2)))
+        );
+        assert_eq!(
+            machine.run_instruction(allocate),
+            Err(MachineError::BadContinuation(0))
+        );
+        machine.reset();
+        machine.e = 0;
+        assert_eq!(
+            machine.run_instruction(Instruction::Deallocate),
+            Err(MachineError::StackOverflow(1))
+        );
+    }
+
+    #[test]
+    fn occurs_check_rejects_cyclic_terms() {
+        let count = |occurs_check, query| {
+            let mut machine = Machine::new(&[]);
+            machine.set_occurs_check(occurs_check);
+            let results = machine
+                .run_query(vec![Structure::parse(query).unwrap()])
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query");
+            results.len()
+        };
+        assert_eq!(count(false, "unify_with_occurs_check(X, f(X))"), 0);
+        assert_eq!(count(false, "unify_with_occurs_check(X, f(Y))"), 1);
+        assert_eq!(count(true, "'='(X, f(g(X)))"), 0);
+        assert_eq!(count(true, "'='(f(X, Y), f(Y, [X]))"), 0);
+        assert_eq!(count(true, "'='(X, X)"), 1);
+
+        // The check terminates on a term that is already cyclic.
+        let mut machine = Machine::new(&[]);
+        let query = vec![
+            Structure::parse("'='(X, f(X))").unwrap(),
+            Structure::parse("unify_with_occurs_check(Y, X)").unwrap(),
+        ];
+        let results = machine
+            .run_query(query)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn loads_programs() {
+        let mut machine = Machine::new(&[Clause::parse("p(a).").unwrap()]);
+        let program = vec![
+            Clause::parse("p(b).").unwrap(),
+            Clause::parse("p(X) :- catch(throw(c), X, true).").unwrap(),
+        ];
+        machine.load_program(&program).unwrap();
+        let results = machine
+            .run_query(vec![Structure::parse("p(X)").unwrap()])
+            .map(|r| r.map(|mut r| r.remove(&variable!("X")).unwrap()))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to run query");
+        assert_eq!(results, vec![constant("b"), constant("c")]);
+    }
+
+    #[test]
+    fn resumes_interrupted_queries() {
+        let program = vec![
+            Clause::parse("nat(z).").unwrap(),
+            Clause::parse("nat(s(X)) :- nat(X).").unwrap(),
+        ];
+        let mut machine = Machine::new(&program);
+        let interrupt = Arc::new(AtomicBool::new(true));
+        machine.set_interrupt(interrupt.clone());
+        let mut solutions = machine
+            .run_query(vec![Structure::parse("nat(X)").unwrap()])
+            .map(|r| match r {
+                Ok(mut bindings) => {
+                    Ok(bindings.remove(&variable!("X")).unwrap().to_string())
+                }
+                Err(err) => Err(err.downcast::<Interrupted>().unwrap()),
+            });
+
+        assert_eq!(solutions.next(), Some(Err(Interrupted)));
+        assert!(!interrupt.load(Ordering::SeqCst));
+        assert_eq!(solutions.next(), Some(Ok("z".to_string())));
+        interrupt.store(true, Ordering::SeqCst);
+        assert_eq!(solutions.next(), Some(Err(Interrupted)));
+        assert_eq!(solutions.next(), Some(Ok("s(z)".to_string())));
+    }
+
+    #[test]
+    fn extracts_cyclic_terms() {
+        let mut machine = Machine::new(&[]);
+        let mut run = |query: &str| {
+            let query = format!("{}.", query);
+            let goals = parsers::query(&query);
+            machine
+                .run_query(ParseError::from_iresult(goals, &query).unwrap())
+                .map(|r| {
+                    let mut bindings = r
+                        .map_err(|err| err.to_string())?
+                        .into_iter()
+                        .map(|(var, term)| format!("{} = {}", var, term))
+                        .collect::<Vec<_>>();
+                    bindings.sort();
+                    Ok(bindings.join(", "))
+                })
+                .collect::<Vec<Result<_, String>>>()
+        };
+        let ok = |s: &str| Ok(s.to_string());
+
+        assert_eq!(run("X = f(X)"), vec![ok("X = f(X)")]);
+        assert_eq!(run("X = [a | X]"), vec![ok("X = [a | X]")]);
+        assert_eq!(
+            run("X = f(Y), Y = g(Y)"),
+            vec![ok("X = f(_S1), Y = _S1, _S1 = g(_S1)")]
+        );
+        assert_eq!(
+            run("X = f(X), catch(X =.. _, error(type_error(T, _), _), true)"),
+            vec![ok("T = acyclic_term, X = f(X)")]
+        );
+    }
+
+    #[test]
+    fn unifies_terms_on_the_heap() {
+        let program = vec![
+            Clause::parse("q(a).").unwrap(),
+            Clause::parse("p(X) :- q(X).").unwrap(),
+        ];
+        let mut machine = Machine::new(&program);
+        let mut count = |occurs_check, query: &[&str]| {
+            machine.set_occurs_check(occurs_check);
+            let query = query
+                .iter()
+                .map(|s| Structure::parse(s).unwrap())
+                .collect();
+            let results = machine
+                .run_query(query)
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to run query");
+            results.len()
+        };
+        assert_eq!(count(false, &["X = a", "p(X)"]), 1);
+        assert_eq!(count(false, &["q(X)", "X = a"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = X"]), 1);
+        assert_eq!(count(false, &["X = f(X)", "Y = f(Y)", "X = Y"]), 1);
+        assert_eq!(count(false, &["f(A, f(A)) \\= f(f(A), A)"]), 0);
+        assert_eq!(count(true, &["f(A, f(A)) \\= f(f(A), A)"]), 1);
+        assert_eq!(count(false, &["f(X, X) \\= f(a, b)", "var(X)"]), 1);
+    }
+
+    #[test]
+    fn catches_thrown_errors() {
+        let program = vec![
+            "p(X) :- X is foo + 1.",
+            "r(X) :- X = 1, throw(r).",
+            "u(1).",
+            "u(_) :- throw(redo).",
+        ].into_iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let mut run = |query: &str, var: &str| {
+            let query = format!("{}.", query);
+            let goals = parsers::query(&query);
+            machine
+                .run_query(ParseError::from_iresult(goals, &query).unwrap())
+                .map(|r| match r {
+                    Ok(mut bindings) => {
+                        Ok(bindings.remove(&variable!(var)).unwrap().to_string())
+                    }
+                    Err(err) => Err(err.to_string()),
+                })
+                .collect::<Vec<_>>()
+        };
+        let ok = |s: &str| Ok(s.to_string());
+
+        assert_eq!(run("catch(throw(oops), E, true)", "E"), vec![ok("oops")]);
+        assert_eq!(
+            run("catch(p(X), error(E, _), true)", "E"),
+            vec![ok("type_error(evaluable, foo / 0)")]
+        );
+        assert_eq!(
+            run("catch(nope, error(existence_error(_, P), _), true)", "P"),
+            vec![ok("nope / 0")]
+        );
+        assert_eq!(
+            run("catch(functor(T, f, 100000), error(E, _), true)", "E"),
+            vec![ok("representation_error(max_arity)")]
+        );
+        assert_eq!(
+            run("catch(catch(throw(a), b, true), E, X = E)", "X"),
+            vec![ok("a")]
+        );
+        assert_eq!(
+            run("catch(throw(a), b, true)", "X"),
+            vec![Err("Uncaught exception: a".to_string())]
+        );
+
+        // Bindings made by the goal are undone before the recovery runs.
+        assert_eq!(run("catch(r(X), r, true)", "X"), vec![ok("X")]);
+
+        // A catch/3 is inactive once its goal exits, and active again after
+        // backtracking into it.
+        assert_eq!(
+            run("catch(u(X), _, true), throw(late)", "X"),
+            vec![Err("Uncaught exception: late".to_string())]
+        );
+        assert_eq!(
+            run("catch(u(X), E, true), X = 2", "E"),
+            vec![ok("redo")]
+        );
+    }
+
+    #[test]
+    fn calls_control_constructs() {
+        let program = vec!["m(1).", "m(2).", "m(3)."]
+            .into_iter()
+            .map(|s| Clause::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let mut machine = Machine::new(&program);
+        let mut run = |query: &str| {
+            let query = format!("{}.", query);
+            let goals = parsers::query(&query);
+            machine
+                .run_query(ParseError::from_iresult(goals, &query).unwrap())
+                .map(|r| r.map(|mut r| r.remove(&variable!("X")).unwrap()))
+                .map(|r| r.expect("Failed to run query").to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run("catch((m(X), !), _, true)"), vec!["1"]);
+        assert_eq!(
+            run("catch((m(X) ; X = 4), E, true)"),
+            vec!["1", "2", "3", "4"]
+        );
+        assert_eq!(run("G = (m(X), X > 1), call(G)"), vec!["2", "3"]);
+        assert_eq!(
+            run("call((m(Y), (Y > 1 -> X = big ; X = small)))"),
+            vec!["small", "big", "big"]
+        );
+        assert_eq!(run("call(((m(X), X > 5) -> true ; X = 0))"), vec!["0"]);
+
+        // A cut in a disjunction cuts through the whole goal, but no further.
+        assert_eq!(run("call((m(X), (X > 1, ! ; fail)))"), vec!["2"]);
+        assert!(run("call((m(X), !, fail ; true)), m(X)").is_empty());
+        assert_eq!(run("call(!), m(X)"), vec!["1", "2", "3"]);
+
+        // Control constructs can be used in the query itself.
+        assert_eq!(run("m(X) ; X = 4"), vec!["1", "2", "3", "4"]);
+        assert_eq!(run("m(X), X > 1 -> true ; X = 0"), vec!["2"]);
+        assert_eq!(run("m(X), \\+ X = 2"), vec!["1", "3"]);
+        assert_eq!(run("\\+ m(4), X = 0"), vec!["0"]);
+        assert!(run("\\+ m(1), X = 0").is_empty());
+    }
+}
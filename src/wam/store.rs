@@ -0,0 +1,202 @@
+use std::ops::{Index, IndexMut};
+
+use common::{Functor, HeapCell, MachineError};
+
+/// The address of the first stack cell. Stack cells share an address space
+/// with heap cells, and are always at higher addresses than any heap cell, so
+/// that binding the newer of two variables never leaves a heap cell pointing
+/// into the stack.
+pub const STACK_BASE: usize = usize::MAX / 2 + 1;
+
+/// The heap, aka the global stack.
+#[derive(Debug)]
+pub struct Heap(Vec<HeapCell>);
+
+impl Heap {
+    /// Constructs a new, empty Heap.
+    pub fn new() -> Heap {
+        Heap(Vec::new())
+    }
+
+    /// Allocates a single heap cell whose value is not address-dependent.
+    /// Returns the address the cell was allocated at.
+    pub fn alloc(&mut self, cell: HeapCell) -> usize {
+        self.alloc_with(|_| cell)
+    }
+
+    /// Allocates a single heap cell. The address the heap cell will be placed
+    /// at is passed to the function. Returns the address the cell was
+    /// allocated at.
+    pub fn alloc_with<F: FnOnce(usize) -> HeapCell>(&mut self, f: F) -> usize {
+        let n = self.0.len();
+        self.0.push(f(n));
+        n
+    }
+
+    /// Gets the cell stored at the given address.
+    pub fn get(&self, addr: usize) -> Result<HeapCell, MachineError> {
+        self.0
+            .get(addr)
+            .cloned()
+            .ok_or(MachineError::HeapOverflow(addr))
+    }
+
+    /// Gets the functor stored at the given address.
+    pub fn get_functor(&self, addr: usize) -> Result<Functor, MachineError> {
+        match self.get(addr)? {
+            HeapCell::Functor(f) => Ok(f),
+            cell => Err(MachineError::BadFunctorCell(addr, cell)),
+        }
+    }
+
+    /// Returns the address that will be returned by the next allocation, aka
+    /// the H register.
+    pub fn next_addr(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Clears the heap.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+
+    /// Writes the cell at the given address.
+    pub fn set(
+        &mut self,
+        addr: usize,
+        cell: HeapCell,
+    ) -> Result<(), MachineError> {
+        match self.0.get_mut(addr) {
+            Some(slot) => {
+                *slot = cell;
+                Ok(())
+            }
+            None => Err(MachineError::HeapOverflow(addr)),
+        }
+    }
+
+    /// Discards every cell at or above the given address.
+    pub fn truncate(&mut self, h: usize) {
+        self.0.truncate(h);
+    }
+}
+
+/// The registers. Unlike in the earlier machines, these hold cells directly
+/// rather than heap addresses, so constants and list cells need not be
+/// allocated on the heap just to be passed as arguments.
+#[derive(Debug)]
+pub struct Registers(Vec<HeapCell>);
+
+impl Registers {
+    /// Constructs a new, empty Registers object.
+    pub fn new() -> Registers {
+        Registers(Vec::new())
+    }
+
+    /// Reads the value of a register.
+    pub fn get(&self, i: usize) -> Result<HeapCell, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::RegisterOutOfRange(i))
+    }
+
+    /// Resets all the registers.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Index<usize> for Registers {
+    type Output = HeapCell;
+
+    fn index(&self, i: usize) -> &HeapCell {
+        &self.0[i]
+    }
+}
+
+impl IndexMut<usize> for Registers {
+    fn index_mut(&mut self, i: usize) -> &mut HeapCell {
+        while self.0.len() <= i {
+            self.0.push(HeapCell::Ref(usize::MAX));
+        }
+        &mut self.0[i]
+    }
+}
+
+/// A single cell on the stack.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StackCell {
+    /// A control value, such as a saved register or a count.
+    Control(usize),
+
+    /// A term, such as a permanent variable or a saved argument register.
+    Value(HeapCell),
+}
+
+/// The stack, which holds environment frames and choice points.
+#[derive(Debug)]
+pub struct Stack(Vec<StackCell>);
+
+impl Stack {
+    /// Constructs a new, empty Stack.
+    pub fn new() -> Stack {
+        Stack(Vec::new())
+    }
+
+    /// Gets the cell stored at the given index.
+    pub fn get(&self, i: usize) -> Result<StackCell, MachineError> {
+        self.0
+            .get(i)
+            .cloned()
+            .ok_or(MachineError::StackOverflow(i))
+    }
+
+    /// Gets the control value stored at the given index.
+    pub fn control(&self, i: usize) -> Result<usize, MachineError> {
+        match self.get(i)? {
+            StackCell::Control(n) => Ok(n),
+            StackCell::Value(cell) => {
+                Err(MachineError::BadControlCell(i, cell))
+            }
+        }
+    }
+
+    /// Gets the term stored at the given index.
+    pub fn value(&self, i: usize) -> Result<HeapCell, MachineError> {
+        match self.get(i)? {
+            StackCell::Control(n) => Err(MachineError::BadValueCell(i, n)),
+            StackCell::Value(cell) => Ok(cell),
+        }
+    }
+
+    /// Pushes a cell onto the top of the stack.
+    pub fn push(&mut self, cell: StackCell) {
+        self.0.push(cell);
+    }
+
+    /// Clears the stack.
+    pub fn reset(&mut self) {
+        self.0.clear();
+    }
+
+    /// Writes the cell at the given index.
+    pub fn set(
+        &mut self,
+        i: usize,
+        cell: StackCell,
+    ) -> Result<(), MachineError> {
+        match self.0.get_mut(i) {
+            Some(slot) => {
+                *slot = cell;
+                Ok(())
+            }
+            None => Err(MachineError::StackOverflow(i)),
+        }
+    }
+
+    /// Discards every cell at or above the given index.
+    pub fn truncate(&mut self, n: usize) {
+        self.0.truncate(n);
+    }
+}